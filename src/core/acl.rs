@@ -0,0 +1,152 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use super::config::{normalize_name, AclConfig, AclsConfig};
+
+/// Networks recursion is allowed from unless configured otherwise: the
+/// loopback and private ranges, so the server is not an open resolver.
+const LOCAL_NETWORKS: &[&str] = &[
+    "127.0.0.0/8",
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+/// What a request asks the server to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Query,
+    Recursion,
+    Transfer,
+    Update,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Acl {
+    allow: Option<Vec<IpNet>>,
+    deny: Vec<IpNet>,
+    /// Names of the TSIG keys allowed
+    keys: Vec<String>,
+}
+
+impl Acl {
+    fn from_config(config: &AclConfig) -> Acl {
+        Acl {
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+            keys: config.keys.iter().map(|key| normalize_name(key)).collect(),
+        }
+    }
+
+    fn allow(networks: Vec<IpNet>) -> Acl {
+        Acl {
+            allow: Some(networks),
+            deny: Vec::new(),
+            keys: Vec::new(),
+        }
+    }
+
+    /// Whether a request from `addr`, signed with `key` if any, is allowed
+    pub(crate) fn allows(&self, addr: IpAddr, key: Option<&str>) -> bool {
+        if self.deny.iter().any(|network| network.contains(&addr)) {
+            return false;
+        }
+        if key.is_some_and(|key| self.keys.iter().any(|allowed| allowed == key)) {
+            return true;
+        }
+        match &self.allow {
+            Some(allow) => allow.iter().any(|network| network.contains(&addr)),
+            None => self.keys.is_empty(),
+        }
+    }
+}
+
+/// The access control of the server, a listener or a zone, one ACL per
+/// operation. Operations without an ACL are allowed.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Acls {
+    query: Option<Acl>,
+    recursion: Option<Acl>,
+    transfer: Option<Acl>,
+    update: Option<Acl>,
+}
+
+impl Acls {
+    pub(crate) fn from_config(config: &AclsConfig) -> Acls {
+        Acls {
+            query: config.query.as_ref().map(Acl::from_config),
+            recursion: config.recursion.as_ref().map(Acl::from_config),
+            transfer: config.transfer.as_ref().map(Acl::from_config),
+            update: config.update.as_ref().map(Acl::from_config),
+        }
+    }
+
+    /// The server wide ACLs, where recursion defaults to the local
+    /// networks and transfers and updates to nobody.
+    pub(crate) fn server(config: &AclsConfig) -> Acls {
+        let mut acls = Acls::from_config(config);
+        acls.recursion.get_or_insert_with(|| {
+            Acl::allow(
+                LOCAL_NETWORKS
+                    .iter()
+                    .map(|network| network.parse().unwrap())
+                    .collect(),
+            )
+        });
+        acls.transfer.get_or_insert_with(|| Acl::allow(Vec::new()));
+        acls.update.get_or_insert_with(|| Acl::allow(Vec::new()));
+        acls
+    }
+
+    pub(crate) fn allows(&self, operation: Operation, addr: IpAddr, key: Option<&str>) -> bool {
+        let acl = match operation {
+            Operation::Query => &self.query,
+            Operation::Recursion => &self.recursion,
+            Operation::Transfer => &self.transfer,
+            Operation::Update => &self.update,
+        };
+        acl.as_ref().is_none_or(|acl| acl.allows(addr, key))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Acls, Operation};
+    use crate::core::config::AclsConfig;
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let config: AclsConfig = toml::from_str(
+            r#"
+            query = { allow = ["10.0.0.0/8"], deny = ["10.0.1.0/24"] }
+            transfer = { allow = ["192.0.2.1/32"] }
+            "#,
+        )
+        .unwrap();
+        let acls = Acls::server(&config);
+
+        assert!(acls.allows(Operation::Query, "10.0.0.1".parse().unwrap(), None));
+        assert!(!acls.allows(Operation::Query, "10.0.1.1".parse().unwrap(), None));
+        assert!(!acls.allows(Operation::Query, "192.0.2.1".parse().unwrap(), None));
+
+        assert!(acls.allows(Operation::Transfer, "192.0.2.1".parse().unwrap(), None));
+        assert!(!acls.allows(Operation::Update, "192.0.2.1".parse().unwrap(), None));
+        assert!(acls.allows(Operation::Recursion, "::1".parse().unwrap(), None));
+        assert!(!acls.allows(Operation::Recursion, "198.51.100.1".parse().unwrap(), None));
+
+        let signed: AclsConfig =
+            toml::from_str(r#"update = { keys = ["dhcp."], deny = ["10.0.1.0/24"] }"#).unwrap();
+        let acls = Acls::server(&signed);
+        assert!(acls.allows(
+            Operation::Update,
+            "192.0.2.1".parse().unwrap(),
+            Some("dhcp")
+        ));
+        assert!(!acls.allows(Operation::Update, "192.0.2.1".parse().unwrap(), None));
+        assert!(!acls.allows(Operation::Update, "10.0.1.1".parse().unwrap(), Some("dhcp")));
+    }
+}
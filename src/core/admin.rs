@@ -0,0 +1,93 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::io::Result;
+use tokio::net::{TcpListener, ToSocketAddrs};
+
+use super::dns::tcp_listener::accept;
+use super::metrics::Metrics;
+use super::reload::Reloader;
+use super::shutdown::Shutdown;
+
+/// Small HTTP API to operate a running server:
+///
+/// - `POST /reload` re-reads the configuration, like SIGHUP does
+/// - `GET /metrics` exposes the server metrics to Prometheus
+pub struct AdminServer {
+    reloader: Arc<Reloader>,
+    metrics: Arc<Metrics>,
+    shutdown: Shutdown,
+}
+
+impl AdminServer {
+    pub fn new(reloader: Arc<Reloader>, metrics: Arc<Metrics>, shutdown: Shutdown) -> AdminServer {
+        AdminServer {
+            reloader,
+            metrics,
+            shutdown,
+        }
+    }
+
+    pub async fn start<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+
+        loop {
+            let (stream, _) = tokio::select! {
+                _ = self.shutdown.triggered() => return Ok(()),
+                accepted = accept(&listener, "admin") => accepted,
+            };
+            let reloader = self.reloader.clone();
+            let metrics = self.metrics.clone();
+            let shutdown = self.shutdown.clone();
+
+            // A reload being answered finishes before exiting
+            self.shutdown.spawn(async move {
+                let service = service_fn(move |request| {
+                    Self::handle(request, reloader.clone(), metrics.clone())
+                });
+                let connection =
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+                tokio::pin!(connection);
+
+                let _ = tokio::select! {
+                    closed = connection.as_mut() => closed,
+                    _ = shutdown.triggered() => {
+                        connection.as_mut().graceful_shutdown();
+                        connection.await
+                    }
+                };
+            });
+        }
+    }
+
+    async fn handle(
+        request: Request<Incoming>,
+        reloader: Arc<Reloader>,
+        metrics: Arc<Metrics>,
+    ) -> std::result::Result<Response<Full<Bytes>>, Infallible> {
+        let response = match (request.method(), request.uri().path()) {
+            (&Method::POST, "/reload") => match reloader.reload_in_background().await {
+                Ok(()) => Self::text(StatusCode::OK, "configuration reloaded\n".to_owned()),
+                Err(e) => Self::text(StatusCode::UNPROCESSABLE_ENTITY, format!("{}\n", e)),
+            },
+            (_, "/reload") => Self::text(StatusCode::METHOD_NOT_ALLOWED, "use POST\n".to_owned()),
+            (&Method::GET, "/metrics") => Self::text(StatusCode::OK, metrics.render()),
+            (_, "/metrics") => Self::text(StatusCode::METHOD_NOT_ALLOWED, "use GET\n".to_owned()),
+            _ => Self::text(StatusCode::NOT_FOUND, "not found\n".to_owned()),
+        };
+
+        Ok(response)
+    }
+
+    fn text(status: StatusCode, body: String) -> Response<Full<Bytes>> {
+        let mut response = Response::new(Full::new(Bytes::from(body)));
+        *response.status_mut() = status;
+        response
+    }
+}
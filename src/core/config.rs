@@ -0,0 +1,1249 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use clap::{Parser, ValueEnum};
+use ipnet::IpNet;
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use super::dns::dnssec::SigningKey;
+use super::dns::message::{Class, Record};
+use super::dns::tls::Certificates;
+use super::dns::validator::trust_anchors;
+
+const DEFAULT_LISTEN: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 1053);
+const DEFAULT_UPSTREAM_PORT: u16 = 53;
+/// Name of the view made of the top level zones and registry
+pub(crate) const DEFAULT_VIEW: &str = "default";
+
+/// Command line flags. Every flag overrides the matching configuration key.
+#[derive(Debug, Parser)]
+#[command(version, about = "Service discovery DNS server")]
+pub struct Cli {
+    /// Path to the TOML configuration file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on for UDP queries, can be repeated (`listen.udp`)
+    #[arg(long)]
+    pub udp: Vec<SocketAddr>,
+
+    /// Address to listen on for TCP queries, can be repeated (`listen.tcp`)
+    #[arg(long)]
+    pub tcp: Vec<SocketAddr>,
+
+    /// Address to listen on for DNS over TLS, can be repeated (`listen.tls`)
+    #[arg(long)]
+    pub tls: Vec<SocketAddr>,
+
+    /// Address to listen on for DNS over HTTPS, can be repeated (`listen.https`)
+    #[arg(long)]
+    pub https: Vec<SocketAddr>,
+
+    /// Address to listen on for DNS over QUIC, can be repeated (`listen.quic`)
+    #[arg(long)]
+    pub quic: Vec<SocketAddr>,
+
+    /// Sockets bound per address with SO_REUSEPORT (`listen.reuse_port`)
+    #[arg(long)]
+    pub reuse_port: Option<usize>,
+
+    /// Upstream resolver, can be repeated (`upstreams`)
+    #[arg(long = "upstream")]
+    pub upstreams: Vec<String>,
+
+    /// Default TTL of authoritative answers (`defaults.ttl`)
+    #[arg(long)]
+    pub ttl: Option<u32>,
+
+    /// Address of the admin HTTP API (`admin.listen`)
+    #[arg(long)]
+    pub admin: Option<SocketAddr>,
+
+    /// Log level (`log_level`)
+    #[arg(long, value_enum)]
+    pub log_level: Option<LogLevel>,
+
+    /// Log output format (`log_format`)
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Log a line for every query answered (`query_log.enabled`)
+    #[arg(long)]
+    pub query_log: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub query_log: QueryLogConfig,
+    pub upstreams: Vec<String>,
+    pub listen: ListenConfig,
+    pub defaults: DefaultsConfig,
+    pub admin: Option<AdminConfig>,
+    pub shutdown: ShutdownConfig,
+    pub registry: Option<RegistryConfig>,
+    pub localities: Vec<LocalityConfig>,
+    pub zones: Vec<ZoneConfig>,
+    pub views: Vec<ViewConfig>,
+    pub acl: AclsConfig,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub keys: Vec<KeyConfig>,
+    pub validation: Option<ValidationConfig>,
+    pub tls: Option<TlsConfig>,
+    pub mdns: Option<MdnsConfig>,
+    pub updates: Option<UpdatesConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    #[serde(deserialize_with = "one_or_many")]
+    pub udp: Vec<SocketAddr>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub tcp: Vec<SocketAddr>,
+    /// DNS over TLS (RFC 7858), usually on port 853
+    #[serde(deserialize_with = "one_or_many")]
+    pub tls: Vec<SocketAddr>,
+    /// DNS over HTTPS (RFC 8484) at `/dns-query`, usually on port 443
+    #[serde(deserialize_with = "one_or_many")]
+    pub https: Vec<SocketAddr>,
+    /// DNS over QUIC (RFC 9250), usually on UDP port 853
+    #[serde(deserialize_with = "one_or_many")]
+    pub quic: Vec<SocketAddr>,
+    /// Sockets bound per address, sharing it through SO_REUSEPORT when more than one
+    pub reuse_port: usize,
+    /// Access control of the requests received on a listen address, the
+    /// ones for addresses not listened on (e.g. replaced on the command
+    /// line) are ignored
+    pub acl: BTreeMap<SocketAddr, AclsConfig>,
+}
+
+/// Who may do what, every operation being allowed when not set. The top
+/// level `acl` only allows recursion from private networks and denies
+/// transfers and updates unless configured.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclsConfig {
+    pub query: Option<AclConfig>,
+    pub recursion: Option<AclConfig>,
+    pub transfer: Option<AclConfig>,
+    pub update: Option<AclConfig>,
+}
+
+/// Networks allowed and denied an operation, `deny` taking precedence.
+/// Every client is allowed when neither `allow` nor `keys` is set, none
+/// when `allow` is empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclConfig {
+    pub allow: Option<Vec<IpNet>>,
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+    /// TSIG keys whose signed requests are allowed from any network not denied
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+/// Shared secret authenticating requests and responses with TSIG (RFC 8945)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+    /// Name of the key, which both ends must agree on
+    pub name: String,
+    pub algorithm: TsigAlgorithm,
+    /// Base64 encoded secret, as generated by `tsig-keygen`
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+/// HTTP API used to operate the server, disabled when not configured
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub listen: SocketAddr,
+}
+
+/// Certificate of the encrypted listeners, read again on reload
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, the server certificate first
+    pub certificate: PathBuf,
+    /// PEM private key of the certificate, PKCS#8, PKCS#1 or SEC1
+    pub private_key: PathBuf,
+}
+
+/// File the changes of dynamic updates are saved to and read back from on
+/// startup. Without it they are lost on restart.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdatesConfig {
+    pub path: PathBuf,
+}
+
+/// Publishes the services of the top level registry on the local link with
+/// multicast DNS (RFC 6762), as `<service>.local`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MdnsConfig {
+    pub ipv4: bool,
+    pub ipv6: bool,
+    /// Address of the IPv4 interface published on, 0.0.0.0 for the one of
+    /// the default route
+    pub interface: Ipv4Addr,
+    /// Index of the IPv6 interface published on, 0 for the default one
+    pub interface_v6: u32,
+    /// Only ever changed to test, every other host listens on 5353
+    pub port: u16,
+}
+
+/// One line per answered query, logged at info level under the `query` target
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryLogConfig {
+    pub enabled: bool,
+    /// Fraction of the queries that are logged, between 0 and 1
+    pub sample_rate: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Seconds to wait for the queries in flight once SIGTERM is received
+    pub drain_timeout: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DefaultsConfig {
+    /// TTL of authoritative records that do not set their own
+    pub ttl: u32,
+    /// TTL of negative answers, used as the SOA minimum of zones that do not set one
+    pub negative_ttl: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegistryConfig {
+    /// Zone under which the registered services are published
+    pub zone: String,
+    pub ttl: Option<u32>,
+    /// Order of the addresses in answers, services can override it
+    #[serde(default)]
+    pub order: AnswerOrder,
+    /// Most addresses returned per answer, all of them when not set
+    pub max_answers: Option<usize>,
+    #[serde(default)]
+    pub acl: AclsConfig,
+    /// Secondaries sent a NOTIFY when the registry changes, as ip[:port]
+    #[serde(default)]
+    pub notify: Vec<String>,
+    /// Signs the answers of the registry when set
+    pub dnssec: Option<DnssecConfig>,
+    #[serde(default)]
+    pub services: Vec<ServiceConfig>,
+}
+
+/// Key the answers of a zone are signed with as they are sent (DNSSEC)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnssecConfig {
+    pub algorithm: DnssecAlgorithm,
+    /// Base64 encoded PKCS#8 private key, as generated by `openssl genpkey`
+    pub private_key: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DnssecAlgorithm {
+    EcdsaP256Sha256,
+    Ed25519,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub name: String,
+    pub order: Option<AnswerOrder>,
+    pub max_answers: Option<usize>,
+    /// Makes the service browsable with DNS-SD when set
+    pub dns_sd: Option<DnsSdConfig>,
+    #[serde(default)]
+    pub instances: Vec<InstanceConfig>,
+}
+
+/// How a service is browsed with DNS-SD (RFC 6763): as an instance of its
+/// service type, named after the service and listening on `port`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsSdConfig {
+    /// Service type and transport protocol, as `_http._tcp`
+    pub r#type: String,
+    pub port: u16,
+    /// `key=value` pairs of the TXT record
+    #[serde(default)]
+    pub txt: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceConfig {
+    pub address: IpAddr,
+    /// Share of the traffic with the `weighted` order, 0 only answers once
+    /// every other instance is listed
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    pub region: Option<String>,
+    pub zone: Option<String>,
+}
+
+/// Response rate limiting of UDP answers (BIND RRL), so the server can not
+/// be used to amplify traffic towards a spoofed source address
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Identical responses per second to a client network, the same name
+    /// and rcode counting as identical
+    pub responses_per_second: u32,
+    /// Responses per second to a client network, 0 for no limit
+    pub all_per_second: u32,
+    /// Prefix length grouping IPv4 clients in one network
+    pub ipv4_prefix: u8,
+    /// Prefix length grouping IPv6 clients in one network
+    pub ipv6_prefix: u8,
+    /// Every `slip` limited response is sent truncated instead of being
+    /// dropped, so that real clients retry over TCP. 0 drops them all.
+    pub slip: u32,
+    /// Networks never limited
+    pub exempt: Vec<IpNet>,
+}
+
+/// DNSSEC validation of the answers forwarded to the upstreams
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValidationConfig {
+    /// DS records the chains of trust start from, as in zone files, e.g.
+    /// `. DS 20326 8 2 E06D44B8...` for the root key
+    pub trust_anchors: Vec<String>,
+}
+
+/// Zones and registry seen by the clients of some networks instead of the
+/// top level ones, for split-horizon DNS. A client gets the first view
+/// matching its source address.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ViewConfig {
+    pub name: String,
+    pub clients: Vec<IpNet>,
+    #[serde(default)]
+    pub zones: Vec<ZoneConfig>,
+    pub registry: Option<RegistryConfig>,
+}
+
+/// Where the clients of some networks are, so that they are answered with
+/// the instances of their own zone, then of their region, first.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalityConfig {
+    pub networks: Vec<IpNet>,
+    pub region: Option<String>,
+    pub zone: Option<String>,
+}
+
+/// How the addresses of a service are ordered in answers, clients mostly
+/// use the first one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnswerOrder {
+    /// As listed in the configuration
+    #[default]
+    Fixed,
+    /// Rotated by one on every query
+    RoundRobin,
+    /// Shuffled on every query
+    Random,
+    /// Shuffled, instances with a higher weight coming first more often
+    Weighted,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    pub name: String,
+    pub ttl: Option<u32>,
+    #[serde(default)]
+    pub soa: SoaConfig,
+    #[serde(default)]
+    pub acl: AclsConfig,
+    /// Secondaries sent a NOTIFY when the zone changes, as ip[:port]
+    #[serde(default)]
+    pub notify: Vec<String>,
+    /// Primaries the zone is transferred from, as ip[:port], making this
+    /// server a secondary for it. The zone then has no records of its own.
+    #[serde(default)]
+    pub primaries: Vec<String>,
+    /// Key signing the transfers from the primaries
+    pub key: Option<String>,
+    #[serde(default)]
+    pub records: Vec<RecordConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SoaConfig {
+    pub mname: Option<String>,
+    pub rname: Option<String>,
+    /// The time the server started when not set, see `startup_serial`
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordConfig {
+    /// Relative to the zone, `@` for the apex or absolute when ending with a dot
+    pub name: String,
+    #[serde(rename = "type")]
+    pub r#type: String,
+    pub value: String,
+    pub ttl: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        message: String,
+    },
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(
+                    f,
+                    "could not read configuration file {}: {}",
+                    path.display(),
+                    source
+                )
+            }
+            ConfigError::Parse { path, message } => {
+                write!(
+                    f,
+                    "could not parse configuration file {}: {}",
+                    path.display(),
+                    message
+                )
+            }
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        ListenConfig {
+            udp: vec![DEFAULT_LISTEN],
+            tcp: vec![DEFAULT_LISTEN],
+            tls: Vec::new(),
+            https: Vec::new(),
+            quic: Vec::new(),
+            reuse_port: 1,
+            acl: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl Default for QueryLogConfig {
+    fn default() -> Self {
+        QueryLogConfig {
+            enabled: false,
+            sample_rate: 1.0,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            responses_per_second: 10,
+            all_per_second: 0,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            slip: 2,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        MdnsConfig {
+            ipv4: true,
+            ipv6: true,
+            interface: Ipv4Addr::UNSPECIFIED,
+            interface_v6: 0,
+            port: 5353,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { drain_timeout: 5 }
+    }
+}
+
+impl Default for DefaultsConfig {
+    fn default() -> Self {
+        DefaultsConfig {
+            ttl: 300,
+            negative_ttl: 60,
+        }
+    }
+}
+
+impl Default for SoaConfig {
+    fn default() -> Self {
+        SoaConfig {
+            mname: None,
+            rname: None,
+            serial: startup_serial(),
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: None,
+        }
+    }
+}
+
+/// Serial of the zones, and of the registry, that do not set one: the Unix
+/// time the server started. Reloads bump it on every change, so a restart
+/// serves them with a serial newer than the one secondaries got from the
+/// last run, unless that run made more changes than it lasted seconds.
+fn startup_serial() -> u32 {
+    static STARTED: OnceLock<u32> = OnceLock::new();
+    *STARTED.get_or_init(|| serial_at(SystemTime::now()))
+}
+
+pub(crate) fn serial_at(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(1, |since| since.as_secs() as u32)
+}
+
+impl Config {
+    /// Loads the configuration file given on the command line (if any),
+    /// applies the command line overrides and validates the result.
+    pub fn load(cli: &Cli) -> Result<Config, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        if !cli.udp.is_empty() {
+            config.listen.udp = cli.udp.clone();
+        }
+        if !cli.tcp.is_empty() {
+            config.listen.tcp = cli.tcp.clone();
+        }
+        if !cli.tls.is_empty() {
+            config.listen.tls = cli.tls.clone();
+        }
+        if !cli.https.is_empty() {
+            config.listen.https = cli.https.clone();
+        }
+        if !cli.quic.is_empty() {
+            config.listen.quic = cli.quic.clone();
+        }
+        if let Some(reuse_port) = cli.reuse_port {
+            config.listen.reuse_port = reuse_port;
+        }
+        if !cli.upstreams.is_empty() {
+            config.upstreams = cli.upstreams.clone();
+        }
+        if let Some(admin) = cli.admin {
+            config.admin = Some(AdminConfig { listen: admin });
+        }
+        if let Some(ttl) = cli.ttl {
+            config.defaults.ttl = ttl;
+        }
+        if let Some(log_level) = cli.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(log_format) = cli.log_format {
+            config.log_format = log_format;
+        }
+        if cli.query_log {
+            config.query_log.enabled = true;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        toml::from_str(&content).map_err(|e| ConfigError::Parse {
+            path: path.to_owned(),
+            message: e.to_string(),
+        })
+    }
+
+    /// Checks everything that serde can not, reporting all problems at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        let listen = &self.listen;
+        let protocols = [
+            ("udp", &listen.udp),
+            ("tcp", &listen.tcp),
+            ("tls", &listen.tls),
+            ("https", &listen.https),
+            ("quic", &listen.quic),
+        ];
+        if protocols.iter().all(|(_, addrs)| addrs.is_empty()) {
+            problems.push(
+                "listen: at least one udp, tcp, tls, https or quic address is required".to_owned(),
+            );
+        }
+        for (protocol, addrs) in protocols {
+            let mut seen = HashSet::new();
+            for addr in addrs {
+                if !seen.insert(addr) {
+                    problems.push(format!(
+                        "listen.{}: {} is listed more than once",
+                        protocol, addr
+                    ));
+                }
+            }
+        }
+        for addr in listen.tls.iter().filter(|addr| listen.tcp.contains(addr)) {
+            problems.push(format!("listen.tls: {} is also a tcp address", addr));
+        }
+        for addr in listen
+            .https
+            .iter()
+            .filter(|addr| listen.tcp.contains(addr) || listen.tls.contains(addr))
+        {
+            problems.push(format!(
+                "listen.https: {} is also a tcp or tls address",
+                addr
+            ));
+        }
+        for addr in listen.quic.iter().filter(|addr| listen.udp.contains(addr)) {
+            problems.push(format!("listen.quic: {} is also a udp address", addr));
+        }
+        match &self.tls {
+            Some(tls) => {
+                if let Err(e) = Certificates::load(tls) {
+                    problems.push(format!("tls: {}", e));
+                }
+            }
+            None => {
+                for (protocol, addrs) in [
+                    ("tls", &listen.tls),
+                    ("https", &listen.https),
+                    ("quic", &listen.quic),
+                ] {
+                    if !addrs.is_empty() {
+                        problems.push(format!(
+                            "listen.{}: a tls certificate and private key are required",
+                            protocol
+                        ));
+                    }
+                }
+            }
+        }
+        if self.listen.reuse_port == 0 {
+            problems.push("listen.reuse_port must be at least 1".to_owned());
+        } else if self.listen.reuse_port > 1 && !cfg!(unix) {
+            problems.push("listen.reuse_port is only supported on unix".to_owned());
+        }
+
+        if !(0.0..=1.0).contains(&self.query_log.sample_rate) {
+            problems.push("query_log.sample_rate must be between 0 and 1".to_owned());
+        }
+
+        if let Some(mdns) = &self.mdns {
+            if !mdns.ipv4 && !mdns.ipv6 {
+                problems.push("mdns: at least one of ipv4 and ipv6 is required".to_owned());
+            }
+            if self.registry.is_none() {
+                problems
+                    .push("mdns: a registry with the services to publish is required".to_owned());
+            }
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            if rate_limit.responses_per_second == 0 {
+                problems.push("rate_limit.responses_per_second must be greater than 0".to_owned());
+            }
+            if rate_limit.ipv4_prefix > 32 || rate_limit.ipv6_prefix > 128 {
+                problems.push(
+                    "rate_limit: ipv4_prefix must be at most 32 and ipv6_prefix at most 128"
+                        .to_owned(),
+                );
+            }
+        }
+
+        if self.defaults.ttl == 0 {
+            problems.push("defaults.ttl must be greater than 0".to_owned());
+        }
+
+        if let Some(validation) = &self.validation {
+            if validation.trust_anchors.is_empty() {
+                problems.push(
+                    "validation.trust_anchors: at least one trust anchor is required".to_owned(),
+                );
+            }
+            let (_, invalid) = trust_anchors(validation);
+            problems.extend(invalid);
+            if self.upstreams.is_empty() {
+                problems.push(
+                    "validation: upstreams are required to validate their answers".to_owned(),
+                );
+            }
+        }
+
+        for upstream in &self.upstreams {
+            if let Err(e) = parse_upstream(upstream) {
+                problems.push(format!("upstreams: {}", e));
+            }
+        }
+
+        for (i, locality) in self.localities.iter().enumerate() {
+            if locality.networks.is_empty() {
+                problems.push(format!(
+                    "localities[{}]: at least one network is required",
+                    i
+                ));
+            }
+            if locality.region.is_none() && locality.zone.is_none() {
+                problems.push(format!("localities[{}]: a region or a zone is required", i));
+            }
+        }
+
+        let mut key_names = HashSet::new();
+        for (i, key) in self.keys.iter().enumerate() {
+            let context = format!("keys[{}] ({})", i, key.name);
+            if let Err(e) = validate_name(&key.name) {
+                problems.push(format!("{}: invalid key name: {}", context, e));
+            } else if !key_names.insert(normalize_name(&key.name)) {
+                problems.push(format!("{}: key is defined more than once", context));
+            }
+            match BASE64_STANDARD.decode(key.secret.trim()) {
+                Ok(secret) if !secret.is_empty() => {}
+                _ => problems.push(format!("{}: secret must be non empty base64", context)),
+            }
+        }
+
+        self.validate_acls("acl", &self.acl, &mut problems);
+        for (addr, acl) in &self.listen.acl {
+            self.validate_acls(&format!("listen.acl.\"{}\"", addr), acl, &mut problems);
+        }
+
+        self.validate_data("", &self.zones, self.registry.as_ref(), &mut problems);
+
+        let mut view_names = HashSet::new();
+        for (i, view) in self.views.iter().enumerate() {
+            let context = format!("views[{}] ({})", i, view.name);
+            if !view_names.insert(view.name.as_str()) || view.name == DEFAULT_VIEW {
+                problems.push(format!("{}: view name is already used", context));
+            }
+            if view.clients.is_empty() {
+                problems.push(format!(
+                    "{}: at least one client network is required",
+                    context
+                ));
+            }
+            self.validate_data(
+                &format!("{}.", context),
+                &view.zones,
+                view.registry.as_ref(),
+                &mut problems,
+            );
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// Checks the zones and registry of the top level or of a view, `prefix`
+    /// being where they are in the file.
+    fn validate_data(
+        &self,
+        prefix: &str,
+        zones: &[ZoneConfig],
+        registry: Option<&RegistryConfig>,
+        problems: &mut Vec<String>,
+    ) {
+        let mut zone_names = HashSet::new();
+        for (i, zone) in zones.iter().enumerate() {
+            let context = format!("{}zones[{}] ({})", prefix, i, zone.name);
+            if let Err(e) = validate_name(&zone.name) {
+                problems.push(format!("{}: invalid zone name: {}", context, e));
+                continue;
+            }
+            if !zone_names.insert(normalize_name(&zone.name)) {
+                problems.push(format!("{}: zone is defined more than once", context));
+            }
+
+            for secondary in &zone.notify {
+                if let Err(e) = parse_upstream(secondary) {
+                    problems.push(format!("{}: notify: {}", context, e));
+                }
+            }
+            for primary in &zone.primaries {
+                if let Err(e) = parse_upstream(primary) {
+                    problems.push(format!("{}: primaries: {}", context, e));
+                }
+            }
+            if !zone.primaries.is_empty() && !zone.records.is_empty() {
+                problems.push(format!(
+                    "{}: a zone with primaries can not have records",
+                    context
+                ));
+            }
+            match &zone.key {
+                Some(_) if zone.primaries.is_empty() => {
+                    problems.push(format!("{}: a key is only used with primaries", context))
+                }
+                Some(key) if self.key(key).is_none() => {
+                    problems.push(format!("{}: unknown key {}", context, key))
+                }
+                _ => {}
+            }
+            self.validate_acls(&format!("{}.acl", context), &zone.acl, problems);
+
+            let origin = normalize_name(&zone.name);
+            for (j, record) in zone.records.iter().enumerate() {
+                if let Err(e) = record.to_record(&origin, self.defaults.ttl) {
+                    problems.push(format!(
+                        "{}: records[{}] ({}): {}",
+                        context, j, record.name, e
+                    ));
+                }
+            }
+        }
+
+        if let Some(registry) = registry {
+            if let Err(e) = validate_name(&registry.zone) {
+                problems.push(format!("{}registry.zone: {}", prefix, e));
+            } else if zone_names.contains(&normalize_name(&registry.zone)) {
+                problems.push(format!(
+                    "{}registry.zone: {} is also defined in zones",
+                    prefix, registry.zone
+                ));
+            }
+
+            if registry.max_answers == Some(0) {
+                problems.push(format!("{}registry.max_answers must be at least 1", prefix));
+            }
+            self.validate_acls(&format!("{}registry.acl", prefix), &registry.acl, problems);
+            if let Some(Err(e)) = registry.dnssec.as_ref().map(SigningKey::from_config) {
+                problems.push(format!("{}registry.dnssec.private_key: {}", prefix, e));
+            }
+            for secondary in &registry.notify {
+                if let Err(e) = parse_upstream(secondary) {
+                    problems.push(format!("{}registry.notify: {}", prefix, e));
+                }
+            }
+
+            let mut service_names = HashSet::new();
+            for (i, service) in registry.services.iter().enumerate() {
+                let context = format!("{}registry.services[{}] ({})", prefix, i, service.name);
+                if service.max_answers == Some(0) {
+                    problems.push(format!("{}: max_answers must be at least 1", context));
+                }
+                if service.name.contains('.') || validate_name(&service.name).is_err() {
+                    problems.push(format!("{}: service name must be a single label", context));
+                } else if !service_names.insert(service.name.to_ascii_lowercase()) {
+                    problems.push(format!("{}: service is defined more than once", context));
+                }
+                if let Some(dns_sd) = &service.dns_sd {
+                    if let Err(e) = validate_service_type(&dns_sd.r#type) {
+                        problems.push(format!("{}: dns_sd.type: {}", context, e));
+                    }
+                    for pair in &dns_sd.txt {
+                        if pair.len() > 255 || pair.is_empty() || pair.starts_with('=') {
+                            problems.push(format!(
+                                "{}: dns_sd.txt: '{}' is not a key=value pair",
+                                context, pair
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks that the ACLs only name keys that exist
+    fn validate_acls(&self, context: &str, acls: &AclsConfig, problems: &mut Vec<String>) {
+        let operations = [
+            ("query", &acls.query),
+            ("recursion", &acls.recursion),
+            ("transfer", &acls.transfer),
+            ("update", &acls.update),
+        ];
+        for (operation, acl) in operations {
+            for key in acl.iter().flat_map(|acl| &acl.keys) {
+                if self.key(key).is_none() {
+                    problems.push(format!("{}.{}: unknown key {}", context, operation, key));
+                }
+            }
+        }
+    }
+
+    /// The TSIG key named `name`
+    pub fn key(&self, name: &str) -> Option<&KeyConfig> {
+        let name = normalize_name(name);
+        self.keys
+            .iter()
+            .find(|key| normalize_name(&key.name) == name)
+    }
+
+    /// The zones and registry of the view named `name`, the top level ones
+    /// for the default view
+    pub(crate) fn view(&self, name: &str) -> Option<(&[ZoneConfig], Option<&RegistryConfig>)> {
+        if name == DEFAULT_VIEW {
+            return Some((&self.zones, self.registry.as_ref()));
+        }
+        self.views
+            .iter()
+            .find(|view| view.name == name)
+            .map(|view| (view.zones.as_slice(), view.registry.as_ref()))
+    }
+
+    pub fn upstream_addrs(&self) -> Vec<SocketAddr> {
+        self.upstreams
+            .iter()
+            .filter_map(|upstream| parse_upstream(upstream).ok())
+            .collect()
+    }
+}
+
+impl RecordConfig {
+    pub(crate) fn to_record(&self, origin: &str, default_ttl: u32) -> Result<Record, String> {
+        validate_name(&self.name)?;
+        let name = absolute_name(&self.name, origin);
+        if name != origin && !name.ends_with(&format!(".{}", origin)) {
+            return Err(format!("{} is outside of zone {}", name, origin));
+        }
+
+        let ttl = self.ttl.unwrap_or(default_ttl);
+        let class = Class::IN;
+        let value = self.value.trim();
+
+        let record = match self.r#type.to_ascii_uppercase().as_str() {
+            "A" => Record::A {
+                name,
+                class,
+                addr: value
+                    .parse::<Ipv4Addr>()
+                    .map_err(|_| format!("'{}' is not an IPv4 address", value))?,
+                ttl,
+            },
+            "AAAA" => Record::AAAA {
+                name,
+                class,
+                addr: value
+                    .parse::<Ipv6Addr>()
+                    .map_err(|_| format!("'{}' is not an IPv6 address", value))?,
+                ttl,
+            },
+            "CNAME" => Record::CNAME {
+                name,
+                class,
+                host: host_name(value, origin)?,
+                ttl,
+            },
+            "NS" => Record::NS {
+                name,
+                class,
+                host: host_name(value, origin)?,
+                ttl,
+            },
+            "MX" => {
+                let (priority, host) = value
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| format!("'{}' is not of the form '<priority> <host>'", value))?;
+                Record::MX {
+                    name,
+                    class,
+                    priority: priority
+                        .parse()
+                        .map_err(|_| format!("'{}' is not a valid MX priority", priority))?,
+                    host: host_name(host.trim(), origin)?,
+                    ttl,
+                }
+            }
+            "TXT" => {
+                if value.len() > 255 {
+                    return Err("TXT values are limited to 255 characters".to_owned());
+                }
+                Record::TXT {
+                    name,
+                    class,
+                    data: vec![value.to_owned()],
+                    ttl,
+                }
+            }
+            other => return Err(format!("unsupported record type '{}'", other)),
+        };
+
+        Ok(record)
+    }
+}
+
+/// Accepts a single address as well as a list of them
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error> {
+    struct AddrsVisitor;
+
+    impl<'de> Visitor<'de> for AddrsVisitor {
+        type Value = Vec<SocketAddr>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an ip:port address or a list of them")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            value.parse().map(|addr| vec![addr]).map_err(|_| {
+                E::custom(format!("'{}' is not an address of the form ip:port", value))
+            })
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut addrs = Vec::new();
+            while let Some(addr) = seq.next_element()? {
+                addrs.push(addr);
+            }
+            Ok(addrs)
+        }
+    }
+
+    deserializer.deserialize_any(AddrsVisitor)
+}
+
+/// Accepts `ip`, `ip:port` and `[ipv6]:port`, defaulting to port 53.
+pub(crate) fn parse_upstream(value: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+
+    value
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, DEFAULT_UPSTREAM_PORT))
+        .map_err(|_| format!("'{}' is not an address of the form ip[:port]", value))
+}
+
+/// Lower cases the name and removes the trailing dot
+pub(crate) fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn absolute_name(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_owned()
+    } else if name.ends_with('.') {
+        normalize_name(name)
+    } else {
+        format!("{}.{}", name.to_ascii_lowercase(), origin)
+    }
+}
+
+fn host_name(value: &str, origin: &str) -> Result<String, String> {
+    validate_name(value)?;
+    Ok(absolute_name(value, origin))
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    if name == "@" {
+        return Ok(());
+    }
+
+    let trimmed = name.trim_end_matches('.');
+    if trimmed.is_empty() {
+        return Err("name is empty".to_owned());
+    }
+    if trimmed.len() > 253 {
+        return Err(format!("'{}' is longer than 253 characters", name));
+    }
+
+    for label in trimmed.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("'{}' has an empty or too long label", name));
+        }
+        if !label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!("'{}' contains invalid characters", name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks a DNS-SD service type, an underscore and a service name of up to
+/// 15 letters, digits and hyphens, then `_tcp` or `_udp` (RFC 6763 7)
+fn validate_service_type(r#type: &str) -> Result<(), String> {
+    let (service, protocol) = r#type.split_once('.').ok_or_else(|| {
+        format!(
+            "'{}' is not of the form '_<service>._tcp' or '_<service>._udp'",
+            r#type
+        )
+    })?;
+    let name = service
+        .strip_prefix('_')
+        .filter(|name| (1..=15).contains(&name.len()))
+        .filter(|name| name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+        .filter(|name| name.chars().any(|c| c.is_ascii_alphabetic()))
+        .filter(|name| !name.starts_with('-') && !name.ends_with('-'));
+    if name.is_none() || !matches!(protocol.to_ascii_lowercase().as_str(), "_tcp" | "_udp") {
+        return Err(format!(
+            "'{}' is not of the form '_<service>._tcp' or '_<service>._udp'",
+            r#type
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::{parse_upstream, Config, ConfigError, RecordConfig};
+    use crate::core::dns::message::Record;
+
+    const EXAMPLE: &str = r#"
+        log_level = "debug"
+        upstreams = ["1.1.1.1", "[2606:4700:4700::1111]:53"]
+
+        [listen]
+        udp = ["127.0.0.1:5353", "[::1]:5353"]
+        tcp = "127.0.0.1:5353"
+
+        [registry]
+        zone = "svc.internal"
+
+        [[registry.services]]
+        name = "api"
+        instances = [{ address = "10.0.0.1" }, { address = "fd00::1" }]
+
+        [[zones]]
+        name = "example.internal."
+
+        [[zones.records]]
+        name = "www"
+        type = "A"
+        value = "10.0.0.10"
+    "#;
+
+    #[test]
+    fn parse_example() {
+        let config: Config = toml::from_str(EXAMPLE).unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.listen.udp.len(), 2);
+        assert_eq!(config.listen.tcp, vec!["127.0.0.1:5353".parse().unwrap()]);
+        assert_eq!(config.upstream_addrs().len(), 2);
+        assert_eq!(config.registry.unwrap().services[0].instances.len(), 2);
+        assert_eq!(config.zones[0].records.len(), 1);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut config: Config = toml::from_str(EXAMPLE).unwrap();
+        config.upstreams.push("not-an-address".to_owned());
+        config.zones[0].records[0].value = "10.0.0".to_owned();
+
+        match config.validate() {
+            Err(ConfigError::Invalid(problems)) => {
+                assert_eq!(problems.len(), 2);
+                assert!(problems[0].contains("not-an-address"));
+                assert!(problems[1].contains("is not an IPv4 address"));
+            }
+            other => panic!("expected invalid configuration, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn record_names_are_relative_to_the_zone() {
+        let record = RecordConfig {
+            name: "@".to_owned(),
+            r#type: "a".to_owned(),
+            value: "10.0.0.1".to_owned(),
+            ttl: None,
+        };
+
+        assert_eq!(
+            record.to_record("example.internal", 300).unwrap(),
+            Record::new_type_a(
+                "example.internal".to_owned(),
+                Ipv4Addr::new(10, 0, 0, 1),
+                300
+            )
+        );
+        assert_eq!(parse_upstream("8.8.8.8").unwrap().port(), 53);
+    }
+}
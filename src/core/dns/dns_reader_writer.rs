@@ -1,17 +1,14 @@
 use tokio::io::{AsyncReadExt, AsyncWriteExt, Result};
 
-use super::message::{FromAsyncReader, Header, Message, Writable};
+use super::message::{FromAsyncReader, Message, Writable};
 
 pub(crate) struct DnsReader<T> {
     reader: T,
 }
 
-
 impl<T: AsyncReadExt + Unpin + Send> From<T> for DnsReader<T> {
     fn from(value: T) -> Self {
-        DnsReader {
-            reader: value
-        }
+        DnsReader { reader: value }
     }
 }
 
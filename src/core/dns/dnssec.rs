@@ -0,0 +1,386 @@
+use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use tokio::io::Result;
+
+use crate::core::config::{normalize_name, DnssecAlgorithm, DnssecConfig};
+
+use super::message::{write_dns_encoded_name, Class, QueryType, Record};
+
+/// DNSSEC algorithm numbers (RFC 8624)
+pub(crate) const ECDSA_P256_SHA256: u8 = 13;
+pub(crate) const ED25519: u8 = 15;
+
+/// A zone key with the secure entry point flag: the single key of the
+/// zone signs both the DNSKEY RRset and everything else (RFC 4034 2.1.1)
+const DNSKEY_FLAGS: u16 = 257;
+const DNSKEY_PROTOCOL: u8 = 3;
+
+/// Signatures are valid from an hour ago, for resolvers with a clock
+/// running late, to a week from now
+const INCEPTION_OFFSET: u32 = 3600;
+const VALIDITY: u32 = 7 * 86400;
+
+/// Type listed by the NSEC of a name that does not exist (RFC 9824 3.2)
+const NXNAME: u16 = 128;
+
+/// The private key of a zone
+#[derive(Debug)]
+pub(crate) enum SigningKey {
+    Ecdsa(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+impl SigningKey {
+    pub(crate) fn from_config(config: &DnssecConfig) -> std::result::Result<SigningKey, String> {
+        let pkcs8 = BASE64_STANDARD
+            .decode(config.private_key.trim())
+            .map_err(|_| "not valid base64".to_owned())?;
+
+        match config.algorithm {
+            DnssecAlgorithm::EcdsaP256Sha256 => EcdsaKeyPair::from_pkcs8(
+                &ECDSA_P256_SHA256_FIXED_SIGNING,
+                &pkcs8,
+                &SystemRandom::new(),
+            )
+            .map(SigningKey::Ecdsa)
+            .map_err(|e| format!("not a PKCS#8 P-256 key: {}", e)),
+            DnssecAlgorithm::Ed25519 => Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8)
+                .map(SigningKey::Ed25519)
+                .map_err(|e| format!("not a PKCS#8 Ed25519 key: {}", e)),
+        }
+    }
+
+    fn algorithm(&self) -> u8 {
+        match self {
+            SigningKey::Ecdsa(_) => ECDSA_P256_SHA256,
+            SigningKey::Ed25519(_) => ED25519,
+        }
+    }
+
+    /// The public key as in DNSKEY records, ECDSA points without their
+    /// uncompressed prefix (RFC 6605 4)
+    fn public_key(&self) -> Vec<u8> {
+        match self {
+            SigningKey::Ecdsa(key) => key.public_key().as_ref()[1..].to_vec(),
+            SigningKey::Ed25519(key) => key.public_key().as_ref().to_vec(),
+        }
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            SigningKey::Ecdsa(key) => key
+                .sign(&SystemRandom::new(), data)
+                .map(|signature| signature.as_ref().to_vec())
+                .map_err(|_| Error::other("could not sign")),
+            SigningKey::Ed25519(key) => Ok(key.sign(data).as_ref().to_vec()),
+        }
+    }
+}
+
+/// Signs the answers of a zone as they are sent (online signing), so
+/// answers that change with every query can be signed too.
+#[derive(Debug)]
+pub(crate) struct Signer {
+    zone: String,
+    key: SigningKey,
+    dnskey: Record,
+    key_tag: u16,
+}
+
+impl Signer {
+    pub(crate) fn new(zone: &str, key: SigningKey, ttl: u32) -> Signer {
+        let public_key = key.public_key();
+        let mut rdata = DNSKEY_FLAGS.to_be_bytes().to_vec();
+        rdata.extend([DNSKEY_PROTOCOL, key.algorithm()]);
+        rdata.extend(&public_key);
+
+        Signer {
+            zone: zone.to_owned(),
+            dnskey: Record::DNSKEY {
+                name: zone.to_owned(),
+                class: Class::IN,
+                ttl,
+                flags: DNSKEY_FLAGS,
+                protocol: DNSKEY_PROTOCOL,
+                algorithm: key.algorithm(),
+                public_key,
+            },
+            key_tag: key_tag(&rdata),
+            key,
+        }
+    }
+
+    /// The DNSKEY record of the apex
+    pub(crate) fn dnskey(&self) -> Record {
+        self.dnskey.clone()
+    }
+
+    /// The RRSIG of every RRset of `records`, in the order of the RRsets
+    pub(crate) async fn sign(&self, records: &[Record]) -> Result<Vec<Record>> {
+        let now = now();
+        let mut signatures = Vec::new();
+
+        for rrset in rrsets(records) {
+            let mut rrsig = Record::RRSIG {
+                name: rrset[0].name().to_owned(),
+                class: Class::IN,
+                ttl: rrset[0].ttl(),
+                type_covered: rrset[0].query_type().to_u16(),
+                algorithm: self.key.algorithm(),
+                labels: labels(rrset[0].name()),
+                original_ttl: rrset[0].ttl(),
+                expiration: now.wrapping_add(VALIDITY),
+                inception: now.wrapping_sub(INCEPTION_OFFSET),
+                key_tag: self.key_tag,
+                signer: self.zone.clone(),
+                signature: Vec::new(),
+            };
+
+            let signed = self.key.sign(&signed_data(&rrsig, &rrset).await?)?;
+            if let Record::RRSIG { signature, .. } = &mut rrsig {
+                *signature = signed;
+            }
+            signatures.push(rrsig);
+        }
+
+        Ok(signatures)
+    }
+
+    /// The NSEC proving that `qname` has none of the types but `types`, or
+    /// does not exist when None. Compact denial of existence (RFC 9824)
+    /// covers only `qname`, so no other name of the zone can be walked,
+    /// with its next name the smallest name after it.
+    pub(crate) fn deny(&self, qname: &str, types: Option<&[QueryType]>, ttl: u32) -> Record {
+        let mut present: Vec<u16> = match types {
+            Some(types) => types.iter().map(QueryType::to_u16).collect(),
+            None => vec![NXNAME],
+        };
+        present.extend([QueryType::RRSIG.to_u16(), QueryType::NSEC.to_u16()]);
+
+        Record::NSEC {
+            name: qname.to_owned(),
+            class: Class::IN,
+            ttl,
+            next: format!("\0.{}", qname),
+            types: present,
+        }
+    }
+}
+
+/// Groups `records` by name and type, leaving out the ones never signed
+pub(crate) fn rrsets(records: &[Record]) -> Vec<Vec<&Record>> {
+    let mut rrsets: Vec<Vec<&Record>> = Vec::new();
+    let unsigned =
+        |record: &Record| matches!(record.query_type(), QueryType::RRSIG | QueryType::OPT);
+
+    for record in records.iter().filter(|record| !unsigned(record)) {
+        let same = |rrset: &&mut Vec<&Record>| {
+            rrset[0].query_type() == record.query_type()
+                && normalize_name(rrset[0].name()) == normalize_name(record.name())
+        };
+        match rrsets.iter_mut().find(same) {
+            Some(rrset) => rrset.push(record),
+            None => rrsets.push(vec![record]),
+        }
+    }
+    rrsets
+}
+
+/// What the signature of `rrsig` covers: its own data without the
+/// signature, then every record of `rrset` in canonical form and order
+/// (RFC 4034 3.1.8.1 and 6.2)
+pub(crate) async fn signed_data(rrsig: &Record, rrset: &[&Record]) -> Result<Vec<u8>> {
+    let (original_ttl, signer) = match rrsig {
+        Record::RRSIG {
+            original_ttl,
+            signer,
+            ..
+        } => (*original_ttl, signer),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "not an RRSIG")),
+    };
+    // The fixed size fields, then the signer without the signature
+    let mut data = rrsig.rdata().await?;
+    data.truncate(18);
+    write_dns_encoded_name(&mut data, &signer.to_ascii_lowercase()).await?;
+
+    let mut records = Vec::with_capacity(rrset.len());
+    for record in rrset {
+        let mut wire = Vec::new();
+        write_dns_encoded_name(&mut wire, &normalize_name(record.name())).await?;
+        wire.extend(record.query_type().to_u16().to_be_bytes());
+        wire.extend(record.class().to_u16().to_be_bytes());
+        wire.extend(original_ttl.to_be_bytes());
+        let rdata = canonical(record).rdata().await?;
+        wire.extend((rdata.len() as u16).to_be_bytes());
+        records.push((rdata, wire));
+    }
+    records.sort();
+    records.dedup();
+    for (rdata, wire) in records {
+        data.extend(wire);
+        data.extend(rdata);
+    }
+
+    Ok(data)
+}
+
+/// The record with the names in its data lowercase (RFC 4034 6.2)
+fn canonical(record: &Record) -> Record {
+    let mut record = record.clone();
+    match &mut record {
+        Record::NS { host, .. }
+        | Record::CNAME { host, .. }
+        | Record::PTR { host, .. }
+        | Record::MX { host, .. } => host.make_ascii_lowercase(),
+        Record::SRV { target, .. } => target.make_ascii_lowercase(),
+        Record::SOA { mname, rname, .. } => {
+            mname.make_ascii_lowercase();
+            rname.make_ascii_lowercase();
+        }
+        _ => {}
+    }
+    record
+}
+
+/// Labels of the owner name, the root not counted (RFC 4034 3.1.3)
+pub(crate) fn labels(name: &str) -> u8 {
+    name.split('.').filter(|label| !label.is_empty()).count() as u8
+}
+
+/// The tag identifying a key in the RRSIGs made with it (RFC 4034 B)
+pub(crate) fn key_tag(rdata: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for (i, byte) in rdata.iter().enumerate() {
+        sum += if i % 2 == 0 {
+            (*byte as u32) << 8
+        } else {
+            *byte as u32
+        };
+    }
+    sum += (sum >> 16) & 0xffff;
+    (sum & 0xffff) as u16
+}
+
+/// Seconds since the epoch, in the serial arithmetic of RRSIG times
+pub(crate) fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use ring::rand::SystemRandom;
+    use ring::signature::{
+        EcdsaKeyPair, Ed25519KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED,
+        ECDSA_P256_SHA256_FIXED_SIGNING, ED25519,
+    };
+
+    use super::{key_tag, signed_data, Signer, SigningKey, NXNAME};
+    use crate::core::config::{DnssecAlgorithm, DnssecConfig};
+    use crate::core::dns::message::{QueryType, Record};
+
+    fn signer(algorithm: DnssecAlgorithm) -> Signer {
+        let rng = SystemRandom::new();
+        let pkcs8 = match algorithm {
+            DnssecAlgorithm::EcdsaP256Sha256 => {
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap()
+            }
+            DnssecAlgorithm::Ed25519 => Ed25519KeyPair::generate_pkcs8(&rng).unwrap(),
+        };
+        let config = DnssecConfig {
+            algorithm,
+            private_key: BASE64_STANDARD.encode(pkcs8.as_ref()),
+        };
+        Signer::new(
+            "svc.internal",
+            SigningKey::from_config(&config).unwrap(),
+            30,
+        )
+    }
+
+    #[tokio::test]
+    async fn signatures_verify_with_the_dnskey() {
+        for algorithm in [DnssecAlgorithm::EcdsaP256Sha256, DnssecAlgorithm::Ed25519] {
+            let signer = signer(algorithm);
+            let (public_key, dnskey_rdata) = match signer.dnskey() {
+                dnskey @ Record::DNSKEY { .. } => {
+                    let rdata = dnskey.rdata().await.unwrap();
+                    let Record::DNSKEY { public_key, .. } = dnskey else {
+                        unreachable!()
+                    };
+                    (public_key, rdata)
+                }
+                other => panic!("expected a DNSKEY, got {:?}", other),
+            };
+            let verifier = match algorithm {
+                DnssecAlgorithm::EcdsaP256Sha256 => UnparsedPublicKey::new(
+                    &ECDSA_P256_SHA256_FIXED,
+                    [&[4u8][..], &public_key].concat(),
+                ),
+                DnssecAlgorithm::Ed25519 => UnparsedPublicKey::new(&ED25519, public_key),
+            };
+
+            // Two RRsets, signed whatever the case and order of the records
+            let records = vec![
+                Record::new_type_a(
+                    "API.svc.internal".to_owned(),
+                    Ipv4Addr::new(10, 0, 0, 2),
+                    30,
+                ),
+                signer.dnskey(),
+                Record::new_type_a(
+                    "api.svc.internal".to_owned(),
+                    Ipv4Addr::new(10, 0, 0, 1),
+                    30,
+                ),
+            ];
+            let signatures = signer.sign(&records).await.unwrap();
+            assert_eq!(signatures.len(), 2);
+
+            let reordered = [&records[2], &records[0]];
+            match &signatures[0] {
+                rrsig @ Record::RRSIG {
+                    key_tag: tag,
+                    labels,
+                    signature,
+                    ..
+                } => {
+                    assert_eq!(*tag, key_tag(&dnskey_rdata));
+                    assert_eq!(*labels, 3);
+                    let data = signed_data(rrsig, &reordered).await.unwrap();
+                    verifier.verify(&data, signature).unwrap();
+                }
+                other => panic!("expected an RRSIG, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn denies_with_a_single_name() {
+        let signer = signer(DnssecAlgorithm::Ed25519);
+
+        match signer.deny("db.svc.internal", None, 60) {
+            Record::NSEC { next, types, .. } => {
+                assert_eq!(next, "\0.db.svc.internal");
+                assert_eq!(types, [NXNAME, 46, 47]);
+            }
+            other => panic!("expected an NSEC, got {:?}", other),
+        }
+        match signer.deny("api.svc.internal", Some(&[QueryType::A]), 60) {
+            Record::NSEC { types, .. } => assert_eq!(types, [1, 46, 47]),
+            other => panic!("expected an NSEC, got {:?}", other),
+        }
+        match signer.deny("_tcp.svc.internal", Some(&[]), 60) {
+            Record::NSEC { types, .. } => assert_eq!(types, [46, 47]),
+            other => panic!("expected an NSEC, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnet::IpNet;
+use tokio::io::Result;
+
+use super::message::{Class, EdnsOption, Message, Record};
+
+/// Largest UDP payload advertised and sent, small enough to avoid IP
+/// fragmentation on most paths (DNS flag day 2020)
+pub(crate) const MAX_PAYLOAD_SIZE: u16 = 1232;
+
+/// Option code of the EDNS Client Subnet option (RFC 7871)
+const CLIENT_SUBNET: u16 = 8;
+
+/// Flag of the OPT TTL asking for DNSSEC records (RFC 3225)
+const DNSSEC_OK: u32 = 0x8000;
+
+/// The EDNS (RFC 6891) parameters of a message, read from and written as
+/// its OPT record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Edns {
+    pub(crate) payload_size: u16,
+    /// Upper 8 bits of the 12 bit rcode
+    pub(crate) extended_rcode: u8,
+    pub(crate) version: u8,
+    pub(crate) dnssec_ok: bool,
+    pub(crate) options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub(crate) fn new() -> Edns {
+        Edns {
+            payload_size: MAX_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// The EDNS parameters of `message`, if it has an OPT record. A message
+    /// can only have one, and only in its additional section.
+    pub(crate) fn from_message(message: &Message) -> Result<Option<Edns>> {
+        let mut opts = message
+            .resources
+            .iter()
+            .filter(|record| matches!(record, Record::OPT { .. }));
+
+        let edns = match opts.next() {
+            Some(Record::OPT {
+                name,
+                class,
+                ttl,
+                options,
+            }) => {
+                if !name.is_empty() {
+                    return Err(invalid("OPT record owner is not the root"));
+                }
+                Edns {
+                    payload_size: class.to_u16(),
+                    extended_rcode: (ttl >> 24) as u8,
+                    version: (ttl >> 16) as u8,
+                    dnssec_ok: ttl & DNSSEC_OK != 0,
+                    options: options.clone(),
+                }
+            }
+            _ => return Ok(None),
+        };
+
+        let misplaced = message
+            .answers
+            .iter()
+            .chain(message.authority.iter())
+            .any(|record| matches!(record, Record::OPT { .. }));
+        if opts.next().is_some() || misplaced {
+            return Err(invalid("more than one OPT record"));
+        }
+
+        Ok(Some(edns))
+    }
+
+    pub(crate) fn to_record(&self) -> Record {
+        let mut ttl = (self.extended_rcode as u32) << 24 | (self.version as u32) << 16;
+        if self.dnssec_ok {
+            ttl |= DNSSEC_OK;
+        }
+
+        Record::OPT {
+            name: String::new(),
+            class: Class::from(self.payload_size),
+            ttl,
+            options: self.options.clone(),
+        }
+    }
+
+    /// The client subnet option, an error when it is malformed
+    pub(crate) fn client_subnet(&self) -> Result<Option<ClientSubnet>> {
+        self.options
+            .iter()
+            .find(|option| option.code == CLIENT_SUBNET)
+            .map(|option| ClientSubnet::parse(&option.data))
+            .transpose()
+    }
+}
+
+/// Network of the client a resolver is asking on behalf of (RFC 7871)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ClientSubnet {
+    pub(crate) address: IpAddr,
+    pub(crate) source_prefix: u8,
+    /// Leading bits of the address the answer depends on, set in responses
+    pub(crate) scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    fn parse(data: &[u8]) -> Result<ClientSubnet> {
+        if data.len() < 4 {
+            return Err(invalid("client subnet option is too short"));
+        }
+        let family = u16::from_be_bytes([data[0], data[1]]);
+        let source_prefix = data[2];
+        let scope_prefix = data[3];
+        let bytes = &data[4..];
+
+        let max_prefix = match family {
+            1 => 32,
+            2 => 128,
+            _ => return Err(invalid("unknown client subnet family")),
+        };
+        if source_prefix > max_prefix || scope_prefix > max_prefix {
+            return Err(invalid("client subnet prefix is too long"));
+        }
+        // Only the bytes covered by the prefix are sent, the bits after it
+        // being zero (RFC 7871 6)
+        if bytes.len() != (source_prefix as usize).div_ceil(8) {
+            return Err(invalid("client subnet address does not match its prefix"));
+        }
+
+        let address = match family {
+            1 => {
+                let mut octets = [0; 4];
+                octets[..bytes.len()].copy_from_slice(bytes);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            _ => {
+                let mut octets = [0; 16];
+                octets[..bytes.len()].copy_from_slice(bytes);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+        };
+
+        let subnet = ClientSubnet {
+            address,
+            source_prefix,
+            scope_prefix,
+        };
+        if subnet.network().addr() != address {
+            return Err(invalid(
+                "client subnet address has bits set after its prefix",
+            ));
+        }
+
+        Ok(subnet)
+    }
+
+    pub(crate) fn network(&self) -> IpNet {
+        IpNet::new(self.address, self.source_prefix)
+            .unwrap()
+            .trunc()
+    }
+
+    /// The option echoing this subnet with the given scope
+    pub(crate) fn to_option(self, scope_prefix: u8) -> EdnsOption {
+        let (family, octets) = match self.address {
+            IpAddr::V4(addr) => (1u16, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (2u16, addr.octets().to_vec()),
+        };
+
+        let mut data = Vec::with_capacity(4 + octets.len());
+        data.extend_from_slice(&family.to_be_bytes());
+        data.push(self.source_prefix);
+        data.push(scope_prefix);
+        data.extend_from_slice(&octets[..(self.source_prefix as usize).div_ceil(8)]);
+
+        EdnsOption {
+            code: CLIENT_SUBNET,
+            data,
+        }
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClientSubnet, Edns};
+    use crate::core::dns::dns_reader_writer::DnsReader;
+    use crate::core::dns::message::{Class, QueryType};
+    use crate::core::dns::message_builder::MessageBuilder;
+
+    #[tokio::test]
+    async fn client_subnet_survives_the_wire() {
+        let subnet = ClientSubnet {
+            address: "192.0.2.0".parse().unwrap(),
+            source_prefix: 24,
+            scope_prefix: 0,
+        };
+        let mut edns = Edns::new();
+        edns.dnssec_ok = true;
+        edns.options.push(subnet.to_option(0));
+
+        let request = MessageBuilder::new_request(1)
+            .add_new_question("api.svc.internal".to_owned(), QueryType::A, Class::IN)
+            .add_resources(edns.to_record())
+            .build();
+        let bytes = request.to_bytes().await.unwrap();
+        // family 1, source /24, scope 0, then only three address bytes
+        assert!(bytes.ends_with(&[0, 8, 0, 7, 0, 1, 24, 0, 192, 0, 2]));
+
+        let parsed = DnsReader::from(&*bytes).read().await.unwrap();
+        let parsed = Edns::from_message(&parsed).unwrap().unwrap();
+        assert_eq!(parsed, edns);
+        assert_eq!(parsed.client_subnet().unwrap(), Some(subnet));
+    }
+
+    #[test]
+    fn rejects_bits_after_the_prefix() {
+        assert!(ClientSubnet::parse(&[0, 1, 24, 0, 192, 0, 2]).is_ok());
+        assert!(ClientSubnet::parse(&[0, 1, 23, 0, 192, 0, 3]).is_err());
+        assert!(ClientSubnet::parse(&[0, 1, 24, 0, 192, 0]).is_err());
+        assert!(ClientSubnet::parse(&[0, 3, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,715 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::prelude::{Engine, BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, ALLOW, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use serde_json::{json, Value};
+use tokio::io::Result;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+
+use crate::core::shutdown::Shutdown;
+
+use super::dns_reader_writer::DnsReader;
+use super::edns::Edns;
+use super::message::{Class, Message, QueryType, Record};
+use super::message_builder::MessageBuilder;
+use super::responder::{Client, Responder, Transport};
+use super::tcp_listener::accept;
+use super::tls::{Certificates, HANDSHAKE_TIMEOUT};
+
+/// Application protocols of DNS over HTTPS, HTTP/2 preferred (RFC 8484 5.2)
+const ALPN_HTTPS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/// HTTP/1.1 connections waiting longer than this for a request are closed,
+/// HTTP/2 ones are pinged when quiet for as long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// HTTP/2 connections not answering a ping by then are closed
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+const PATH: &str = "/dns-query";
+
+/// Media type of DNS messages in wire format (RFC 8484 6)
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Media type of the JSON API, as served by the public resolvers
+const DNS_JSON: &str = "application/dns-json";
+
+/// Largest DNS message, a body over it can not be one
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+type HttpResponse = Response<Full<Bytes>>;
+
+/// Serves queries over HTTPS (RFC 8484) at `/dns-query`, as a `dns`
+/// parameter of GET or the body of POST in wire format. GET also takes a
+/// `name` and `type` instead, answered in JSON like the public resolvers do.
+pub struct HttpsListener {
+    responder: Arc<Responder>,
+    acceptor: TlsAcceptor,
+    shutdown: Shutdown,
+}
+
+impl HttpsListener {
+    pub fn new(
+        responder: Arc<Responder>,
+        certificates: &Arc<Certificates>,
+        shutdown: Shutdown,
+    ) -> HttpsListener {
+        HttpsListener {
+            responder,
+            acceptor: TlsAcceptor::from(certificates.server_config(&ALPN_HTTPS)),
+            shutdown,
+        }
+    }
+
+    /// Accepts connections until shutdown is triggered, open connections
+    /// are closed once their current requests are answered.
+    pub async fn serve(&self, listener: tokio::net::TcpListener) -> Result<()> {
+        let local = listener.local_addr()?;
+
+        loop {
+            let (stream, addr) = tokio::select! {
+                _ = self.shutdown.triggered() => return Ok(()),
+                accepted = accept(&listener, "https") => accepted,
+            };
+            let acceptor = self.acceptor.clone();
+            let responder = self.responder.clone();
+            let shutdown = self.shutdown.clone();
+
+            self.shutdown.spawn(async move {
+                let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => return debug!(client = %addr, transport = "https", "handshake failed: {}", e),
+                    Err(_) => return debug!(client = %addr, transport = "https", "handshake timed out"),
+                };
+
+                let client = Client::new(addr, local, Transport::Https);
+                let service = service_fn(move |request| Self::handle(request, client.clone(), responder.clone()));
+                let mut builder = auto::Builder::new(TokioExecutor::new());
+                builder.http1().timer(TokioTimer::new()).header_read_timeout(IDLE_TIMEOUT);
+                builder
+                    .http2()
+                    .timer(TokioTimer::new())
+                    .keep_alive_interval(IDLE_TIMEOUT)
+                    .keep_alive_timeout(PING_TIMEOUT);
+                let connection = builder.serve_connection(TokioIo::new(stream), service);
+                tokio::pin!(connection);
+
+                let closed = tokio::select! {
+                    closed = connection.as_mut() => closed,
+                    _ = shutdown.triggered() => {
+                        connection.as_mut().graceful_shutdown();
+                        connection.await
+                    }
+                };
+                if let Err(e) = closed {
+                    debug!(client = %addr, transport = "https", "closed connection: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle(
+        request: Request<Incoming>,
+        client: Client,
+        responder: Arc<Responder>,
+    ) -> std::result::Result<HttpResponse, Infallible> {
+        if request.uri().path() != PATH {
+            return Ok(Self::text(StatusCode::NOT_FOUND, "not found\n"));
+        }
+        let params = query_params(request.uri().query().unwrap_or(""));
+
+        let response = match (request.method(), params.get("dns"), params.get("name")) {
+            (&Method::GET, Some(dns), _) => {
+                match BASE64_URL_SAFE_NO_PAD.decode(dns.trim_end_matches('=')) {
+                    Ok(wire) => Self::answer(&wire, &client, &responder).await,
+                    Err(_) => Self::text(StatusCode::BAD_REQUEST, "dns is not base64url\n"),
+                }
+            }
+            (&Method::GET, None, Some(name)) => {
+                Self::answer_json(name, &params, &client, &responder).await
+            }
+            (&Method::GET, None, None) => {
+                Self::text(StatusCode::BAD_REQUEST, "dns or name is required\n")
+            }
+            (&Method::POST, _, _) => {
+                let content_type = request
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok());
+                if content_type != Some(DNS_MESSAGE) {
+                    return Ok(Self::text(
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        "use application/dns-message\n",
+                    ));
+                }
+                match Limited::new(request.into_body(), MAX_MESSAGE_SIZE)
+                    .collect()
+                    .await
+                {
+                    Ok(body) => Self::answer(&body.to_bytes(), &client, &responder).await,
+                    Err(e) if e.is::<LengthLimitError>() => {
+                        Self::text(StatusCode::PAYLOAD_TOO_LARGE, "larger than a dns message\n")
+                    }
+                    Err(e) => {
+                        debug!(client = %client.addr, "could not read request: {}", e);
+                        Self::text(StatusCode::BAD_REQUEST, "could not read the body\n")
+                    }
+                }
+            }
+            _ => {
+                let mut response = Self::text(StatusCode::METHOD_NOT_ALLOWED, "use GET or POST\n");
+                response
+                    .headers_mut()
+                    .insert(ALLOW, HeaderValue::from_static("GET, POST"));
+                response
+            }
+        };
+
+        Ok(response)
+    }
+
+    /// Answers the DNS message `wire`, in wire format
+    async fn answer(wire: &[u8], client: &Client, responder: &Responder) -> HttpResponse {
+        let request = match DnsReader::from(wire).read().await {
+            Ok(request) => request,
+            Err(e) => {
+                responder.metrics().record_parse_failure(Transport::Https);
+                debug!(client = %client.addr, "malformed query: {}", e);
+                return Self::text(StatusCode::BAD_REQUEST, "malformed dns message\n");
+            }
+        };
+        debug!(client = %client.addr, ?request, "received query");
+
+        let (response, session) = responder.respond_signed(wire, request, client).await;
+        debug!(client = %client.addr, ?response, "sending response");
+        let max_age = max_age(&response);
+        let mut body = match response.to_bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                debug!(client = %client.addr, "could not write response: {}", e);
+                return Self::text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "could not write the response\n",
+                );
+            }
+        };
+        if let Some(mut session) = session {
+            body = session.sign(body);
+        }
+
+        Self::message(DNS_MESSAGE, body, max_age)
+    }
+
+    /// Answers the question of the `name`, `type`, `do` and `cd`
+    /// parameters in JSON
+    async fn answer_json(
+        name: &str,
+        params: &HashMap<String, String>,
+        client: &Client,
+        responder: &Responder,
+    ) -> HttpResponse {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let labels_valid = name.is_empty()
+            || name
+                .split('.')
+                .all(|label| !label.is_empty() && label.len() <= 63);
+        if name.len() > 253 || !labels_valid {
+            return Self::text(StatusCode::BAD_REQUEST, "name is not a domain name\n");
+        }
+        let qtype = match params.get("type").map(|qtype| query_type(qtype)) {
+            None => QueryType::A,
+            Some(Some(qtype)) => qtype,
+            Some(None) => {
+                return Self::text(StatusCode::BAD_REQUEST, "type is not a record type\n")
+            }
+        };
+
+        let mut builder = MessageBuilder::new_request(0)
+            .set_recursion_desired()
+            .add_new_question(name, qtype, Class::IN);
+        if is_set(params.get("cd")) {
+            builder = builder.set_checking_disabled();
+        }
+        if is_set(params.get("do")) {
+            let mut edns = Edns::new();
+            edns.dnssec_ok = true;
+            builder = builder.add_resources(edns.to_record());
+        }
+
+        let response = responder.respond(builder.build(), client).await;
+        let max_age = max_age(&response);
+        let body = to_json(&response).to_string();
+
+        Self::message(DNS_JSON, body.into_bytes(), max_age)
+    }
+
+    /// A response HTTP caches may keep as long as its records (RFC 8484 5.1)
+    fn message(content_type: &'static str, body: Vec<u8>, max_age: Option<u32>) -> HttpResponse {
+        let mut response = Response::new(Full::new(Bytes::from(body)));
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        if let Some(max_age) = max_age {
+            let cache_control = format!("max-age={}", max_age);
+            headers.insert(
+                CACHE_CONTROL,
+                HeaderValue::from_str(&cache_control).expect("digits are valid"),
+            );
+        }
+        response
+    }
+
+    fn text(status: StatusCode, body: &'static str) -> HttpResponse {
+        let mut response = Response::new(Full::new(Bytes::from_static(body.as_bytes())));
+        *response.status_mut() = status;
+        response
+    }
+}
+
+/// The parameters of a query string, percent-decoded
+fn query_params(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Whether a boolean parameter of the JSON API is set
+fn is_set(value: Option<&String>) -> bool {
+    matches!(value.map(String::as_str), Some("1" | "true"))
+}
+
+/// A record type given by number or mnemonic, `TYPE<n>` included (RFC 3597 5)
+fn query_type(text: &str) -> Option<QueryType> {
+    if let Ok(value) = text.parse::<u16>() {
+        return Some(QueryType::from(value));
+    }
+    let upper = text.to_ascii_uppercase();
+    if let Some(value) = upper
+        .strip_prefix("TYPE")
+        .and_then(|value| value.parse::<u16>().ok())
+    {
+        return Some(QueryType::from(value));
+    }
+    [1, 2, 5, 6, 15, 16, 28, 43, 46, 47, 48, 50, 251, 252]
+        .into_iter()
+        .find(|value| type_name(*value) == upper)
+        .map(QueryType::from)
+}
+
+fn type_name(value: u16) -> String {
+    match QueryType::from(value) {
+        QueryType::UNKNOWN(value) => format!("TYPE{}", value),
+        qtype => format!("{:?}", qtype),
+    }
+}
+
+/// How long the response may be cached: its smallest TTL, which for a
+/// negative answer is the one of the SOA in authority
+fn max_age(response: &Message) -> Option<u32> {
+    response
+        .answers
+        .iter()
+        .chain(response.authority.iter())
+        .chain(response.resources.iter())
+        .filter(|record| !matches!(record, Record::OPT { .. }))
+        .map(Record::ttl)
+        .min()
+}
+
+/// The response in the JSON format of the public resolvers' APIs
+fn to_json(response: &Message) -> Value {
+    let header = &response.header;
+    let records = |records: &[Record]| -> Value {
+        records
+            .iter()
+            .filter(|record| !matches!(record, Record::OPT { .. }))
+            .map(|record| {
+                json!({
+                    "name": absolute(record.name()),
+                    "type": record.query_type().to_u16(),
+                    "TTL": record.ttl(),
+                    "data": data(record),
+                })
+            })
+            .collect()
+    };
+
+    let mut json = json!({
+        "Status": header.result_code().to(),
+        "TC": header.is_truncated(),
+        "RD": header.is_recursion_desired(),
+        "RA": header.is_recursion_available(),
+        "AD": header.is_authentic_data(),
+        "CD": header.is_checking_disabled(),
+        "Question": response
+            .questions
+            .iter()
+            .map(|question| json!({ "name": absolute(&question.name), "type": question.r#type.to_u16() }))
+            .collect::<Value>(),
+    });
+    for (section, section_records) in [
+        ("Answer", &response.answers),
+        ("Authority", &response.authority),
+        ("Additional", &response.resources),
+    ] {
+        let section_records = records(section_records);
+        if section_records
+            .as_array()
+            .is_some_and(|records| !records.is_empty())
+        {
+            json[section] = section_records;
+        }
+    }
+    json
+}
+
+fn absolute(name: &str) -> String {
+    format!("{}.", name)
+}
+
+/// The data of a record in presentation format
+fn data(record: &Record) -> String {
+    match record {
+        Record::A { addr, .. } => addr.to_string(),
+        Record::AAAA { addr, .. } => addr.to_string(),
+        Record::NS { host, .. } | Record::CNAME { host, .. } | Record::PTR { host, .. } => {
+            absolute(host)
+        }
+        Record::MX { priority, host, .. } => format!("{} {}", priority, absolute(host)),
+        Record::SRV {
+            priority,
+            weight,
+            port,
+            target,
+            ..
+        } => {
+            format!("{} {} {} {}", priority, weight, port, absolute(target))
+        }
+        Record::SOA {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+            ..
+        } => format!(
+            "{} {} {} {} {} {} {}",
+            absolute(mname),
+            absolute(rname),
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum
+        ),
+        Record::TXT { data, .. } => data
+            .iter()
+            .map(|text| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect::<Vec<_>>()
+            .join(" "),
+        Record::RRSIG {
+            type_covered,
+            algorithm,
+            labels,
+            original_ttl,
+            expiration,
+            inception,
+            key_tag,
+            signer,
+            signature,
+            ..
+        } => format!(
+            "{} {} {} {} {} {} {} {} {}",
+            type_name(*type_covered),
+            algorithm,
+            labels,
+            original_ttl,
+            expiration,
+            inception,
+            key_tag,
+            absolute(signer),
+            BASE64_STANDARD.encode(signature)
+        ),
+        Record::NSEC { next, types, .. } => format!("{} {}", absolute(next), type_names(types)),
+        Record::DNSKEY {
+            flags,
+            protocol,
+            algorithm,
+            public_key,
+            ..
+        } => {
+            format!(
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                BASE64_STANDARD.encode(public_key)
+            )
+        }
+        Record::DS {
+            key_tag,
+            algorithm,
+            digest_type,
+            digest,
+            ..
+        } => {
+            format!("{} {} {} {}", key_tag, algorithm, digest_type, hex(digest))
+        }
+        Record::NSEC3 {
+            hash_algorithm,
+            flags,
+            iterations,
+            salt,
+            next,
+            types,
+            ..
+        } => format!(
+            "{} {} {} {} {} {}",
+            hash_algorithm,
+            flags,
+            iterations,
+            if salt.is_empty() {
+                "-".to_owned()
+            } else {
+                hex(salt)
+            },
+            base32hex(next),
+            type_names(types)
+        ),
+        Record::OPT { .. } => String::new(),
+        // RFC 3597 5
+        Record::UNKNOWN { data, .. } => format!("\\# {} {}", data.len(), hex(data)),
+    }
+}
+
+fn type_names(types: &[u16]) -> String {
+    types
+        .iter()
+        .map(|value| type_name(*value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+fn base32hex(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"0123456789abcdefghijklmnopqrstuv";
+    let mut text = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut block = [0; 5];
+        block[..chunk.len()].copy_from_slice(chunk);
+        let bits = block
+            .iter()
+            .fold(0u64, |bits, byte| bits << 8 | *byte as u64);
+        for i in 0..(chunk.len() * 8).div_ceil(5) {
+            text.push(ALPHABET[(bits >> (35 - 5 * i)) as usize & 31] as char);
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+    use rustls::crypto::ring::default_provider;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, RootCertStore};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    use super::HttpsListener;
+    use crate::core::config::Config;
+    use crate::core::dns::dns_reader_writer::DnsReader;
+    use crate::core::dns::message::{Class, Message, QueryType, Record};
+    use crate::core::dns::message_builder::MessageBuilder;
+    use crate::core::dns::responder::Responder;
+    use crate::core::dns::tls::{Certificates, SelfSigned};
+    use crate::core::shutdown::Shutdown;
+
+    /// Sends `head` and `body` over HTTP/1.1, returning the status line and
+    /// headers, and the body of the response
+    async fn send(
+        connector: &TlsConnector,
+        addr: SocketAddr,
+        head: &str,
+        body: &[u8],
+    ) -> (String, Vec<u8>) {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let request = format!("{}\r\nHost: localhost\r\nConnection: close\r\n\r\n", head);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let head = String::from_utf8(response[..end].to_vec()).unwrap();
+        (head.to_ascii_lowercase(), response[end + 4..].to_vec())
+    }
+
+    async fn query() -> Vec<u8> {
+        MessageBuilder::new_request(0)
+            .add_new_question("www.example.internal".to_owned(), QueryType::A, Class::IN)
+            .build()
+            .to_bytes()
+            .await
+            .unwrap()
+    }
+
+    async fn parse(body: &[u8]) -> Message {
+        DnsReader::from(body).read().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_over_https_in_wire_format_and_json() {
+        let certificate = SelfSigned::new();
+
+        let config: Config = toml::from_str(
+            r#"
+            [[zones]]
+            name = "example.internal"
+            records = [{ name = "www", type = "A", value = "10.0.0.1", ttl = 120 }]
+            "#,
+        )
+        .unwrap();
+        let responder = Arc::new(Responder::from_config(&config).unwrap());
+        let certificates = Certificates::from_config(&certificate.config).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let https = HttpsListener::new(responder, &certificates, Shutdown::new());
+        tokio::spawn(async move { https.serve(listener).await });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate.der.clone()).unwrap();
+        let mut client = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let connector = TlsConnector::from(Arc::new(client));
+
+        // GET with the query in base64url, cached as long as the answer
+        let encoded = BASE64_URL_SAFE_NO_PAD.encode(query().await);
+        let (head, body) = send(
+            &connector,
+            addr,
+            &format!("GET /dns-query?dns={} HTTP/1.1", encoded),
+            &[],
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 200"));
+        assert!(head.contains("content-type: application/dns-message"));
+        assert!(head.contains("cache-control: max-age=120"));
+        let response = parse(&body).await;
+        let www = Record::new_type_a(
+            "www.example.internal".to_owned(),
+            "10.0.0.1".parse().unwrap(),
+            120,
+        );
+        assert_eq!(response.answers, vec![www]);
+
+        // POST with the query as body
+        let wire = query().await;
+        let head_lines = format!(
+            "POST /dns-query HTTP/1.1\r\nContent-Type: application/dns-message\r\nContent-Length: {}",
+            wire.len()
+        );
+        let (head, body) = send(&connector, addr, &head_lines, &wire).await;
+        assert!(head.starts_with("http/1.1 200"));
+        assert_eq!(parse(&body).await.answers.len(), 1);
+
+        // The JSON API takes the name and type as parameters
+        let (head, body) = send(
+            &connector,
+            addr,
+            "GET /dns-query?name=www.example.internal.&type=a HTTP/1.1",
+            &[],
+        )
+        .await;
+        assert!(head.contains("content-type: application/dns-json"));
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["Status"], 0);
+        assert_eq!(json["Question"][0]["name"], "www.example.internal.");
+        assert_eq!(json["Answer"][0]["type"], 1);
+        assert_eq!(json["Answer"][0]["TTL"], 120);
+        assert_eq!(json["Answer"][0]["data"], "10.0.0.1");
+
+        let (head, _) = send(
+            &connector,
+            addr,
+            "GET /dns-query?name=missing.example.internal HTTP/1.1",
+            &[],
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 200"));
+
+        // And everything else is refused
+        let (head, _) = send(&connector, addr, "GET /dns-query?dns=!! HTTP/1.1", &[]).await;
+        assert!(head.starts_with("http/1.1 400"));
+        let (head, _) = send(
+            &connector,
+            addr,
+            "POST /dns-query HTTP/1.1\r\nContent-Length: 0",
+            &[],
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 415"));
+        let (head, _) = send(&connector, addr, "DELETE /dns-query HTTP/1.1", &[]).await;
+        assert!(head.starts_with("http/1.1 405"));
+        let (head, _) = send(&connector, addr, "GET /resolve HTTP/1.1", &[]).await;
+        assert!(head.starts_with("http/1.1 404"));
+    }
+}
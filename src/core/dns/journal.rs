@@ -0,0 +1,244 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::message::Record;
+
+/// Changes kept per zone, older ones are answered with a full transfer
+const MAX_CHANGES: usize = 100;
+
+/// What changed in a zone from one serial to the next
+#[derive(Debug, Clone)]
+struct Change {
+    /// SOA of the version the change applies to
+    from: Record,
+    removed: Vec<Record>,
+    /// SOA of the version the change leads to
+    to: Record,
+    added: Vec<Record>,
+}
+
+/// Changes of every zone and registry across reloads, keyed by view and
+/// zone name, answering incremental transfers (RFC 1995). Each reload
+/// builds a new journal from the previous one, dropping removed zones.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Journal {
+    zones: HashMap<(String, String), VecDeque<Change>>,
+}
+
+impl Journal {
+    /// Records the change of a zone from the records `previous` served to
+    /// `current`, both as sent in a zone transfer, on top of its changes in
+    /// the journal `before` the reload. Returns the serial the zone must be
+    /// served with.
+    ///
+    /// The configured serial is used when it is newer than the previous
+    /// one, otherwise the previous serial is kept for the same records and
+    /// bumped for different ones, so secondaries notice every change even
+    /// when the serial is not maintained by hand (or at all, for the registry).
+    pub(crate) fn record(
+        &mut self,
+        before: &Journal,
+        view: &str,
+        zone: &str,
+        previous: Option<Vec<Record>>,
+        current: Vec<Record>,
+    ) -> u32 {
+        let key = (view.to_owned(), zone.to_owned());
+        let configured = serial(&current[0]);
+
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return configured,
+        };
+        let last = serial(&previous[0]);
+        let mut changes = before.zones.get(&key).cloned().unwrap_or_default();
+
+        let old: HashSet<&Record> = previous[1..previous.len() - 1].iter().collect();
+        let new: HashSet<&Record> = current[1..current.len() - 1].iter().collect();
+        let removed: Vec<Record> = previous[1..previous.len() - 1]
+            .iter()
+            .filter(|record| !new.contains(record))
+            .cloned()
+            .collect();
+        let added: Vec<Record> = current[1..current.len() - 1]
+            .iter()
+            .filter(|record| !old.contains(record))
+            .cloned()
+            .collect();
+
+        let serial = if is_newer(configured, last) {
+            configured
+        } else if removed.is_empty() && added.is_empty() {
+            last
+        } else {
+            last.wrapping_add(1)
+        };
+
+        if serial != last {
+            let mut to = current[0].clone();
+            set_serial(&mut to, serial);
+            changes.push_back(Change {
+                from: previous[0].clone(),
+                removed,
+                to,
+                added,
+            });
+            if changes.len() > MAX_CHANGES {
+                changes.pop_front();
+            }
+        }
+        if !changes.is_empty() {
+            self.zones.insert(key, changes);
+        }
+
+        serial
+    }
+
+    /// Drops the changes of a zone no longer served
+    pub(crate) fn forget(&mut self, view: &str, zone: &str) {
+        self.zones.remove(&(view.to_owned(), zone.to_owned()));
+    }
+
+    /// The changes from version `serial` of a zone to the current one, as
+    /// sent in an incremental transfer between two copies of the current
+    /// SOA. None when the journal does not go back that far.
+    pub(crate) fn since(
+        &self,
+        view: &str,
+        zone: &str,
+        current: &Record,
+        serial: u32,
+    ) -> Option<Vec<Record>> {
+        let changes = self.zones.get(&(view.to_owned(), zone.to_owned()))?;
+        let first = changes
+            .iter()
+            .position(|change| self::serial(&change.from) == serial)?;
+
+        let mut records = vec![current.clone()];
+        for change in changes.iter().skip(first) {
+            records.push(change.from.clone());
+            records.extend(change.removed.iter().cloned());
+            records.push(change.to.clone());
+            records.extend(change.added.iter().cloned());
+        }
+        records.push(current.clone());
+        Some(records)
+    }
+}
+
+/// Whether serial `a` comes after `b`, in serial number arithmetic (RFC 1982)
+pub(crate) fn is_newer(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
+
+pub(crate) fn serial(soa: &Record) -> u32 {
+    match soa {
+        Record::SOA { serial, .. } => *serial,
+        _ => 0,
+    }
+}
+
+pub(crate) fn set_serial(soa: &mut Record, to: u32) {
+    if let Record::SOA { serial, .. } = soa {
+        *serial = to;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{is_newer, serial, Journal};
+    use crate::core::config::{serial_at, DefaultsConfig, SoaConfig};
+    use crate::core::dns::message::Record;
+    use crate::core::dns::zone::new_soa;
+
+    fn version(serial: u32, addresses: &[u8]) -> Vec<Record> {
+        let soa = new_soa(
+            "svc.internal",
+            &SoaConfig {
+                serial,
+                ..SoaConfig::default()
+            },
+            &DefaultsConfig::default(),
+            30,
+        );
+        let mut records = vec![soa.clone()];
+        records.extend(addresses.iter().map(|last| {
+            Record::new_type_a(
+                "api.svc.internal".to_owned(),
+                Ipv4Addr::new(10, 0, 0, *last),
+                30,
+            )
+        }));
+        records.push(soa);
+        records
+    }
+
+    fn reload(
+        journal: &Journal,
+        previous: Option<Vec<Record>>,
+        current: Vec<Record>,
+    ) -> (Journal, u32) {
+        let mut next = Journal::default();
+        let serial = next.record(journal, "default", "svc.internal", previous, current);
+        (next, serial)
+    }
+
+    #[test]
+    fn bumps_the_serial_and_answers_the_changes_since() {
+        let (journal, first) = reload(&Journal::default(), None, version(1, &[1]));
+        let (journal, same) = reload(&journal, Some(version(1, &[1])), version(1, &[1]));
+        // The configured serial did not move, the records did
+        let (journal, added) = reload(&journal, Some(version(1, &[1])), version(1, &[1, 2]));
+        let (journal, removed) = reload(&journal, Some(version(2, &[1, 2])), version(1, &[2]));
+        assert_eq!([first, same, added, removed], [1, 1, 2, 3]);
+
+        let current = version(3, &[2]).remove(0);
+        let records = journal
+            .since("default", "svc.internal", &current, 1)
+            .unwrap();
+        let serials: Vec<u32> = records
+            .iter()
+            .filter(|record| matches!(record, Record::SOA { .. }))
+            .map(serial)
+            .collect();
+        assert_eq!(serials, [3, 1, 2, 2, 3, 3]);
+        // SOA 1, SOA 2 and 10.0.0.2 added, SOA 2 and 10.0.0.1 removed, SOA 3
+        assert_eq!(records.len(), 8);
+
+        assert!(journal
+            .since("default", "svc.internal", &current, 0)
+            .is_none());
+        assert!(reload(&journal, None, version(1, &[2]))
+            .0
+            .since("default", "svc.internal", &current, 1)
+            .is_none());
+        assert!(is_newer(0, u32::MAX));
+    }
+
+    #[test]
+    fn restarts_with_a_newer_serial() {
+        // The last run started at `started` and changed the registry twice
+        let started = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let configured = serial_at(started);
+        let (journal, _) = reload(&Journal::default(), None, version(configured, &[1]));
+        let (journal, bumped) = reload(
+            &journal,
+            Some(version(configured, &[1])),
+            version(configured, &[1, 2]),
+        );
+        let (_, last) = reload(
+            &journal,
+            Some(version(bumped, &[1, 2])),
+            version(configured, &[2]),
+        );
+        assert_eq!(last, configured + 2);
+
+        // Restarted a minute later, the configuration unchanged
+        let restarted = serial_at(started + Duration::from_secs(60));
+        let (_, served) = reload(&Journal::default(), None, version(restarted, &[2]));
+        assert!(is_newer(served, last));
+        assert!(is_newer(SoaConfig::default().serial, 1));
+    }
+}
@@ -0,0 +1,914 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Error;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::Result;
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::core::config::MdnsConfig;
+use crate::core::shutdown::Shutdown;
+
+use super::dns_reader_writer::DnsReader;
+use super::message::{Class, Message, QueryType, Question, Record, ResultCode};
+use super::message_builder::MessageBuilder;
+use super::responder::Responder;
+use super::socket::{bind_multicast_v4, bind_multicast_v6};
+
+const GROUP_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+/// Names published on the local link are under it (RFC 6762 3)
+const DOMAIN: &str = "local";
+
+/// TTL of the records naming hosts (RFC 6762 10)
+const TTL: u32 = 120;
+
+/// Highest TTL given to legacy unicast queriers (RFC 6762 6.7)
+const LEGACY_TTL: u32 = 10;
+
+/// Top bit of the class of a record, telling that it replaces the ones
+/// cached for its name and type (RFC 6762 10.2)
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// Top bit of the class of a question, asking for a unicast response
+/// (RFC 6762 5.4)
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+const TYPE_ANY: u16 = 255;
+
+/// Probes sent for a name before claiming it, and the time between them
+/// (RFC 6762 8.1)
+const PROBES: u8 = 3;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A probe that lost against the one of another host is sent again after
+/// (RFC 6762 8.2)
+const PROBE_DEFERRAL: Duration = Duration::from_secs(1);
+
+/// Unsolicited responses sent once a name is claimed, and the time between
+/// them (RFC 6762 8.3)
+const ANNOUNCEMENTS: u8 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A record is multicast at most once per interval (RFC 6762 6.2)
+const MULTICAST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Answers with shared records, which other hosts give too, wait a random
+/// time in this range for the responses not to collide (RFC 6762 6)
+const SHARED_DELAY: Range<Duration> = Duration::from_millis(20)..Duration::from_millis(120);
+
+/// A name another host answers for is probed again after
+const CONFLICT_RETRY: Duration = Duration::from_secs(60);
+
+/// Largest packet received, the Ethernet jumbo frame (RFC 6762 17)
+const MAX_PACKET_SIZE: usize = 9000;
+
+/// Where a name is in its life on the link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Checking that no other host uses it, `sent` probes so far
+    Probing {
+        sent: u8,
+    },
+    /// Claimed and announced `sent` times so far
+    Announcing {
+        sent: u8,
+    },
+    Published,
+    /// Another host answers for it, it is not published
+    Conflict,
+}
+
+#[derive(Debug)]
+struct Name {
+    records: Vec<Record>,
+    phase: Phase,
+    /// When the next probe or announcement is due
+    due: Instant,
+}
+
+impl Name {
+    /// Whether queries for the name are answered
+    fn is_claimed(&self) -> bool {
+        matches!(self.phase, Phase::Announcing { .. } | Phase::Published)
+    }
+}
+
+/// A name being probed and the records it is probed for
+#[derive(Debug)]
+struct Probe {
+    name: String,
+    records: Vec<Record>,
+    first: bool,
+}
+
+/// What the next tick sends
+#[derive(Debug, Default)]
+struct Outgoing {
+    probes: Vec<Probe>,
+    announcements: Vec<Record>,
+    goodbyes: Vec<Record>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Every name of the registry, lowercased
+    names: BTreeMap<String, Name>,
+    /// When each record was last multicast, by name, type and data
+    multicast: HashMap<Record, Instant>,
+}
+
+impl State {
+    /// Follows the changes of `records`: new names are probed, changed
+    /// ones announced again and the records gone get a goodbye. Returns
+    /// the probes and announcements due by `now` with them.
+    fn update(&mut self, records: Vec<Record>, now: Instant) -> Outgoing {
+        let mut current: BTreeMap<String, Vec<Record>> = BTreeMap::new();
+        for record in records {
+            let records = current
+                .entry(record.name().to_ascii_lowercase())
+                .or_default();
+            if !records.contains(&record) {
+                records.push(record);
+            }
+        }
+
+        let mut outgoing = Outgoing::default();
+        self.names.retain(|name, known| {
+            let kept = current.contains_key(name);
+            if !kept && known.is_claimed() {
+                outgoing.goodbyes.extend(known.records.iter().cloned());
+            }
+            kept
+        });
+        for (name, records) in current {
+            match self.names.get_mut(&name) {
+                // Other hosts answer for shared names too, there is
+                // nothing to probe for (RFC 6762 8.1)
+                None if records.iter().all(is_shared) => {
+                    let phase = Phase::Announcing { sent: 0 };
+                    self.names.insert(
+                        name,
+                        Name {
+                            records,
+                            phase,
+                            due: now,
+                        },
+                    );
+                }
+                None => {
+                    let phase = Phase::Probing { sent: 0 };
+                    self.names.insert(
+                        name,
+                        Name {
+                            records,
+                            phase,
+                            due: now,
+                        },
+                    );
+                }
+                Some(known) if known.records != records => {
+                    // Claimed names only need their new records announced
+                    // (RFC 6762 8.4)
+                    if known.is_claimed() {
+                        let removed = known
+                            .records
+                            .iter()
+                            .filter(|record| !records.contains(record));
+                        outgoing.goodbyes.extend(removed.cloned());
+                        known.phase = Phase::Announcing { sent: 0 };
+                        known.due = now;
+                    }
+                    known.records = records;
+                }
+                Some(_) => {}
+            }
+        }
+
+        for (name, known) in self.names.iter_mut().filter(|(_, known)| known.due <= now) {
+            if known.phase == (Phase::Probing { sent: PROBES }) {
+                known.phase = Phase::Announcing { sent: 0 };
+            }
+            match known.phase {
+                Phase::Probing { sent } => {
+                    outgoing.probes.push(Probe {
+                        name: name.clone(),
+                        records: known.records.clone(),
+                        first: sent == 0,
+                    });
+                    known.phase = Phase::Probing { sent: sent + 1 };
+                    known.due = now + PROBE_INTERVAL;
+                }
+                Phase::Announcing { sent } if sent < ANNOUNCEMENTS => {
+                    outgoing.announcements.extend(known.records.iter().cloned());
+                    known.phase = Phase::Announcing { sent: sent + 1 };
+                    known.due = now + ANNOUNCE_INTERVAL;
+                }
+                Phase::Announcing { .. } => known.phase = Phase::Published,
+                Phase::Conflict => {
+                    known.phase = Phase::Probing { sent: 0 };
+                    known.due = now;
+                }
+                Phase::Published => {}
+            }
+        }
+        for record in &outgoing.announcements {
+            self.multicast.insert(normalized(record), now);
+        }
+        outgoing
+    }
+
+    /// Looks for the records of other hosts in the answers of a response
+    /// for names of ours (RFC 6762 9). Names being probed can not be
+    /// claimed then, claimed names are probed again.
+    fn check_conflicts(&mut self, answers: &[Record], now: Instant) {
+        for answer in answers
+            .iter()
+            .filter(|answer| answer.ttl() > 0 && !is_shared(answer))
+        {
+            let known = match self.names.get_mut(&answer.name().to_ascii_lowercase()) {
+                Some(known) => known,
+                None => continue,
+            };
+            if known.records.iter().any(|record| same_data(record, answer)) {
+                continue;
+            }
+
+            match known.phase {
+                Phase::Probing { .. } => {
+                    warn!(
+                        "mDNS name {} is already used on the link, it is not published",
+                        answer.name()
+                    );
+                    known.phase = Phase::Conflict;
+                    known.due = now + CONFLICT_RETRY;
+                }
+                Phase::Announcing { .. } | Phase::Published
+                    if known
+                        .records
+                        .iter()
+                        .any(|record| record.query_type() == answer.query_type()) =>
+                {
+                    debug!(
+                        "mDNS name {} is also answered by another host, probing it again",
+                        answer.name()
+                    );
+                    known.phase = Phase::Probing { sent: 0 };
+                    known.due = now;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The records answering `questions`, leaving out the ones the querier
+    /// already knows with at least half their TTL left (RFC 6762 7.1).
+    /// Records of the same names that were not asked for come second, with
+    /// the ones of the instances and hosts the answers point to, so that
+    /// browsing takes a single query (RFC 6763 12).
+    fn answer(&self, questions: &[Question], known: &[Record]) -> (Vec<Record>, Vec<Record>) {
+        let mut answers: Vec<Record> = Vec::new();
+        let mut names = Vec::new();
+        for question in questions {
+            let class = question.class.to_u16() & !UNICAST_RESPONSE;
+            let name = match self.names.get(&question.name.to_ascii_lowercase()) {
+                Some(name) if name.is_claimed() && (class == CLASS_IN || class == CLASS_ANY) => {
+                    name
+                }
+                _ => continue,
+            };
+            let qtype = question.r#type.to_u16();
+            answers.extend(
+                name.records
+                    .iter()
+                    .filter(|record| qtype == TYPE_ANY || record.query_type().to_u16() == qtype)
+                    .filter(|record| !answers.contains(record))
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            );
+            names.push(name);
+        }
+
+        let is_known = |record: &Record| {
+            known
+                .iter()
+                .any(|known| same_data(known, record) && known.ttl() >= record.ttl() / 2)
+        };
+        answers.retain(|record| !is_known(record));
+
+        let mut targets: Vec<&str> = answers.iter().filter_map(target).collect();
+        while let Some(next) = targets.pop() {
+            match self.names.get(&next.to_ascii_lowercase()) {
+                // The PTRs of service types would list every instance
+                Some(name)
+                    if name.is_claimed()
+                        && !name.records.iter().all(is_shared)
+                        && !names.iter().any(|known| std::ptr::eq(*known, name)) =>
+                {
+                    targets.extend(name.records.iter().filter_map(target));
+                    names.push(name);
+                }
+                _ => {}
+            }
+        }
+        let mut additional: Vec<Record> = Vec::new();
+        for record in names.iter().flat_map(|name| name.records.iter()) {
+            if !answers.contains(record) && !additional.contains(record) && !is_known(record) {
+                additional.push(record.clone());
+            }
+        }
+        (answers, additional)
+    }
+
+    /// Leaves out the records multicast less than a second ago, noting
+    /// the others as multicast `now`
+    fn rate_limit(&mut self, records: Vec<Record>, now: Instant) -> Vec<Record> {
+        self.multicast
+            .retain(|_, sent| now.duration_since(*sent) < MULTICAST_INTERVAL);
+        records
+            .into_iter()
+            .filter(|record| {
+                let key = normalized(record);
+                match self.multicast.get(&key) {
+                    Some(sent) if now.duration_since(*sent) < MULTICAST_INTERVAL => false,
+                    _ => {
+                        self.multicast.insert(key, now);
+                        true
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Every record of the claimed names, to say goodbye to on shutdown
+    fn withdraw(&mut self) -> Vec<Record> {
+        let names = std::mem::take(&mut self.names);
+        names
+            .into_values()
+            .filter(Name::is_claimed)
+            .flat_map(|name| name.records)
+            .collect()
+    }
+}
+
+/// One of the multicast groups the responder joined
+struct Link {
+    socket: UdpSocket,
+    group: SocketAddr,
+}
+
+/// Publishes the services of the registry on the local link with multicast
+/// DNS (RFC 6762), as `<service>.local`, and the DNS-SD records browsing
+/// them. Every name is probed before it is claimed, then announced, and its
+/// records are sent with the cache-flush bit since this responder is the
+/// only one answering for them, but for the shared PTRs of DNS-SD.
+pub struct MdnsResponder {
+    responder: Arc<Responder>,
+    links: Vec<Link>,
+    port: u16,
+    state: Mutex<State>,
+    shutdown: Shutdown,
+}
+
+impl MdnsResponder {
+    /// Joins the mDNS groups of `config`, reporting the one that failed
+    pub fn bind(
+        config: &MdnsConfig,
+        responder: Arc<Responder>,
+        shutdown: Shutdown,
+    ) -> Result<MdnsResponder> {
+        let mut links = Vec::new();
+        if config.ipv4 {
+            let socket =
+                bind_multicast_v4(GROUP_V4, config.port, config.interface).map_err(|e| {
+                    Error::new(e.kind(), format!("could not join mdns {}: {}", GROUP_V4, e))
+                })?;
+            links.push(Link {
+                socket,
+                group: SocketAddr::from((GROUP_V4, config.port)),
+            });
+        }
+        if config.ipv6 {
+            let socket =
+                bind_multicast_v6(GROUP_V6, config.port, config.interface_v6).map_err(|e| {
+                    Error::new(e.kind(), format!("could not join mdns {}: {}", GROUP_V6, e))
+                })?;
+            links.push(Link {
+                socket,
+                group: SocketAddr::from((GROUP_V6, config.port)),
+            });
+        }
+
+        Ok(MdnsResponder {
+            responder,
+            links,
+            port: config.port,
+            state: Mutex::new(State::default()),
+            shutdown,
+        })
+    }
+
+    /// Answers queries and keeps the published names in line with the
+    /// registry until shutdown, when every name gets a goodbye.
+    pub async fn serve(self: Arc<Self>) -> Result<()> {
+        let mut tasks = JoinSet::new();
+        for link in 0..self.links.len() {
+            tasks.spawn(self.clone().receive(link));
+        }
+        tasks.spawn(self.clone().publish());
+
+        while let Some(joined) = tasks.join_next().await {
+            joined.map_err(Error::other)??;
+        }
+        Ok(())
+    }
+
+    async fn publish(self: Arc<Self>) -> Result<()> {
+        // Hosts starting together should not probe in step (RFC 6762 8.1)
+        sleep(PROBE_INTERVAL.mul_f64(rand::random::<f64>())).await;
+
+        loop {
+            let records = self.responder.local_records(DOMAIN, TTL);
+            let outgoing = self.state.lock().unwrap().update(records, Instant::now());
+
+            if !outgoing.probes.is_empty() {
+                self.multicast(probe(outgoing.probes)).await?;
+            }
+            if !outgoing.announcements.is_empty() {
+                self.multicast(response(outgoing.announcements, Vec::new(), TTL))
+                    .await?;
+            }
+            if !outgoing.goodbyes.is_empty() {
+                self.multicast(response(outgoing.goodbyes, Vec::new(), 0))
+                    .await?;
+            }
+
+            tokio::select! {
+                _ = self.shutdown.triggered() => break,
+                _ = sleep(PROBE_INTERVAL) => {}
+            }
+        }
+
+        // Caches of the link flush the records at once (RFC 6762 10.1)
+        let goodbyes = self.state.lock().unwrap().withdraw();
+        if !goodbyes.is_empty() {
+            self.multicast(response(goodbyes, Vec::new(), 0)).await?;
+        }
+        Ok(())
+    }
+
+    async fn receive(self: Arc<Self>, link: usize) -> Result<()> {
+        let socket = &self.links[link].socket;
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+
+        loop {
+            let received = tokio::select! {
+                _ = self.shutdown.triggered() => return Ok(()),
+                received = socket.recv_from(&mut buf) => received,
+            };
+            // Errors, as the ICMP ones reported for what we sent, only
+            // concern that packet
+            let (len, from) = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!(transport = "mdns", "could not receive: {}", e);
+                    continue;
+                }
+            };
+            let message = match DnsReader::from(&buf[..len]).read().await {
+                Ok(message) => message,
+                Err(e) => {
+                    debug!(client = %from, transport = "mdns", "dropped packet: {}", e);
+                    continue;
+                }
+            };
+            if message.header.op_code() != 0 || message.header.result_code() != ResultCode::NOERROR
+            {
+                continue;
+            }
+
+            if !message.header.is_query() {
+                // Responses from any other port are not from a responder
+                // (RFC 6762 11)
+                if from.port() == self.port {
+                    self.state
+                        .lock()
+                        .unwrap()
+                        .check_conflicts(&message.answers, Instant::now());
+                }
+                continue;
+            }
+
+            if !message.authority.is_empty() {
+                if let Err(e) = self.break_tie(&message.authority).await {
+                    debug!(client = %from, transport = "mdns", "could not compare probes: {}", e);
+                }
+            }
+            // Answers may wait, the next queries should not
+            let mdns = self.clone();
+            self.shutdown.spawn(async move {
+                if let Err(e) = mdns.answer(link, message, from).await {
+                    debug!(client = %from, transport = "mdns", "could not answer: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Answers a query, by multicast unless it asks for a unicast response
+    /// or comes from a legacy resolver, which is not on the mDNS port
+    async fn answer(&self, link: usize, query: Message, from: SocketAddr) -> Result<()> {
+        let legacy = from.port() != self.port;
+        let unicast = !query.questions.is_empty()
+            && query
+                .questions
+                .iter()
+                .all(|question| question.class.to_u16() & UNICAST_RESPONSE != 0);
+        let (answers, additional) = self
+            .state
+            .lock()
+            .unwrap()
+            .answer(&query.questions, &query.answers);
+        if answers.is_empty() {
+            return Ok(());
+        }
+        sleep(response_delay(&answers)).await;
+        let socket = &self.links[link].socket;
+
+        if legacy {
+            // Answered like a unicast DNS server would, with its question
+            // and short TTLs in case of a conflict (RFC 6762 6.7)
+            let answers = answers
+                .into_iter()
+                .map(|mut record| {
+                    let ttl = record.ttl().min(LEGACY_TTL);
+                    let (_, class, record_ttl) = record.fields_mut();
+                    *class = Class::IN;
+                    *record_ttl = ttl;
+                    record
+                })
+                .collect();
+            let response = MessageBuilder::from_request(query)
+                .set_is_authoritive()
+                .set_answers(answers)
+                .build();
+            socket.send_to(&response.to_bytes().await?, from).await?;
+        } else if unicast {
+            socket
+                .send_to(&response(answers, additional, TTL).to_bytes().await?, from)
+                .await?;
+        } else {
+            let answers = self
+                .state
+                .lock()
+                .unwrap()
+                .rate_limit(answers, Instant::now());
+            if !answers.is_empty() {
+                self.multicast(response(answers, additional, TTL)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares the records another host probes with ours for the same
+    /// names, the lexicographically later set winning (RFC 6762 8.2). The
+    /// names we lose are probed again a second later.
+    async fn break_tie(&self, authority: &[Record]) -> Result<()> {
+        let probing: Vec<(String, Vec<Record>)> = {
+            let state = self.state.lock().unwrap();
+            state
+                .names
+                .iter()
+                .filter(|(_, known)| matches!(known.phase, Phase::Probing { .. }))
+                .map(|(name, known)| (name.clone(), known.records.clone()))
+                .collect()
+        };
+
+        for (name, ours) in probing {
+            let theirs: Vec<Record> = authority
+                .iter()
+                .filter(|record| record.name().eq_ignore_ascii_case(&name))
+                .cloned()
+                .collect();
+            if theirs.is_empty() || tie_breaker(&theirs).await? <= tie_breaker(&ours).await? {
+                continue;
+            }
+
+            debug!(
+                "mDNS name {} is probed by another host too, deferring",
+                name
+            );
+            if let Some(known) = self.state.lock().unwrap().names.get_mut(&name) {
+                known.phase = Phase::Probing { sent: 0 };
+                known.due = Instant::now() + PROBE_DEFERRAL;
+            }
+        }
+        Ok(())
+    }
+
+    async fn multicast(&self, message: Message) -> Result<()> {
+        let buf = message.to_bytes().await?;
+        for link in &self.links {
+            link.socket.send_to(&buf, link.group).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A query for every name, with the records we are about to claim for them
+/// in authority (RFC 6762 8.1). The first probe of a name asks for unicast
+/// responses, there is no cache to refresh yet.
+fn probe(probes: Vec<Probe>) -> Message {
+    let mut builder = MessageBuilder::new_request(0);
+    for probe in probes {
+        let class = match probe.first {
+            true => Class::UNKNOWN(CLASS_IN | UNICAST_RESPONSE),
+            false => Class::IN,
+        };
+        builder = builder.add_new_question(probe.name, QueryType::UNKNOWN(TYPE_ANY), class);
+        for record in probe.records {
+            builder = builder.add_authority(record);
+        }
+    }
+    builder.build()
+}
+
+/// An unsolicited or multicast response, without question and with the
+/// cache-flush bit on every record we are the only responder for
+fn response(answers: Vec<Record>, additional: Vec<Record>, ttl: u32) -> Message {
+    let flushing = |mut record: Record| {
+        let shared = is_shared(&record);
+        let (_, class, record_ttl) = record.fields_mut();
+        if !shared {
+            *class = Class::UNKNOWN(CLASS_IN | CACHE_FLUSH);
+        }
+        *record_ttl = ttl;
+        record
+    };
+    MessageBuilder::from_request(MessageBuilder::new_request(0).build())
+        .set_is_authoritive()
+        .set_answers(answers.into_iter().map(flushing).collect())
+        .set_resources(additional.into_iter().map(flushing).collect())
+        .build()
+}
+
+/// How long to wait before sending `answers`, only those with shared records
+/// wait
+fn response_delay(answers: &[Record]) -> Duration {
+    if !answers.iter().any(is_shared) {
+        return Duration::ZERO;
+    }
+    let spread = SHARED_DELAY.end - SHARED_DELAY.start;
+    SHARED_DELAY.start + spread.mul_f64(rand::random::<f64>())
+}
+
+/// Whether other hosts may answer with records of the same name and type,
+/// as the PTRs listing service types and instances do (RFC 6762 10.2)
+fn is_shared(record: &Record) -> bool {
+    matches!(record, Record::PTR { .. })
+}
+
+/// The name a record points to, whose records answer the next query
+fn target(record: &Record) -> Option<&str> {
+    match record {
+        Record::PTR { host, .. } => Some(host),
+        Record::SRV { target, .. } => Some(target),
+        _ => None,
+    }
+}
+
+/// The record without what does not tell it apart from the same one sent
+/// by another host
+fn normalized(record: &Record) -> Record {
+    let mut record = record.clone();
+    let (name, class, ttl) = record.fields_mut();
+    name.make_ascii_lowercase();
+    *class = Class::from(class.to_u16() & !CACHE_FLUSH);
+    *ttl = 0;
+    record
+}
+
+fn same_data(a: &Record, b: &Record) -> bool {
+    normalized(a) == normalized(b)
+}
+
+/// The records in the order they are compared in, by class, type and data
+async fn tie_breaker(records: &[Record]) -> Result<Vec<(u16, u16, Vec<u8>)>> {
+    let mut keys = Vec::with_capacity(records.len());
+    for record in records.iter().map(normalized) {
+        keys.push((
+            record.class().to_u16(),
+            record.query_type().to_u16(),
+            record.rdata().await?,
+        ));
+    }
+    keys.sort();
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use tokio::net::UdpSocket;
+    use tokio::time::timeout;
+
+    use super::{
+        response, response_delay, MdnsResponder, Phase, State, GROUP_V4, PROBES, PROBE_INTERVAL,
+        SHARED_DELAY,
+    };
+    use crate::core::config::{Config, MdnsConfig};
+    use crate::core::dns::dns_reader_writer::DnsReader;
+    use crate::core::dns::message::{Class, Message, QueryType, Record};
+    use crate::core::dns::message_builder::MessageBuilder;
+    use crate::core::dns::responder::Responder;
+    use crate::core::dns::socket::bind_multicast_v4;
+    use crate::core::shutdown::Shutdown;
+
+    async fn receive(socket: &UdpSocket) -> Option<Message> {
+        let mut buf = vec![0; 9000];
+        let (len, _) = timeout(Duration::from_millis(500), socket.recv_from(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        Some(DnsReader::from(&buf[..len]).read().await.unwrap())
+    }
+
+    fn api(ttl: u32) -> Record {
+        Record::new_type_a("api.local".to_owned(), Ipv4Addr::new(10, 0, 0, 1), ttl)
+    }
+
+    #[tokio::test]
+    async fn probes_announces_and_answers_on_the_link() {
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config: Config = toml::from_str(
+            r#"
+            [registry]
+            zone = "svc.internal"
+            [[registry.services]]
+            name = "api"
+            instances = [{ address = "10.0.0.1" }]
+            "#,
+        )
+        .unwrap();
+        let responder = Arc::new(Responder::from_config(&config).unwrap());
+        let loopback = Ipv4Addr::LOCALHOST;
+        let mdns = MdnsConfig {
+            ipv6: false,
+            interface: loopback,
+            port,
+            ..MdnsConfig::default()
+        };
+        // Joined before the responder starts, to see its first probe
+        let link = bind_multicast_v4(GROUP_V4, port, loopback).unwrap();
+        let mdns = Arc::new(MdnsResponder::bind(&mdns, responder, Shutdown::new()).unwrap());
+        tokio::spawn(mdns.serve());
+
+        // The name is probed for, then announced with the cache-flush bit
+        let mut probes = 0;
+        let announcement = loop {
+            let message = receive(&link).await.unwrap();
+            if message.header.is_query() {
+                assert_eq!(message.authority, vec![api(120)]);
+                probes += 1;
+            } else {
+                break message;
+            }
+        };
+        assert_eq!(probes, PROBES);
+        assert_eq!(announcement.answers.len(), 1);
+        assert_eq!(announcement.answers[0].class(), &Class::UNKNOWN(0x8001));
+        assert_eq!(announcement.answers[0].ttl(), 120);
+
+        // Resolvers not on the mDNS port are answered like unicast DNS
+        let legacy = bind_multicast_v4(GROUP_V4, 0, loopback).unwrap();
+        let query = |known: Option<Record>| async move {
+            let mut builder = MessageBuilder::new_request(42).add_new_question(
+                "API.local".to_owned(),
+                QueryType::A,
+                Class::IN,
+            );
+            if let Some(known) = known {
+                builder = builder.add_answers(known);
+            }
+            builder.build().to_bytes().await.unwrap()
+        };
+        legacy
+            .send_to(&query(None).await, (GROUP_V4, port))
+            .await
+            .unwrap();
+        let response = receive(&legacy).await.unwrap();
+        assert_eq!(response.header.id, 42);
+        assert_eq!(response.questions.len(), 1);
+        assert_eq!(response.answers, vec![api(10)]);
+
+        // Unless they already know the answer
+        legacy
+            .send_to(&query(Some(api(120))).await, (GROUP_V4, port))
+            .await
+            .unwrap();
+        assert!(receive(&legacy).await.is_none());
+    }
+
+    #[test]
+    fn gives_up_names_other_hosts_answer_for() {
+        let mut state = State::default();
+        let now = Instant::now();
+        let probes = state.update(vec![api(120)], now).probes;
+        assert_eq!(probes.len(), 1);
+        assert!(probes[0].first);
+
+        // Our own probe and announcement looped back are no conflict
+        state.check_conflicts(&[api(120)], now);
+        assert_eq!(state.names["api.local"].phase, Phase::Probing { sent: 1 });
+
+        let taken = Record::new_type_a("api.local".to_owned(), Ipv4Addr::new(10, 9, 9, 9), 120);
+        state.check_conflicts(&[taken], now);
+        assert_eq!(state.names["api.local"].phase, Phase::Conflict);
+        let later = state.update(vec![api(120)], now + PROBE_INTERVAL * 4);
+        assert!(later.probes.is_empty() && later.announcements.is_empty());
+
+        // Nor is it answered
+        let question = MessageBuilder::new_request(0)
+            .add_new_question("api.local".to_owned(), QueryType::A, Class::IN)
+            .build()
+            .questions;
+        assert!(state.answer(&question, &[]).0.is_empty());
+    }
+
+    #[test]
+    fn shares_the_browsing_records() {
+        let ptr = |name: &str, host: &str| Record::PTR {
+            name: name.to_owned(),
+            class: Class::IN,
+            host: host.to_owned(),
+            ttl: 120,
+        };
+        let srv = Record::SRV {
+            name: "api._http._tcp.local".to_owned(),
+            class: Class::IN,
+            ttl: 120,
+            priority: 0,
+            weight: 0,
+            port: 8080,
+            target: "api.local".to_owned(),
+        };
+        let records = vec![
+            ptr("_services._dns-sd._udp.local", "_http._tcp.local"),
+            ptr("_http._tcp.local", "api._http._tcp.local"),
+            srv.clone(),
+            api(120),
+        ];
+
+        // Shared names are announced at once, the others probed first
+        let mut state = State::default();
+        let now = Instant::now();
+        let outgoing = state.update(records.clone(), now);
+        assert_eq!(outgoing.announcements.len(), 2);
+        assert!(outgoing
+            .announcements
+            .iter()
+            .all(|record| records[..2].contains(record)));
+        assert_eq!(outgoing.probes.len(), 2);
+        for probe in 1..=PROBES {
+            state.update(records.clone(), now + PROBE_INTERVAL * probe as u32);
+        }
+
+        // Without the cache-flush bit, other hosts have instances too
+        let announced = response(outgoing.announcements, Vec::new(), 120);
+        assert!(announced
+            .answers
+            .iter()
+            .all(|record| record.class() == &Class::IN));
+
+        // Browsing gets the instance and its address along
+        let question = MessageBuilder::new_request(0)
+            .add_new_question("_http._tcp.local".to_owned(), QueryType::PTR, Class::IN)
+            .build()
+            .questions;
+        let (answers, additional) = state.answer(&question, &[]);
+        assert_eq!(answers, records[1..2]);
+        assert_eq!(additional, vec![srv, api(120)]);
+
+        // Other hosts answer it too, so the response waits a little
+        assert!(SHARED_DELAY.contains(&response_delay(&answers)));
+        assert_eq!(response_delay(&[api(120)]), Duration::ZERO);
+
+        // An instance of another host is no conflict
+        state.check_conflicts(&[ptr("_http._tcp.local", "other._http._tcp.local")], now);
+        assert!(state.names["_http._tcp.local"].is_claimed());
+    }
+}
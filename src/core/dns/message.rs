@@ -1,7 +1,10 @@
-use std::{num, net::Ipv4Addr};
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use async_trait::async_trait;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf, Result};
 
 #[async_trait]
 pub trait FromAsyncReader<T: Send>: Sized {
@@ -23,7 +26,113 @@ pub trait Writable<T: Send>: Sized + Send {
     async fn write(&self, writer: &mut T) -> Result<()>;
 }
 
-#[derive(Debug)]
+/**
+ * Wraps the reader of a whole message and keeps every byte consumed so far,
+ * so that compressed names (RFC 1035 4.1.4) can follow pointers back into
+ * the part of the message that was already read.
+ */
+pub(crate) struct PacketReader<R> {
+    inner: R,
+    consumed: Vec<u8>,
+}
+
+impl<R> From<R> for PacketReader<R> {
+    fn from(value: R) -> Self {
+        PacketReader {
+            inner: value,
+            consumed: Vec::with_capacity(512),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for PacketReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            this.consumed.extend_from_slice(&buf.filled()[before..]);
+        }
+        result
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> PacketReader<R> {
+    pub(crate) fn position(&self) -> usize {
+        self.consumed.len()
+    }
+
+    pub(crate) async fn read_name(&mut self, str: &mut String) -> Result<()> {
+        loop {
+            let lenght = self.read_u8().await?;
+            if lenght & 0b1100_0000 == 0b1100_0000 {
+                let low = self.read_u8().await?;
+                let offset = (((lenght & 0b0011_1111) as usize) << 8) | low as usize;
+                return decode_name(&self.consumed, offset, str);
+            }
+
+            if lenght == 0 {
+                return Ok(());
+            }
+
+            if !str.is_empty() {
+                str.push('.');
+            }
+
+            for _ in 0..lenght {
+                let c = self.read_u8().await?;
+                str.push(c as char);
+            }
+        }
+    }
+}
+
+/**
+ * Decodes a name that starts at `offset` of an already read message,
+ * following compression pointers. Pointers may only point backwards,
+ * which also protects against loops.
+ */
+pub(crate) fn decode_name(buf: &[u8], mut offset: usize, str: &mut String) -> Result<()> {
+    let mut limit = buf.len();
+    loop {
+        if offset >= limit {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "bad compression pointer",
+            ));
+        }
+
+        let lenght = buf[offset];
+        if lenght & 0b1100_0000 == 0b1100_0000 {
+            let low = *buf
+                .get(offset + 1)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "bad compression pointer"))?;
+            limit = offset;
+            offset = (((lenght & 0b0011_1111) as usize) << 8) | low as usize;
+            continue;
+        }
+
+        if lenght == 0 {
+            return Ok(());
+        }
+
+        let label = buf
+            .get(offset + 1..offset + 1 + lenght as usize)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "label out of bounds"))?;
+
+        if !str.is_empty() {
+            str.push('.');
+        }
+        str.extend(label.iter().map(|c| *c as char));
+        offset += 1 + lenght as usize;
+    }
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct Message {
     pub(crate) header: Header,
     pub(crate) questions: Vec<Question>,
@@ -32,16 +141,87 @@ pub(crate) struct Message {
     pub(crate) resources: Vec<Record>,
 }
 
-impl Message {}
+impl Message {
+    pub(crate) async fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(512);
+        self.write(&mut buf).await?;
+        Ok(buf)
+    }
+
+    /// Drops every record but the OPT one and sets the TC bit, telling the
+    /// client to retry over TCP
+    pub(crate) fn truncated(mut self) -> Message {
+        self.header.flags |= 0b0000001000000000;
+        self.answers.clear();
+        self.authority.clear();
+        self.resources
+            .retain(|record| matches!(record, Record::OPT { .. }));
+        self.header.awnsers = 0;
+        self.header.authority_entries = 0;
+        self.header.ressource_entries = self.resources.len() as u16;
+        self
+    }
+
+    /// Spreads the answers over messages of at most `max` bytes each, for
+    /// zone transfers too large for a single message. Every message repeats
+    /// the header, question and additional records, the authority records
+    /// only go with the first one. A record larger than `max` on its own
+    /// still gets a message.
+    pub(crate) async fn split(mut self, max: usize) -> Result<Vec<Message>> {
+        let answers = std::mem::take(&mut self.answers);
+        let authority = std::mem::take(&mut self.authority);
+        // Names are written uncompressed, so sizes simply add up
+        let base = self.to_bytes().await?.len();
+        let mut size = base;
+        for record in &authority {
+            size += encoded_len(record).await?;
+        }
+
+        let mut messages = Vec::new();
+        let mut chunk: Vec<Record> = Vec::new();
+        for record in answers {
+            let len = encoded_len(&record).await?;
+            if !chunk.is_empty() && size + len > max {
+                messages.push(self.with_answers(std::mem::take(&mut chunk)));
+                size = base;
+            }
+            size += len;
+            chunk.push(record);
+        }
+        if !chunk.is_empty() || messages.is_empty() {
+            messages.push(self.with_answers(chunk));
+        }
+
+        messages[0].header.authority_entries = authority.len() as u16;
+        messages[0].authority = authority;
+        Ok(messages)
+    }
+
+    fn with_answers(&self, answers: Vec<Record>) -> Message {
+        let mut message = self.clone();
+        message.header.awnsers = answers.len() as u16;
+        message.header.authority_entries = 0;
+        message.answers = answers;
+        message
+    }
+}
+
+/// Bytes `record` takes in a message
+async fn encoded_len(record: &Record) -> Result<usize> {
+    let mut buf = Vec::new();
+    record.write(&mut buf).await?;
+    Ok(buf.len())
+}
 
 #[async_trait]
 impl<T: AsyncReadExt + Unpin + Send> FromAsyncReader<T> for Message {
     async fn from(reader: &mut T) -> Result<Message> {
-        let header: Header = FromAsyncReader::from(reader).await?;
-        let questions = Question::from_n(reader, header.questions as usize).await?;
-        let answers = Record::from_n(reader, header.awnsers as usize).await?;
-        let authority = Record::from_n(reader, header.authority_entries as usize).await?;
-        let resources = Record::from_n(reader, header.ressource_entries as usize).await?;
+        let mut reader = PacketReader::from(reader);
+        let header: Header = FromAsyncReader::from(&mut reader).await?;
+        let questions = Question::from_n(&mut reader, header.questions as usize).await?;
+        let answers = Record::from_n(&mut reader, header.awnsers as usize).await?;
+        let authority = Record::from_n(&mut reader, header.authority_entries as usize).await?;
+        let resources = Record::from_n(&mut reader, header.ressource_entries as usize).await?;
 
         return Ok(Message {
             header,
@@ -80,15 +260,26 @@ where
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum ResultCode {
-    NOERROR = 0,
-    FORMERR = 1,
-    SERVFAIL = 2,
-    NXDOMAIN = 3,
-    NOTIMP = 4,
-    REFUSED = 5,
-    UNKNOWN = 6,
+    NOERROR,
+    FORMERR,
+    SERVFAIL,
+    NXDOMAIN,
+    NOTIMP,
+    REFUSED,
+    /// A name that should not exist does (RFC 2136)
+    YXDOMAIN,
+    /// An RRset that should not exist does (RFC 2136)
+    YXRRSET,
+    /// An RRset that should exist does not (RFC 2136)
+    NXRRSET,
+    /// The server is not authoritative for the zone (RFC 2136)
+    NOTAUTH,
+    /// A name is outside the zone of an update (RFC 2136)
+    NOTZONE,
+    UNKNOWN(u8),
 }
 
 impl ResultCode {
@@ -100,7 +291,12 @@ impl ResultCode {
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
-            _ => ResultCode::UNKNOWN,
+            6 => ResultCode::YXDOMAIN,
+            7 => ResultCode::YXRRSET,
+            8 => ResultCode::NXRRSET,
+            9 => ResultCode::NOTAUTH,
+            10 => ResultCode::NOTZONE,
+            _ => ResultCode::UNKNOWN(num),
         }
     }
 
@@ -112,12 +308,17 @@ impl ResultCode {
             ResultCode::NXDOMAIN => 3,
             ResultCode::NOTIMP => 4,
             ResultCode::REFUSED => 5,
-            ResultCode::UNKNOWN => 6,
+            ResultCode::YXDOMAIN => 6,
+            ResultCode::YXRRSET => 7,
+            ResultCode::NXRRSET => 8,
+            ResultCode::NOTAUTH => 9,
+            ResultCode::NOTZONE => 10,
+            ResultCode::UNKNOWN(num) => *num,
         }
-    } 
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Header {
     pub id: u16,
 
@@ -130,7 +331,6 @@ pub struct Header {
 }
 
 impl Header {
-    
     fn new() -> Header {
         Header {
             id: 0,
@@ -166,10 +366,24 @@ impl Header {
         (self.flags & 0b0000000010000000) >> 7 == 1
     }
 
+    /// Whether the answer was validated with DNSSEC (RFC 4035 3.2.3)
+    pub(crate) fn is_authentic_data(&self) -> bool {
+        (self.flags & 0b0000000000100000) >> 5 == 1
+    }
+
+    /// Whether the client asked for answers that failed validation too
+    pub(crate) fn is_checking_disabled(&self) -> bool {
+        (self.flags & 0b0000000000010000) >> 4 == 1
+    }
+
     pub(crate) fn result_code(&self) -> ResultCode {
         let result_code = (self.flags & 0b0000000000001111) as u8;
         ResultCode::from(result_code)
     }
+
+    pub(crate) fn set_result_code(&mut self, rcode: ResultCode) {
+        self.flags = (self.flags & 0b1111111111110000) | (rcode.to() as u16 & 0b0000000000001111);
+    }
 }
 
 #[async_trait]
@@ -201,38 +415,81 @@ impl<T: AsyncWriteExt + Unpin + Send> Writable<T> for Header {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum QueryType {
     A,
+    NS,
+    CNAME,
+    SOA,
+    PTR,
+    MX,
+    TXT,
     AAAA,
-    UNKNOWN, // TODO there are more
+    SRV,
+    OPT,
+    DS,
+    RRSIG,
+    NSEC,
+    DNSKEY,
+    NSEC3,
+    IXFR,
+    AXFR,
+    UNKNOWN(u16), // TODO there are more
 }
 
 impl QueryType {
-    fn from(value: u16) -> QueryType {
+    pub(crate) fn from(value: u16) -> QueryType {
         match value {
             1 => QueryType::A,
+            2 => QueryType::NS,
+            5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
+            15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
-            _ => QueryType::UNKNOWN,
+            33 => QueryType::SRV,
+            41 => QueryType::OPT,
+            43 => QueryType::DS,
+            46 => QueryType::RRSIG,
+            47 => QueryType::NSEC,
+            48 => QueryType::DNSKEY,
+            50 => QueryType::NSEC3,
+            251 => QueryType::IXFR,
+            252 => QueryType::AXFR,
+            _ => QueryType::UNKNOWN(value),
         }
     }
 
-    fn as_u16(self) -> u16 {
-        self.to_u16()
-    }
-
-    fn to_u16(&self) -> u16 {
+    pub(crate) fn to_u16(&self) -> u16 {
         match self {
             QueryType::A => 1,
+            QueryType::NS => 2,
+            QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
+            QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
-            QueryType::UNKNOWN => 0,
+            QueryType::SRV => 33,
+            QueryType::OPT => 41,
+            QueryType::DS => 43,
+            QueryType::RRSIG => 46,
+            QueryType::NSEC => 47,
+            QueryType::DNSKEY => 48,
+            QueryType::NSEC3 => 50,
+            QueryType::IXFR => 251,
+            QueryType::AXFR => 252,
+            QueryType::UNKNOWN(value) => *value,
         }
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Class {
-    UNKNOWN,
+    UNKNOWN(u16),
     RESERVED,
     IN,
     QCLASSNONE,
@@ -240,32 +497,28 @@ pub enum Class {
 }
 
 impl Class {
-    fn from(value: u16) -> Class {
+    pub(crate) fn from(value: u16) -> Class {
         match value {
             0 => Class::RESERVED,
             1 => Class::IN,
             254 => Class::QCLASSNONE,
             255 => Class::QCLASSANY,
-            _ => Class::UNKNOWN,
+            _ => Class::UNKNOWN(value),
         }
     }
 
-    fn as_u16(self) -> u16 {
-        self.to_u16()
-    }
-
-    fn to_u16(&self) -> u16 {
+    pub(crate) fn to_u16(&self) -> u16 {
         match self {
             Class::RESERVED => 0,
             Class::IN => 1,
             Class::QCLASSNONE => 254,
             Class::QCLASSANY => 255,
-            Class::UNKNOWN => 256,
+            Class::UNKNOWN(value) => *value,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct Question {
     pub name: String,
     pub r#type: QueryType,
@@ -276,18 +529,18 @@ impl Question {
     fn new() -> Question {
         Question {
             name: String::new(),
-            r#type: QueryType::UNKNOWN,
-            class: Class::UNKNOWN,
+            r#type: QueryType::UNKNOWN(0),
+            class: Class::UNKNOWN(0),
         }
     }
 }
 
 #[async_trait]
-impl<T: AsyncReadExt + Unpin + Send> FromAsyncReader<T> for Question {
-    async fn from(reader: &mut T) -> Result<Question> {
+impl<T: AsyncRead + Unpin + Send> FromAsyncReader<PacketReader<T>> for Question {
+    async fn from(reader: &mut PacketReader<T>) -> Result<Question> {
         let mut question = Question::new();
 
-        read_dns_encoded_name(reader, &mut question.name).await?;
+        reader.read_name(&mut question.name).await?;
 
         let r#type = reader.read_u16().await?;
         question.r#type = QueryType::from(r#type);
@@ -309,39 +562,450 @@ impl<T: AsyncWriteExt + Unpin + Send> Writable<T> for Question {
     }
 }
 
-#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) enum Record {
     UNKNOWN {
         name: String,
         r#type: u16,
         class: Class,
         ttl: u32,
-        len: u16
+        data: Vec<u8>,
     },
     A {
         name: String,
         class: Class,
         addr: Ipv4Addr,
-        ttl: u32
-    }
+        ttl: u32,
+    },
+    NS {
+        name: String,
+        class: Class,
+        host: String,
+        ttl: u32,
+    },
+    CNAME {
+        name: String,
+        class: Class,
+        host: String,
+        ttl: u32,
+    },
+    SOA {
+        name: String,
+        class: Class,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    },
+    PTR {
+        name: String,
+        class: Class,
+        host: String,
+        ttl: u32,
+    },
+    MX {
+        name: String,
+        class: Class,
+        priority: u16,
+        host: String,
+        ttl: u32,
+    },
+    TXT {
+        name: String,
+        class: Class,
+        data: Vec<String>,
+        ttl: u32,
+    },
+    AAAA {
+        name: String,
+        class: Class,
+        addr: Ipv6Addr,
+        ttl: u32,
+    },
+    /// Where a service runs, the host and port to connect to (RFC 2782)
+    SRV {
+        name: String,
+        class: Class,
+        ttl: u32,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    /// EDNS pseudo record (RFC 6891), the class carries the UDP payload
+    /// size and the TTL the extended rcode, version and flags.
+    OPT {
+        name: String,
+        class: Class,
+        ttl: u32,
+        options: Vec<EdnsOption>,
+    },
+    /// Signature of the RRset of `type_covered` at its name (RFC 4034 3)
+    RRSIG {
+        name: String,
+        class: Class,
+        ttl: u32,
+        type_covered: u16,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer: String,
+        signature: Vec<u8>,
+    },
+    /// The types present at a name and the next name of the zone (RFC 4034 4)
+    NSEC {
+        name: String,
+        class: Class,
+        ttl: u32,
+        next: String,
+        types: Vec<u16>,
+    },
+    /// Public key of a signed zone (RFC 4034 2)
+    DNSKEY {
+        name: String,
+        class: Class,
+        ttl: u32,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+    },
+    /// Digest of a DNSKEY of the child zone, in its parent (RFC 4034 5)
+    DS {
+        name: String,
+        class: Class,
+        ttl: u32,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+    },
+    /// The types present at a hashed name and the next hashed name of the
+    /// zone (RFC 5155 3)
+    NSEC3 {
+        name: String,
+        class: Class,
+        ttl: u32,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next: Vec<u8>,
+        types: Vec<u16>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct EdnsOption {
+    pub(crate) code: u16,
+    pub(crate) data: Vec<u8>,
 }
 
 impl Record {
     pub(crate) fn new_type_a(name: String, addr: Ipv4Addr, ttl: u32) -> Record {
         Record::A {
-            name: name,
+            name,
             class: Class::IN,
             ttl,
             addr,
         }
     }
+
+    pub(crate) fn new_type_aaaa(name: String, addr: Ipv6Addr, ttl: u32) -> Record {
+        Record::AAAA {
+            name,
+            class: Class::IN,
+            ttl,
+            addr,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            Record::UNKNOWN { name, .. }
+            | Record::A { name, .. }
+            | Record::NS { name, .. }
+            | Record::CNAME { name, .. }
+            | Record::SOA { name, .. }
+            | Record::PTR { name, .. }
+            | Record::MX { name, .. }
+            | Record::TXT { name, .. }
+            | Record::AAAA { name, .. }
+            | Record::SRV { name, .. }
+            | Record::OPT { name, .. }
+            | Record::RRSIG { name, .. }
+            | Record::NSEC { name, .. }
+            | Record::DNSKEY { name, .. }
+            | Record::DS { name, .. }
+            | Record::NSEC3 { name, .. } => name,
+        }
+    }
+
+    pub(crate) fn ttl(&self) -> u32 {
+        match self {
+            Record::UNKNOWN { ttl, .. }
+            | Record::A { ttl, .. }
+            | Record::NS { ttl, .. }
+            | Record::CNAME { ttl, .. }
+            | Record::SOA { ttl, .. }
+            | Record::PTR { ttl, .. }
+            | Record::MX { ttl, .. }
+            | Record::TXT { ttl, .. }
+            | Record::AAAA { ttl, .. }
+            | Record::SRV { ttl, .. }
+            | Record::OPT { ttl, .. }
+            | Record::RRSIG { ttl, .. }
+            | Record::NSEC { ttl, .. }
+            | Record::DNSKEY { ttl, .. }
+            | Record::DS { ttl, .. }
+            | Record::NSEC3 { ttl, .. } => *ttl,
+        }
+    }
+
+    pub(crate) fn query_type(&self) -> QueryType {
+        match self {
+            Record::UNKNOWN { r#type, .. } => QueryType::from(*r#type),
+            Record::A { .. } => QueryType::A,
+            Record::NS { .. } => QueryType::NS,
+            Record::CNAME { .. } => QueryType::CNAME,
+            Record::SOA { .. } => QueryType::SOA,
+            Record::PTR { .. } => QueryType::PTR,
+            Record::MX { .. } => QueryType::MX,
+            Record::TXT { .. } => QueryType::TXT,
+            Record::AAAA { .. } => QueryType::AAAA,
+            Record::SRV { .. } => QueryType::SRV,
+            Record::OPT { .. } => QueryType::OPT,
+            Record::RRSIG { .. } => QueryType::RRSIG,
+            Record::NSEC { .. } => QueryType::NSEC,
+            Record::DNSKEY { .. } => QueryType::DNSKEY,
+            Record::DS { .. } => QueryType::DS,
+            Record::NSEC3 { .. } => QueryType::NSEC3,
+        }
+    }
+
+    pub(crate) fn class(&self) -> &Class {
+        match self {
+            Record::UNKNOWN { class, .. }
+            | Record::A { class, .. }
+            | Record::NS { class, .. }
+            | Record::CNAME { class, .. }
+            | Record::SOA { class, .. }
+            | Record::PTR { class, .. }
+            | Record::MX { class, .. }
+            | Record::TXT { class, .. }
+            | Record::AAAA { class, .. }
+            | Record::SRV { class, .. }
+            | Record::OPT { class, .. }
+            | Record::RRSIG { class, .. }
+            | Record::NSEC { class, .. }
+            | Record::DNSKEY { class, .. }
+            | Record::DS { class, .. }
+            | Record::NSEC3 { class, .. } => class,
+        }
+    }
+
+    /// The owner name, class and TTL, the fields every type of record has
+    pub(crate) fn fields_mut(&mut self) -> (&mut String, &mut Class, &mut u32) {
+        match self {
+            Record::UNKNOWN {
+                name, class, ttl, ..
+            }
+            | Record::A {
+                name, class, ttl, ..
+            }
+            | Record::NS {
+                name, class, ttl, ..
+            }
+            | Record::CNAME {
+                name, class, ttl, ..
+            }
+            | Record::SOA {
+                name, class, ttl, ..
+            }
+            | Record::PTR {
+                name, class, ttl, ..
+            }
+            | Record::MX {
+                name, class, ttl, ..
+            }
+            | Record::TXT {
+                name, class, ttl, ..
+            }
+            | Record::AAAA {
+                name, class, ttl, ..
+            }
+            | Record::SRV {
+                name, class, ttl, ..
+            }
+            | Record::OPT {
+                name, class, ttl, ..
+            }
+            | Record::RRSIG {
+                name, class, ttl, ..
+            }
+            | Record::NSEC {
+                name, class, ttl, ..
+            }
+            | Record::DNSKEY {
+                name, class, ttl, ..
+            }
+            | Record::DS {
+                name, class, ttl, ..
+            }
+            | Record::NSEC3 {
+                name, class, ttl, ..
+            } => (name, class, ttl),
+        }
+    }
+
+    /// The data of the record as sent, names uncompressed
+    pub(crate) async fn rdata(&self) -> Result<Vec<u8>> {
+        let mut rdata = Vec::new();
+        self.write_rdata(&mut rdata).await?;
+        Ok(rdata)
+    }
+
+    async fn write_rdata(&self, rdata: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Record::UNKNOWN { data, .. } => rdata.extend_from_slice(data),
+            Record::A { addr, .. } => rdata.extend_from_slice(&addr.octets()),
+            Record::NS { host, .. } | Record::CNAME { host, .. } | Record::PTR { host, .. } => {
+                write_dns_encoded_name(rdata, host).await?;
+            }
+            Record::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => {
+                write_dns_encoded_name(rdata, mname).await?;
+                write_dns_encoded_name(rdata, rname).await?;
+                rdata.write_u32(*serial).await?;
+                rdata.write_u32(*refresh).await?;
+                rdata.write_u32(*retry).await?;
+                rdata.write_u32(*expire).await?;
+                rdata.write_u32(*minimum).await?;
+            }
+            Record::MX { priority, host, .. } => {
+                rdata.write_u16(*priority).await?;
+                write_dns_encoded_name(rdata, host).await?;
+            }
+            Record::TXT { data, .. } => {
+                for string in data {
+                    rdata.write_u8(string.len() as u8).await?;
+                    rdata.extend_from_slice(string.as_bytes());
+                }
+            }
+            Record::AAAA { addr, .. } => rdata.extend_from_slice(&addr.octets()),
+            Record::SRV {
+                priority,
+                weight,
+                port,
+                target,
+                ..
+            } => {
+                rdata.write_u16(*priority).await?;
+                rdata.write_u16(*weight).await?;
+                rdata.write_u16(*port).await?;
+                write_dns_encoded_name(rdata, target).await?;
+            }
+            Record::OPT { options, .. } => {
+                for option in options {
+                    rdata.write_u16(option.code).await?;
+                    rdata.write_u16(option.data.len() as u16).await?;
+                    rdata.extend_from_slice(&option.data);
+                }
+            }
+            Record::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+                ..
+            } => {
+                rdata.write_u16(*type_covered).await?;
+                rdata.write_u8(*algorithm).await?;
+                rdata.write_u8(*labels).await?;
+                rdata.write_u32(*original_ttl).await?;
+                rdata.write_u32(*expiration).await?;
+                rdata.write_u32(*inception).await?;
+                rdata.write_u16(*key_tag).await?;
+                write_dns_encoded_name(rdata, signer).await?;
+                rdata.extend_from_slice(signature);
+            }
+            Record::NSEC { next, types, .. } => {
+                write_dns_encoded_name(rdata, next).await?;
+                write_type_bitmap(rdata, types);
+            }
+            Record::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => {
+                rdata.write_u16(*flags).await?;
+                rdata.write_u8(*protocol).await?;
+                rdata.write_u8(*algorithm).await?;
+                rdata.extend_from_slice(public_key);
+            }
+            Record::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => {
+                rdata.write_u16(*key_tag).await?;
+                rdata.write_u8(*algorithm).await?;
+                rdata.write_u8(*digest_type).await?;
+                rdata.extend_from_slice(digest);
+            }
+            Record::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next,
+                types,
+                ..
+            } => {
+                rdata.write_u8(*hash_algorithm).await?;
+                rdata.write_u8(*flags).await?;
+                rdata.write_u16(*iterations).await?;
+                rdata.write_u8(salt.len() as u8).await?;
+                rdata.extend_from_slice(salt);
+                rdata.write_u8(next.len() as u8).await?;
+                rdata.extend_from_slice(next);
+                write_type_bitmap(rdata, types);
+            }
+        };
+        Ok(())
+    }
 }
 
 #[async_trait]
-impl<T: AsyncReadExt + Unpin + Send> FromAsyncReader<T> for Record {
-    async fn from(reader: &mut T) -> Result<Record> {
+impl<T: AsyncRead + Unpin + Send> FromAsyncReader<PacketReader<T>> for Record {
+    async fn from(reader: &mut PacketReader<T>) -> Result<Record> {
         let mut name = String::new();
-        read_dns_encoded_name(reader, &mut name).await?;
+        reader.read_name(&mut name).await?;
 
         let qtype_u16 = reader.read_u16().await?;
         let qtype = QueryType::from(qtype_u16);
@@ -349,69 +1013,319 @@ impl<T: AsyncReadExt + Unpin + Send> FromAsyncReader<T> for Record {
         let class = Class::from(class);
         let ttl = reader.read_u32().await?;
         let len = reader.read_u16().await?;
+        let end = reader.position() + len as usize;
 
         let res = match qtype {
-            QueryType::A => {
+            QueryType::A if len == 4 => {
                 let a = reader.read_u8().await?;
                 let b = reader.read_u8().await?;
                 let c = reader.read_u8().await?;
                 let d = reader.read_u8().await?;
-                let addr = Ipv4Addr::new(a,b,c,d);
+                let addr = Ipv4Addr::new(a, b, c, d);
 
                 Self::A {
                     name,
                     class,
                     addr,
-                    ttl
+                    ttl,
+                }
+            }
+            QueryType::AAAA if len == 16 => {
+                let addr = Ipv6Addr::from(reader.read_u128().await?);
+
+                Self::AAAA {
+                    name,
+                    class,
+                    addr,
+                    ttl,
+                }
+            }
+            // Without data, as in the deletions of dynamic updates (RFC 2136 2.5)
+            QueryType::NS | QueryType::CNAME | QueryType::PTR if len > 0 => {
+                let mut host = String::new();
+                reader.read_name(&mut host).await?;
+
+                match qtype {
+                    QueryType::NS => Self::NS {
+                        name,
+                        class,
+                        host,
+                        ttl,
+                    },
+                    QueryType::CNAME => Self::CNAME {
+                        name,
+                        class,
+                        host,
+                        ttl,
+                    },
+                    _ => Self::PTR {
+                        name,
+                        class,
+                        host,
+                        ttl,
+                    },
+                }
+            }
+            QueryType::SOA if len > 0 => {
+                let mut mname = String::new();
+                reader.read_name(&mut mname).await?;
+                let mut rname = String::new();
+                reader.read_name(&mut rname).await?;
+
+                Self::SOA {
+                    name,
+                    class,
+                    mname,
+                    rname,
+                    serial: reader.read_u32().await?,
+                    refresh: reader.read_u32().await?,
+                    retry: reader.read_u32().await?,
+                    expire: reader.read_u32().await?,
+                    minimum: reader.read_u32().await?,
+                    ttl,
+                }
+            }
+            QueryType::MX if len > 0 => {
+                let priority = reader.read_u16().await?;
+                let mut host = String::new();
+                reader.read_name(&mut host).await?;
+
+                Self::MX {
+                    name,
+                    class,
+                    priority,
+                    host,
+                    ttl,
+                }
+            }
+            QueryType::SRV if len > 6 => {
+                let priority = reader.read_u16().await?;
+                let weight = reader.read_u16().await?;
+                let port = reader.read_u16().await?;
+                let mut target = String::new();
+                reader.read_name(&mut target).await?;
+
+                Self::SRV {
+                    name,
+                    class,
+                    ttl,
+                    priority,
+                    weight,
+                    port,
+                    target,
+                }
+            }
+            QueryType::TXT => {
+                let mut data = Vec::new();
+                while reader.position() < end {
+                    let size = reader.read_u8().await?;
+                    let mut string = vec![0; size as usize];
+                    reader.read_exact(&mut string).await?;
+                    data.push(String::from_utf8_lossy(&string).into_owned());
+                }
+
+                Self::TXT {
+                    name,
+                    class,
+                    data,
+                    ttl,
+                }
+            }
+            QueryType::OPT => {
+                let mut options = Vec::new();
+                while reader.position() < end {
+                    let code = reader.read_u16().await?;
+                    let size = reader.read_u16().await?;
+                    let mut data = vec![0; size as usize];
+                    reader.read_exact(&mut data).await?;
+                    options.push(EdnsOption { code, data });
+                }
+
+                Self::OPT {
+                    name,
+                    class,
+                    ttl,
+                    options,
+                }
+            }
+            QueryType::RRSIG if len > 18 => {
+                let type_covered = reader.read_u16().await?;
+                let algorithm = reader.read_u8().await?;
+                let labels = reader.read_u8().await?;
+                let original_ttl = reader.read_u32().await?;
+                let expiration = reader.read_u32().await?;
+                let inception = reader.read_u32().await?;
+                let key_tag = reader.read_u16().await?;
+                let mut signer = String::new();
+                reader.read_name(&mut signer).await?;
+                let mut signature = vec![0; end.saturating_sub(reader.position())];
+                reader.read_exact(&mut signature).await?;
+                Self::RRSIG {
+                    name,
+                    class,
+                    ttl,
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer,
+                    signature,
                 }
-            },
-            qtype => Self::UNKNOWN {
-                name,
-                r#type: qtype_u16,
-                class,
-                ttl,
-                len
-            },
+            }
+            QueryType::NSEC if len > 0 => {
+                let mut next = String::new();
+                reader.read_name(&mut next).await?;
+                let mut bitmap = vec![0; end.saturating_sub(reader.position())];
+                reader.read_exact(&mut bitmap).await?;
+                Self::NSEC {
+                    name,
+                    class,
+                    ttl,
+                    next,
+                    types: read_type_bitmap(&bitmap)?,
+                }
+            }
+            QueryType::DNSKEY if len > 4 => {
+                let flags = reader.read_u16().await?;
+                let protocol = reader.read_u8().await?;
+                let algorithm = reader.read_u8().await?;
+                let mut public_key = vec![0; len as usize - 4];
+                reader.read_exact(&mut public_key).await?;
+                Self::DNSKEY {
+                    name,
+                    class,
+                    ttl,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                }
+            }
+            QueryType::DS if len > 4 => {
+                let key_tag = reader.read_u16().await?;
+                let algorithm = reader.read_u8().await?;
+                let digest_type = reader.read_u8().await?;
+                let mut digest = vec![0; len as usize - 4];
+                reader.read_exact(&mut digest).await?;
+                Self::DS {
+                    name,
+                    class,
+                    ttl,
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                }
+            }
+            QueryType::NSEC3 if len > 5 => {
+                let hash_algorithm = reader.read_u8().await?;
+                let flags = reader.read_u8().await?;
+                let iterations = reader.read_u16().await?;
+                let mut salt = vec![0; reader.read_u8().await? as usize];
+                reader.read_exact(&mut salt).await?;
+                let mut next = vec![0; reader.read_u8().await? as usize];
+                reader.read_exact(&mut next).await?;
+                let mut bitmap = vec![0; end.saturating_sub(reader.position())];
+                reader.read_exact(&mut bitmap).await?;
+                Self::NSEC3 {
+                    name,
+                    class,
+                    ttl,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next,
+                    types: read_type_bitmap(&bitmap)?,
+                }
+            }
+            _ => {
+                let mut data = vec![0; len as usize];
+                reader.read_exact(&mut data).await?;
+
+                Self::UNKNOWN {
+                    name,
+                    r#type: qtype_u16,
+                    class,
+                    ttl,
+                    data,
+                }
+            }
         };
 
+        if reader.position() != end {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "record data does not match its length",
+            ));
+        }
+
         Ok(res)
     }
 }
 
-
 #[async_trait]
 impl<T: AsyncWriteExt + Unpin + Send> Writable<T> for Record {
     async fn write(&self, writer: &mut T) -> Result<()> {
-        match self {
-            Record::UNKNOWN { name, r#type, class, ttl,len } => {
-                write_dns_encoded_name(writer, name).await?;
-                writer.write_u16(*r#type).await?;
-                writer.write_u16(class.to_u16()).await?;
-                writer.write_u32(*ttl).await?;
-                writer.write_u16(*len).await?;
-
-            },
-            Record::A { name, class, addr, ttl } => {
-                write_dns_encoded_name(writer, name).await?;
-                writer.write_u16(1).await?;
-                writer.write_u16(class.to_u16()).await?;
-                writer.write_u32(*ttl).await?;
-                writer.write_u16(4).await?;
-                
-
-                let bytes = addr.octets();
-                writer.write_u8(bytes[0]).await?;
-                writer.write_u8(bytes[1]).await?;
-                writer.write_u8(bytes[2]).await?;
-                writer.write_u8(bytes[3]).await?;
-            },
-        };
+        let mut rdata = Vec::new();
+        self.write_rdata(&mut rdata).await?;
+
+        write_dns_encoded_name(writer, self.name()).await?;
+        writer.write_u16(self.query_type().to_u16()).await?;
+        writer.write_u16(self.class().to_u16()).await?;
+        writer.write_u32(self.ttl()).await?;
+        writer.write_u16(rdata.len() as u16).await?;
+        writer.write_all(&rdata).await?;
 
-        
         Ok(())
     }
 }
 
+/// Writes the types of an NSEC record as windows of up to 256 types, each
+/// a bitmap of the types present (RFC 4034 4.1.2)
+fn write_type_bitmap(rdata: &mut Vec<u8>, types: &[u16]) {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+
+    for window in types.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let last = (window[window.len() - 1] & 0xff) as usize;
+        let mut bitmap = vec![0u8; last / 8 + 1];
+        for r#type in window {
+            let bit = (r#type & 0xff) as usize;
+            bitmap[bit / 8] |= 0x80 >> (bit % 8);
+        }
+        rdata.push((window[0] >> 8) as u8);
+        rdata.push(bitmap.len() as u8);
+        rdata.extend(bitmap);
+    }
+}
+
+fn read_type_bitmap(mut bitmap: &[u8]) -> Result<Vec<u16>> {
+    let mut types = Vec::new();
+    while let [window, len, rest @ ..] = bitmap {
+        let len = *len as usize;
+        if len == 0 || len > 32 || rest.len() < len {
+            return Err(Error::new(ErrorKind::InvalidData, "bad NSEC type bitmap"));
+        }
+        for (i, byte) in rest[..len].iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push((*window as u16) << 8 | (i * 8 + bit) as u16);
+                }
+            }
+        }
+        bitmap = &rest[len..];
+    }
+    if !bitmap.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "bad NSEC type bitmap"));
+    }
+    Ok(types)
+}
+
 /**
  * QNAME has the following format:
  * 0x03 -> String of lenght 3 follows
@@ -422,39 +1336,13 @@ impl<T: AsyncWriteExt + Unpin + Send> Writable<T> for Record {
  * 0x656475 -> String is edu
  * 0x00 -> End of this name
  */
-pub async fn read_dns_encoded_name<T>(reader: &mut T, str: &mut String) -> Result<()>
-where
-    T: AsyncReadExt + Unpin + Send,
-{
-    const SPLIT: char = '.';
-
-    while let Ok(lenght) = reader.read_u8().await {
-        if lenght == 0 {
-            break;
-        }
-
-        if str.len() > 0 {
-            str.push(SPLIT);
-        }
-
-        let mut pos = 0;
-        while lenght - pos > 0 {
-            let c = reader.read_u8().await?;
-            str.push(c as char);
-            pos += 1;
-        }
-    }
-
-    Ok(())
-}
-
 pub async fn write_dns_encoded_name<T>(writer: &mut T, str: &str) -> Result<()>
 where
     T: AsyncWriteExt + Unpin + Send,
 {
     const SPLIT: char = '.';
 
-    for word in str.split(SPLIT) {
+    for word in str.split(SPLIT).filter(|word| !word.is_empty()) {
         writer.write_u8(word.len() as u8).await?;
         for c in word.chars() {
             writer.write_u8(c as u8).await?;
@@ -467,12 +1355,15 @@ where
 
 #[cfg(test)]
 mod test {
+    use std::net::Ipv4Addr;
+
     use tokio::io::BufReader;
 
     use super::{
-        read_dns_encoded_name, write_dns_encoded_name, Class, FromAsyncReader, Message, ResultCode,
-        QueryType, Writable,
+        write_dns_encoded_name, Class, FromAsyncReader, Message, PacketReader, QueryType, Record,
+        ResultCode, Writable,
     };
+    use crate::core::dns::message_builder::MessageBuilder;
 
     #[tokio::test]
     async fn deserialize_request_message() {
@@ -493,10 +1384,10 @@ mod test {
         // Verify flags
         assert!(message.header.is_query());
         assert_eq!(message.header.op_code(), 0);
-        assert_eq!(message.header.is_authoritative(), false);
-        assert_eq!(message.header.is_truncated(), false);
-        assert_eq!(message.header.is_recursion_desired(), true);
-        assert_eq!(message.header.is_recursion_available(), false);
+        assert!(!message.header.is_authoritative());
+        assert!(!message.header.is_truncated());
+        assert!(message.header.is_recursion_desired());
+        assert!(!message.header.is_recursion_available());
         assert_eq!(message.header.result_code(), ResultCode::NOERROR);
 
         // Question should be for google.com of type A and class IN
@@ -524,6 +1415,26 @@ mod test {
         println!("{:?}", message);
     }
 
+    #[tokio::test]
+    async fn deserialize_compressed_response_message() {
+        let bytes = tokio::fs::read("./src/core/dns/test/response_packet.txt")
+            .await
+            .unwrap();
+
+        let message: Message = FromAsyncReader::from(&mut &*bytes).await.unwrap();
+
+        assert!(!message.header.is_query());
+        assert_eq!(message.answers.len(), 1);
+        assert_eq!(
+            message.answers[0],
+            Record::new_type_a(
+                "google.com".to_owned(),
+                Ipv4Addr::new(142, 250, 184, 174),
+                70
+            )
+        );
+    }
+
     #[tokio::test]
     async fn test_read_qname() {
         let hex: Vec<u8> = vec![
@@ -531,9 +1442,9 @@ mod test {
             0x65, 0x72, 0x6e, 0x03, 0x65, 0x64, 0x75, 0x00,
         ];
 
-        let mut reader = tokio::io::BufReader::new(&*hex);
+        let mut reader = PacketReader::from(&*hex);
         let mut buff = String::new();
-        read_dns_encoded_name(&mut reader, &mut buff).await.unwrap();
+        reader.read_name(&mut buff).await.unwrap();
 
         assert_eq!(buff, "www.northeastern.edu")
     }
@@ -550,4 +1461,34 @@ mod test {
         write_dns_encoded_name(&mut result, given).await.unwrap();
         assert_eq!(result, expects);
     }
+
+    #[tokio::test]
+    async fn split_keeps_every_message_below_the_size() {
+        let record = |i: u8| {
+            Record::new_type_a(
+                format!("host-{}.example.internal", i),
+                Ipv4Addr::new(10, 0, 0, i),
+                60,
+            )
+        };
+        let message = (0..=255)
+            .map(record)
+            .fold(MessageBuilder::new_request(1), |builder, record| {
+                builder.add_answers(record)
+            })
+            .add_new_question("example.internal".to_owned(), QueryType::AXFR, Class::IN)
+            .add_authority(record(0))
+            .build();
+
+        let messages = message.split(512).await.unwrap();
+        assert!(messages.len() > 1);
+        assert_eq!(messages[0].authority.len(), 1);
+        let mut answers = 0;
+        for message in messages {
+            assert!(message.to_bytes().await.unwrap().len() <= 512);
+            assert_eq!(message.header.awnsers as usize, message.answers.len());
+            answers += message.answers.len();
+        }
+        assert_eq!(answers, 256);
+    }
 }
@@ -1,6 +1,6 @@
-use std::{marker::PhantomData, net::Ipv4Addr};
+use std::marker::PhantomData;
 
-use super::message::{Message, Header, Question, QueryType, Class, Record, ResultCode};
+use super::message::{Class, Header, Message, QueryType, Question, Record, ResultCode};
 
 pub struct Request;
 pub struct Response;
@@ -13,36 +13,37 @@ pub struct MessageBuilder<T> {
     authority: Vec<Record>,
     resources: Vec<Record>,
 
-    phantom: PhantomData<T>
+    phantom: PhantomData<T>,
 }
 
 impl MessageBuilder<Response> {
     pub(crate) fn from_request(message: Message) -> MessageBuilder<Response> {
-        MessageBuilder{
+        MessageBuilder {
             id: message.header.id,
-            flags: 0b1000000000000000,
+            // Response bit, echoing the request opcode, recursion desired and
+            // checking disabled bits
+            flags: 0b1000000000000000 | (message.header.flags & 0b0111100100010000),
             questions: message.questions,
             answers: Vec::new(),
             authority: Vec::new(),
             resources: Vec::new(),
-            phantom: PhantomData::default()
+            phantom: PhantomData,
         }
     }
 
-    pub(crate) fn set_is_authoritive(mut self) -> Self {
-        self.flags = self.flags | 0b0000010000000000;
+    pub(crate) fn set_recursive_available(mut self) -> Self {
+        self.flags |= 0b0000000010000000;
         self
     }
 
-    pub(crate) fn set_recursive_available(mut self) -> Self {
-        self.flags = self.flags | 0b0000000010000000;
+    pub(crate) fn set_authentic_data(mut self) -> Self {
+        self.flags |= 0b0000000000100000;
         self
     }
 
     pub(crate) fn set_status_code(mut self, rcode: ResultCode) -> Self {
-        let code = rcode.to();
-        self.flags = self.flags & 0b1111111111111111;
-        self.flags = self.flags & 0b1111111111111111;
+        let code = rcode.to() as u16;
+        self.flags = (self.flags & 0b1111111111110000) | (code & 0b0000000000001111);
         self
     }
 
@@ -51,52 +52,17 @@ impl MessageBuilder<Response> {
         self
     }
 
-    pub(crate) fn add_answers(mut self, answer: Record) -> Self {
-        self.answers.push(answer);
-        self
-    }
-
-    pub(crate) fn add_typeA_answer(mut self, name: String, addr: Ipv4Addr, ttl: u32) -> Self {
-        self.answers.push(Record::new_type_a(name, addr, ttl));
-        self
-    }
-
-
     pub(crate) fn set_authority(mut self, authority: Vec<Record>) -> Self {
         self.authority = authority;
         self
     }
 
-    pub(crate) fn add_authority(mut self, authority: Record) -> Self {
-        self.authority.push(authority);
-        self
-    }
-
-    pub(crate) fn add_typeA_authority(mut self, name: String, addr: Ipv4Addr, ttl: u32) -> Self {
-        self.authority.push(Record::new_type_a(name, addr, ttl));
-        self
-    }
-
-
-
     pub(crate) fn set_resources(mut self, resources: Vec<Record>) -> Self {
         self.resources = resources;
         self
     }
-
-    pub(crate) fn add_resources(mut self, resources: Record) -> Self {
-        self.resources.push(resources);
-        self
-    }
-
-    pub(crate) fn add_typeA_resources(mut self, name: String, addr: Ipv4Addr, ttl: u32) -> Self {
-        self.resources.push(Record::new_type_a(name, addr, ttl));
-        self
-    }
-
 }
 
-
 impl MessageBuilder<Request> {
     pub(crate) fn new_request(id: u16) -> MessageBuilder<Request> {
         MessageBuilder {
@@ -106,17 +72,22 @@ impl MessageBuilder<Request> {
             answers: Vec::new(),
             authority: Vec::new(),
             resources: Vec::new(),
-            phantom: PhantomData::default(),
+            phantom: PhantomData,
         }
     }
 
     pub(crate) fn set_recursion_desired(mut self) -> Self {
-        self.flags = self.flags | 0b0000000100000000;
+        self.flags |= 0b0000000100000000;
+        self
+    }
+
+    pub(crate) fn set_checking_disabled(mut self) -> Self {
+        self.flags |= 0b0000000000010000;
         self
     }
 
-    pub(crate) fn set_questions(mut self, questions: Vec<Question>) -> Self {
-        self.questions = questions;
+    pub(crate) fn set_op_code(mut self, op_code: u8) -> Self {
+        self.flags = (self.flags & 0b1000011111111111) | ((op_code as u16 & 0b1111) << 11);
         self
     }
 
@@ -125,19 +96,44 @@ impl MessageBuilder<Request> {
         self
     }
 
-    pub(crate) fn add_new_question(mut self, name: String, r#type: QueryType, class: Class) -> Self {
-        self.questions.push(Question{
+    pub(crate) fn add_new_question(
+        mut self,
+        name: String,
+        r#type: QueryType,
+        class: Class,
+    ) -> Self {
+        self.questions.push(Question {
             name,
             r#type,
-            class
+            class,
         });
         self
     }
 }
 
 impl<T> MessageBuilder<T> {
+    pub(crate) fn set_is_authoritive(mut self) -> Self {
+        self.flags |= 0b0000010000000000;
+        self
+    }
+
+    pub(crate) fn add_authority(mut self, authority: Record) -> Self {
+        self.authority.push(authority);
+        self
+    }
+
+    pub(crate) fn add_answers(mut self, answer: Record) -> Self {
+        self.answers.push(answer);
+        self
+    }
+
+    pub(crate) fn add_resources(mut self, resources: Record) -> Self {
+        self.resources.push(resources);
+        self
+    }
+
     pub(crate) fn build(self) -> Message {
-        Message{
+        Message {
             header: Header {
                 id: self.id,
                 flags: self.flags,
@@ -152,4 +148,4 @@ impl<T> MessageBuilder<T> {
             resources: self.resources,
         }
     }
-}
\ No newline at end of file
+}
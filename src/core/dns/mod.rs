@@ -1,4 +1,24 @@
 pub mod dns_reader_writer;
+pub mod dnssec;
+pub mod edns;
+pub mod https_listener;
+pub mod journal;
+pub mod mdns;
 pub mod message;
+pub mod message_builder;
+pub mod notify;
+pub mod query_log;
+pub mod quic_listener;
+pub mod rate_limit;
+pub mod responder;
+pub mod secondary;
+pub mod socket;
+pub mod tcp_listener;
+pub mod tls;
+pub mod tls_listener;
+pub mod tsig;
 pub mod udp_listener;
-pub mod message_builder;
\ No newline at end of file
+pub mod update;
+pub mod upstream;
+pub mod validator;
+pub mod zone;
@@ -0,0 +1,87 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::Result;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use super::message::{Class, QueryType, Record, ResultCode};
+use super::message_builder::MessageBuilder;
+use super::upstream::Upstream;
+use crate::core::shutdown::Shutdown;
+
+/// Opcode of zone change notifications (RFC 1996)
+pub(crate) const OPCODE_NOTIFY: u8 = 4;
+
+/// Tries per secondary before giving up until the next change
+const ATTEMPTS: u32 = 5;
+
+/// Wait for the first acknowledgement, doubled on every retry
+const FIRST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A zone whose serial changed, to announce to its secondaries so they
+/// transfer it without waiting for their refresh timer
+#[derive(Debug)]
+pub(crate) struct Notify {
+    pub(crate) zone: String,
+    pub(crate) soa: Record,
+    pub(crate) secondaries: Vec<SocketAddr>,
+}
+
+impl Notify {
+    /// Notifies every secondary in the background, retrying the ones that
+    /// do not acknowledge. Shutdown waits for them, up to its deadline.
+    pub(crate) fn spawn(self, shutdown: &Shutdown) {
+        for secondary in self.secondaries {
+            let zone = self.zone.clone();
+            let soa = self.soa.clone();
+
+            shutdown.spawn(async move {
+                match Self::send(&zone, &soa, secondary).await {
+                    Ok(()) => debug!(zone, %secondary, "secondary acknowledged notify"),
+                    Err(e) => warn!(zone, %secondary, "could not notify secondary: {}", e),
+                }
+            });
+        }
+    }
+
+    async fn send(zone: &str, soa: &Record, secondary: SocketAddr) -> Result<()> {
+        // The SOA in the answer section hints at the new serial (RFC 1996 3.7)
+        let request = MessageBuilder::new_request(rand::random())
+            .set_op_code(OPCODE_NOTIFY)
+            .set_is_authoritive()
+            .add_new_question(zone.to_owned(), QueryType::SOA, Class::IN)
+            .add_answers(soa.clone())
+            .build();
+
+        let mut wait = FIRST_TIMEOUT;
+        let mut last_error = Error::new(ErrorKind::TimedOut, "no acknowledgement");
+        for _ in 0..ATTEMPTS {
+            match timeout(wait, Upstream::query_udp(secondary, &request)).await {
+                // Only a NOERROR answer to the NOTIFY acknowledges it (RFC 1996)
+                Ok(Ok(response))
+                    if response.header.op_code() == OPCODE_NOTIFY
+                        && response.header.result_code() == ResultCode::NOERROR =>
+                {
+                    return Ok(())
+                }
+                Ok(Ok(response)) => {
+                    let code = response.header.result_code();
+                    warn!(zone, %secondary, ?code, "secondary rejected notify, retrying");
+                    last_error = Error::other(format!("rejected with {:?}", code));
+                    tokio::time::sleep(wait).await;
+                }
+                Ok(Err(e)) => {
+                    last_error = e;
+                    // Refused right away, give the secondary time to come up
+                    tokio::time::sleep(wait).await;
+                }
+                Err(_) => last_error = Error::new(ErrorKind::TimedOut, "no acknowledgement"),
+            }
+            wait *= 2;
+        }
+
+        Err(last_error)
+    }
+}
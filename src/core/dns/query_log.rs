@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::core::config::QueryLogConfig;
+
+use super::message::{Message, Question};
+use super::responder::Client;
+
+/// Logs one line per answered query, or a sample of them on busy servers
+#[derive(Debug, Clone)]
+pub(crate) struct QueryLog {
+    enabled: bool,
+    sample_rate: f64,
+}
+
+impl QueryLog {
+    pub(crate) fn from_config(config: &QueryLogConfig) -> QueryLog {
+        QueryLog {
+            enabled: config.enabled,
+            sample_rate: config.sample_rate,
+        }
+    }
+
+    fn sampled(&self) -> bool {
+        self.enabled && (self.sample_rate >= 1.0 || rand::random::<f64>() < self.sample_rate)
+    }
+
+    pub(crate) fn record(
+        &self,
+        client: &Client,
+        question: Option<&Question>,
+        response: &Message,
+        latency: Duration,
+    ) {
+        if !self.sampled() {
+            return;
+        }
+
+        let (qname, qtype) = match question {
+            Some(question) => (question.name.as_str(), format!("{:?}", question.r#type)),
+            None => ("", String::new()),
+        };
+
+        info!(
+            target: "query",
+            client = %client.addr,
+            transport = %client.transport,
+            qname,
+            qtype,
+            rcode = ?response.header.result_code(),
+            answers = response.answers.len(),
+            latency_us = latency.as_micros() as u64,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::QueryLog;
+
+    #[test]
+    fn samples_the_configured_fraction() {
+        let log = |enabled, sample_rate| QueryLog {
+            enabled,
+            sample_rate,
+        };
+
+        assert!(log(true, 1.0).sampled());
+        assert!(!log(true, 0.0).sampled());
+        assert!(!log(false, 1.0).sampled());
+
+        let half = log(true, 0.5);
+        let sampled = (0..10_000).filter(|_| half.sampled()).count();
+        assert!((4_000..6_000).contains(&sampled));
+    }
+}
@@ -0,0 +1,273 @@
+use std::io::Error;
+use std::sync::Arc;
+
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{
+    Connection, EndpointConfig, RecvStream, SendStream, ServerConfig, TokioRuntime, VarInt,
+};
+use tokio::io::Result;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tracing::debug;
+
+use crate::core::shutdown::Shutdown;
+
+use super::dns_reader_writer::DnsReader;
+use super::responder::{Client, Responder, Transport};
+use super::tcp_listener::{frame_len, TRANSFER_SIZE};
+use super::tls::{Certificates, HANDSHAKE_TIMEOUT};
+
+/// Application protocol of DNS over QUIC (RFC 9250 4.1.1)
+const ALPN_DOQ: &[u8] = b"doq";
+
+/// Closes a connection whose client broke the protocol (RFC 9250 4.3)
+const DOQ_PROTOCOL_ERROR: VarInt = VarInt::from_u32(2);
+
+/// Largest DNS message, with its two byte length
+const MAX_QUERY_SIZE: usize = 2 + 65535;
+
+/// Serves queries over QUIC (RFC 9250). Every query takes its own stream
+/// of a connection, so a slow answer does not hold back the others.
+pub struct QuicListener {
+    responder: Arc<Responder>,
+    config: ServerConfig,
+    shutdown: Shutdown,
+}
+
+impl QuicListener {
+    pub fn new(
+        responder: Arc<Responder>,
+        certificates: &Arc<Certificates>,
+        shutdown: Shutdown,
+    ) -> QuicListener {
+        let crypto = QuicServerConfig::try_from(certificates.server_config(&[ALPN_DOQ]))
+            .expect("the default provider supports TLS 1.3");
+        QuicListener {
+            responder,
+            config: ServerConfig::with_crypto(Arc::new(crypto)),
+            shutdown,
+        }
+    }
+
+    /// Accepts connections until shutdown is triggered, open connections
+    /// are closed once their current queries are answered.
+    pub async fn serve(&self, socket: UdpSocket) -> Result<()> {
+        let endpoint = quinn::Endpoint::new(
+            EndpointConfig::default(),
+            Some(self.config.clone()),
+            socket.into_std()?,
+            Arc::new(TokioRuntime),
+        )?;
+        let local = endpoint.local_addr()?;
+
+        loop {
+            let incoming = tokio::select! {
+                _ = self.shutdown.triggered() => return Ok(()),
+                incoming = endpoint.accept() => match incoming {
+                    Some(incoming) => incoming,
+                    None => return Ok(()),
+                },
+            };
+            let responder = self.responder.clone();
+            let shutdown = self.shutdown.clone();
+
+            self.shutdown.spawn(async move {
+                let addr = incoming.remote_address();
+                let connection = match timeout(HANDSHAKE_TIMEOUT, incoming).await {
+                    Ok(Ok(connection)) => connection,
+                    Ok(Err(e)) => {
+                        return debug!(client = %addr, transport = "quic", "handshake failed: {}", e)
+                    }
+                    Err(_) => {
+                        return debug!(client = %addr, transport = "quic", "handshake timed out")
+                    }
+                };
+
+                let client = Client::new(addr, local, Transport::Quic);
+                Self::accept_streams(connection, client, responder, &shutdown).await;
+            });
+        }
+    }
+
+    /// Answers every stream the client opens, until the connection is
+    /// closed or shutdown is triggered. Then the connection goes away with
+    /// the last of the queries being answered.
+    async fn accept_streams(
+        connection: Connection,
+        client: Client,
+        responder: Arc<Responder>,
+        shutdown: &Shutdown,
+    ) {
+        loop {
+            let (send, recv) = tokio::select! {
+                _ = shutdown.triggered() => return,
+                accepted = connection.accept_bi() => match accepted {
+                    Ok(streams) => streams,
+                    Err(e) => return debug!(client = %client.addr, transport = "quic", "closed connection: {}", e),
+                },
+            };
+            let connection = connection.clone();
+            let client = client.clone();
+            let responder = responder.clone();
+
+            shutdown.spawn(async move {
+                if let Err(e) = Self::process(send, recv, &connection, &client, responder).await {
+                    debug!(client = %client.addr, transport = "quic", "closed stream: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Answers the one query of a stream, its response ending the stream
+    /// (RFC 9250 4.2)
+    async fn process(
+        mut send: SendStream,
+        mut recv: RecvStream,
+        connection: &Connection,
+        client: &Client,
+        responder: Arc<Responder>,
+    ) -> Result<()> {
+        let frame = recv
+            .read_to_end(MAX_QUERY_SIZE)
+            .await
+            .map_err(Error::other)?;
+        let msg = match frame.split_first_chunk::<2>() {
+            Some((len, wire)) if u16::from_be_bytes(*len) as usize == wire.len() => {
+                DnsReader::from(wire).read().await.map(|msg| (msg, wire))
+            }
+            _ => Err(Error::other("query length does not match its frame")),
+        };
+
+        // Clients must leave the ID to 0, they have the stream to match
+        // the response with the query (RFC 9250 4.2.1)
+        let (msg, wire) = match msg {
+            Ok((msg, wire)) if msg.header.id == 0 => (msg, wire),
+            Ok(_) => {
+                connection.close(DOQ_PROTOCOL_ERROR, b"message id must be 0");
+                return Err(Error::other("message id is not 0"));
+            }
+            Err(e) => {
+                responder.metrics().record_parse_failure(client.transport);
+                connection.close(DOQ_PROTOCOL_ERROR, b"malformed query");
+                return Err(e);
+            }
+        };
+        debug!(client = %client.addr, ?msg, "received query");
+
+        let (resp, mut session) = responder.respond_signed(wire, msg, client).await;
+        debug!(client = %client.addr, ?resp, "sending response");
+
+        // Transfers go on the same stream, a message after the other
+        for resp in resp.split(TRANSFER_SIZE).await? {
+            let mut buf = resp.to_bytes().await?;
+            if let Some(session) = session.as_mut() {
+                buf = session.sign(buf);
+            }
+            send.write_all(&frame_len(&buf)?.to_be_bytes())
+                .await
+                .map_err(Error::other)?;
+            send.write_all(&buf).await.map_err(Error::other)?;
+        }
+        send.finish().map_err(Error::other)?;
+
+        // Keeps the connection until the client has the response, which
+        // would otherwise be lost if the process is shutting down
+        let _ = send.stopped().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use quinn::crypto::rustls::QuicClientConfig;
+    use quinn::{Connection, ConnectionError, Endpoint};
+    use rustls::crypto::ring::default_provider;
+    use rustls::{ClientConfig, RootCertStore};
+    use tokio::net::UdpSocket;
+
+    use super::{QuicListener, ALPN_DOQ, DOQ_PROTOCOL_ERROR};
+    use crate::core::config::Config;
+    use crate::core::dns::dns_reader_writer::DnsReader;
+    use crate::core::dns::message::{Class, Message, QueryType};
+    use crate::core::dns::message_builder::MessageBuilder;
+    use crate::core::dns::responder::Responder;
+    use crate::core::dns::tls::{Certificates, SelfSigned};
+    use crate::core::shutdown::Shutdown;
+
+    /// Sends the query on a stream of its own and reads the response
+    async fn exchange(connection: &Connection, id: u16, name: &str) -> Option<Message> {
+        let request = MessageBuilder::new_request(id)
+            .add_new_question(name.to_owned(), QueryType::A, Class::IN)
+            .build()
+            .to_bytes()
+            .await
+            .unwrap();
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        send.write_all(&(request.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        send.write_all(&request).await.unwrap();
+        send.finish().unwrap();
+
+        let frame = recv.read_to_end(65537).await.ok()?;
+        Some(DnsReader::from(&frame[2..]).read().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn answers_every_stream_over_quic() {
+        let certificate = SelfSigned::new();
+
+        let config: Config = toml::from_str(
+            r#"
+            [[zones]]
+            name = "example.internal"
+            records = [
+                { name = "www", type = "A", value = "10.0.0.1" },
+                { name = "api", type = "A", value = "10.0.0.2" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let responder = Arc::new(Responder::from_config(&config).unwrap());
+        let certificates = Certificates::from_config(&certificate.config).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let quic = QuicListener::new(responder, &certificates, Shutdown::new());
+        tokio::spawn(async move { quic.serve(socket).await });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate.der.clone()).unwrap();
+        let mut client = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client.alpn_protocols = vec![ALPN_DOQ.to_vec()];
+        let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(client).unwrap(),
+        )));
+        let connection = endpoint.connect(addr, "localhost").unwrap().await.unwrap();
+
+        // Queries of one connection are answered side by side
+        let (www, api) = tokio::join!(
+            exchange(&connection, 0, "www.example.internal"),
+            exchange(&connection, 0, "api.example.internal"),
+        );
+        assert_eq!(www.unwrap().answers[0].name(), "www.example.internal");
+        assert_eq!(api.unwrap().answers[0].name(), "api.example.internal");
+
+        // A message ID other than 0 is a protocol error
+        assert!(exchange(&connection, 7, "www.example.internal")
+            .await
+            .is_none());
+        match connection.closed().await {
+            ConnectionError::ApplicationClosed(close) => {
+                assert_eq!(close.error_code, DOQ_PROTOCOL_ERROR)
+            }
+            e => panic!("unexpected close: {}", e),
+        }
+    }
+}
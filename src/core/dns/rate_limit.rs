@@ -0,0 +1,319 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ipnet::IpNet;
+
+use crate::core::config::{normalize_name, RateLimitConfig};
+
+use super::message::{Message, Record, ResultCode};
+
+/// Most buckets kept. Past it, clients without a bucket get truncated
+/// responses, which a real client retries over TCP.
+const MAX_BUCKETS: usize = 100_000;
+
+/// How often the buckets that refilled are forgotten. Left alone for this
+/// long, a bucket is full again, as good as new.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do with a response to a UDP query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Send,
+    /// Send it truncated, so a real client retries over TCP
+    Slip,
+    Drop,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Responses limited so far, to slip one every `slip`
+    limited: u32,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Bucket {
+        Bucket {
+            tokens: rate,
+            updated: now,
+            limited: 0,
+        }
+    }
+
+    /// Takes a token if there is one, after refilling `rate` tokens per
+    /// second up to a burst of `rate`.
+    fn take(&mut self, rate: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum Key {
+    /// Every response to a client network
+    All(IpNet),
+    /// Identical responses to a client network
+    Response(IpNet, String, u8),
+}
+
+/// The buckets of every client network. They outlive the limiter, kept by
+/// the responder across reloads so that reloading does not refill them.
+#[derive(Debug)]
+pub(crate) struct Buckets {
+    buckets: HashMap<Key, Bucket>,
+    swept: Instant,
+    capacity: usize,
+}
+
+impl Default for Buckets {
+    fn default() -> Buckets {
+        Buckets {
+            buckets: HashMap::new(),
+            swept: Instant::now(),
+            capacity: MAX_BUCKETS,
+        }
+    }
+}
+
+impl Buckets {
+    /// The bucket of `key`, a full one if it is new and there is room
+    fn get(&mut self, key: Key, rate: f64, now: Instant) -> Option<&mut Bucket> {
+        let room = self.buckets.len() < self.capacity;
+        match self.buckets.entry(key) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(entry) if room => Some(entry.insert(Bucket::new(rate, now))),
+            Entry::Vacant(_) => None,
+        }
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.swept) >= SWEEP_INTERVAL {
+            self.buckets
+                .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < SWEEP_INTERVAL);
+            self.swept = now;
+        }
+    }
+}
+
+/// Token buckets per client network, limiting identical responses and,
+/// optionally, all responses (BIND RRL).
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter {
+    responses_per_second: f64,
+    all_per_second: f64,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    slip: u32,
+    exempt: Vec<IpNet>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn from_config(
+        config: &RateLimitConfig,
+        buckets: &Arc<Mutex<Buckets>>,
+    ) -> RateLimiter {
+        RateLimiter {
+            responses_per_second: config.responses_per_second as f64,
+            all_per_second: config.all_per_second as f64,
+            ipv4_prefix: config.ipv4_prefix,
+            ipv6_prefix: config.ipv6_prefix,
+            slip: config.slip,
+            exempt: config.exempt.clone(),
+            buckets: buckets.clone(),
+        }
+    }
+
+    pub(crate) fn check(&self, addr: IpAddr, response: &Message) -> Verdict {
+        self.check_at(addr, response, Instant::now())
+    }
+
+    fn check_at(&self, addr: IpAddr, response: &Message, now: Instant) -> Verdict {
+        if self.exempt.iter().any(|network| network.contains(&addr)) {
+            return Verdict::Send;
+        }
+
+        let prefix = match addr {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+        let network = IpNet::new(addr, prefix).unwrap().trunc();
+        let key = Key::Response(
+            network,
+            Self::identity(response),
+            response.header.result_code().to(),
+        );
+
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.sweep(now);
+
+        let all_allowed = match self.all_per_second {
+            0.0 => true,
+            rate => match buckets.get(Key::All(network), rate, now) {
+                Some(bucket) => bucket.take(rate, now),
+                None => return Verdict::Slip,
+            },
+        };
+
+        let bucket = match buckets.get(key, self.responses_per_second, now) {
+            Some(bucket) => bucket,
+            None => return Verdict::Slip,
+        };
+        // A response the network may not get anyway keeps its token
+        if all_allowed && bucket.take(self.responses_per_second, now) {
+            return Verdict::Send;
+        }
+
+        bucket.limited = bucket.limited.wrapping_add(1);
+        if self.slip > 0 && bucket.limited.is_multiple_of(self.slip) {
+            Verdict::Slip
+        } else {
+            Verdict::Drop
+        }
+    }
+
+    /// The name identical responses share. Names that do not exist count
+    /// as their zone, so random subdomains do not get a bucket each.
+    fn identity(response: &Message) -> String {
+        let soa = response.authority.iter().find_map(|record| match record {
+            Record::SOA { name, .. } => Some(name),
+            _ => None,
+        });
+
+        match (
+            response.header.result_code(),
+            soa,
+            response.questions.first(),
+        ) {
+            (ResultCode::NXDOMAIN, Some(zone), _) => normalize_name(zone),
+            (_, _, Some(question)) => normalize_name(&question.name),
+            _ => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use ipnet::IpNet;
+
+    use super::{Key, RateLimiter, Verdict};
+    use crate::core::config::RateLimitConfig;
+    use crate::core::dns::message::{Class, Message, QueryType};
+    use crate::core::dns::message_builder::MessageBuilder;
+
+    fn response(name: &str) -> Message {
+        let request = MessageBuilder::new_request(1)
+            .add_new_question(name.to_owned(), QueryType::A, Class::IN)
+            .build();
+        MessageBuilder::from_request(request).build()
+    }
+
+    #[test]
+    fn limits_identical_responses_per_network() {
+        let limiter = RateLimiter::from_config(
+            &RateLimitConfig {
+                responses_per_second: 2,
+                ..RateLimitConfig::default()
+            },
+            &Arc::default(),
+        );
+        let now = Instant::now();
+        let check = |addr: &str, name: &str, at| {
+            limiter.check_at(addr.parse().unwrap(), &response(name), at)
+        };
+
+        assert_eq!(
+            check("192.0.2.1", "www.example.internal", now),
+            Verdict::Send
+        );
+        assert_eq!(
+            check("192.0.2.2", "www.example.internal", now),
+            Verdict::Send
+        );
+        // Same /24, same response: every other limited one slips
+        assert_eq!(
+            check("192.0.2.3", "www.example.internal", now),
+            Verdict::Drop
+        );
+        assert_eq!(
+            check("192.0.2.3", "www.example.internal", now),
+            Verdict::Slip
+        );
+
+        assert_eq!(
+            check("192.0.2.1", "api.example.internal", now),
+            Verdict::Send
+        );
+        assert_eq!(
+            check("198.51.100.1", "www.example.internal", now),
+            Verdict::Send
+        );
+
+        let later = now + Duration::from_millis(500);
+        assert_eq!(
+            check("192.0.2.1", "www.example.internal", later),
+            Verdict::Send
+        );
+    }
+
+    #[test]
+    fn keeps_a_bounded_number_of_buckets() {
+        let limiter = RateLimiter::from_config(
+            &RateLimitConfig {
+                responses_per_second: 3,
+                all_per_second: 1,
+                ..RateLimitConfig::default()
+            },
+            &Arc::default(),
+        );
+        limiter.buckets.lock().unwrap().capacity = 4;
+        let now = Instant::now();
+        let check = |addr: &str, name: &str, at| {
+            limiter.check_at(addr.parse().unwrap(), &response(name), at)
+        };
+
+        assert_eq!(
+            check("192.0.2.1", "www.example.internal", now),
+            Verdict::Send
+        );
+        // Denied by the network limit, the response keeps its tokens
+        assert_ne!(
+            check("192.0.2.1", "www.example.internal", now),
+            Verdict::Send
+        );
+        let network: IpNet = "192.0.2.0/24".parse().unwrap();
+        let key = Key::Response(network, "www.example.internal".to_owned(), 0);
+        assert_eq!(limiter.buckets.lock().unwrap().buckets[&key].tokens, 2.0);
+
+        // New clients past the capacity are sent to TCP, until the sweep
+        assert_eq!(
+            check("198.51.100.1", "www.example.internal", now),
+            Verdict::Send
+        );
+        assert_eq!(
+            check("203.0.113.1", "www.example.internal", now),
+            Verdict::Slip
+        );
+        assert_eq!(limiter.buckets.lock().unwrap().buckets.len(), 4);
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            check("203.0.113.1", "www.example.internal", later),
+            Verdict::Send
+        );
+    }
+}
@@ -0,0 +1,1934 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use ipnet::IpNet;
+use tracing::{debug, error};
+
+use crate::core::acl::{Acls, Operation};
+use crate::core::config::{
+    normalize_name, Config, ConfigError, RegistryConfig, ZoneConfig, DEFAULT_VIEW,
+};
+use crate::core::locality::Localities;
+use crate::core::metrics::Metrics;
+use crate::core::registry::Registry;
+use crate::core::shutdown::Shutdown;
+
+use super::edns::{ClientSubnet, Edns};
+use super::journal::{is_newer, serial, Journal};
+use super::message::{Message, QueryType, Record, ResultCode};
+use super::message_builder::MessageBuilder;
+use super::notify::{Notify, OPCODE_NOTIFY};
+use super::query_log::QueryLog;
+use super::rate_limit::{Buckets, RateLimiter, Verdict};
+use super::secondary::Secondaries;
+use super::tsig::{Keys, Session, Verification, TYPE_TSIG};
+use super::update::{self, Updates, OPCODE_UPDATE};
+use super::upstream::Upstream;
+use super::validator::{Cuts, Security, Validator};
+use super::zone::{Lookup, Zone, ZoneStore};
+
+/// Zones and registry seen by the clients of some networks
+#[derive(Debug, Clone)]
+struct View {
+    name: String,
+    clients: Vec<IpNet>,
+    zones: ZoneStore,
+    registry: Option<Arc<Registry>>,
+}
+
+impl View {
+    fn from_config(
+        name: &str,
+        clients: &[IpNet],
+        zones: &[ZoneConfig],
+        registry: Option<&RegistryConfig>,
+        config: &Config,
+        secondaries: &Secondaries,
+        updates: &Updates,
+    ) -> Result<View, ConfigError> {
+        let mut view = View {
+            name: name.to_owned(),
+            clients: clients.to_vec(),
+            zones: ZoneStore::from_config(zones, &config.defaults, &secondaries.transferred(name))?,
+            registry: registry
+                .map(|registry| Arc::new(Self::registry(name, registry, config, updates))),
+        };
+
+        for zone in view.zones.iter_mut().filter(|zone| !zone.is_secondary()) {
+            if let Some(changes) = updates.changes(name, zone.name()) {
+                zone.apply(&changes);
+            }
+        }
+        Ok(view)
+    }
+
+    /// The registry of the view named `name`, as updated
+    fn registry(
+        name: &str,
+        registry: &RegistryConfig,
+        config: &Config,
+        updates: &Updates,
+    ) -> Registry {
+        let mut registry = Registry::from_config(registry, &config.defaults);
+        if let Some(changes) = updates.changes(name, registry.zone()) {
+            registry.apply(&changes);
+        }
+        registry
+    }
+
+    /// The zone of the view named `name` configured by `zone`, as updated,
+    /// or as last transferred for a secondary zone. None for a secondary
+    /// zone not transferred yet.
+    fn zone(
+        name: &str,
+        zone: &ZoneConfig,
+        config: &Config,
+        secondaries: &Secondaries,
+        updates: &Updates,
+    ) -> Result<Option<Zone>, ConfigError> {
+        if !zone.primaries.is_empty() {
+            let records = secondaries.records(name, &normalize_name(&zone.name));
+            return Ok(records.map(|records| Zone::from_transfer(zone, records)));
+        }
+
+        let mut zone = Zone::from_config(zone, &config.defaults)
+            .map_err(|e| ConfigError::Invalid(vec![format!("zone {}: {}", zone.name, e)]))?;
+        if let Some(changes) = updates.changes(name, zone.name()) {
+            zone.apply(&changes);
+        }
+        Ok(Some(zone))
+    }
+}
+
+/// What a view answers authoritatively for a name
+#[derive(Clone, Copy)]
+enum Authority<'a> {
+    Registry(&'a Registry),
+    Zone(&'a Zone),
+}
+
+impl<'a> Authority<'a> {
+    fn acl(self) -> &'a Acls {
+        match self {
+            Authority::Registry(registry) => &registry.acl,
+            Authority::Zone(zone) => &zone.acl,
+        }
+    }
+
+    fn apex(self) -> &'a str {
+        match self {
+            Authority::Registry(registry) => registry.zone(),
+            Authority::Zone(zone) => zone.name(),
+        }
+    }
+
+    fn soa(self) -> &'a Record {
+        match self {
+            Authority::Registry(registry) => registry.soa(),
+            Authority::Zone(zone) => zone.soa(),
+        }
+    }
+
+    fn transfer(self) -> Vec<Record> {
+        match self {
+            Authority::Registry(registry) => registry.transfer(),
+            Authority::Zone(zone) => zone.transfer(),
+        }
+    }
+
+    fn notify(self) -> &'a [SocketAddr] {
+        match self {
+            Authority::Registry(registry) => &registry.notify,
+            Authority::Zone(zone) => &zone.notify,
+        }
+    }
+
+    /// Applies a dynamic update to the records as served
+    fn update(self, request: &Message) -> Result<update::Changes, ResultCode> {
+        let mut records = self.transfer();
+        records.pop();
+
+        match self {
+            // The registry has a single TTL, changes are kept without one
+            // so that they still apply when it is reconfigured
+            Authority::Registry(registry) => {
+                records
+                    .iter_mut()
+                    .for_each(|record| *record.fields_mut().2 = 0);
+                update::apply(registry.zone(), &records, request, Some(0), |record| {
+                    registry.accepts(record)
+                })
+            }
+            Authority::Zone(zone) => update::apply(zone.name(), &records, request, None, |_| true),
+        }
+    }
+}
+
+impl View {
+    fn authority(&self, qname: &str) -> Option<Authority<'_>> {
+        match self.registry.as_deref() {
+            Some(registry) if registry.contains(qname) => Some(Authority::Registry(registry)),
+            _ => self.zones.find(qname).map(Authority::Zone),
+        }
+    }
+
+    /// The registry and every zone of the view
+    fn authorities(&self) -> impl Iterator<Item = Authority<'_>> {
+        self.registry
+            .as_deref()
+            .map(Authority::Registry)
+            .into_iter()
+            .chain(self.zones.iter().map(Authority::Zone))
+    }
+}
+
+/// Everything answers are built from, replaced as a whole on reload. The
+/// stores built on updates and transfers share the zones they do not change.
+#[derive(Debug, Clone)]
+struct Store {
+    /// The top level zones and registry, for clients outside of every view
+    default: View,
+    views: Vec<View>,
+    acl: Acls,
+    listener_acl: HashMap<SocketAddr, Acls>,
+    localities: Localities,
+    upstream: Upstream,
+    validator: Option<Validator>,
+    query_log: QueryLog,
+    rate_limit: Option<RateLimiter>,
+    journal: Journal,
+    secondaries: Arc<Secondaries>,
+    keys: Keys,
+}
+
+impl Store {
+    /// Builds the store of `config`, its zones following the serials and
+    /// journal of the `previous` store when reloading.
+    fn from_config(
+        config: &Config,
+        metrics: &Arc<Metrics>,
+        secondaries: &Arc<Secondaries>,
+        updates: &Updates,
+        buckets: &Arc<Mutex<Buckets>>,
+        cuts: &Arc<Mutex<Cuts>>,
+        previous: Option<&Store>,
+    ) -> Result<Store, ConfigError> {
+        let default = View::from_config(
+            DEFAULT_VIEW,
+            &[],
+            &config.zones,
+            config.registry.as_ref(),
+            config,
+            secondaries,
+            updates,
+        )?;
+        let views = config
+            .views
+            .iter()
+            .map(|view| {
+                View::from_config(
+                    &view.name,
+                    &view.clients,
+                    &view.zones,
+                    view.registry.as_ref(),
+                    config,
+                    secondaries,
+                    updates,
+                )
+            })
+            .collect::<Result<_, _>>()?;
+        let acl = Acls::server(&config.acl);
+        let listener_acl = config
+            .listen
+            .acl
+            .iter()
+            .map(|(addr, acl)| (*addr, Acls::from_config(acl)))
+            .collect();
+        let localities = Localities::from_config(&config.localities);
+        let upstream = Upstream::new(
+            config.upstream_addrs(),
+            config.validation.is_some(),
+            metrics.clone(),
+        );
+        let validator = config
+            .validation
+            .as_ref()
+            .map(|validation| Validator::from_config(validation, cuts));
+        let query_log = QueryLog::from_config(&config.query_log);
+        let rate_limit = config
+            .rate_limit
+            .as_ref()
+            .map(|rate_limit| RateLimiter::from_config(rate_limit, buckets));
+
+        let mut store = Store {
+            default,
+            views,
+            acl,
+            listener_acl,
+            localities,
+            upstream,
+            validator,
+            query_log,
+            rate_limit,
+            journal: Journal::default(),
+            secondaries: secondaries.clone(),
+            keys: Keys::from_config(&config.keys),
+        };
+        store.follow(previous);
+        Ok(store)
+    }
+
+    /// Serves every zone and registry with a serial following the one it
+    /// had in the `previous` store, journaling the changes since.
+    fn follow(&mut self, previous: Option<&Store>) {
+        let empty = Journal::default();
+        let before = previous.map_or(&empty, |store| &store.journal);
+        let mut journal = Journal::default();
+
+        for view in std::iter::once(&mut self.default).chain(self.views.iter_mut()) {
+            let old = previous.and_then(|store| store.view_named(&view.name));
+
+            if let Some(registry) = &mut view.registry {
+                let registry = Arc::get_mut(registry)
+                    .expect("registries are only changed before being served");
+                let served = old
+                    .and_then(|old| old.registry.as_deref())
+                    .filter(|old| old.zone() == registry.zone())
+                    .map(Registry::transfer);
+                let serial = journal.record(
+                    before,
+                    &view.name,
+                    registry.zone(),
+                    served,
+                    registry.transfer(),
+                );
+                registry.set_serial(serial);
+            }
+
+            for zone in view.zones.iter_mut() {
+                let served = old
+                    .and_then(|old| old.zones.get(zone.name()))
+                    .map(Zone::transfer);
+                let serial =
+                    journal.record(before, &view.name, zone.name(), served, zone.transfer());
+                zone.set_serial(serial);
+            }
+        }
+
+        self.journal = journal;
+    }
+
+    /// A copy of the store with the registry or zone `apex` of `view` built
+    /// again from `config`, once an update or a transfer changed it, and
+    /// served with a serial following the previous one. The other zones are
+    /// shared with this store.
+    fn rebuilt(
+        &self,
+        config: &Config,
+        view: &str,
+        apex: &str,
+        updates: &Updates,
+    ) -> Result<Store, ConfigError> {
+        let mut store = self.clone();
+        let (zones, registry) = match config.view(view) {
+            Some(configured) => configured,
+            None => return Ok(store),
+        };
+        let target = match std::iter::once(&mut store.default)
+            .chain(store.views.iter_mut())
+            .find(|target| target.name == view)
+        {
+            Some(target) => target,
+            None => return Ok(store),
+        };
+        let served = target
+            .authority(apex)
+            .filter(|authority| authority.apex() == apex)
+            .map(Authority::transfer);
+
+        if let Some(registry) = registry.filter(|registry| normalize_name(&registry.zone) == apex) {
+            let mut registry = View::registry(view, registry, config, updates);
+            let serial =
+                store
+                    .journal
+                    .record(&self.journal, view, apex, served, registry.transfer());
+            registry.set_serial(serial);
+            target.registry = Some(Arc::new(registry));
+            return Ok(store);
+        }
+
+        let zone = match zones.iter().find(|zone| normalize_name(&zone.name) == apex) {
+            Some(zone) => View::zone(view, zone, config, &self.secondaries, updates)?,
+            None => None,
+        };
+        let zone = match zone {
+            Some(mut zone) => {
+                let serial =
+                    store
+                        .journal
+                        .record(&self.journal, view, apex, served, zone.transfer());
+                zone.set_serial(serial);
+                Some(zone)
+            }
+            // An expired secondary zone starts over once transferred again
+            None => {
+                store.journal.forget(view, apex);
+                None
+            }
+        };
+        target.zones.replace(apex, zone);
+        Ok(store)
+    }
+
+    /// Zones with secondaries whose serial changed since `previous`, or
+    /// every one of them on startup
+    fn notifications(&self, previous: Option<&Store>) -> Vec<Notify> {
+        let mut notifications = Vec::new();
+
+        for view in std::iter::once(&self.default).chain(self.views.iter()) {
+            let old = previous.and_then(|store| store.view_named(&view.name));
+
+            for authority in view
+                .authorities()
+                .filter(|authority| !authority.notify().is_empty())
+            {
+                let unchanged = old
+                    .and_then(|old| old.authority(authority.apex()))
+                    .filter(|old| old.apex() == authority.apex())
+                    .is_some_and(|old| serial(old.soa()) == serial(authority.soa()));
+                if unchanged {
+                    continue;
+                }
+
+                notifications.push(Notify {
+                    zone: authority.apex().to_owned(),
+                    soa: authority.soa().clone(),
+                    secondaries: authority.notify().to_vec(),
+                });
+            }
+        }
+
+        notifications
+    }
+
+    fn view_named(&self, name: &str) -> Option<&View> {
+        std::iter::once(&self.default)
+            .chain(self.views.iter())
+            .find(|view| view.name == name)
+    }
+
+    /// The first view matching the source address of the client. ECS is
+    /// not used here, as any resolver could claim to ask for a client
+    /// inside a view.
+    fn view(&self, client: &Client) -> &View {
+        let addr = client.addr.ip();
+        self.views
+            .iter()
+            .find(|view| view.clients.iter().any(|network| network.contains(&addr)))
+            .unwrap_or(&self.default)
+    }
+
+    /// Whether the server, the listener that received the request and the
+    /// zone asked about (if any) all allow `operation` to the client.
+    fn allows(&self, operation: Operation, client: &Client, zone: Option<&Acls>) -> bool {
+        let (addr, key) = (client.addr.ip(), client.key.as_deref());
+
+        self.acl.allows(operation, addr, key)
+            && self
+                .listener_acl
+                .get(&client.local)
+                .is_none_or(|acl| acl.allows(operation, addr, key))
+            && zone.is_none_or(|acl| acl.allows(operation, addr, key))
+    }
+
+    fn registries(&self) -> impl Iterator<Item = (&str, &Registry)> {
+        std::iter::once(&self.default)
+            .chain(self.views.iter())
+            .filter_map(|view| {
+                view.registry
+                    .as_deref()
+                    .map(|registry| (view.name.as_str(), registry))
+            })
+    }
+}
+
+/// How a request reached the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+    Quic,
+}
+
+impl Transport {
+    /// Whether a response may take several messages, as transfers do
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Transport::Tcp | Transport::Tls | Transport::Quic)
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Udp => write!(f, "udp"),
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Tls => write!(f, "tls"),
+            Transport::Https => write!(f, "https"),
+            Transport::Quic => write!(f, "quic"),
+        }
+    }
+}
+
+/// Who sent a request, handed to the responder by the listener
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub addr: SocketAddr,
+    /// Address of the listener the request was received on
+    pub local: SocketAddr,
+    pub transport: Transport,
+    /// Network of the client a resolver asks on behalf of, from the EDNS
+    /// Client Subnet option
+    pub subnet: Option<IpNet>,
+    /// Name of the TSIG key the request was signed with, once verified
+    pub key: Option<String>,
+}
+
+impl Client {
+    pub fn new(addr: SocketAddr, local: SocketAddr, transport: Transport) -> Client {
+        Client {
+            addr,
+            local,
+            transport,
+            subnet: None,
+            key: None,
+        }
+    }
+
+    /// Where the client is, its subnet when a resolver gave one
+    pub fn location(&self) -> IpAddr {
+        match self.subnet {
+            Some(subnet) => subnet.addr(),
+            None => self.addr.ip(),
+        }
+    }
+}
+
+/// Builds the response to a request, shared by every listener.
+///
+/// Questions for the registry zone or one of the configured zones are
+/// answered authoritatively, anything else is forwarded upstream.
+#[derive(Debug)]
+pub struct Responder {
+    store: RwLock<Arc<Store>>,
+    metrics: Arc<Metrics>,
+    secondaries: Arc<Secondaries>,
+    updates: Updates,
+    /// Rate limiting buckets and validated cuts, kept across reloads
+    buckets: Arc<Mutex<Buckets>>,
+    cuts: Arc<Mutex<Cuts>>,
+    /// Tracks the notifications still being sent
+    shutdown: Shutdown,
+    /// The configuration the store was built from, the zones transferred or
+    /// updated are built again with it
+    config: Mutex<Config>,
+}
+
+impl Responder {
+    #[cfg(test)]
+    pub fn from_config(config: &Config) -> Result<Responder, ConfigError> {
+        Self::with_updates(config, Updates::default(), Shutdown::new())
+    }
+
+    /// The responder of `config` serving the zones with the changes of
+    /// `updates`, as saved before a restart
+    pub(crate) fn with_updates(
+        config: &Config,
+        updates: Updates,
+        shutdown: Shutdown,
+    ) -> Result<Responder, ConfigError> {
+        let metrics = Arc::new(Metrics::new());
+        let secondaries = Arc::new(Secondaries::default());
+        secondaries.configure(config);
+        let buckets = Arc::default();
+        let cuts = Arc::default();
+        let store = Store::from_config(
+            config,
+            &metrics,
+            &secondaries,
+            &updates,
+            &buckets,
+            &cuts,
+            None,
+        )?;
+        metrics.set_registries(store.registries());
+        store
+            .notifications(None)
+            .into_iter()
+            .for_each(|notify| notify.spawn(&shutdown));
+
+        Ok(Responder {
+            store: RwLock::new(Arc::new(store)),
+            metrics,
+            secondaries,
+            updates,
+            buckets,
+            cuts,
+            shutdown,
+            config: Mutex::new(config.clone()),
+        })
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Swaps in the views, zones, registry and upstreams of `config`. Requests
+    /// already being answered finish with the previous ones, and nothing
+    /// changes if `config` can not be loaded.
+    pub fn reload(&self, config: &Config) -> Result<(), ConfigError> {
+        let mut current = self.config.lock().unwrap();
+        let followed = self.secondaries.configure(config);
+        if let Err(e) = self.rebuild(config) {
+            self.secondaries.restore(followed);
+            return Err(e);
+        }
+        *current = config.clone();
+        Ok(())
+    }
+
+    fn rebuild(&self, config: &Config) -> Result<(), ConfigError> {
+        let previous = self.store();
+        let store = Store::from_config(
+            config,
+            &self.metrics,
+            &self.secondaries,
+            &self.updates,
+            &self.buckets,
+            &self.cuts,
+            Some(&previous),
+        )?;
+        self.serve(store, &previous);
+        Ok(())
+    }
+
+    /// Answers with `store` from now on, notifying the secondaries of the
+    /// zones changed since `previous`
+    fn serve(&self, store: Store, previous: &Store) {
+        self.metrics.set_registries(store.registries());
+        store
+            .notifications(Some(previous))
+            .into_iter()
+            .for_each(|notify| notify.spawn(&self.shutdown));
+        *self.store.write().unwrap() = Arc::new(store);
+    }
+
+    /// Keeps the secondary zones up to date with their primaries until
+    /// shutdown, serving them as soon as they are transferred.
+    pub async fn refresh_secondaries(self: Arc<Self>, shutdown: Shutdown) {
+        loop {
+            tokio::select! {
+                _ = shutdown.triggered() => return,
+                _ = self.secondaries.due() => {}
+            }
+
+            let changed = self.secondaries.refresh().await;
+            if changed.is_empty() {
+                continue;
+            }
+
+            let config = self.config.lock().unwrap();
+            let previous = self.store();
+            let store = changed
+                .iter()
+                .try_fold((*previous).clone(), |store, (view, zone)| {
+                    store.rebuilt(&config, view, zone, &self.updates)
+                });
+            match store {
+                Ok(store) => self.serve(store, &previous),
+                Err(e) => error!("could not serve the transferred zones: {}", e),
+            }
+        }
+    }
+
+    /// Saves the changes of dynamic updates every time they change, for
+    /// them to be applied again after a restart, and a last time on
+    /// shutdown.
+    pub async fn save_updates(self: Arc<Self>, shutdown: Shutdown) {
+        loop {
+            let last = tokio::select! {
+                _ = shutdown.triggered() => true,
+                _ = self.updates.changed() => false,
+            };
+            if let Err(e) = self.updates.save().await {
+                error!("could not save the dynamic updates: {}", e);
+            }
+            if last {
+                return;
+            }
+        }
+    }
+
+    /// The services of the top level registry as published on the local
+    /// link under `domain`, none without a registry
+    pub(crate) fn local_records(&self, domain: &str, ttl: u32) -> Vec<Record> {
+        let store = self.store();
+        store
+            .default
+            .registry
+            .as_ref()
+            .map(|registry| registry.local_records(domain, ttl))
+            .unwrap_or_default()
+    }
+
+    fn store(&self) -> Arc<Store> {
+        self.store.read().unwrap().clone()
+    }
+
+    pub(crate) async fn respond(&self, request: Message, client: &Client) -> Message {
+        self.respond_as(request, client, None).await
+    }
+
+    /// Responds to the request read from `wire`, checking its TSIG record
+    /// if it has one. The response must then be signed with the returned
+    /// session once serialized.
+    pub(crate) async fn respond_signed(
+        &self,
+        wire: &[u8],
+        mut request: Message,
+        client: &Client,
+    ) -> (Message, Option<Session>) {
+        let verification = self.store().keys.verify(wire);
+        // Not part of the question, and not to be forwarded upstream
+        request
+            .resources
+            .retain(|record| record.query_type() != QueryType::UNKNOWN(TYPE_TSIG));
+
+        match verification {
+            Verification::Unsigned => (self.respond(request, client).await, None),
+            Verification::Signed(session) => {
+                let mut client = client.clone();
+                client.key = session.key().map(str::to_owned);
+                (self.respond(request, &client).await, Some(session))
+            }
+            Verification::Failed(session) => {
+                debug!("rejected the signature of {}", client.addr);
+                let response = self
+                    .respond_as(request, client, Some(ResultCode::NOTAUTH))
+                    .await;
+                (response, Some(session))
+            }
+            Verification::Malformed => (
+                self.respond_as(request, client, Some(ResultCode::FORMERR))
+                    .await,
+                None,
+            ),
+        }
+    }
+
+    /// Answers `request`, or `rejected` it without looking at it
+    async fn respond_as(
+        &self,
+        request: Message,
+        client: &Client,
+        rejected: Option<ResultCode>,
+    ) -> Message {
+        let started = Instant::now();
+        let store = self.store();
+        let question = request.questions.first().cloned();
+
+        let response = match (rejected, Edns::from_message(&request)) {
+            (Some(rcode), _) => Self::error(request, rcode),
+            (None, Ok(None)) => self.answer(&store, request, client).await,
+            (None, Ok(Some(edns))) => self.answer_edns(&store, request, client, edns).await,
+            (None, Err(_)) => Self::error(request, ResultCode::FORMERR),
+        };
+
+        let latency = started.elapsed();
+        self.metrics
+            .record_query(client.transport, question.as_ref(), &response, latency);
+        store
+            .query_log
+            .record(client, question.as_ref(), &response, latency);
+        response
+    }
+
+    /// Whether `response` may be sent to the client as is. Only UDP is rate
+    /// limited, TCP clients can not spoof their address.
+    pub(crate) fn limit(&self, client: &Client, response: &Message) -> Verdict {
+        let store = self.store();
+        let verdict = match (&store.rate_limit, client.transport) {
+            (Some(limiter), Transport::Udp) => limiter.check(client.addr.ip(), response),
+            _ => Verdict::Send,
+        };
+
+        match verdict {
+            Verdict::Send => {}
+            Verdict::Slip => self.metrics.record_rate_limited("slip"),
+            Verdict::Drop => self.metrics.record_rate_limited("drop"),
+        }
+        verdict
+    }
+
+    /// Answers a request carrying an OPT record, with an OPT record of our
+    /// own echoing its client subnet.
+    async fn answer_edns(
+        &self,
+        store: &Store,
+        request: Message,
+        client: &Client,
+        edns: Edns,
+    ) -> Message {
+        let mut reply = Edns::new();
+
+        if edns.version != 0 {
+            // BADVERS, the upper bits of rcode 16 (RFC 6891 6.1.3)
+            reply.extended_rcode = 1;
+            return Self::with_edns(Self::error(request, ResultCode::NOERROR), reply);
+        }
+
+        let subnet = match edns.client_subnet() {
+            Ok(subnet) => subnet,
+            Err(_) => return Self::with_edns(Self::error(request, ResultCode::FORMERR), reply),
+        };
+
+        // A source prefix of 0 means the client asked not to be located
+        let mut client = client.clone();
+        client.subnet = subnet
+            .filter(|subnet| subnet.source_prefix > 0)
+            .map(|subnet| subnet.network());
+        let scope = subnet.map(|subnet| Self::scope(store, &request, &client, &subnet));
+
+        let mut response = self.answer(store, request, &client).await;
+        if edns.dnssec_ok {
+            response = Self::sign(store, &client, response).await;
+            reply.dnssec_ok = true;
+        }
+
+        if let (Some(subnet), Some(scope)) = (subnet, scope) {
+            reply.options.push(subnet.to_option(scope));
+        }
+        Self::with_edns(response, reply)
+    }
+
+    /// How many bits of the client subnet the answer depends on: only the
+    /// registry answers differently depending on where the client is.
+    fn scope(store: &Store, request: &Message, client: &Client, subnet: &ClientSubnet) -> u8 {
+        let located = match (&store.view(client).registry, request.questions.first()) {
+            (Some(registry), Some(question)) => {
+                !store.localities.is_empty() && registry.contains(&normalize_name(&question.name))
+            }
+            _ => false,
+        };
+
+        if located {
+            subnet.source_prefix
+        } else {
+            0
+        }
+    }
+
+    /// Adds the RRSIGs of a signed registry to its answers. Negative ones
+    /// become compact denials (RFC 9824): NOERROR with an NSEC listing the
+    /// types the name has, none for a name that does not exist.
+    async fn sign(store: &Store, client: &Client, mut response: Message) -> Message {
+        let (qname, qtype) = match response.questions.first() {
+            Some(question) => (normalize_name(&question.name), question.r#type.clone()),
+            None => return response,
+        };
+        let registry = match store.view(client).authority(&qname) {
+            Some(Authority::Registry(registry)) => registry,
+            _ => return response,
+        };
+        let signer = match &registry.signer {
+            // Transfers are sent as configured, unsigned
+            Some(signer)
+                if response.header.is_authoritative()
+                    && !matches!(qtype, QueryType::AXFR | QueryType::IXFR) =>
+            {
+                signer
+            }
+            _ => return response,
+        };
+
+        if response.answers.is_empty() {
+            let ttl = response.authority.first().map_or(0, Record::ttl);
+            response
+                .authority
+                .push(signer.deny(&qname, registry.types(&qname).as_deref(), ttl));
+            response.header.set_result_code(ResultCode::NOERROR);
+        }
+
+        let signatures = match (
+            signer.sign(&response.answers).await,
+            signer.sign(&response.authority).await,
+        ) {
+            (Ok(answers), Ok(authority)) => (answers, authority),
+            (Err(e), _) | (_, Err(e)) => {
+                error!(zone = registry.zone(), "could not sign the answer: {}", e);
+                response.answers.clear();
+                response.authority.clear();
+                response.header.set_result_code(ResultCode::SERVFAIL);
+                (Vec::new(), Vec::new())
+            }
+        };
+        response.answers.extend(signatures.0);
+        response.authority.extend(signatures.1);
+        response.header.awnsers = response.answers.len() as u16;
+        response.header.authority_entries = response.authority.len() as u16;
+        response
+    }
+
+    fn with_edns(mut response: Message, edns: Edns) -> Message {
+        response
+            .resources
+            .retain(|record| !matches!(record, Record::OPT { .. }));
+        response.resources.push(edns.to_record());
+        response.header.ressource_entries = response.resources.len() as u16;
+        response
+    }
+
+    async fn answer(&self, store: &Store, request: Message, client: &Client) -> Message {
+        if !request.header.is_query() {
+            return Self::error(request, ResultCode::NOTIMP);
+        }
+
+        if request.header.op_code() == OPCODE_UPDATE {
+            return self.update(request, client);
+        }
+
+        if request.header.op_code() == OPCODE_NOTIFY {
+            return Self::notified(store, request, client);
+        }
+
+        if request.header.op_code() != 0 {
+            return Self::error(request, ResultCode::NOTIMP);
+        }
+
+        if request.questions.len() != 1 {
+            return Self::error(request, ResultCode::FORMERR);
+        }
+
+        let question = &request.questions[0];
+        let qname = normalize_name(&question.name);
+        let view = store.view(client);
+
+        let authority = match view.authority(&qname) {
+            Some(authority) => authority,
+            None if !store.upstream.is_empty() && request.header.is_recursion_desired() => {
+                if !store.allows(Operation::Recursion, client, None) {
+                    return Self::error(request, ResultCode::REFUSED);
+                }
+                return Self::forward(store, request).await;
+            }
+            None => return Self::error(request, ResultCode::REFUSED),
+        };
+
+        if matches!(question.r#type, QueryType::AXFR | QueryType::IXFR) {
+            if !store.allows(Operation::Transfer, client, Some(authority.acl())) {
+                return Self::error(request, ResultCode::REFUSED);
+            }
+            if qname != authority.apex() {
+                return Self::error(request, ResultCode::NOTAUTH);
+            }
+            return Self::transfer(store, &view.name, authority, request, client);
+        }
+
+        if !store.allows(Operation::Query, client, Some(authority.acl())) {
+            return Self::error(request, ResultCode::REFUSED);
+        }
+
+        let lookup = match authority {
+            Authority::Registry(registry) => {
+                let locality = store.localities.find(client.location());
+                registry.lookup(&qname, &question.r#type, locality)
+            }
+            Authority::Zone(zone) => zone.lookup(&qname, &question.r#type),
+        };
+        Self::authoritative(request, lookup)
+    }
+
+    /// Applies a dynamic update (RFC 2136) to a zone or the registry of the
+    /// view of the client, serving it with a new serial right away.
+    fn update(&self, request: Message, client: &Client) -> Message {
+        // The zone section names the zone to update
+        if request.questions.len() != 1 || request.questions[0].r#type != QueryType::SOA {
+            return Self::error(request, ResultCode::FORMERR);
+        }
+
+        // Updates are applied one at a time, each on top of the previous one
+        let config = self.config.lock().unwrap();
+        let store = self.store();
+        let zone = normalize_name(&request.questions[0].name);
+        let view = store.view(client);
+        let authority = view
+            .authority(&zone)
+            .filter(|authority| authority.apex() == zone);
+
+        if !store.allows(
+            Operation::Update,
+            client,
+            authority.map(|authority| authority.acl()),
+        ) {
+            return Self::error(request, ResultCode::REFUSED);
+        }
+        let changes = match authority {
+            None => return Self::error(request, ResultCode::NOTAUTH),
+            // Secondaries do not forward updates to their primary
+            Some(Authority::Zone(zone)) if zone.is_secondary() => {
+                return Self::error(request, ResultCode::NOTIMP);
+            }
+            Some(authority) => match authority.update(&request) {
+                Ok(changes) => changes,
+                Err(rcode) => return Self::error(request, rcode),
+            },
+        };
+
+        debug!(
+            client = %client.addr,
+            zone,
+            removed = changes.removed.len(),
+            added = changes.added.len(),
+            "applied update"
+        );
+        if !changes.is_empty() {
+            let previous = self.updates.record(&view.name, &zone, changes);
+            match store.rebuilt(&config, &view.name, &zone, &self.updates) {
+                Ok(updated) => self.serve(updated, &store),
+                Err(e) => {
+                    error!("could not serve the updated zone: {}", e);
+                    self.updates.restore(&view.name, &zone, previous);
+                    return Self::error(request, ResultCode::SERVFAIL);
+                }
+            }
+        }
+
+        MessageBuilder::from_request(request).build()
+    }
+
+    /// Acknowledges a NOTIFY for one of our zones, refreshing it when we
+    /// are its secondary and the NOTIFY comes from its primary.
+    fn notified(store: &Store, request: Message, client: &Client) -> Message {
+        if request.questions.len() != 1 || request.questions[0].r#type != QueryType::SOA {
+            return Self::error(request, ResultCode::FORMERR);
+        }
+
+        let zone = normalize_name(&request.questions[0].name);
+        let view = store.view(client);
+        // Secondary zones are checked right away when a primary notifies
+        let secondary = store
+            .secondaries
+            .notified(&view.name, &zone, client.addr.ip());
+        let authority = view
+            .authority(&zone)
+            .filter(|authority| authority.apex() == zone);
+        if !secondary && authority.is_none() {
+            return Self::error(request, ResultCode::NOTAUTH);
+        }
+
+        debug!(client = %client.addr, zone, "received notify");
+        MessageBuilder::from_request(request)
+            .set_is_authoritive()
+            .build()
+    }
+
+    /// Answers AXFR with the whole zone and IXFR with the changes since the
+    /// serial of the SOA the client sends in the authority section, or the
+    /// whole zone when the journal does not go back that far (RFC 1995 4).
+    fn transfer(
+        store: &Store,
+        view: &str,
+        authority: Authority,
+        request: Message,
+        client: &Client,
+    ) -> Message {
+        let current = authority.soa();
+
+        let records = match request.questions[0].r#type {
+            // Transfers take several messages, only possible over a stream
+            QueryType::AXFR if !client.transport.is_stream() => {
+                return Self::error(request, ResultCode::NOTIMP);
+            }
+            QueryType::AXFR => authority.transfer(),
+            _ => {
+                let known = request.authority.iter().find_map(|record| match record {
+                    Record::SOA { serial, .. } => Some(*serial),
+                    _ => None,
+                });
+                let known = match known {
+                    Some(known) => known,
+                    None => return Self::error(request, ResultCode::FORMERR),
+                };
+
+                // A single SOA tells the client it is up to date, or over
+                // UDP or HTTPS that it should ask again over TCP
+                if !is_newer(serial(current), known) || !client.transport.is_stream() {
+                    vec![current.clone()]
+                } else {
+                    store
+                        .journal
+                        .since(view, authority.apex(), current, known)
+                        .unwrap_or_else(|| authority.transfer())
+                }
+            }
+        };
+
+        Self::authoritative(request, Lookup::Answer(records))
+    }
+
+    fn authoritative(request: Message, lookup: Lookup) -> Message {
+        let builder = MessageBuilder::from_request(request).set_is_authoritive();
+
+        match lookup {
+            Lookup::Answer(answers) => builder.set_answers(answers),
+            Lookup::NoData(soa) => builder.add_authority(soa),
+            Lookup::NxDomain(soa) => builder
+                .set_status_code(ResultCode::NXDOMAIN)
+                .add_authority(soa),
+        }
+        .build()
+    }
+
+    /// Forwards `request` upstream. With trust anchors the answer is
+    /// validated: bogus ones are answered SERVFAIL unless the client
+    /// disabled checking, and secure ones get the AD bit when the client
+    /// understands it (RFC 6840 5.8).
+    async fn forward(store: &Store, request: Message) -> Message {
+        let response = match store.upstream.query(&request.questions[0]).await {
+            Ok(response) => response,
+            Err(_) => return Self::error(request, ResultCode::SERVFAIL),
+        };
+
+        let security = match &store.validator {
+            Some(validator) => validator.validate(&store.upstream, &response).await,
+            None => Security::Insecure,
+        };
+        let dnssec_ok = matches!(Edns::from_message(&request), Ok(Some(edns)) if edns.dnssec_ok);
+        let authentic = dnssec_ok || request.header.is_authentic_data();
+        let qtype = request.questions[0].r#type.clone();
+
+        let mut builder = match security {
+            Security::Bogus(reason) if !request.header.is_checking_disabled() => {
+                debug!(qname = %request.questions[0].name, "bogus answer: {}", reason);
+                return Self::error(request, ResultCode::SERVFAIL);
+            }
+            Security::Secure if authentic => {
+                MessageBuilder::from_request(request).set_authentic_data()
+            }
+            _ => MessageBuilder::from_request(request),
+        };
+        builder = builder
+            .set_recursive_available()
+            .set_status_code(response.header.result_code());
+
+        // Asked for to validate, only sent to the clients asking for them
+        let keep = |record: &Record| match record.query_type() {
+            QueryType::OPT => false,
+            rtype @ (QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3) => {
+                dnssec_ok || rtype == qtype
+            }
+            _ => true,
+        };
+        builder
+            .set_answers(response.answers.into_iter().filter(keep).collect())
+            .set_authority(response.authority.into_iter().filter(keep).collect())
+            .set_resources(response.resources.into_iter().filter(keep).collect())
+            .build()
+    }
+
+    fn error(request: Message, rcode: ResultCode) -> Message {
+        MessageBuilder::from_request(request)
+            .set_status_code(rcode)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use ring::digest::{digest, SHA256};
+    use ring::rand::SystemRandom;
+    use ring::signature::Ed25519KeyPair;
+    use tokio::net::UdpSocket;
+
+    use super::{Client, Responder, Store, Transport};
+    use crate::core::config::{Config, SoaConfig};
+    use crate::core::dns::dns_reader_writer::DnsReader;
+    use crate::core::dns::dnssec::key_tag;
+    use crate::core::dns::edns::{ClientSubnet, Edns};
+    use crate::core::dns::message::{Class, Message, QueryType, Record, ResultCode};
+    use crate::core::dns::message_builder::MessageBuilder;
+    use crate::core::dns::notify::OPCODE_NOTIFY;
+    use crate::core::dns::secondary::Secondaries;
+    use crate::core::dns::update::{Updates, OPCODE_UPDATE};
+    use crate::core::metrics::Metrics;
+    use crate::core::shutdown::Shutdown;
+
+    fn config(address: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            [[zones]]
+            name = "example.internal"
+            records = [{{ name = "www", type = "A", value = "{}" }}]
+            "#,
+            address
+        ))
+        .unwrap()
+    }
+
+    fn query(name: &str) -> Message {
+        query_type(name, QueryType::A)
+    }
+
+    fn query_type(name: &str, qtype: QueryType) -> Message {
+        MessageBuilder::new_request(1)
+            .add_new_question(name.to_owned(), qtype, Class::IN)
+            .build()
+    }
+
+    fn client() -> Client {
+        Client::new(
+            "127.0.0.1:5300".parse().unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Udp,
+        )
+    }
+
+    #[tokio::test]
+    async fn reload_swaps_zones() {
+        let responder = Responder::from_config(&config("10.0.0.1")).unwrap();
+
+        responder.reload(&config("10.0.0.2")).unwrap();
+        let response = responder
+            .respond(query("www.example.internal"), &client())
+            .await;
+
+        assert_eq!(
+            response.answers,
+            vec![Record::new_type_a(
+                "www.example.internal".to_owned(),
+                Ipv4Addr::new(10, 0, 0, 2),
+                300
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_reload_keeps_previous_zones() {
+        let responder = Responder::from_config(&config("10.0.0.1")).unwrap();
+
+        assert!(responder.reload(&config("10.0.0")).is_err());
+        let response = responder
+            .respond(query("www.example.internal"), &client())
+            .await;
+
+        assert_eq!(response.header.result_code(), ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn client_subnet_locates_the_client() {
+        let config: Config = toml::from_str(
+            r#"
+            [registry]
+            zone = "svc.internal"
+            [[registry.services]]
+            name = "api"
+            instances = [
+                { address = "10.0.0.1", zone = "a" },
+                { address = "10.0.0.2", zone = "b" },
+            ]
+
+            [[localities]]
+            networks = ["192.0.2.0/24"]
+            zone = "b"
+            "#,
+        )
+        .unwrap();
+        let responder = Responder::from_config(&config).unwrap();
+
+        let subnet = ClientSubnet {
+            address: "192.0.2.0".parse().unwrap(),
+            source_prefix: 24,
+            scope_prefix: 0,
+        };
+        let mut edns = Edns::new();
+        edns.options.push(subnet.to_option(0));
+        let request = MessageBuilder::new_request(1)
+            .add_new_question("api.svc.internal".to_owned(), QueryType::A, Class::IN)
+            .add_resources(edns.to_record())
+            .build();
+
+        let response = responder.respond(request, &client()).await;
+
+        assert_eq!(
+            response.answers[0],
+            Record::new_type_a(
+                "api.svc.internal".to_owned(),
+                Ipv4Addr::new(10, 0, 0, 2),
+                300
+            )
+        );
+        let echoed = Edns::from_message(&response).unwrap().unwrap();
+        assert_eq!(
+            echoed.client_subnet().unwrap(),
+            Some(ClientSubnet {
+                scope_prefix: 24,
+                ..subnet
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn signs_registry_answers_for_dnssec_clients() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let config: Config = toml::from_str(&format!(
+            r#"
+            [registry]
+            zone = "svc.internal"
+            dnssec = {{ algorithm = "ed25519", private_key = "{}" }}
+
+            [[registry.services]]
+            name = "api"
+            dns_sd = {{ type = "_http._tcp", port = 8080 }}
+            instances = [{{ address = "10.0.0.1" }}]
+            "#,
+            BASE64_STANDARD.encode(pkcs8.as_ref())
+        ))
+        .unwrap();
+        let responder = Responder::from_config(&config).unwrap();
+        let signed = |name: &str, qtype: QueryType| {
+            let mut edns = Edns::new();
+            edns.dnssec_ok = true;
+            MessageBuilder::new_request(1)
+                .add_new_question(name.to_owned(), qtype, Class::IN)
+                .add_resources(edns.to_record())
+                .build()
+        };
+        let types = |records: &[Record]| records.iter().map(Record::query_type).collect::<Vec<_>>();
+
+        let answer = responder
+            .respond(signed("api.svc.internal", QueryType::A), &client())
+            .await;
+        assert_eq!(types(&answer.answers), [QueryType::A, QueryType::RRSIG]);
+        assert!(Edns::from_message(&answer).unwrap().unwrap().dnssec_ok);
+
+        // Names that do not exist are denied with an NSEC of their own
+        let missing = responder
+            .respond(signed("db.svc.internal", QueryType::A), &client())
+            .await;
+        assert_eq!(missing.header.result_code(), ResultCode::NOERROR);
+        assert_eq!(
+            types(&missing.authority),
+            [
+                QueryType::SOA,
+                QueryType::NSEC,
+                QueryType::RRSIG,
+                QueryType::RRSIG
+            ]
+        );
+
+        // Names between the DNS-SD ones and the zone exist without records
+        let empty = responder
+            .respond(signed("_tcp.svc.internal", QueryType::PTR), &client())
+            .await;
+        assert_eq!(empty.header.result_code(), ResultCode::NOERROR);
+        match &empty.authority[1] {
+            Record::NSEC { types, .. } => assert_eq!(types, &[46, 47]),
+            other => panic!("expected an NSEC, got {:?}", other),
+        }
+
+        let dnskey = responder
+            .respond(query_type("svc.internal", QueryType::DNSKEY), &client())
+            .await;
+        assert_eq!(types(&dnskey.answers), [QueryType::DNSKEY]);
+
+        // Without the DO bit answers stay as they were
+        let unsigned = responder.respond(query("db.svc.internal"), &client()).await;
+        assert_eq!(unsigned.header.result_code(), ResultCode::NXDOMAIN);
+        assert_eq!(types(&unsigned.authority), [QueryType::SOA]);
+    }
+
+    /// Answers over UDP on a local port, as an upstream
+    async fn serve(responder: Responder) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1232];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let request = DnsReader::from(&buf[..len]).read().await.unwrap();
+                let response = responder
+                    .respond(request, &Client::new(from, addr, Transport::Udp))
+                    .await;
+                socket
+                    .send_to(&response.to_bytes().await.unwrap(), from)
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn validates_forwarded_answers() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let registry: Config = toml::from_str(&format!(
+            r#"
+            [registry]
+            zone = "svc.internal"
+            dnssec = {{ algorithm = "ed25519", private_key = "{}" }}
+            services = [{{ name = "api", instances = [{{ address = "10.0.0.1" }}] }}]
+            "#,
+            BASE64_STANDARD.encode(pkcs8.as_ref())
+        ))
+        .unwrap();
+        let upstream = Responder::from_config(&registry).unwrap();
+        let dnskey = upstream
+            .respond(query_type("svc.internal", QueryType::DNSKEY), &client())
+            .await;
+        let rdata = dnskey.answers[0].rdata().await.unwrap();
+        let addr = serve(upstream).await;
+
+        let resolver = |owner: &[u8]| {
+            let digest = digest(&SHA256, &[owner, &rdata].concat());
+            let hex: String = digest
+                .as_ref()
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            let config: Config = toml::from_str(&format!(
+                r#"
+                upstreams = ["{}"]
+                [validation]
+                trust_anchors = ["svc.internal. IN DS {} 15 2 {}"]
+                "#,
+                addr,
+                key_tag(&rdata),
+                hex
+            ))
+            .unwrap();
+            Responder::from_config(&config).unwrap()
+        };
+        let recursive = |name: &str, dnssec_ok: bool, checking_disabled: bool| {
+            let mut builder = MessageBuilder::new_request(1)
+                .set_recursion_desired()
+                .add_new_question(name.to_owned(), QueryType::A, Class::IN);
+            if checking_disabled {
+                builder = builder.set_checking_disabled();
+            }
+            if dnssec_ok {
+                let mut edns = Edns::new();
+                edns.dnssec_ok = true;
+                builder = builder.add_resources(edns.to_record());
+            }
+            builder.build()
+        };
+        let types = |records: &[Record]| records.iter().map(Record::query_type).collect::<Vec<_>>();
+
+        let valid = resolver(b"\x03svc\x08internal\x00");
+        let secure = valid
+            .respond(recursive("api.svc.internal", true, false), &client())
+            .await;
+        assert!(secure.header.is_authentic_data());
+        assert_eq!(types(&secure.answers), [QueryType::A, QueryType::RRSIG]);
+        let denied = valid
+            .respond(recursive("db.svc.internal", true, false), &client())
+            .await;
+        assert_eq!(denied.header.result_code(), ResultCode::NOERROR);
+        assert!(denied.header.is_authentic_data());
+        // Clients not asking for DNSSEC get neither the AD bit nor the records
+        let plain = valid
+            .respond(recursive("api.svc.internal", false, false), &client())
+            .await;
+        assert!(!plain.header.is_authentic_data());
+        assert_eq!(types(&plain.answers), [QueryType::A]);
+
+        // An anchor for another key makes every answer bogus
+        let wrong = resolver(b"\x05other\x08internal\x00");
+        let bogus = wrong
+            .respond(recursive("api.svc.internal", true, false), &client())
+            .await;
+        assert_eq!(bogus.header.result_code(), ResultCode::SERVFAIL);
+        let unchecked = wrong
+            .respond(recursive("api.svc.internal", true, true), &client())
+            .await;
+        assert_eq!(unchecked.header.result_code(), ResultCode::NOERROR);
+        assert!(unchecked.header.is_checking_disabled());
+        assert!(!unchecked.header.is_authentic_data());
+        assert_eq!(types(&unchecked.answers), [QueryType::A, QueryType::RRSIG]);
+    }
+
+    #[tokio::test]
+    async fn views_are_chosen_by_source_address() {
+        let config: Config = toml::from_str(
+            r#"
+            [registry]
+            zone = "svc.internal"
+            services = [{ name = "api", instances = [{ address = "10.0.0.1" }] }]
+
+            [[views]]
+            name = "partners"
+            clients = ["203.0.113.0/24"]
+            [views.registry]
+            zone = "svc.internal"
+            services = [{ name = "api", instances = [{ address = "198.51.100.10" }] }]
+            "#,
+        )
+        .unwrap();
+        let responder = Responder::from_config(&config).unwrap();
+        let partner = Client::new(
+            "203.0.113.7:5300".parse().unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Udp,
+        );
+
+        let internal = responder
+            .respond(query("api.svc.internal"), &client())
+            .await;
+        let external = responder.respond(query("api.svc.internal"), &partner).await;
+
+        assert_eq!(
+            internal.answers,
+            vec![Record::new_type_a(
+                "api.svc.internal".to_owned(),
+                Ipv4Addr::new(10, 0, 0, 1),
+                300
+            )]
+        );
+        assert_eq!(
+            external.answers,
+            vec![Record::new_type_a(
+                "api.svc.internal".to_owned(),
+                Ipv4Addr::new(198, 51, 100, 10),
+                300
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn acls_refuse_denied_clients() {
+        let config: Config = toml::from_str(
+            r#"
+            upstreams = ["192.0.2.53"]
+
+            [[zones]]
+            name = "example.internal"
+            acl = { query = { deny = ["203.0.113.0/24"] } }
+            records = [{ name = "www", type = "A", value = "10.0.0.1" }]
+            "#,
+        )
+        .unwrap();
+        let responder = Responder::from_config(&config).unwrap();
+        let outsider = Client::new(
+            "203.0.113.7:5300".parse().unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Udp,
+        );
+        let recursive = MessageBuilder::new_request(1)
+            .set_recursion_desired()
+            .add_new_question("example.com".to_owned(), QueryType::A, Class::IN)
+            .build();
+
+        let denied = responder
+            .respond(query("www.example.internal"), &outsider)
+            .await;
+        let open_resolver = responder.respond(recursive, &outsider).await;
+        let allowed = responder
+            .respond(query("www.example.internal"), &client())
+            .await;
+
+        assert_eq!(denied.header.result_code(), ResultCode::REFUSED);
+        assert_eq!(open_resolver.header.result_code(), ResultCode::REFUSED);
+        assert_eq!(allowed.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn transfers_the_registry_over_tcp() {
+        let config: Config = toml::from_str(
+            r#"
+            [acl]
+            transfer = { allow = ["127.0.0.0/8"] }
+
+            [registry]
+            zone = "svc.internal"
+            services = [{ name = "api", instances = [{ address = "10.0.0.1" }, { address = "fd00::1" }] }]
+            "#,
+        )
+        .unwrap();
+        let responder = Responder::from_config(&config).unwrap();
+        let axfr = || {
+            MessageBuilder::new_request(1)
+                .add_new_question("svc.internal".to_owned(), QueryType::AXFR, Class::IN)
+                .build()
+        };
+        let tcp = Client::new(
+            "127.0.0.1:5300".parse().unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Tcp,
+        );
+
+        let transfer = responder.respond(axfr(), &tcp).await;
+        let types: Vec<QueryType> = transfer.answers.iter().map(Record::query_type).collect();
+        assert_eq!(
+            types,
+            [
+                QueryType::SOA,
+                QueryType::NS,
+                QueryType::A,
+                QueryType::AAAA,
+                QueryType::SOA
+            ]
+        );
+
+        let over_udp = responder.respond(axfr(), &client()).await;
+        assert_eq!(over_udp.header.result_code(), ResultCode::NOTIMP);
+
+        let outsider = Client::new(
+            "203.0.113.7:5300".parse().unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Tcp,
+        );
+        let denied = responder.respond(axfr(), &outsider).await;
+        assert_eq!(denied.header.result_code(), ResultCode::REFUSED);
+    }
+
+    #[tokio::test]
+    async fn answers_ixfr_from_the_journal() {
+        let registry = |address: &str| -> Config {
+            toml::from_str(&format!(
+                r#"
+                [acl]
+                transfer = {{ allow = ["127.0.0.0/8"] }}
+
+                [registry]
+                zone = "svc.internal"
+                services = [{{ name = "api", instances = [{{ address = "{}" }}] }}]
+                "#,
+                address
+            ))
+            .unwrap()
+        };
+        let responder = Responder::from_config(&registry("10.0.0.1")).unwrap();
+        responder.reload(&registry("10.0.0.2")).unwrap();
+
+        let ixfr = |serial| {
+            let soa = Record::SOA {
+                name: "svc.internal".to_owned(),
+                class: Class::IN,
+                mname: String::new(),
+                rname: String::new(),
+                serial,
+                refresh: 0,
+                retry: 0,
+                expire: 0,
+                minimum: 0,
+                ttl: 0,
+            };
+            let mut request = MessageBuilder::new_request(1)
+                .add_new_question("svc.internal".to_owned(), QueryType::IXFR, Class::IN)
+                .build();
+            request.authority.push(soa);
+            request.header.authority_entries = 1;
+            request
+        };
+        let tcp = Client::new(
+            "127.0.0.1:5300".parse().unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Tcp,
+        );
+
+        let started = SoaConfig::default().serial;
+        let incremental = responder.respond(ixfr(started), &tcp).await;
+        let types: Vec<QueryType> = incremental.answers.iter().map(Record::query_type).collect();
+        assert_eq!(
+            types,
+            [
+                QueryType::SOA,
+                QueryType::SOA,
+                QueryType::A,
+                QueryType::SOA,
+                QueryType::A,
+                QueryType::SOA
+            ]
+        );
+
+        let up_to_date = responder.respond(ixfr(started + 1), &tcp).await;
+        assert_eq!(up_to_date.answers.len(), 1);
+
+        // Not in the journal, the whole zone
+        let full = responder.respond(ixfr(0), &tcp).await;
+        assert_eq!(full.answers.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn notifies_secondaries_of_changed_zones() {
+        let zone = |address: &str| -> Config {
+            toml::from_str(&format!(
+                r#"
+                [[zones]]
+                name = "example.internal"
+                notify = ["192.0.2.54"]
+                records = [{{ name = "www", type = "A", value = "{}" }}]
+                "#,
+                address
+            ))
+            .unwrap()
+        };
+        let metrics = Arc::new(Metrics::new());
+        let secondaries = Arc::new(Secondaries::default());
+        let updates = Updates::default();
+        let (buckets, cuts) = (Arc::default(), Arc::default());
+        let store = |config: &Config, previous: Option<&Store>| {
+            Store::from_config(
+                config,
+                &metrics,
+                &secondaries,
+                &updates,
+                &buckets,
+                &cuts,
+                previous,
+            )
+            .unwrap()
+        };
+        let first = store(&zone("10.0.0.1"), None);
+        let same = store(&zone("10.0.0.1"), Some(&first));
+        let changed = store(&zone("10.0.0.2"), Some(&same));
+
+        assert_eq!(first.notifications(None).len(), 1);
+        assert!(same.notifications(Some(&first)).is_empty());
+        let notifications = changed.notifications(Some(&same));
+        assert_eq!(
+            notifications[0].secondaries,
+            ["192.0.2.54:53".parse().unwrap()]
+        );
+
+        // And acknowledges the ones it receives
+        let responder = Responder::from_config(&config("10.0.0.1")).unwrap();
+        let notify = |zone: &str| {
+            MessageBuilder::new_request(1)
+                .set_op_code(OPCODE_NOTIFY)
+                .add_new_question(zone.to_owned(), QueryType::SOA, Class::IN)
+                .build()
+        };
+        let acknowledged = responder
+            .respond(notify("example.internal"), &client())
+            .await;
+        let unknown = responder.respond(notify("example.com"), &client()).await;
+        assert_eq!(acknowledged.header.result_code(), ResultCode::NOERROR);
+        assert_eq!(acknowledged.header.op_code(), OPCODE_NOTIFY);
+        assert_eq!(unknown.header.result_code(), ResultCode::NOTAUTH);
+    }
+
+    #[tokio::test]
+    async fn updates_the_registry() {
+        let registry = |ttl: u32| -> Config {
+            toml::from_str(&format!(
+                r#"
+                [acl]
+                update = {{ allow = ["127.0.0.0/8"] }}
+
+                [registry]
+                zone = "svc.internal"
+                ttl = {}
+                services = [{{ name = "api", instances = [{{ address = "10.0.0.1" }}] }}]
+                "#,
+                ttl
+            ))
+            .unwrap()
+        };
+        let responder = Responder::from_config(&registry(30)).unwrap();
+        let update = |records: Vec<Record>| {
+            let mut request = MessageBuilder::new_request(1)
+                .set_op_code(OPCODE_UPDATE)
+                .add_new_question("svc.internal".to_owned(), QueryType::SOA, Class::IN)
+                .build();
+            request.header.authority_entries = records.len() as u16;
+            request.authority = records;
+            request
+        };
+        let delete =
+            Record::new_type_a("api.svc.internal".to_owned(), Ipv4Addr::new(10, 0, 0, 1), 0);
+        let delete = match delete {
+            Record::A {
+                name, addr, ttl, ..
+            } => Record::A {
+                name,
+                class: Class::QCLASSNONE,
+                addr,
+                ttl,
+            },
+            _ => unreachable!(),
+        };
+        let register = update(vec![
+            delete,
+            Record::new_type_a(
+                "api.svc.internal".to_owned(),
+                Ipv4Addr::new(10, 0, 0, 2),
+                3600,
+            ),
+            Record::new_type_a(
+                "db.svc.internal".to_owned(),
+                Ipv4Addr::new(10, 0, 0, 3),
+                3600,
+            ),
+        ]);
+
+        let outsider = Client::new(
+            "203.0.113.7:5300".parse().unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Udp,
+        );
+        let denied = responder.respond(register.clone(), &outsider).await;
+        assert_eq!(denied.header.result_code(), ResultCode::REFUSED);
+
+        let updated = responder.respond(register, &client()).await;
+        assert_eq!(updated.header.result_code(), ResultCode::NOERROR);
+        let api = responder
+            .respond(query("api.svc.internal"), &client())
+            .await;
+        assert_eq!(
+            api.answers,
+            vec![Record::new_type_a(
+                "api.svc.internal".to_owned(),
+                Ipv4Addr::new(10, 0, 0, 2),
+                30
+            )]
+        );
+        let soa = MessageBuilder::new_request(1)
+            .add_new_question("svc.internal".to_owned(), QueryType::SOA, Class::IN)
+            .build();
+        let started = SoaConfig::default().serial;
+        let served = responder.respond(soa, &client()).await.answers.remove(0);
+        assert!(matches!(served, Record::SOA { serial, .. } if serial == started + 1));
+
+        // Kept across reloads, only names of services can be added
+        responder.reload(&registry(60)).unwrap();
+        let db = responder.respond(query("db.svc.internal"), &client()).await;
+        assert_eq!(
+            db.answers,
+            vec![Record::new_type_a(
+                "db.svc.internal".to_owned(),
+                Ipv4Addr::new(10, 0, 0, 3),
+                60
+            )]
+        );
+        let nested = update(vec![Record::new_type_a(
+            "a.db.svc.internal".to_owned(),
+            Ipv4Addr::new(10, 0, 0, 4),
+            60,
+        )]);
+        assert_eq!(
+            responder
+                .respond(nested, &client())
+                .await
+                .header
+                .result_code(),
+            ResultCode::REFUSED
+        );
+
+        let deregister = update(vec![Record::UNKNOWN {
+            name: "db.svc.internal".to_owned(),
+            r#type: 255,
+            class: Class::QCLASSANY,
+            ttl: 0,
+            data: Vec::new(),
+        }]);
+        responder.respond(deregister, &client()).await;
+        let gone = responder.respond(query("db.svc.internal"), &client()).await;
+        assert_eq!(gone.header.result_code(), ResultCode::NXDOMAIN);
+    }
+
+    #[tokio::test]
+    async fn updates_rebuild_only_the_updated_zone() {
+        let config: Config = toml::from_str(
+            r#"
+            [acl]
+            transfer = { allow = ["127.0.0.0/8"] }
+            update = { allow = ["127.0.0.0/8"] }
+
+            [[zones]]
+            name = "example.internal"
+            records = [{ name = "www", type = "A", value = "10.0.0.1" }]
+
+            [[zones]]
+            name = "other.internal"
+            records = [{ name = "www", type = "A", value = "10.0.0.2" }]
+            "#,
+        )
+        .unwrap();
+        let responder = Responder::from_config(&config).unwrap();
+        let mut update = MessageBuilder::new_request(1)
+            .set_op_code(OPCODE_UPDATE)
+            .add_new_question("example.internal".to_owned(), QueryType::SOA, Class::IN)
+            .build();
+        update.header.authority_entries = 1;
+        update.authority = vec![Record::new_type_a(
+            "api.example.internal".to_owned(),
+            Ipv4Addr::new(10, 0, 0, 3),
+            60,
+        )];
+
+        let before = responder.store();
+        let updated = responder.respond(update, &client()).await;
+        assert_eq!(updated.header.result_code(), ResultCode::NOERROR);
+        let after = responder.store();
+
+        let zone = |store: &Store, name: &str| store.default.zones.get(name).unwrap() as *const _;
+        assert_eq!(
+            zone(&before, "other.internal"),
+            zone(&after, "other.internal")
+        );
+        assert_ne!(
+            zone(&before, "example.internal"),
+            zone(&after, "example.internal")
+        );
+        let api = responder
+            .respond(query("api.example.internal"), &client())
+            .await;
+        assert_eq!(api.answers.len(), 1);
+
+        // The IXFR from the first serial has the added record
+        let mut ixfr = query_type("example.internal", QueryType::IXFR);
+        ixfr.header.authority_entries = 1;
+        ixfr.authority = vec![before
+            .default
+            .zones
+            .get("example.internal")
+            .unwrap()
+            .soa()
+            .clone()];
+        let tcp = Client::new(
+            "127.0.0.1:5300".parse().unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Tcp,
+        );
+        let changes = responder.respond(ixfr, &tcp).await;
+        assert_eq!(changes.answers.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn saves_the_updates_before_shutdown_completes() {
+        let path = std::env::temp_dir().join(format!("dns-shutdown-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let updates = Updates::load(&path).await.unwrap();
+        let shutdown = Shutdown::new();
+        let responder = Arc::new(
+            Responder::with_updates(&config("10.0.0.1"), updates, shutdown.clone()).unwrap(),
+        );
+
+        shutdown.spawn(responder.save_updates(shutdown.clone()));
+        shutdown.trigger();
+        assert_eq!(shutdown.drain(Duration::from_secs(1)).await, 0);
+
+        let saved = std::fs::remove_file(&path);
+        assert!(saved.is_ok());
+    }
+}
@@ -0,0 +1,614 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Result};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::{timeout, Instant};
+use tracing::{info, warn};
+
+use crate::core::config::{
+    normalize_name, parse_upstream, Config, KeyConfig, ZoneConfig, DEFAULT_VIEW,
+};
+
+use super::dns_reader_writer::DnsReader;
+use super::journal::{is_newer, serial};
+use super::message::{Class, Message, QueryType, Record, ResultCode};
+use super::message_builder::MessageBuilder;
+use super::tcp_listener::frame_len;
+use super::tsig::{self, Session};
+use super::upstream::Upstream;
+
+/// Wait for the SOA of a primary
+const SOA_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait for every message of a transfer
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Retry interval of zones never transferred, which have no SOA timers yet
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+/// View and name of a secondary zone
+type Key = (String, String);
+
+#[derive(Debug)]
+struct Secondary {
+    primaries: Vec<SocketAddr>,
+    /// Key the transfers are signed with
+    key: Option<KeyConfig>,
+    /// The SOA then every record of the zone, None until the first transfer
+    /// and again once the zone expired
+    records: Option<Vec<Record>>,
+    next_check: Instant,
+    expires: Option<Instant>,
+}
+
+impl Secondary {
+    fn new(primaries: Vec<SocketAddr>, key: Option<KeyConfig>) -> Secondary {
+        Secondary {
+            primaries,
+            key,
+            records: None,
+            next_check: Instant::now(),
+            expires: None,
+        }
+    }
+
+    /// The refresh, retry and expire timers of the SOA held
+    fn timers(&self) -> Option<(Duration, Duration, Duration)> {
+        match self.records.as_deref()?.first()? {
+            // At least a second, a zero would have us ask in a loop
+            Record::SOA {
+                refresh,
+                retry,
+                expire,
+                ..
+            } => Some((
+                Duration::from_secs((*refresh).max(1) as u64),
+                Duration::from_secs((*retry).max(1) as u64),
+                Duration::from_secs(*expire as u64),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Schedules the next check in `wait`, or when the zone expires if that
+    /// comes first so it is not served any longer than it may be
+    fn check_in(&mut self, now: Instant, wait: Duration) {
+        self.next_check = match self.expires {
+            Some(expires) => (now + wait).min(expires),
+            None => now + wait,
+        };
+    }
+
+    /// Stops serving the zone once it expired, returns whether it did
+    fn expire(&mut self, zone: &str, now: Instant) -> bool {
+        if self.expires.is_none_or(|expires| expires > now) {
+            return false;
+        }
+        warn!(zone, "zone expired, no longer serving it");
+        self.records = None;
+        self.expires = None;
+        true
+    }
+}
+
+/// The secondary zones a reload stopped and started following, for
+/// `Secondaries::restore` to undo it
+#[derive(Debug, Default)]
+pub(crate) struct Followed {
+    removed: Vec<(Key, Secondary)>,
+    added: Vec<Key>,
+}
+
+/// Zones this server is a secondary for, kept up to date with their
+/// primaries (RFC 1034 4.3.5). Their records outlive reloads.
+#[derive(Debug, Default)]
+pub(crate) struct Secondaries {
+    zones: Mutex<HashMap<Key, Secondary>>,
+    wake: Notify,
+}
+
+impl Secondaries {
+    /// Follows the secondary zones of `config`, keeping the records of the
+    /// ones that did not change and checking new ones right away
+    pub(crate) fn configure(&self, config: &Config) -> Followed {
+        let mut wanted: HashMap<Key, (Vec<SocketAddr>, Option<KeyConfig>)> = HashMap::new();
+        let views = std::iter::once((DEFAULT_VIEW, &config.zones)).chain(
+            config
+                .views
+                .iter()
+                .map(|view| (view.name.as_str(), &view.zones)),
+        );
+        for (view, zones) in views {
+            for zone in zones.iter().filter(|zone| !zone.primaries.is_empty()) {
+                let key = zone.key.as_deref().and_then(|key| config.key(key)).cloned();
+                wanted.insert(
+                    (view.to_owned(), normalize_name(&zone.name)),
+                    (Self::primaries(zone), key),
+                );
+            }
+        }
+
+        let mut zones = self.zones.lock().unwrap();
+        let removed = zones
+            .extract_if(|zone, secondary| {
+                !wanted.get(zone).is_some_and(|(primaries, key)| {
+                    *primaries == secondary.primaries && *key == secondary.key
+                })
+            })
+            .collect();
+        let mut followed = Followed {
+            removed,
+            added: Vec::new(),
+        };
+        for (zone, (primaries, key)) in wanted {
+            if let Entry::Vacant(entry) = zones.entry(zone) {
+                followed.added.push(entry.key().clone());
+                entry.insert(Secondary::new(primaries, key));
+            }
+        }
+        drop(zones);
+
+        self.wake.notify_one();
+        followed
+    }
+
+    /// Follows the zones again as they were before `configure` returned
+    /// `followed`, with the records they had
+    pub(crate) fn restore(&self, followed: Followed) {
+        let mut zones = self.zones.lock().unwrap();
+        for zone in followed.added {
+            zones.remove(&zone);
+        }
+        zones.extend(followed.removed);
+        drop(zones);
+
+        self.wake.notify_one();
+    }
+
+    fn primaries(zone: &ZoneConfig) -> Vec<SocketAddr> {
+        zone.primaries
+            .iter()
+            .filter_map(|primary| parse_upstream(primary).ok())
+            .collect()
+    }
+
+    /// The records of the secondary zones of `view` that are loaded
+    pub(crate) fn transferred(&self, view: &str) -> HashMap<String, Vec<Record>> {
+        self.zones
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((zone_view, _), _)| zone_view == view)
+            .filter_map(|((_, zone), secondary)| Some((zone.clone(), secondary.records.clone()?)))
+            .collect()
+    }
+
+    /// The records of the secondary zone `zone` of `view`, if it is loaded
+    pub(crate) fn records(&self, view: &str, zone: &str) -> Option<Vec<Record>> {
+        let zones = self.zones.lock().unwrap();
+        zones
+            .get(&(view.to_owned(), zone.to_owned()))?
+            .records
+            .clone()
+    }
+
+    /// Checks `zone` right away when `source` is one of its primaries,
+    /// returns whether it is
+    pub(crate) fn notified(&self, view: &str, zone: &str, source: IpAddr) -> bool {
+        let mut zones = self.zones.lock().unwrap();
+        let secondary = match zones.get_mut(&(view.to_owned(), zone.to_owned())) {
+            Some(secondary)
+                if secondary
+                    .primaries
+                    .iter()
+                    .any(|primary| primary.ip() == source) =>
+            {
+                secondary
+            }
+            _ => return false,
+        };
+        secondary.next_check = Instant::now();
+        drop(zones);
+
+        self.wake.notify_one();
+        true
+    }
+
+    /// Waits until a zone is due for a check, or the zones changed
+    pub(crate) async fn due(&self) {
+        let next_check = self
+            .zones
+            .lock()
+            .unwrap()
+            .values()
+            .map(|secondary| secondary.next_check)
+            .min();
+
+        match next_check {
+            Some(next_check) => {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(next_check) => {}
+                    _ = self.wake.notified() => {}
+                }
+            }
+            None => self.wake.notified().await,
+        }
+    }
+
+    /// Stops serving the zones that expired, whether or not they are due
+    /// for a check. Returns the ones that did.
+    fn expire(&self, now: Instant) -> Vec<Key> {
+        let mut zones = self.zones.lock().unwrap();
+        zones
+            .iter_mut()
+            .filter_map(|(key, secondary)| secondary.expire(&key.1, now).then(|| key.clone()))
+            .collect()
+    }
+
+    /// Checks the zones due with their primaries and transfers the ones
+    /// that changed. Returns the zones whose records changed.
+    pub(crate) async fn refresh(&self) -> Vec<Key> {
+        let now = Instant::now();
+        let mut changed = self.expire(now);
+        type Due = (Key, Vec<SocketAddr>, Option<tsig::Key>, Option<Vec<Record>>);
+        let due: Vec<Due> = self
+            .zones
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, secondary)| secondary.next_check <= now)
+            .map(|(key, secondary)| {
+                let tsig = secondary.key.as_ref().map(tsig::Key::from_config);
+                (
+                    key.clone(),
+                    secondary.primaries.clone(),
+                    tsig,
+                    secondary.records.clone(),
+                )
+            })
+            .collect();
+
+        for (key, primaries, tsig, records) in due {
+            let result = Self::check(&key.1, &primaries, tsig.as_ref(), records.as_deref()).await;
+
+            let mut zones = self.zones.lock().unwrap();
+            // Removed by a reload in the meantime
+            let secondary = match zones.get_mut(&key) {
+                Some(secondary) => secondary,
+                None => continue,
+            };
+            let now = Instant::now();
+
+            match result {
+                Ok(records) => {
+                    if let Some(records) = records {
+                        info!(
+                            zone = key.1,
+                            serial = serial(&records[0]),
+                            records = records.len() - 1,
+                            "transferred zone"
+                        );
+                        secondary.records = Some(records);
+                        changed.push(key.clone());
+                    }
+                    if let Some((refresh, _, expire)) = secondary.timers() {
+                        secondary.expires = Some(now + expire);
+                        secondary.check_in(now, refresh);
+                    }
+                }
+                Err(e) => {
+                    warn!(zone = key.1, "could not refresh zone: {}", e);
+                    let retry = secondary
+                        .timers()
+                        .map_or(INITIAL_RETRY, |(_, retry, _)| retry);
+                    secondary.check_in(now, retry);
+                    if secondary.expire(&key.1, now) {
+                        changed.push(key.clone());
+                    }
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Asks the primaries in order for the zone, returning its records when
+    /// they are newer than the `current` ones. Transfers are signed with
+    /// `key` if given, and so must be every answer to them.
+    async fn check(
+        zone: &str,
+        primaries: &[SocketAddr],
+        key: Option<&tsig::Key>,
+        current: Option<&[Record]>,
+    ) -> Result<Option<Vec<Record>>> {
+        let mut last_error = Error::new(ErrorKind::NotFound, "no primary configured");
+
+        for primary in primaries {
+            match Self::pull(zone, *primary, key, current).await {
+                Ok(records) => return Ok(records),
+                Err(e) => last_error = Error::new(e.kind(), format!("{}: {}", primary, e)),
+            }
+        }
+
+        Err(last_error)
+    }
+
+    async fn pull(
+        zone: &str,
+        primary: SocketAddr,
+        key: Option<&tsig::Key>,
+        current: Option<&[Record]>,
+    ) -> Result<Option<Vec<Record>>> {
+        let request = MessageBuilder::new_request(rand::random())
+            .add_new_question(zone.to_owned(), QueryType::SOA, Class::IN)
+            .build();
+        let response = timeout(SOA_TIMEOUT, Upstream::query_udp(primary, &request))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "SOA query timed out"))??;
+        let latest = match response
+            .answers
+            .iter()
+            .find(|record| matches!(record, Record::SOA { .. }))
+        {
+            Some(soa) if response.header.result_code() == ResultCode::NOERROR => serial(soa),
+            _ => return Err(invalid("primary did not answer the SOA of the zone")),
+        };
+
+        // Incremental when there is something to start from (RFC 1995)
+        let request = match current {
+            Some(current) if !is_newer(latest, serial(&current[0])) => return Ok(None),
+            Some(current) => MessageBuilder::new_request(rand::random())
+                .add_new_question(zone.to_owned(), QueryType::IXFR, Class::IN)
+                .add_authority(current[0].clone())
+                .build(),
+            None => MessageBuilder::new_request(rand::random())
+                .add_new_question(zone.to_owned(), QueryType::AXFR, Class::IN)
+                .build(),
+        };
+
+        let answers = timeout(TRANSFER_TIMEOUT, Self::transfer(primary, &request, key))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "transfer timed out"))??;
+        if answers.len() == 1 {
+            // Up to date after all
+            return Ok(None);
+        }
+        Self::apply(current, answers).map(Some)
+    }
+
+    /// The records of every message of the transfer answering `request`
+    async fn transfer(
+        primary: SocketAddr,
+        request: &Message,
+        key: Option<&tsig::Key>,
+    ) -> Result<Vec<Record>> {
+        let mut stream = TcpStream::connect(primary).await?;
+        let mut buf = request.to_bytes().await?;
+        let mut session = None;
+        if let Some(key) = key {
+            let (signed, signing) = Session::request(key, buf);
+            buf = signed;
+            session = Some(signing);
+        }
+        stream.write_u16(frame_len(&buf)?).await?;
+        stream.write_all(&buf).await?;
+
+        let mut records: Vec<Record> = Vec::new();
+        loop {
+            let len = stream.read_u16().await?;
+            let mut frame = vec![0; len as usize];
+            stream.read_exact(&mut frame).await?;
+            if let Some(session) = session.as_mut() {
+                session.verify(&frame)?;
+            }
+
+            let response = DnsReader::from(&*frame).read().await?;
+            if !Upstream::is_response_to(request, &response) {
+                return Err(invalid("unexpected transfer response"));
+            }
+            if response.header.result_code() != ResultCode::NOERROR {
+                return Err(invalid(&format!(
+                    "transfer refused with {:?}",
+                    response.header.result_code()
+                )));
+            }
+
+            let first = records.is_empty();
+            records.extend(response.answers);
+            match records.first() {
+                Some(Record::SOA { .. }) => {}
+                _ => return Err(invalid("transfer does not start with a SOA")),
+            }
+            // A lone SOA answering IXFR means the zone did not change
+            if (first && records.len() == 1 && request.questions[0].r#type == QueryType::IXFR)
+                || Self::is_complete(&records)
+            {
+                // The last messages must be covered by a signature too
+                if session.is_some_and(|session| !session.is_complete()) {
+                    return Err(invalid("transfer does not end with a signed message"));
+                }
+                return Ok(records);
+            }
+        }
+    }
+
+    /// Whether the transfer ended: a full one closes with its first SOA, an
+    /// incremental one also has that SOA closing its last change.
+    fn is_complete(records: &[Record]) -> bool {
+        let latest = serial(&records[0]);
+        let is_latest =
+            |record: &Record| matches!(record, Record::SOA { .. }) && serial(record) == latest;
+
+        if records.len() < 2 || !is_latest(&records[records.len() - 1]) {
+            return false;
+        }
+        let closing = records[1..]
+            .iter()
+            .filter(|record| is_latest(record))
+            .count();
+        if Self::is_incremental(records) {
+            closing >= 2
+        } else {
+            true
+        }
+    }
+
+    /// An incremental transfer follows the latest SOA with an older one
+    fn is_incremental(records: &[Record]) -> bool {
+        matches!(&records[1], Record::SOA { .. }) && serial(&records[1]) != serial(&records[0])
+    }
+
+    /// The records of the zone once `transferred` is applied to `current`,
+    /// SOA first
+    fn apply(current: Option<&[Record]>, transferred: Vec<Record>) -> Result<Vec<Record>> {
+        let last = transferred.len() - 1;
+        if !Self::is_incremental(&transferred) {
+            return Ok(transferred[..last].to_vec());
+        }
+
+        let current = match current {
+            Some(current) if serial(&current[0]) == serial(&transferred[1]) => current,
+            _ => {
+                return Err(invalid(
+                    "incremental transfer does not start from our serial",
+                ))
+            }
+        };
+
+        let is_soa = |record: &Record| matches!(record, Record::SOA { .. });
+        let mut records: Vec<Record> = current[1..].to_vec();
+        let mut i = 1;
+        while i < last {
+            // Older SOA then the records removed, newer SOA then the ones added
+            i += 1;
+            while i < last && !is_soa(&transferred[i]) {
+                let removed = &transferred[i];
+                records.retain(|record| record != removed);
+                i += 1;
+            }
+            i += 1;
+            while i < last && !is_soa(&transferred[i]) {
+                records.push(transferred[i].clone());
+                i += 1;
+            }
+        }
+
+        let mut zone = vec![transferred[0].clone()];
+        zone.extend(records);
+        Ok(zone)
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::Secondaries;
+    use crate::core::config::{Config, DefaultsConfig, SoaConfig, DEFAULT_VIEW};
+    use crate::core::dns::journal::serial;
+    use crate::core::dns::message::Record;
+    use crate::core::dns::zone::new_soa;
+
+    fn soa(serial: u32) -> Record {
+        new_soa(
+            "example.internal",
+            &SoaConfig {
+                serial,
+                ..SoaConfig::default()
+            },
+            &DefaultsConfig::default(),
+            300,
+        )
+    }
+
+    fn a(last: u8) -> Record {
+        Record::new_type_a(
+            "www.example.internal".to_owned(),
+            Ipv4Addr::new(10, 0, 0, last),
+            300,
+        )
+    }
+
+    #[test]
+    fn applies_full_and_incremental_transfers() {
+        let full = vec![soa(1), a(1), a(2), soa(1)];
+        assert!(!Secondaries::is_complete(&full[..3]));
+        assert!(Secondaries::is_complete(&full));
+        let zone = Secondaries::apply(None, full).unwrap();
+        assert_eq!(zone, [soa(1), a(1), a(2)]);
+
+        // 1 to 2 removes 10.0.0.1, 2 to 3 adds 10.0.0.3
+        let incremental = vec![soa(3), soa(1), a(1), soa(2), soa(2), soa(3), a(3), soa(3)];
+        assert!(!Secondaries::is_complete(&incremental[..6]));
+        assert!(Secondaries::is_complete(&incremental));
+        let zone = Secondaries::apply(Some(&zone), incremental.clone()).unwrap();
+        assert_eq!(serial(&zone[0]), 3);
+        assert_eq!(zone[1..], [a(2), a(3)]);
+
+        assert!(Secondaries::apply(Some(&zone), incremental).is_err());
+    }
+
+    #[test]
+    fn restores_the_zones_followed_before() {
+        let config = |primary: &str| -> Config {
+            toml::from_str(&format!(
+                "[[zones]]\nname = \"example.internal\"\nprimaries = [\"{}\"]\n",
+                primary
+            ))
+            .unwrap()
+        };
+        let secondaries = Secondaries::default();
+        secondaries.configure(&config("192.0.2.53"));
+        let key = (DEFAULT_VIEW.to_owned(), "example.internal".to_owned());
+        secondaries
+            .zones
+            .lock()
+            .unwrap()
+            .get_mut(&key)
+            .unwrap()
+            .records = Some(vec![soa(1), a(1)]);
+
+        let followed = secondaries.configure(&config("192.0.2.54"));
+        assert!(secondaries.transferred(DEFAULT_VIEW).is_empty());
+
+        secondaries.restore(followed);
+        let zones = secondaries.zones.lock().unwrap();
+        assert_eq!(zones[&key].primaries, ["192.0.2.53:53".parse().unwrap()]);
+        assert_eq!(zones[&key].records, Some(vec![soa(1), a(1)]));
+    }
+
+    #[tokio::test]
+    async fn stops_serving_expired_zones_before_their_next_check() {
+        let config: Config = toml::from_str(
+            "[[zones]]\nname = \"example.internal\"\nprimaries = [\"192.0.2.53\"]\n",
+        )
+        .unwrap();
+        let secondaries = Secondaries::default();
+        secondaries.configure(&config);
+        let key = (DEFAULT_VIEW.to_owned(), "example.internal".to_owned());
+        {
+            let mut zones = secondaries.zones.lock().unwrap();
+            let secondary = zones.get_mut(&key).unwrap();
+            let now = Instant::now();
+            secondary.records = Some(vec![soa(1), a(1)]);
+            secondary.expires = Some(now);
+            secondary.next_check = now + Duration::from_secs(3600);
+        }
+
+        assert_eq!(secondaries.refresh().await, [key]);
+        assert!(secondaries.transferred(DEFAULT_VIEW).is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+use std::io::{Error, Result};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::core::config::ListenConfig;
+
+const TCP_BACKLOG: i32 = 1024;
+
+/// Every socket the server listens on, bound before any of them is served
+/// so that a bad address is reported at startup.
+#[derive(Debug)]
+pub struct Sockets {
+    pub udp: Vec<UdpSocket>,
+    pub tcp: Vec<TcpListener>,
+    pub tls: Vec<TcpListener>,
+    pub https: Vec<TcpListener>,
+    pub quic: Vec<UdpSocket>,
+}
+
+impl Sockets {
+    /// Binds `reuse_port` sockets per address, sharing the port through
+    /// SO_REUSEPORT when there is more than one so the kernel spreads
+    /// queries across them.
+    pub fn bind(config: &ListenConfig) -> Result<Sockets> {
+        let reuse_port = config.reuse_port > 1;
+        let mut sockets = Sockets {
+            udp: Vec::new(),
+            tcp: Vec::new(),
+            tls: Vec::new(),
+            https: Vec::new(),
+            quic: Vec::new(),
+        };
+
+        for addr in &config.udp {
+            for _ in 0..config.reuse_port {
+                let socket = bind_udp(*addr, reuse_port).map_err(|e| {
+                    Error::new(e.kind(), format!("could not bind udp {}: {}", addr, e))
+                })?;
+                sockets.udp.push(socket);
+            }
+        }
+
+        for addr in &config.tcp {
+            for _ in 0..config.reuse_port {
+                let listener = bind_tcp(*addr, reuse_port).map_err(|e| {
+                    Error::new(e.kind(), format!("could not bind tcp {}: {}", addr, e))
+                })?;
+                sockets.tcp.push(listener);
+            }
+        }
+
+        for addr in &config.tls {
+            for _ in 0..config.reuse_port {
+                let listener = bind_tcp(*addr, reuse_port).map_err(|e| {
+                    Error::new(e.kind(), format!("could not bind tls {}: {}", addr, e))
+                })?;
+                sockets.tls.push(listener);
+            }
+        }
+
+        for addr in &config.https {
+            for _ in 0..config.reuse_port {
+                let listener = bind_tcp(*addr, reuse_port).map_err(|e| {
+                    Error::new(e.kind(), format!("could not bind https {}: {}", addr, e))
+                })?;
+                sockets.https.push(listener);
+            }
+        }
+
+        for addr in &config.quic {
+            for _ in 0..config.reuse_port {
+                let socket = bind_udp(*addr, reuse_port).map_err(|e| {
+                    Error::new(e.kind(), format!("could not bind quic {}: {}", addr, e))
+                })?;
+                sockets.quic.push(socket);
+            }
+        }
+
+        Ok(sockets)
+    }
+}
+
+pub(crate) fn bind_udp(addr: SocketAddr, reuse_port: bool) -> Result<UdpSocket> {
+    let socket = new_socket(addr, Type::DGRAM, Protocol::UDP, reuse_port)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+pub(crate) fn bind_tcp(addr: SocketAddr, reuse_port: bool) -> Result<TcpListener> {
+    let socket = new_socket(addr, Type::STREAM, Protocol::TCP, reuse_port)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(TCP_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Binds `port` and joins `group` on the IPv4 `interface`, sharing the
+/// port with the other multicast DNS responders of the host. Packets are
+/// sent with a TTL of 255 and looped back to them (RFC 6762 11).
+pub(crate) fn bind_multicast_v4(
+    group: Ipv4Addr,
+    port: u16,
+    interface: Ipv4Addr,
+) -> Result<UdpSocket> {
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let socket = new_socket(addr, Type::DGRAM, Protocol::UDP, true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.join_multicast_v4(&group, &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_multicast_loop_v4(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Same as `bind_multicast_v4`, on the IPv6 interface of index `interface`
+pub(crate) fn bind_multicast_v6(group: Ipv6Addr, port: u16, interface: u32) -> Result<UdpSocket> {
+    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+    let socket = new_socket(addr, Type::DGRAM, Protocol::UDP, true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.join_multicast_v6(&group, interface)?;
+    socket.set_multicast_if_v6(interface)?;
+    socket.set_multicast_hops_v6(255)?;
+    socket.set_multicast_loop_v6(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn new_socket(
+    addr: SocketAddr,
+    r#type: Type,
+    protocol: Protocol,
+    reuse_port: bool,
+) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), r#type, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+
+    // IPv6 sockets only take IPv6 traffic, so that `[::]` and `0.0.0.0` can
+    // be bound side by side on the same port for dual-stack.
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    if reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+    }
+
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::{bind_udp, Sockets};
+    use crate::core::config::ListenConfig;
+
+    #[tokio::test]
+    async fn reuse_port_shares_the_address() {
+        let first = bind_udp("127.0.0.1:0".parse().unwrap(), true).unwrap();
+        let addr = first.local_addr().unwrap();
+
+        let config = ListenConfig {
+            udp: vec![addr],
+            tcp: vec![addr],
+            reuse_port: 2,
+            ..ListenConfig::default()
+        };
+        let sockets = Sockets::bind(&config).unwrap();
+
+        assert_eq!(sockets.udp.len(), 2);
+        assert_eq!(sockets.tcp.len(), 2);
+        assert!(sockets.udp.iter().all(|s| s.local_addr().unwrap() == addr));
+    }
+
+    #[tokio::test]
+    async fn reports_the_address_that_failed() {
+        let taken = bind_udp("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr: SocketAddr = taken.local_addr().unwrap();
+
+        let config = ListenConfig {
+            udp: vec![addr],
+            tcp: Vec::new(),
+            reuse_port: 1,
+            ..ListenConfig::default()
+        };
+        let error = Sockets::bind(&config).unwrap_err();
+
+        assert!(error.to_string().contains(&format!("udp {}", addr)));
+    }
+}
@@ -0,0 +1,163 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Result};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, warn};
+
+use crate::core::shutdown::Shutdown;
+
+use super::dns_reader_writer::{DnsReader, DnsWriter};
+use super::responder::{Client, Responder, Transport};
+
+/// Connections without a new query for this long are closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bytes per message of a zone transfer before it is signed, leaving room
+/// for the TSIG record in the 64 KiB a TCP frame can carry
+pub(crate) const TRANSFER_SIZE: usize = 16384;
+
+/// Pause after failing to accept a connection for lack of resources, as
+/// file descriptors, so that open connections get the time to close
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serves queries over TCP, where every message is prefixed by its
+/// two byte length (RFC 1035 4.2.2) and a connection can carry many queries.
+pub struct TcpListener {
+    responder: Arc<Responder>,
+    shutdown: Shutdown,
+}
+
+impl TcpListener {
+    pub fn new(responder: Arc<Responder>, shutdown: Shutdown) -> TcpListener {
+        TcpListener {
+            responder,
+            shutdown,
+        }
+    }
+
+    /// Accepts connections until shutdown is triggered, open connections
+    /// are closed once their current query is answered.
+    pub async fn serve(&self, listener: tokio::net::TcpListener) -> Result<()> {
+        let local = listener.local_addr()?;
+
+        loop {
+            let (stream, addr) = tokio::select! {
+                _ = self.shutdown.triggered() => return Ok(()),
+                accepted = accept(&listener, "tcp") => accepted,
+            };
+            let responder = self.responder.clone();
+            let shutdown = self.shutdown.clone();
+
+            self.shutdown.spawn(async move {
+                if let Err(e) = Self::process(
+                    stream,
+                    Client::new(addr, local, Transport::Tcp),
+                    responder,
+                    shutdown,
+                )
+                .await
+                {
+                    debug!(client = %addr, transport = "tcp", "closed connection: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Answers the queries of a connection until it is closed or idle,
+    /// over TCP or any stream framed the same way
+    pub(crate) async fn process<S>(
+        mut stream: S,
+        client: Client,
+        responder: Arc<Responder>,
+        shutdown: Shutdown,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            let next = tokio::select! {
+                _ = shutdown.triggered() => return Ok(()),
+                next = timeout(IDLE_TIMEOUT, stream.read_u16()) => next,
+            };
+
+            let len = match next {
+                Ok(Ok(len)) => len,
+                Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(()),
+            };
+
+            // A client sending the length but not the message must not hold
+            // the connection open any longer than an idle one
+            let mut frame = vec![0; len as usize];
+            match timeout(IDLE_TIMEOUT, stream.read_exact(&mut frame)).await {
+                Ok(read) => read?,
+                Err(_) => return Ok(()),
+            };
+
+            let mut reader = DnsReader::from(&*frame);
+            let msg = match reader.read().await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    responder.metrics().record_parse_failure(client.transport);
+                    return Err(e);
+                }
+            };
+            debug!(client = %client.addr, ?msg, "received query");
+
+            let (resp, mut session) = responder.respond_signed(&frame, msg, &client).await;
+            debug!(client = %client.addr, ?resp, "sending response");
+
+            // Every message of a transfer is signed, chained to the previous one
+            for resp in resp.split(TRANSFER_SIZE).await? {
+                let mut buf: Vec<u8> = Vec::with_capacity(512);
+                let mut writer = DnsWriter::from(&mut buf);
+                writer.write(resp).await?;
+                if let Some(session) = session.as_mut() {
+                    buf = session.sign(buf);
+                }
+
+                stream.write_u16(frame_len(&buf)?).await?;
+                stream.write_all(&buf).await?;
+            }
+        }
+    }
+}
+
+/// The two byte length prefixing `buf` on a stream (RFC 1035 4.2.2),
+/// an error for a message too long to be described by it
+pub(crate) fn frame_len(buf: &[u8]) -> Result<u16> {
+    u16::try_from(buf.len())
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "message longer than 65535 bytes"))
+}
+
+/// The next connection of `listener`. Failing to accept one, as when
+/// clients holding connections open exhaust the file descriptors, is
+/// logged and does not stop the listener, and with it the server.
+pub(crate) async fn accept(
+    listener: &tokio::net::TcpListener,
+    transport: &str,
+) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            // The client went away before the connection was accepted
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+                ) =>
+            {
+                debug!(transport, "could not accept a connection: {}", e);
+            }
+            Err(e) => {
+                warn!(transport, "could not accept a connection: {}", e);
+                sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
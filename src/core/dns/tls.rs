@@ -0,0 +1,121 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use rustls::crypto::ring::{default_provider, Ticketer};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+
+use crate::core::config::TlsConfig;
+
+/// Clients of the encrypted listeners that have not completed the
+/// handshake by then are dropped
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The certificate of the encrypted listeners. Reloading swaps it for the
+/// handshakes that follow, connections already open keep the one they got.
+#[derive(Debug)]
+pub struct Certificates {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    pub fn from_config(config: &TlsConfig) -> Result<Arc<Certificates>, String> {
+        Ok(Arc::new(Certificates {
+            current: RwLock::new(Arc::new(Self::load(config)?)),
+        }))
+    }
+
+    /// Reads the certificate chain and private key files of `config`,
+    /// checking that they belong together
+    pub fn load(config: &TlsConfig) -> Result<CertifiedKey, String> {
+        let chain = CertificateDer::pem_file_iter(&config.certificate)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("could not read {}: {}", config.certificate.display(), e))?;
+        if chain.is_empty() {
+            return Err(format!(
+                "no certificate in {}",
+                config.certificate.display()
+            ));
+        }
+        let key = PrivateKeyDer::from_pem_file(&config.private_key)
+            .map_err(|e| format!("could not read {}: {}", config.private_key.display(), e))?;
+
+        CertifiedKey::from_der(chain, key, &default_provider())
+            .map_err(|e| format!("invalid certificate or private key: {}", e))
+    }
+
+    /// Serves `certified` from the next handshake on
+    pub fn replace(&self, certified: CertifiedKey) {
+        *self.current.write().unwrap() = Arc::new(certified);
+    }
+
+    /// The TLS configuration of a listener negotiating one of `alpn`.
+    /// Session tickets let clients resume their sessions without a full
+    /// handshake when they reconnect.
+    pub fn server_config(self: &Arc<Self>, alpn: &[&[u8]]) -> Arc<ServerConfig> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("the default protocol versions are supported")
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        if let Ok(ticketer) = Ticketer::new() {
+            config.ticketer = ticketer;
+        }
+        Arc::new(config)
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// A self-signed certificate for `localhost` written to a directory of its
+/// own, removed when dropped, even by a failing assertion
+#[cfg(test)]
+pub(crate) struct SelfSigned {
+    dir: std::path::PathBuf,
+    pub(crate) config: TlsConfig,
+    pub(crate) der: CertificateDer<'static>,
+}
+
+#[cfg(test)]
+impl SelfSigned {
+    pub(crate) fn new() -> SelfSigned {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "dns-tls-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let config = TlsConfig {
+            certificate: dir.join("cert.pem"),
+            private_key: dir.join("key.pem"),
+        };
+        std::fs::write(&config.certificate, generated.cert.pem()).unwrap();
+        std::fs::write(&config.private_key, generated.key_pair.serialize_pem()).unwrap();
+
+        SelfSigned {
+            dir,
+            config,
+            der: generated.cert.der().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for SelfSigned {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
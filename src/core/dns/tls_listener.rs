@@ -0,0 +1,183 @@
+use std::sync::Arc;
+
+use tokio::io::Result;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+
+use crate::core::shutdown::Shutdown;
+
+use super::responder::{Client, Responder, Transport};
+use super::tcp_listener::{accept, TcpListener};
+use super::tls::{Certificates, HANDSHAKE_TIMEOUT};
+
+/// Application protocol of DNS over TLS (RFC 7858 3.2)
+const ALPN_DOT: &[u8] = b"dot";
+
+/// Serves queries over TLS (RFC 7858). Once the handshake is done messages
+/// are framed as over TCP, and a connection can carry many queries.
+pub struct TlsListener {
+    responder: Arc<Responder>,
+    acceptor: TlsAcceptor,
+    shutdown: Shutdown,
+}
+
+impl TlsListener {
+    pub fn new(
+        responder: Arc<Responder>,
+        certificates: &Arc<Certificates>,
+        shutdown: Shutdown,
+    ) -> TlsListener {
+        TlsListener {
+            responder,
+            acceptor: TlsAcceptor::from(certificates.server_config(&[ALPN_DOT])),
+            shutdown,
+        }
+    }
+
+    /// Accepts connections until shutdown is triggered, open connections
+    /// are closed once their current query is answered.
+    pub async fn serve(&self, listener: tokio::net::TcpListener) -> Result<()> {
+        let local = listener.local_addr()?;
+
+        loop {
+            let (stream, addr) = tokio::select! {
+                _ = self.shutdown.triggered() => return Ok(()),
+                accepted = accept(&listener, "tls") => accepted,
+            };
+            let acceptor = self.acceptor.clone();
+            let responder = self.responder.clone();
+            let shutdown = self.shutdown.clone();
+
+            self.shutdown.spawn(async move {
+                let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        return debug!(client = %addr, transport = "tls", "handshake failed: {}", e)
+                    }
+                    Err(_) => {
+                        return debug!(client = %addr, transport = "tls", "handshake timed out")
+                    }
+                };
+
+                let client = Client::new(addr, local, Transport::Tls);
+                if let Err(e) = TcpListener::process(stream, client, responder, shutdown).await {
+                    debug!(client = %addr, transport = "tls", "closed connection: {}", e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use rustls::crypto::ring::default_provider;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use rustls::{ClientConfig, HandshakeKind, RootCertStore};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::TlsConnector;
+
+    use super::{TlsListener, ALPN_DOT};
+    use crate::core::config::Config;
+    use crate::core::dns::dns_reader_writer::DnsReader;
+    use crate::core::dns::message::{Class, Message, QueryType};
+    use crate::core::dns::message_builder::MessageBuilder;
+    use crate::core::dns::responder::Responder;
+    use crate::core::dns::tls::{Certificates, SelfSigned};
+    use crate::core::shutdown::Shutdown;
+
+    async fn connect(connector: &TlsConnector, addr: SocketAddr) -> TlsStream<TcpStream> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        connector
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap()
+    }
+
+    async fn exchange(stream: &mut TlsStream<TcpStream>, id: u16) -> Message {
+        let request = MessageBuilder::new_request(id)
+            .add_new_question("www.example.internal".to_owned(), QueryType::A, Class::IN)
+            .build()
+            .to_bytes()
+            .await
+            .unwrap();
+        stream.write_u16(request.len() as u16).await.unwrap();
+        stream.write_all(&request).await.unwrap();
+
+        let mut frame = vec![0; stream.read_u16().await.unwrap() as usize];
+        stream.read_exact(&mut frame).await.unwrap();
+        DnsReader::from(&*frame).read().await.unwrap()
+    }
+
+    fn peer(stream: &TlsStream<TcpStream>) -> CertificateDer<'static> {
+        stream.get_ref().1.peer_certificates().unwrap()[0]
+            .clone()
+            .into_owned()
+    }
+
+    #[tokio::test]
+    async fn answers_over_tls_and_swaps_certificates() {
+        let first = SelfSigned::new();
+        let second = SelfSigned::new();
+        let (first_der, second_der) = (first.der.clone(), second.der.clone());
+
+        let config: Config = toml::from_str(
+            r#"
+            [[zones]]
+            name = "example.internal"
+            records = [{ name = "www", type = "A", value = "10.0.0.1" }]
+            "#,
+        )
+        .unwrap();
+        let responder = Arc::new(Responder::from_config(&config).unwrap());
+        let certificates = Certificates::from_config(&first.config).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tls = TlsListener::new(responder, &certificates, Shutdown::new());
+        tokio::spawn(async move { tls.serve(listener).await });
+
+        // Each with its own session cache
+        let connector = || {
+            let mut roots = RootCertStore::empty();
+            roots.add(first_der.clone()).unwrap();
+            roots.add(second_der.clone()).unwrap();
+            let mut client = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            client.alpn_protocols = vec![ALPN_DOT.to_vec()];
+            TlsConnector::from(Arc::new(client))
+        };
+
+        // One connection carries several queries
+        let first_client = connector();
+        let mut before = connect(&first_client, addr).await;
+        assert_eq!(before.get_ref().1.alpn_protocol(), Some(ALPN_DOT));
+        assert_eq!(peer(&before), first_der);
+        for id in 1..=2 {
+            let response = exchange(&mut before, id).await;
+            assert_eq!(response.header.id, id);
+            assert_eq!(response.answers.len(), 1);
+        }
+
+        // And reconnecting resumes the session
+        let resumed = connect(&first_client, addr).await;
+        assert_eq!(
+            resumed.get_ref().1.handshake_kind(),
+            Some(HandshakeKind::Resumed)
+        );
+
+        // New handshakes get the new certificate, open connections go on
+        certificates.replace(Certificates::load(&second.config).unwrap());
+        let mut after = connect(&connector(), addr).await;
+        assert_eq!(peer(&after), second_der);
+        assert_eq!(exchange(&mut after, 3).await.answers.len(), 1);
+        assert_eq!(exchange(&mut before, 4).await.answers.len(), 1);
+    }
+}
@@ -0,0 +1,517 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use ring::hmac;
+use tokio::io::Result;
+
+use crate::core::config::{normalize_name, KeyConfig, TsigAlgorithm};
+
+use super::message::decode_name;
+
+/// Type of transaction signatures (RFC 8945)
+pub(crate) const TYPE_TSIG: u16 = 250;
+
+const CLASS_ANY: u16 = 255;
+
+/// TSIG errors, extending the NOTAUTH rcode of the response (RFC 8945 3)
+const BADSIG: u16 = 16;
+const BADKEY: u16 = 17;
+const BADTIME: u16 = 18;
+
+/// Seconds the clocks of the signer and the verifier may differ by
+const FUDGE: u16 = 300;
+
+/// A shared secret, named as both ends know it
+#[derive(Debug, Clone)]
+pub(crate) struct Key {
+    name: String,
+    algorithm: TsigAlgorithm,
+    secret: hmac::Key,
+}
+
+impl Key {
+    /// The key of a validated `config`
+    pub(crate) fn from_config(config: &KeyConfig) -> Key {
+        let secret = BASE64_STANDARD
+            .decode(config.secret.trim())
+            .unwrap_or_default();
+        let algorithm = match config.algorithm {
+            TsigAlgorithm::HmacSha256 => hmac::HMAC_SHA256,
+            TsigAlgorithm::HmacSha512 => hmac::HMAC_SHA512,
+        };
+
+        Key {
+            name: normalize_name(&config.name),
+            algorithm: config.algorithm,
+            secret: hmac::Key::new(algorithm, &secret),
+        }
+    }
+
+    /// Name of the algorithm in TSIG records
+    fn algorithm_name(&self) -> &'static str {
+        match self.algorithm {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        hmac::sign(&self.secret, data).as_ref().to_vec()
+    }
+
+    /// Truncated MACs (RFC 8945 5.2.2.1) are not accepted
+    fn verify(&self, data: &[u8], mac: &[u8]) -> bool {
+        hmac::verify(&self.secret, data, mac).is_ok()
+    }
+}
+
+/// The keys of the configuration, by name
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Keys {
+    keys: HashMap<String, Key>,
+}
+
+impl Keys {
+    pub(crate) fn from_config(configs: &[KeyConfig]) -> Keys {
+        let keys = configs
+            .iter()
+            .map(Key::from_config)
+            .map(|key| (key.name.clone(), key))
+            .collect();
+        Keys { keys }
+    }
+
+    /// Checks the TSIG record ending the request `wire`, if any (RFC 8945 5.2)
+    pub(crate) fn verify(&self, wire: &[u8]) -> Verification {
+        self.verify_at(wire, now())
+    }
+
+    fn verify_at(&self, wire: &[u8], now: u64) -> Verification {
+        let (message, tsig) = match split(wire) {
+            Ok(Some(signed)) => signed,
+            Ok(None) => return Verification::Unsigned,
+            Err(_) => return Verification::Malformed,
+        };
+
+        let mut session = Session {
+            key_name: tsig.name.clone(),
+            algorithm: tsig.algorithm.clone(),
+            key: None,
+            mac: Vec::new(),
+            pending: Vec::new(),
+            first: true,
+            time: None,
+            error: 0,
+        };
+
+        let key = match self.keys.get(&tsig.name) {
+            Some(key) if key.algorithm_name() == tsig.algorithm => key,
+            _ => {
+                session.error = BADKEY;
+                return Verification::Failed(session);
+            }
+        };
+        if !key.verify(&session.digest(&message, &tsig), &tsig.mac) {
+            session.error = BADSIG;
+            return Verification::Failed(session);
+        }
+
+        session.key = Some(key.clone());
+        session.mac = tsig.mac;
+        if now.abs_diff(tsig.time) > tsig.fudge as u64 {
+            // Signed with the time of the request, so the client can check it
+            session.error = BADTIME;
+            session.time = Some(tsig.time);
+            return Verification::Failed(session);
+        }
+        Verification::Signed(session)
+    }
+}
+
+/// What the TSIG record of a request says about it
+#[derive(Debug)]
+pub(crate) enum Verification {
+    Unsigned,
+    /// Signed with one of our keys, the responses are signed with it too
+    Signed(Session),
+    /// To answer NOTAUTH, with a TSIG record telling the client why
+    Failed(Session),
+    /// The TSIG record is not the last record or can not be parsed
+    Malformed,
+}
+
+/// The signatures of an exchange: a request then every message of its
+/// response, each one covering the MAC of the previous signed one
+/// (RFC 8945 5.3.1). Servers sign their responses with it, clients sign
+/// their request and verify the responses.
+#[derive(Debug, Clone)]
+pub(crate) struct Session {
+    key_name: String,
+    algorithm: String,
+    /// None when the key is unknown or the MAC wrong, the responses then
+    /// carry an empty MAC
+    key: Option<Key>,
+    /// MAC of the previous signed message
+    mac: Vec<u8>,
+    /// Unsigned messages received since then
+    pending: Vec<u8>,
+    /// Whether the next message is the first one in its direction
+    first: bool,
+    /// Time to sign with instead of the current one
+    time: Option<u64>,
+    error: u16,
+}
+
+impl Session {
+    /// Signs a request with `key`, the session then verifies its responses
+    pub(crate) fn request(key: &Key, message: Vec<u8>) -> (Vec<u8>, Session) {
+        let mut session = Session {
+            key_name: key.name.clone(),
+            algorithm: key.algorithm_name().to_owned(),
+            key: Some(key.clone()),
+            mac: Vec::new(),
+            pending: Vec::new(),
+            first: true,
+            time: None,
+            error: 0,
+        };
+        let signed = session.sign(message);
+        session.first = true;
+        (signed, session)
+    }
+
+    /// Name of the key the request was signed with, when it verified
+    pub(crate) fn key(&self) -> Option<&str> {
+        match (&self.key, self.error) {
+            (Some(key), 0) => Some(&key.name),
+            _ => None,
+        }
+    }
+
+    /// Appends a TSIG record to `message`, signed unless the key could not
+    /// be used
+    pub(crate) fn sign(&mut self, mut message: Vec<u8>) -> Vec<u8> {
+        let now = now();
+        let mut tsig = Tsig {
+            name: self.key_name.clone(),
+            algorithm: self.algorithm.clone(),
+            time: self.time.unwrap_or(now),
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: u16::from_be_bytes([message[0], message[1]]),
+            error: self.error,
+            // The server time, for the client to see how far off it is
+            other: match self.error {
+                BADTIME => now.to_be_bytes()[2..].to_vec(),
+                _ => Vec::new(),
+            },
+        };
+
+        if let Some(key) = &self.key {
+            tsig.mac = key.sign(&self.digest(&message, &tsig));
+            self.mac = tsig.mac.clone();
+            self.first = false;
+        }
+
+        message.extend(tsig.to_wire());
+        let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+        message[10..12].copy_from_slice(&additional.to_be_bytes());
+        message
+    }
+
+    /// Checks a message of the response to a request signed with
+    /// `Session::request`. Messages after the first one may be unsigned, as
+    /// long as a later one is.
+    pub(crate) fn verify(&mut self, wire: &[u8]) -> Result<()> {
+        let key = self
+            .key
+            .clone()
+            .ok_or_else(|| invalid("no key to verify with"))?;
+        let (message, tsig) = match split(wire)? {
+            Some(signed) => signed,
+            None if self.first => return Err(invalid("response is not signed")),
+            None => {
+                self.pending.extend_from_slice(wire);
+                return Ok(());
+            }
+        };
+
+        if tsig.error != 0 {
+            return Err(invalid(&format!("response has TSIG error {}", tsig.error)));
+        }
+        if tsig.name != key.name || tsig.algorithm != key.algorithm_name() {
+            return Err(invalid("response is signed with another key"));
+        }
+        if !key.verify(&self.digest(&message, &tsig), &tsig.mac) {
+            return Err(invalid("response has a wrong signature"));
+        }
+        if now().abs_diff(tsig.time) > tsig.fudge as u64 {
+            return Err(invalid("response was signed too long ago"));
+        }
+
+        self.mac = tsig.mac;
+        self.pending.clear();
+        self.first = false;
+        Ok(())
+    }
+
+    /// Whether every message received so far was covered by a signature
+    pub(crate) fn is_complete(&self) -> bool {
+        !self.first && self.pending.is_empty()
+    }
+
+    /// What the MAC of `message` is computed over: the previous MAC, the
+    /// unsigned messages since, the message and the TSIG variables, only
+    /// the timers after the first message (RFC 8945 4.3)
+    fn digest(&self, message: &[u8], tsig: &Tsig) -> Vec<u8> {
+        let mut data = Vec::with_capacity(message.len() + self.pending.len() + 128);
+        if !self.mac.is_empty() {
+            data.extend((self.mac.len() as u16).to_be_bytes());
+            data.extend(&self.mac);
+        }
+        data.extend(&self.pending);
+        data.extend(message);
+
+        if self.first {
+            data.extend(wire_name(&tsig.name));
+            data.extend(CLASS_ANY.to_be_bytes());
+            data.extend(0u32.to_be_bytes());
+            data.extend(wire_name(&tsig.algorithm));
+        }
+        data.extend(&tsig.time.to_be_bytes()[2..]);
+        data.extend(tsig.fudge.to_be_bytes());
+        if self.first {
+            data.extend(tsig.error.to_be_bytes());
+            data.extend((tsig.other.len() as u16).to_be_bytes());
+            data.extend(&tsig.other);
+        }
+        data
+    }
+}
+
+/// The fields of a TSIG record
+#[derive(Debug)]
+struct Tsig {
+    /// Name of the key, the owner of the record
+    name: String,
+    algorithm: String,
+    /// Seconds since the epoch, on 48 bits
+    time: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl Tsig {
+    fn from_rdata(name: String, rdata: &[u8]) -> Result<Tsig> {
+        let mut algorithm = String::new();
+        decode_name(rdata, 0, &mut algorithm)?;
+        let mut fields = rdata
+            .get(skip_name(rdata, 0)?..)
+            .ok_or_else(|| invalid("TSIG is too short"))?;
+
+        let mut take = |len: usize| -> Result<&[u8]> {
+            let (taken, rest) = fields
+                .split_at_checked(len)
+                .ok_or_else(|| invalid("TSIG is too short"))?;
+            fields = rest;
+            Ok(taken)
+        };
+        let u16_at = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+
+        let time = take(6)?
+            .iter()
+            .fold(0u64, |time, byte| time << 8 | *byte as u64);
+        let fudge = u16_at(take(2)?);
+        let mac_size = u16_at(take(2)?) as usize;
+        let mac = take(mac_size)?.to_vec();
+        let original_id = u16_at(take(2)?);
+        let error = u16_at(take(2)?);
+        let other_size = u16_at(take(2)?) as usize;
+        let other = take(other_size)?.to_vec();
+
+        Ok(Tsig {
+            name: normalize_name(&name),
+            algorithm: normalize_name(&algorithm),
+            time,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+
+    fn to_wire(&self) -> Vec<u8> {
+        let mut rdata = wire_name(&self.algorithm);
+        rdata.extend(&self.time.to_be_bytes()[2..]);
+        rdata.extend(self.fudge.to_be_bytes());
+        rdata.extend((self.mac.len() as u16).to_be_bytes());
+        rdata.extend(&self.mac);
+        rdata.extend(self.original_id.to_be_bytes());
+        rdata.extend(self.error.to_be_bytes());
+        rdata.extend((self.other.len() as u16).to_be_bytes());
+        rdata.extend(&self.other);
+
+        let mut record = wire_name(&self.name);
+        record.extend(TYPE_TSIG.to_be_bytes());
+        record.extend(CLASS_ANY.to_be_bytes());
+        record.extend(0u32.to_be_bytes());
+        record.extend((rdata.len() as u16).to_be_bytes());
+        record.extend(rdata);
+        record
+    }
+}
+
+/// Splits a message at its TSIG record, which must be the last one: the
+/// message as it was before being signed, with its original ID and one
+/// additional record less, and the record. None when it is not signed.
+fn split(wire: &[u8]) -> Result<Option<(Vec<u8>, Tsig)>> {
+    let header = wire
+        .get(..12)
+        .ok_or_else(|| invalid("message is too short"))?;
+    let count = |at: usize| u16::from_be_bytes([header[at], header[at + 1]]) as usize;
+    let (questions, records, additional) = (count(4), count(6) + count(8) + count(10), count(10));
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(wire, offset)? + 4;
+    }
+
+    let mut tsig = None;
+    for i in 0..records {
+        let start = offset;
+        offset = skip_name(wire, offset)?;
+        let fixed = wire
+            .get(offset..offset + 10)
+            .ok_or_else(|| invalid("record is too short"))?;
+        let r#type = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let rdata = wire
+            .get(offset + 10..offset + 10 + len)
+            .ok_or_else(|| invalid("record data is too short"))?;
+        offset += 10 + len;
+
+        if r#type == TYPE_TSIG {
+            if i + 1 != records || additional == 0 {
+                return Err(invalid("TSIG is not the last record"));
+            }
+            let mut name = String::new();
+            decode_name(wire, start, &mut name)?;
+            tsig = Some((start, Tsig::from_rdata(name, rdata)?));
+        }
+    }
+
+    Ok(tsig.map(|(start, tsig)| {
+        let mut message = wire[..start].to_vec();
+        message[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+        message[10..12].copy_from_slice(&((additional - 1) as u16).to_be_bytes());
+        (message, tsig)
+    }))
+}
+
+/// Offset right after the name at `offset`
+fn skip_name(wire: &[u8], mut offset: usize) -> Result<usize> {
+    loop {
+        let len = *wire
+            .get(offset)
+            .ok_or_else(|| invalid("name is too short"))?;
+        match len {
+            0 => return Ok(offset + 1),
+            len if len & 0b1100_0000 == 0b1100_0000 => return Ok(offset + 2),
+            len => offset += 1 + len as usize,
+        }
+    }
+}
+
+/// A name in canonical wire format, lowercase and uncompressed
+fn wire_name(name: &str) -> Vec<u8> {
+    let mut wire = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        wire.push(label.len() as u8);
+        wire.extend(label.to_ascii_lowercase().bytes());
+    }
+    wire.push(0);
+    wire
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{now, Key, Keys, Session, Verification, BADSIG, BADTIME};
+    use crate::core::config::KeyConfig;
+    use crate::core::dns::message::{Class, QueryType};
+    use crate::core::dns::message_builder::MessageBuilder;
+
+    fn key() -> KeyConfig {
+        toml::from_str(
+            r#"
+            name = "transfer."
+            algorithm = "hmac-sha256"
+            secret = "c2VjcmV0IG9mIHRoZSB0cmFuc2Zlcg=="
+            "#,
+        )
+        .unwrap()
+    }
+
+    async fn query() -> Vec<u8> {
+        MessageBuilder::new_request(7)
+            .add_new_question("svc.internal".to_owned(), QueryType::AXFR, Class::IN)
+            .build()
+            .to_bytes()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn signs_and_verifies_multi_message_exchanges() {
+        let keys = Keys::from_config(&[key()]);
+        let key = &Key::from_config(&key());
+        let (request, mut client) = Session::request(key, query().await);
+
+        let mut server = match keys.verify(&request) {
+            Verification::Signed(session) => session,
+            other => panic!("expected a signed request, got {:?}", other),
+        };
+        assert_eq!(server.key(), Some("transfer"));
+
+        // Messages of a stream may go unsigned, as long as a later one is
+        let messages = [query().await, query().await, query().await];
+        client.verify(&server.sign(messages[0].clone())).unwrap();
+        client.verify(&messages[1]).unwrap();
+        assert!(!client.is_complete());
+        server.pending = messages[1].clone();
+        client.verify(&server.sign(messages[2].clone())).unwrap();
+        assert!(client.is_complete());
+
+        // Signed with another MAC chain
+        let (_, mut other) = Session::request(key, query().await);
+        other.verify(&server.sign(messages[0].clone())).unwrap_err();
+
+        let mut tampered = request.clone();
+        tampered[2] ^= 1;
+        assert!(
+            matches!(keys.verify(&tampered), Verification::Failed(session) if session.error == BADSIG)
+        );
+        assert!(
+            matches!(keys.verify_at(&request, now() + 301), Verification::Failed(session) if session.error == BADTIME)
+        );
+        assert!(matches!(
+            keys.verify(&query().await),
+            Verification::Unsigned
+        ));
+    }
+}
@@ -1,70 +1,172 @@
-use std::net::{SocketAddr, Ipv4Addr};
+use std::net::SocketAddr;
 use std::sync::Arc;
 
-use tokio::net::{UdpSocket, ToSocketAddrs};
 use tokio::io::Result;
+use tokio::net::UdpSocket;
+use tracing::debug;
 
-use crate::core::dns::dns_reader_writer::DnsWriter;
+use crate::core::shutdown::Shutdown;
 
 use super::dns_reader_writer::DnsReader;
-use super::message::Message;
-use super::message_builder::MessageBuilder;
+use super::edns::{Edns, MAX_PAYLOAD_SIZE};
+use super::rate_limit::Verdict;
+use super::responder::{Client, Responder, Transport};
+use super::tsig::Session;
 
+/// Largest response sent over UDP without EDNS (RFC 1035 4.2.1)
+const MAX_UDP_SIZE: usize = 512;
+
+/// Largest datagram received, requests as signed queries and updates can
+/// be larger than any response we send
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
 
 pub struct UdpListener {
+    responder: Arc<Responder>,
+    shutdown: Shutdown,
 }
 
 impl UdpListener {
-
-    pub fn new() -> UdpListener {
-        UdpListener {}
+    pub fn new(responder: Arc<Responder>, shutdown: Shutdown) -> UdpListener {
+        UdpListener {
+            responder,
+            shutdown,
+        }
     }
 
-
-    //
-    pub async fn start<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
-        let socket = UdpSocket::bind(addr).await?;
+    /// Answers queries until shutdown is triggered. The queries being
+    /// answered at that point are left to `Shutdown::drain`.
+    pub async fn serve(&self, socket: UdpSocket) -> Result<()> {
         let socket = Arc::new(socket);
-        
+        let local = socket.local_addr()?;
+
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
         loop {
-            let mut buf = [0; 512];
-            let info = socket.recv_from(&mut buf).await?;
+            let (len, from) = tokio::select! {
+                _ = self.shutdown.triggered() => return Ok(()),
+                info = socket.recv_from(&mut buf) => info?,
+            };
+            let datagram = buf[..len].to_vec();
 
             let socket = socket.clone();
+            let responder = self.responder.clone();
 
-            tokio::spawn(async move {
-                let _ = Self::process(buf, info, socket).await;
+            self.shutdown.spawn(async move {
+                if let Err(e) = Self::process(datagram, from, local, socket, responder).await {
+                    debug!(client = %from, transport = "udp", "dropped query: {}", e);
+                }
             });
         }
-
-        Ok(())
     }
 
-    async fn process(buf: [u8; 512], info: (usize,SocketAddr), socket: Arc<UdpSocket>) -> Result<()> {
-        let useful_bytes = &buf[..info.0];
-        let mut reader = DnsReader::from(useful_bytes);
-
-        let mut buf: Vec<u8> = Vec::with_capacity(512);
-        let mut writer = DnsWriter::from(&mut buf);
-
-        let msg = reader.read().await?;
-        println!("{:?}", msg);
-
-        let resp = Self::build_message(msg);
-        println!("{:?}", resp);
-        writer.write(resp).await?;
-        socket.send_to(&buf, info.1).await?;
+    async fn process(
+        datagram: Vec<u8>,
+        from: SocketAddr,
+        local: SocketAddr,
+        socket: Arc<UdpSocket>,
+        responder: Arc<Responder>,
+    ) -> Result<()> {
+        let mut reader = DnsReader::from(&*datagram);
+
+        let msg = match reader.read().await {
+            Ok(msg) => msg,
+            Err(e) => {
+                responder.metrics().record_parse_failure(Transport::Udp);
+                return Err(e);
+            }
+        };
+        debug!(client = %from, ?msg, "received query");
+
+        // Clients using EDNS tell how large a response they can take
+        let max_size = match Edns::from_message(&msg) {
+            Ok(Some(edns)) => {
+                (edns.payload_size as usize).clamp(MAX_UDP_SIZE, MAX_PAYLOAD_SIZE as usize)
+            }
+            _ => MAX_UDP_SIZE,
+        };
+
+        let client = Client::new(from, local, Transport::Udp);
+        let (resp, session) = responder.respond_signed(&datagram, msg, &client).await;
+
+        let resp = match responder.limit(&client, &resp) {
+            Verdict::Send => resp,
+            Verdict::Slip => resp.truncated(),
+            Verdict::Drop => {
+                debug!(client = %from, "response dropped by rate limiting");
+                return Ok(());
+            }
+        };
+        debug!(client = %from, ?resp, "sending response");
+
+        // Signed once its final size is known, truncating drops the signature
+        let sign = |buf: Vec<u8>, mut session: Option<Session>| match session.as_mut() {
+            Some(session) => session.sign(buf),
+            None => buf,
+        };
+        let mut buf = sign(resp.clone().to_bytes().await?, session.clone());
+        if buf.len() > max_size {
+            buf = sign(resp.truncated().to_bytes().await?, session);
+        }
+        socket.send_to(&buf, from).await?;
 
         Ok(())
     }
+}
 
-    fn build_message(request: Message) -> Message {
-        let builder = MessageBuilder::from_request(request);
-
-        let addr = Ipv4Addr::from([192,168,1,254]);
-        let builder = builder.
-                            add_typeA_answer("google.pt".to_owned(), addr, 10000);
-        builder.build()
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use tokio::net::UdpSocket;
+
+    use super::UdpListener;
+    use crate::core::config::Config;
+    use crate::core::dns::dns_reader_writer::DnsReader;
+    use crate::core::dns::message::{Class, EdnsOption, QueryType, Record};
+    use crate::core::dns::message_builder::MessageBuilder;
+    use crate::core::dns::responder::Responder;
+    use crate::core::shutdown::Shutdown;
+
+    #[tokio::test]
+    async fn answers_queries_larger_than_512_bytes() {
+        let config: Config = toml::from_str(
+            r#"
+            [[zones]]
+            name = "example.internal"
+            records = [{ name = "www", type = "A", value = "10.0.0.1" }]
+            "#,
+        )
+        .unwrap();
+        let responder = Arc::new(Responder::from_config(&config).unwrap());
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let udp = UdpListener::new(responder, Shutdown::new());
+        tokio::spawn(async move { udp.serve(socket).await });
+
+        // Padded (RFC 7830) past the size of a datagram without EDNS
+        let padding = EdnsOption {
+            code: 12,
+            data: vec![0; 600],
+        };
+        let query = MessageBuilder::new_request(7)
+            .add_new_question("www.example.internal".to_owned(), QueryType::A, Class::IN)
+            .add_resources(Record::OPT {
+                name: String::new(),
+                class: Class::UNKNOWN(1232),
+                ttl: 0,
+                options: vec![padding],
+            })
+            .build()
+            .to_bytes()
+            .await
+            .unwrap();
+        assert!(query.len() > 512);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&query, addr).await.unwrap();
+        let mut buf = vec![0; 1232];
+        let len = client.recv(&mut buf).await.unwrap();
+        let response = DnsReader::from(&buf[..len]).read().await.unwrap();
+        assert_eq!(response.header.id, 7);
+        assert_eq!(response.answers.len(), 1);
     }
-
-}
\ No newline at end of file
+}
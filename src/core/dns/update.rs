@@ -0,0 +1,553 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::core::config::normalize_name;
+
+use super::dns_reader_writer::DnsReader;
+use super::message::{Class, Message, QueryType, Record, ResultCode};
+use super::message_builder::MessageBuilder;
+use super::zone::is_subdomain;
+
+/// Opcode of dynamic updates (RFC 2136)
+pub(crate) const OPCODE_UPDATE: u8 = 5;
+
+/// Type of the prerequisites and deletions about every RRset of a name
+const TYPE_ANY: QueryType = QueryType::UNKNOWN(255);
+
+/// Records an update removed from and added to a zone
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Changes {
+    pub(crate) removed: Vec<Record>,
+    pub(crate) added: Vec<Record>,
+}
+
+impl Changes {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
+}
+
+/// The changes of a zone as saved to the updates file, a DNS message whose
+/// answers are the records added and authority records the ones removed
+#[derive(Debug, Serialize, Deserialize)]
+struct Saved {
+    view: String,
+    zone: String,
+    message: String,
+}
+
+/// What dynamic updates changed in the configured zones and registries,
+/// keyed by view and zone name. Every store built on reload applies them
+/// again on top of the configuration. With a file they are saved to, they
+/// are read back on startup and survive restarts.
+#[derive(Debug, Default)]
+pub(crate) struct Updates {
+    zones: Mutex<HashMap<(String, String), Changes>>,
+    path: Option<PathBuf>,
+    /// Wakes the task saving the changes
+    changed: Notify,
+}
+
+impl Updates {
+    /// The changes saved to `path`, none if it does not exist yet. Changes
+    /// made from now on are saved to it too.
+    pub(crate) async fn load(path: &Path) -> io::Result<Updates> {
+        let mut zones = HashMap::new();
+        let saved: Vec<Saved> = match tokio::fs::read(path).await {
+            Ok(json) => serde_json::from_slice(&json).map_err(|e| invalid(path, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        for saved in saved {
+            let wire = BASE64_STANDARD
+                .decode(&saved.message)
+                .map_err(|e| invalid(path, e))?;
+            let message = DnsReader::from(&*wire).read().await?;
+            let changes = Changes {
+                removed: message.authority,
+                added: message.answers,
+            };
+            zones.insert((saved.view, saved.zone), changes);
+        }
+
+        Ok(Updates {
+            zones: Mutex::new(zones),
+            path: Some(path.to_owned()),
+            changed: Notify::new(),
+        })
+    }
+
+    /// Saves the changes to the file they were loaded from, replacing it
+    /// as a whole so that a crash leaves the previous version
+    pub(crate) async fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let zones: Vec<((String, String), Changes)> = self
+            .zones
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, changes)| (key.clone(), changes.clone()))
+            .collect();
+
+        let mut saved = Vec::with_capacity(zones.len());
+        for ((view, zone), changes) in zones {
+            let builder = MessageBuilder::new_request(0).add_new_question(
+                zone.clone(),
+                QueryType::SOA,
+                Class::IN,
+            );
+            let builder = changes
+                .added
+                .into_iter()
+                .fold(builder, MessageBuilder::add_answers);
+            let message = changes
+                .removed
+                .into_iter()
+                .fold(builder, MessageBuilder::add_authority)
+                .build();
+            saved.push(Saved {
+                view,
+                zone,
+                message: BASE64_STANDARD.encode(message.to_bytes().await?),
+            });
+        }
+
+        let json = serde_json::to_vec_pretty(&saved).map_err(|e| invalid(path, e))?;
+        let mut partial = path.clone().into_os_string();
+        partial.push(".tmp");
+        tokio::fs::write(&partial, json).await?;
+        tokio::fs::rename(&partial, path).await
+    }
+
+    /// Completes once the changes changed since the last call
+    pub(crate) async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    pub(crate) fn changes(&self, view: &str, zone: &str) -> Option<Changes> {
+        let zones = self.zones.lock().unwrap();
+        zones.get(&(view.to_owned(), zone.to_owned())).cloned()
+    }
+
+    /// Adds `changes` to the ones made to a zone so far, adding a record
+    /// back cancelling its removal and the other way around. Returns the
+    /// changes made before, for `restore` to put back.
+    pub(crate) fn record(&self, view: &str, zone: &str, changes: Changes) -> Option<Changes> {
+        let mut zones = self.zones.lock().unwrap();
+        let key = (view.to_owned(), zone.to_owned());
+        let previous = zones.get(&key).cloned();
+        let current = zones.entry(key).or_default();
+
+        for record in changes.removed {
+            match current.added.iter().position(|added| *added == record) {
+                Some(position) => drop(current.added.remove(position)),
+                None => current.removed.push(record),
+            }
+        }
+        for record in changes.added {
+            match current
+                .removed
+                .iter()
+                .position(|removed| *removed == record)
+            {
+                Some(position) => drop(current.removed.remove(position)),
+                None => current.added.push(record),
+            }
+        }
+        drop(zones);
+
+        self.changed.notify_one();
+        previous
+    }
+
+    /// Undoes the last `record` of `zone`, which returned `previous`
+    pub(crate) fn restore(&self, view: &str, zone: &str, previous: Option<Changes>) {
+        let mut zones = self.zones.lock().unwrap();
+        let key = (view.to_owned(), zone.to_owned());
+        match previous {
+            Some(previous) => drop(zones.insert(key, previous)),
+            None => drop(zones.remove(&key)),
+        }
+        drop(zones);
+
+        self.changed.notify_one();
+    }
+}
+
+/// Checks the prerequisites of an update against the `records` of `zone`,
+/// SOA first, and applies its updates to them (RFC 2136 3.2 to 3.4).
+/// Added records must be `accepted` by the zone and get `ttl` if given. Returns what changed, or the rcode to answer with.
+pub(crate) fn apply(
+    zone: &str,
+    records: &[Record],
+    request: &Message,
+    ttl: Option<u32>,
+    accepts: impl Fn(&Record) -> bool,
+) -> Result<Changes, ResultCode> {
+    prerequisites(zone, records, &request.answers)?;
+    prescan(zone, &request.authority, &accepts)?;
+
+    let mut updated = records.to_vec();
+    for update in &request.authority {
+        let mut update = update.clone();
+        let (name, class, record_ttl) = update.fields_mut();
+        *name = normalize_name(name);
+        if let (Class::IN, Some(ttl)) = (&*class, ttl) {
+            *record_ttl = ttl;
+        }
+        perform(zone, &mut updated, update);
+    }
+
+    let old: HashSet<&Record> = records.iter().collect();
+    let new: HashSet<&Record> = updated.iter().collect();
+    Ok(Changes {
+        removed: records
+            .iter()
+            .filter(|record| !new.contains(record))
+            .cloned()
+            .collect(),
+        added: updated
+            .iter()
+            .filter(|record| !old.contains(record))
+            .cloned()
+            .collect(),
+    })
+}
+
+/// RFC 2136 3.2, every prerequisite must hold for the update to be applied
+fn prerequisites(
+    zone: &str,
+    records: &[Record],
+    prerequisites: &[Record],
+) -> Result<(), ResultCode> {
+    // RRsets that must exist with exactly these records
+    let mut expected: HashMap<(String, QueryType), HashSet<Record>> = HashMap::new();
+
+    for prerequisite in prerequisites {
+        let name = normalize_name(prerequisite.name());
+        let qtype = prerequisite.query_type();
+        if prerequisite.ttl() != 0 {
+            return Err(ResultCode::FORMERR);
+        }
+        if !is_subdomain(&name, zone) {
+            return Err(ResultCode::NOTZONE);
+        }
+
+        let mut owned = records
+            .iter()
+            .filter(|record| normalize_name(record.name()) == name);
+        let failed = match prerequisite.class() {
+            Class::QCLASSANY | Class::QCLASSNONE if !is_empty(prerequisite) => {
+                Some(ResultCode::FORMERR)
+            }
+            // Name is in use, RRset exists
+            Class::QCLASSANY if qtype == TYPE_ANY => {
+                owned.next().is_none().then_some(ResultCode::NXDOMAIN)
+            }
+            Class::QCLASSANY => {
+                (!owned.any(|record| record.query_type() == qtype)).then_some(ResultCode::NXRRSET)
+            }
+            // Name is not in use, RRset does not exist
+            Class::QCLASSNONE if qtype == TYPE_ANY => {
+                owned.next().is_some().then_some(ResultCode::YXDOMAIN)
+            }
+            Class::QCLASSNONE => owned
+                .any(|record| record.query_type() == qtype)
+                .then_some(ResultCode::YXRRSET),
+            Class::IN if qtype != TYPE_ANY => {
+                expected
+                    .entry((name, qtype))
+                    .or_default()
+                    .insert(comparable(prerequisite));
+                None
+            }
+            _ => Some(ResultCode::FORMERR),
+        };
+        if let Some(rcode) = failed {
+            return Err(rcode);
+        }
+    }
+
+    for ((name, qtype), expected) in expected {
+        let existing: HashSet<Record> = records
+            .iter()
+            .filter(|record| normalize_name(record.name()) == name && record.query_type() == qtype)
+            .map(comparable)
+            .collect();
+        if existing != expected {
+            return Err(ResultCode::NXRRSET);
+        }
+    }
+
+    Ok(())
+}
+
+/// RFC 2136 3.4.1, the whole update is refused before touching the zone
+fn prescan(
+    zone: &str,
+    updates: &[Record],
+    accepts: impl Fn(&Record) -> bool,
+) -> Result<(), ResultCode> {
+    for update in updates {
+        if !is_subdomain(&normalize_name(update.name()), zone) {
+            return Err(ResultCode::NOTZONE);
+        }
+
+        let qtype = update.query_type();
+        if matches!(qtype, QueryType::OPT | QueryType::AXFR | QueryType::IXFR) {
+            return Err(ResultCode::FORMERR);
+        }
+
+        match update.class() {
+            // Data of a known type that could not be parsed
+            Class::IN
+                if matches!(update, Record::UNKNOWN { .. })
+                    && !matches!(qtype, QueryType::UNKNOWN(_)) =>
+            {
+                return Err(ResultCode::FORMERR)
+            }
+            Class::IN if qtype == TYPE_ANY => return Err(ResultCode::FORMERR),
+            Class::IN if !accepts(update) => return Err(ResultCode::REFUSED),
+            Class::IN => {}
+            Class::QCLASSANY if update.ttl() != 0 || !is_empty(update) => {
+                return Err(ResultCode::FORMERR)
+            }
+            Class::QCLASSNONE if update.ttl() != 0 || qtype == TYPE_ANY => {
+                return Err(ResultCode::FORMERR)
+            }
+            Class::QCLASSANY | Class::QCLASSNONE => {}
+            _ => return Err(ResultCode::FORMERR),
+        }
+    }
+
+    Ok(())
+}
+
+/// RFC 2136 3.4.2, applies one update to the records. The SOA is managed
+/// by the server and the apex keeps at least one NS record.
+fn perform(zone: &str, records: &mut Vec<Record>, update: Record) {
+    let name = update.name().to_owned();
+    let qtype = update.query_type();
+    let protected = |record: &Record| {
+        record.name() == zone && matches!(record.query_type(), QueryType::SOA | QueryType::NS)
+    };
+
+    match update.class() {
+        Class::IN => {
+            if qtype == QueryType::SOA {
+                return;
+            }
+            // A CNAME can not live alongside other data
+            let conflicts = records.iter().any(|record| {
+                record.name() == name
+                    && (record.query_type() == QueryType::CNAME) != (qtype == QueryType::CNAME)
+            });
+            if conflicts {
+                return;
+            }
+            if qtype == QueryType::CNAME {
+                records.retain(|record| {
+                    !(record.name() == name && record.query_type() == QueryType::CNAME)
+                });
+            }
+
+            match records
+                .iter_mut()
+                .find(|record| comparable(record) == comparable(&update))
+            {
+                Some(existing) => *existing = update,
+                None => records.push(update),
+            }
+        }
+        Class::QCLASSANY => records.retain(|record| {
+            record.name() != name
+                || protected(record)
+                || (qtype != TYPE_ANY && record.query_type() != qtype)
+        }),
+        Class::QCLASSNONE => {
+            let last_ns = qtype == QueryType::NS
+                && records
+                    .iter()
+                    .filter(|record| record.name() == name && record.query_type() == QueryType::NS)
+                    .count()
+                    <= 1;
+            if qtype == QueryType::SOA || (name == zone && last_ns) {
+                return;
+            }
+            records.retain(|record| comparable(record) != comparable(&update));
+        }
+        _ => {}
+    }
+}
+
+/// Prerequisites and deletions carry no data
+fn is_empty(record: &Record) -> bool {
+    match record {
+        Record::UNKNOWN { data, .. } => data.is_empty(),
+        Record::TXT { data, .. } => data.is_empty(),
+        _ => false,
+    }
+}
+
+/// The record with its name normalized and without class and TTL, which
+/// records are compared without (RFC 2136 1.1.1)
+fn comparable(record: &Record) -> Record {
+    let mut record = record.clone();
+    let (name, class, ttl) = record.fields_mut();
+    *name = normalize_name(name);
+    *class = Class::IN;
+    *ttl = 0;
+    record
+}
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::{apply, Changes, Updates};
+    use crate::core::dns::message::{Class, Message, QueryType, Record, ResultCode};
+    use crate::core::dns::message_builder::MessageBuilder;
+
+    fn a(last: u8, ttl: u32) -> Record {
+        Record::new_type_a(
+            "www.example.internal".to_owned(),
+            Ipv4Addr::new(10, 0, 0, last),
+            ttl,
+        )
+    }
+
+    fn with_class(mut record: Record, class: Class) -> Record {
+        *record.fields_mut().1 = class;
+        record
+    }
+
+    fn request(prerequisites: Vec<Record>, updates: Vec<Record>) -> Message {
+        let mut request = MessageBuilder::new_request(1)
+            .add_new_question("example.internal".to_owned(), QueryType::SOA, Class::IN)
+            .build();
+        request.answers = prerequisites;
+        request.authority = updates;
+        request
+    }
+
+    fn delete_rrset(name: &str) -> Record {
+        Record::UNKNOWN {
+            name: name.to_owned(),
+            r#type: 1,
+            class: Class::QCLASSANY,
+            ttl: 0,
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn checks_prerequisites_and_applies_updates() {
+        let records = vec![a(1, 300)];
+        let apply =
+            |request: &Message| apply("example.internal", &records, request, None, |_| true);
+
+        // RRset exists with exactly these records, value dependent
+        let replace = request(
+            vec![a(1, 0)],
+            vec![delete_rrset("www.example.internal"), a(2, 60)],
+        );
+        assert_eq!(
+            apply(&replace),
+            Ok(Changes {
+                removed: vec![a(1, 300)],
+                added: vec![a(2, 60)],
+            })
+        );
+        assert_eq!(
+            apply(&request(vec![a(2, 0)], vec![])),
+            Err(ResultCode::NXRRSET)
+        );
+
+        // Name is not in use
+        let mut absent = delete_rrset("www.example.internal");
+        if let Record::UNKNOWN { r#type, class, .. } = &mut absent {
+            *r#type = 255;
+            *class = Class::QCLASSNONE;
+        }
+        assert_eq!(
+            apply(&request(vec![absent], vec![a(2, 60)])),
+            Err(ResultCode::YXDOMAIN)
+        );
+
+        // Deleting a single record compares it without TTL and class
+        let delete = request(vec![], vec![with_class(a(1, 0), Class::QCLASSNONE)]);
+        assert_eq!(apply(&delete).unwrap().removed, vec![a(1, 300)]);
+
+        let outside = request(
+            vec![],
+            vec![Record::new_type_a(
+                "www.other.internal".to_owned(),
+                Ipv4Addr::LOCALHOST,
+                60,
+            )],
+        );
+        assert_eq!(apply(&outside), Err(ResultCode::NOTZONE));
+
+        let updates = Updates::default();
+        assert_eq!(
+            updates.record("default", "example.internal", apply(&replace).unwrap()),
+            None
+        );
+        let previous = updates.record(
+            "default",
+            "example.internal",
+            Changes {
+                removed: vec![a(2, 60)],
+                added: vec![a(1, 300)],
+            },
+        );
+        assert_eq!(
+            updates.changes("default", "example.internal"),
+            Some(Changes::default())
+        );
+
+        // Undone when the updated zone can not be served
+        updates.restore("default", "example.internal", previous);
+        assert_eq!(
+            updates.changes("default", "example.internal"),
+            apply(&replace).ok()
+        );
+        updates.restore("default", "example.internal", None);
+        assert_eq!(updates.changes("default", "example.internal"), None);
+    }
+
+    #[tokio::test]
+    async fn reads_back_the_saved_changes() {
+        let path = std::env::temp_dir().join(format!("dns-updates-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let changes = Changes {
+            removed: vec![a(1, 300)],
+            added: vec![a(2, 60), a(3, 0)],
+        };
+
+        let updates = Updates::load(&path).await.unwrap();
+        assert_eq!(updates.changes("default", "example.internal"), None);
+        updates.record("default", "example.internal", changes.clone());
+        updates.save().await.unwrap();
+
+        let loaded = Updates::load(&path).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            loaded.unwrap().changes("default", "example.internal"),
+            Some(changes)
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Result};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+use crate::core::metrics::Metrics;
+
+use super::dns_reader_writer::DnsReader;
+use super::edns::{Edns, MAX_PAYLOAD_SIZE};
+use super::message::{Message, Question};
+use super::message_builder::MessageBuilder;
+use super::tcp_listener::frame_len;
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Forwards questions we are not authoritative for to the configured resolvers.
+#[derive(Debug, Clone)]
+pub(crate) struct Upstream {
+    servers: Vec<SocketAddr>,
+    /// Asks for the DNSSEC records, and for answers whether they validate
+    /// or not, to validate them ourselves
+    dnssec: bool,
+    metrics: Arc<Metrics>,
+}
+
+impl Upstream {
+    pub(crate) fn new(servers: Vec<SocketAddr>, dnssec: bool, metrics: Arc<Metrics>) -> Upstream {
+        Upstream {
+            servers,
+            dnssec,
+            metrics,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    /// Asks each resolver in order until one of them answers
+    pub(crate) async fn query(&self, question: &Question) -> Result<Message> {
+        let mut last_error = Error::new(ErrorKind::NotFound, "no upstream configured");
+
+        for server in &self.servers {
+            let started = Instant::now();
+            let result = self.query_server(*server, question).await;
+            self.metrics
+                .record_upstream(&server.to_string(), result.is_ok(), started.elapsed());
+
+            match result {
+                Ok(response) => return Ok(response),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    async fn query_server(&self, server: SocketAddr, question: &Question) -> Result<Message> {
+        let mut builder = MessageBuilder::new_request(rand::random())
+            .set_recursion_desired()
+            .add_question(question.clone());
+        if self.dnssec {
+            let mut edns = Edns::new();
+            edns.dnssec_ok = true;
+            builder = builder
+                .set_checking_disabled()
+                .add_resources(edns.to_record());
+        }
+        let request = builder.build();
+
+        let response = timeout(UPSTREAM_TIMEOUT, Self::query_udp(server, &request))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "upstream timed out"))??;
+
+        if !response.header.is_truncated() {
+            return Ok(response);
+        }
+
+        timeout(UPSTREAM_TIMEOUT, Self::query_tcp(server, &request))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "upstream timed out"))?
+    }
+
+    /// Sends `request` over UDP and waits for the response to it, however long it takes
+    pub(crate) async fn query_udp(server: SocketAddr, request: &Message) -> Result<Message> {
+        let local: IpAddr = match server {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((local, 0)).await?;
+        socket.connect(server).await?;
+        socket.send(&request.to_bytes().await?).await?;
+
+        loop {
+            let mut buf = [0; MAX_PAYLOAD_SIZE as usize];
+            let len = socket.recv(&mut buf).await?;
+            let mut reader = DnsReader::from(&buf[..len]);
+
+            // Ignore anything that is not the answer to our request
+            if let Ok(response) = reader.read().await {
+                if Self::is_response_to(request, &response) {
+                    return Ok(response);
+                }
+            }
+        }
+    }
+
+    async fn query_tcp(server: SocketAddr, request: &Message) -> Result<Message> {
+        let mut stream = TcpStream::connect(server).await?;
+
+        let buf = request.to_bytes().await?;
+        stream.write_u16(frame_len(&buf)?).await?;
+        stream.write_all(&buf).await?;
+
+        let len = stream.read_u16().await?;
+        let mut frame = vec![0; len as usize];
+        stream.read_exact(&mut frame).await?;
+
+        let response = DnsReader::from(&*frame).read().await?;
+        if !Self::is_response_to(request, &response) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "unexpected upstream response",
+            ));
+        }
+        Ok(response)
+    }
+
+    pub(crate) fn is_response_to(request: &Message, response: &Message) -> bool {
+        response.header.id == request.header.id
+            && !response.header.is_query()
+            && response.questions.len() == request.questions.len()
+            && response
+                .questions
+                .iter()
+                .zip(&request.questions)
+                .all(|(a, b)| a.name.eq_ignore_ascii_case(&b.name) && a.r#type == b.r#type)
+    }
+}
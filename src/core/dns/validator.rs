@@ -0,0 +1,1310 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY, SHA256, SHA384};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use tracing::debug;
+
+use crate::core::config::{normalize_name, ValidationConfig};
+
+use super::dnssec::{self, key_tag, labels, rrsets, signed_data, ECDSA_P256_SHA256};
+use super::journal::is_newer;
+use super::message::{Class, Message, QueryType, Question, Record, ResultCode};
+use super::upstream::Upstream;
+
+/// DNSSEC algorithms validated (RFC 8624), zones signed with any other
+/// are treated as unsigned
+const RSASHA256: u8 = 8;
+const RSASHA512: u8 = 10;
+const ECDSA_P384_SHA384: u8 = 14;
+
+/// DS digest types (RFC 4509, RFC 6605)
+const DIGEST_SHA1: u8 = 1;
+const DIGEST_SHA256: u8 = 2;
+const DIGEST_SHA384: u8 = 4;
+
+/// Flag of the DNSKEYs signing the zone data (RFC 4034 2.1.1)
+const ZONE_KEY: u16 = 0x0100;
+
+/// The only NSEC3 hash algorithm, SHA-1 (RFC 5155 11)
+const NSEC3_SHA1: u8 = 1;
+/// NSEC3 flag of the spans that may hold unsigned delegations (RFC 5155 3.1.2.1)
+const OPT_OUT: u8 = 1;
+/// NSEC3 iterations above which a denial is treated as insecure (RFC 9276 3.2)
+const MAX_ITERATIONS: u16 = 100;
+
+/// How long the trust put in a zone is kept before its keys are fetched again
+const CACHE_TIME: Duration = Duration::from_secs(300);
+/// How long a zone stays bogus when the upstreams could not be asked for
+/// its keys, short for it to be checked again once they answer
+const FAILURE_CACHE_TIME: Duration = Duration::from_secs(5);
+const CACHE_SIZE: usize = 10_000;
+
+/// What validating an answer found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Security {
+    /// Signed all along a chain of trust from an anchor
+    Secure,
+    /// Out of every chain of trust, or in a zone that is provably unsigned
+    Insecure,
+    /// Should have been signed but is not, or not correctly
+    Bogus(String),
+}
+
+/// What we know of a zone
+#[derive(Debug, Clone)]
+enum Trust {
+    /// Signed, with the keys validated
+    Secure(Vec<Record>),
+    Insecure,
+    Bogus(String),
+}
+
+/// What a name is to the zone above it
+#[derive(Debug, Clone)]
+enum Cut {
+    /// The apex of a zone of its own
+    Zone(Trust),
+    /// A name of the zone above
+    Inside,
+    /// Does not exist, nor does anything below it
+    Missing,
+}
+
+/// What the NSEC or NSEC3 records of an answer prove about a name
+#[derive(Debug, PartialEq, Eq)]
+enum Proof {
+    /// The name exists with only these types
+    NoData(Vec<u16>),
+    NoName,
+    /// The name may be under an unsigned delegation (NSEC3 opt-out), or the
+    /// NSEC3 records can not be checked
+    Insecure,
+    None,
+}
+
+/// The cuts found from some trust anchors, with when they expire. Kept by
+/// the responder across reloads, until the anchors change.
+#[derive(Debug, Default)]
+pub(crate) struct Cuts {
+    anchors: Vec<Record>,
+    cuts: HashMap<String, (Instant, Cut)>,
+}
+
+/// Validates forwarded answers (RFC 4035 5) along chains of trust going
+/// down from the configured trust anchors, asking the upstreams for the DS
+/// and DNSKEY records of every zone on the way.
+#[derive(Debug, Clone)]
+pub(crate) struct Validator {
+    anchors: Vec<Record>,
+    cache: Arc<Mutex<Cuts>>,
+}
+
+impl Validator {
+    /// The cuts of `cache` are kept if they were found from the same
+    /// anchors.
+    pub(crate) fn from_config(config: &ValidationConfig, cache: &Arc<Mutex<Cuts>>) -> Validator {
+        // The anchors that can not be read failed the configuration already
+        let (anchors, _) = trust_anchors(config);
+
+        let mut cuts = cache.lock().unwrap();
+        if cuts.anchors != anchors {
+            cuts.anchors = anchors.clone();
+            cuts.cuts.clear();
+        }
+        drop(cuts);
+
+        Validator {
+            anchors,
+            cache: cache.clone(),
+        }
+    }
+
+    /// Validates `response`, the answer of an upstream asked with the DO and
+    /// CD bits set
+    pub(crate) async fn validate(&self, upstream: &Upstream, response: &Message) -> Security {
+        let question = match response.questions.first() {
+            Some(question) => question,
+            None => return Security::Insecure,
+        };
+        let qname = normalize_name(&question.name);
+        if self.anchor(&qname).is_none() {
+            return Security::Insecure;
+        }
+
+        let mut security = Security::Secure;
+        for rrset in rrsets(&response.answers) {
+            match self.verify_answer(upstream, &rrset, response).await {
+                Security::Secure => {}
+                Security::Insecure => security = Security::Insecure,
+                bogus @ Security::Bogus(_) => return bogus,
+            }
+        }
+
+        // The name the CNAMEs lead to, that the answer is about
+        let mut target = qname;
+        if question.r#type != QueryType::CNAME {
+            for _ in 0..response.answers.len() {
+                match response
+                    .answers
+                    .iter()
+                    .find(|record| normalize_name(record.name()) == target)
+                {
+                    Some(Record::CNAME { host, .. }) => target = normalize_name(host),
+                    _ => break,
+                }
+            }
+        }
+        let answered = response.answers.iter().any(|record| {
+            normalize_name(record.name()) == target && record.query_type() == question.r#type
+        });
+
+        let rcode = response.header.result_code();
+        let denied = match rcode {
+            ResultCode::NXDOMAIN => true,
+            ResultCode::NOERROR => !answered,
+            // Nothing to validate
+            _ => return Security::Insecure,
+        };
+        if !denied {
+            return security;
+        }
+
+        match self
+            .verify_denial(
+                upstream,
+                &target,
+                &question.r#type,
+                rcode,
+                &response.authority,
+            )
+            .await
+        {
+            Security::Secure => security,
+            other => other,
+        }
+    }
+
+    /// Validates an RRset of the answer section
+    async fn verify_answer(
+        &self,
+        upstream: &Upstream,
+        rrset: &[&Record],
+        response: &Message,
+    ) -> Security {
+        let owner = normalize_name(rrset[0].name());
+        let qtype = rrset[0].query_type();
+        let rrsigs = covering(&response.answers, &owner, &qtype);
+
+        let (signer, wildcard) = match rrsigs.first() {
+            Some(Record::RRSIG {
+                signer,
+                labels: signed,
+                ..
+            }) => (normalize_name(signer), *signed < labels(&owner)),
+            _ => {
+                return match self.zone_of(upstream, &owner).await.1 {
+                    Trust::Secure(_) => {
+                        Security::Bogus(format!("{}. {:?} is not signed", owner, qtype))
+                    }
+                    Trust::Insecure => Security::Insecure,
+                    Trust::Bogus(reason) => Security::Bogus(reason),
+                }
+            }
+        };
+        if !is_subdomain(&owner, &signer) {
+            return Security::Bogus(format!("{}. is signed by {}.", owner, signer));
+        }
+
+        let keys = match self.keys(upstream, &signer).await {
+            Ok(Some(keys)) => keys,
+            Ok(None) => return Security::Insecure,
+            Err(reason) => return Security::Bogus(reason),
+        };
+        if let Err(reason) = verify_rrset(rrset, &rrsigs, &signer, &keys).await {
+            return Security::Bogus(reason);
+        }
+
+        // Expanded from a wildcard: the name itself must not exist (RFC 4035 5.3.4)
+        if wildcard {
+            if let Err(reason) = verify_proofs(&response.authority, &signer, &keys).await {
+                return Security::Bogus(reason);
+            }
+            if !denies_expansion(&owner, &rrsigs, &response.authority, &signer) {
+                return Security::Bogus(format!(
+                    "{}. is expanded from a wildcard without proof",
+                    owner
+                ));
+            }
+        }
+        Security::Secure
+    }
+
+    /// Validates the proof that `qname` does not exist, or does not have `qtype`
+    async fn verify_denial(
+        &self,
+        upstream: &Upstream,
+        qname: &str,
+        qtype: &QueryType,
+        rcode: ResultCode,
+        authority: &[Record],
+    ) -> Security {
+        let signer = authority.iter().find_map(|record| match record {
+            Record::RRSIG {
+                type_covered,
+                signer,
+                ..
+            } if matches!(
+                QueryType::from(*type_covered),
+                QueryType::SOA | QueryType::NSEC | QueryType::NSEC3
+            ) =>
+            {
+                Some(normalize_name(signer))
+            }
+            _ => None,
+        });
+
+        let signer = match signer {
+            Some(signer) if is_subdomain(qname, &signer) => signer,
+            Some(signer) => return Security::Bogus(format!("{}. is denied by {}.", qname, signer)),
+            None => {
+                return match self.zone_of(upstream, qname).await.1 {
+                    Trust::Secure(_) => {
+                        Security::Bogus(format!("the denial of {}. is not signed", qname))
+                    }
+                    Trust::Insecure => Security::Insecure,
+                    Trust::Bogus(reason) => Security::Bogus(reason),
+                }
+            }
+        };
+
+        let keys = match self.keys(upstream, &signer).await {
+            Ok(Some(keys)) => keys,
+            Ok(None) => return Security::Insecure,
+            Err(reason) => return Security::Bogus(reason),
+        };
+        if let Err(reason) = verify_proofs(authority, &signer, &keys).await {
+            return Security::Bogus(reason);
+        }
+
+        match (rcode, denial(qname, qtype.to_u16(), authority, &signer)) {
+            (_, Proof::Insecure) => Security::Insecure,
+            (ResultCode::NXDOMAIN, Proof::NoName) | (ResultCode::NOERROR, Proof::NoData(_)) => {
+                Security::Secure
+            }
+            _ => Security::Bogus(format!("{}. {:?} is denied without proof", qname, qtype)),
+        }
+    }
+
+    /// The validated keys of `zone`, none when it is not signed
+    async fn keys(&self, upstream: &Upstream, zone: &str) -> Result<Option<Vec<Record>>, String> {
+        match self.zone_of(upstream, zone).await {
+            (apex, Trust::Secure(keys)) if apex == zone => Ok(Some(keys)),
+            (_, Trust::Secure(_)) => Err(format!("{}. is not the apex of a signed zone", zone)),
+            (_, Trust::Insecure) => Ok(None),
+            (_, Trust::Bogus(reason)) => Err(reason),
+        }
+    }
+
+    /// The zone `name` is in and what we know of it, following the
+    /// delegations from the closest trust anchor down to `name`
+    async fn zone_of(&self, upstream: &Upstream, name: &str) -> (String, Trust) {
+        let anchor = match self.anchor(name) {
+            Some(anchor) => anchor,
+            None => return (String::new(), Trust::Insecure),
+        };
+
+        let mut zone = anchor.to_owned();
+        let mut trust = match self.cut(upstream, None, &zone).await {
+            Cut::Zone(trust) => trust,
+            _ => Trust::Bogus(format!("no DNSKEY for the trust anchor {}.", zone)),
+        };
+
+        let below = ancestors(name)
+            .into_iter()
+            .rev()
+            .filter(|child| labels(child) > labels(anchor));
+        for child in below {
+            let keys = match &trust {
+                Trust::Secure(keys) => keys.clone(),
+                _ => break,
+            };
+            match self.cut(upstream, Some((&zone, &keys)), child).await {
+                Cut::Zone(child_trust) => {
+                    zone = child.to_owned();
+                    trust = child_trust;
+                }
+                Cut::Inside => {}
+                Cut::Missing => break,
+            }
+        }
+
+        (zone, trust)
+    }
+
+    /// What `name` is to the zone `parent` with its validated keys, or to
+    /// the trust anchors without a parent
+    async fn cut(&self, upstream: &Upstream, parent: Option<(&str, &[Record])>, name: &str) -> Cut {
+        if let Some((expires, cut)) = self.cache.lock().unwrap().cuts.get(name) {
+            if *expires > Instant::now() {
+                return cut.clone();
+            }
+        }
+
+        let found = match parent {
+            Some((zone, keys)) => self.delegation(upstream, zone, keys, name).await,
+            None => {
+                let anchors: Vec<&Record> =
+                    self.anchors.iter().filter(|ds| ds.name() == name).collect();
+                self.dnskeys(upstream, name, &anchors).await.map(Cut::Zone)
+            }
+        };
+
+        // Failing to ask says nothing of the zone, it is soon asked again
+        let (cut, time) = match found {
+            Ok(cut) => (cut, CACHE_TIME),
+            Err(reason) => (Cut::Zone(Trust::Bogus(reason)), FAILURE_CACHE_TIME),
+        };
+        self.remember(name, &cut, Instant::now(), time);
+        cut
+    }
+
+    /// Caches `cut` for `name` during `time`, making room first when the
+    /// cache is full: expired entries go, or else the one expiring first
+    fn remember(&self, name: &str, cut: &Cut, now: Instant, time: Duration) {
+        let mut cuts = self.cache.lock().unwrap();
+        let cache = &mut cuts.cuts;
+        if cache.len() >= CACHE_SIZE && !cache.contains_key(name) {
+            cache.retain(|_, (expires, _)| *expires > now);
+            if cache.len() >= CACHE_SIZE {
+                let oldest = cache
+                    .iter()
+                    .min_by_key(|(_, (expires, _))| *expires)
+                    .map(|(name, _)| name.clone());
+                if let Some(oldest) = oldest {
+                    cache.remove(&oldest);
+                }
+            }
+        }
+        cache.insert(name.to_owned(), (now + time, cut.clone()));
+    }
+
+    /// Asks for the DS records of `child`, proving it a signed zone, an
+    /// unsigned one or a name of `parent`. Fails when the upstreams could
+    /// not answer.
+    async fn delegation(
+        &self,
+        upstream: &Upstream,
+        parent: &str,
+        keys: &[Record],
+        child: &str,
+    ) -> Result<Cut, String> {
+        let response = self
+            .query(upstream, child, QueryType::DS)
+            .await
+            .map_err(|e| format!("could not get the DS of {}.: {}", child, e))?;
+
+        match response.header.result_code() {
+            ResultCode::NOERROR => {}
+            // Only stops the walk, whatever is below must then be signed by the parent
+            ResultCode::NXDOMAIN => return Ok(Cut::Missing),
+            rcode => return Err(format!("the DS of {}. was answered {:?}", child, rcode)),
+        }
+
+        let ds: Vec<&Record> = response
+            .answers
+            .iter()
+            .filter(|record| {
+                matches!(record, Record::DS { .. }) && normalize_name(record.name()) == child
+            })
+            .collect();
+        if !ds.is_empty() {
+            let rrsigs = covering(&response.answers, child, &QueryType::DS);
+            return match verify_rrset(&ds, &rrsigs, parent, keys).await {
+                Ok(()) => self.dnskeys(upstream, child, &ds).await.map(Cut::Zone),
+                Err(reason) => Ok(Cut::Zone(Trust::Bogus(reason))),
+            };
+        }
+        if response
+            .answers
+            .iter()
+            .any(|record| matches!(record, Record::CNAME { .. }))
+        {
+            return Ok(Cut::Inside);
+        }
+
+        if let Err(reason) = verify_proofs(&response.authority, parent, keys).await {
+            return Ok(Cut::Zone(Trust::Bogus(reason)));
+        }
+        let ns = QueryType::NS.to_u16();
+        let soa = QueryType::SOA.to_u16();
+        let cut = match denial(child, QueryType::DS.to_u16(), &response.authority, parent) {
+            Proof::NoData(types) if types.contains(&ns) && !types.contains(&soa) => {
+                Cut::Zone(Trust::Insecure)
+            }
+            Proof::NoData(_) => Cut::Inside,
+            Proof::NoName => Cut::Missing,
+            Proof::Insecure => Cut::Zone(Trust::Insecure),
+            Proof::None => Cut::Zone(Trust::Bogus(format!(
+                "{}. has no DS and no proof of it",
+                child
+            ))),
+        };
+        Ok(cut)
+    }
+
+    /// Fetches the keys of `zone`, valid when one of them matches one of
+    /// `ds` and signs them all. Fails when the upstreams could not answer.
+    async fn dnskeys(
+        &self,
+        upstream: &Upstream,
+        zone: &str,
+        ds: &[&Record],
+    ) -> Result<Trust, String> {
+        let supported: Vec<&Record> = ds
+            .iter()
+            .copied()
+            .filter(|ds| match ds {
+                Record::DS {
+                    algorithm,
+                    digest_type,
+                    ..
+                } => {
+                    is_supported(*algorithm)
+                        && matches!(*digest_type, DIGEST_SHA1 | DIGEST_SHA256 | DIGEST_SHA384)
+                }
+                _ => false,
+            })
+            .collect();
+        if supported.is_empty() {
+            return Ok(Trust::Insecure);
+        }
+
+        let response = self
+            .query(upstream, zone, QueryType::DNSKEY)
+            .await
+            .map_err(|e| format!("could not get the DNSKEY of {}.: {}", zone, e))?;
+        match response.header.result_code() {
+            ResultCode::NOERROR => {}
+            rcode => return Err(format!("the DNSKEY of {}. was answered {:?}", zone, rcode)),
+        }
+        let keys: Vec<Record> = response
+            .answers
+            .iter()
+            .filter(|record| {
+                matches!(record, Record::DNSKEY { .. }) && normalize_name(record.name()) == zone
+            })
+            .cloned()
+            .collect();
+        let rrset: Vec<&Record> = keys.iter().collect();
+        let rrsigs = covering(&response.answers, zone, &QueryType::DNSKEY);
+
+        for ds in supported {
+            let (tag, algorithm, digest_type, expected) = match ds {
+                Record::DS {
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                    ..
+                } => (*key_tag, *algorithm, *digest_type, digest),
+                _ => continue,
+            };
+            for key in &keys {
+                let rdata = match key.rdata().await {
+                    Ok(rdata) => rdata,
+                    Err(_) => continue,
+                };
+                let matches = key_tag(&rdata) == tag
+                    && matches!(key, Record::DNSKEY { algorithm: a, .. } if *a == algorithm)
+                    && ds_digest(digest_type, zone, &rdata).as_ref() == Some(expected);
+                if matches
+                    && verify_rrset(&rrset, &rrsigs, zone, std::slice::from_ref(key))
+                        .await
+                        .is_ok()
+                {
+                    return Ok(Trust::Secure(keys));
+                }
+            }
+        }
+
+        debug!(zone, "no DNSKEY matching the DS signs the key set");
+        Ok(Trust::Bogus(format!(
+            "no DNSKEY of {}. matches its DS and signs the key set",
+            zone
+        )))
+    }
+
+    async fn query(
+        &self,
+        upstream: &Upstream,
+        name: &str,
+        qtype: QueryType,
+    ) -> tokio::io::Result<Message> {
+        let question = Question {
+            name: name.to_owned(),
+            r#type: qtype,
+            class: Class::IN,
+        };
+        upstream.query(&question).await
+    }
+
+    /// The closest trust anchor above `name` or at it
+    fn anchor<'a>(&self, name: &'a str) -> Option<&'a str> {
+        ancestors(name)
+            .into_iter()
+            .find(|ancestor| self.anchors.iter().any(|ds| ds.name() == *ancestor))
+    }
+}
+
+/// The trust anchors of `config`, and the problems of the ones that can
+/// not be read. The configuration is refused with these problems, so that
+/// a validator never goes without one of its anchors.
+pub(crate) fn trust_anchors(config: &ValidationConfig) -> (Vec<Record>, Vec<String>) {
+    let mut anchors = Vec::new();
+    let mut problems = Vec::new();
+    for (i, anchor) in config.trust_anchors.iter().enumerate() {
+        match parse_trust_anchor(anchor) {
+            Ok(anchor) => anchors.push(anchor),
+            Err(e) => problems.push(format!("validation.trust_anchors[{}]: {}", i, e)),
+        }
+    }
+    (anchors, problems)
+}
+
+/// Reads a trust anchor given as a DS record in presentation format,
+/// `<owner> [IN] [DS] <key tag> <algorithm> <digest type> <digest>`
+fn parse_trust_anchor(anchor: &str) -> Result<Record, String> {
+    let mut tokens = anchor.split_whitespace();
+    let owner = tokens.next().ok_or("empty trust anchor")?;
+    let fields: Vec<&str> = tokens
+        .skip_while(|token| token.eq_ignore_ascii_case("IN") || token.eq_ignore_ascii_case("DS"))
+        .collect();
+    if fields.len() < 4 {
+        return Err("expected <owner> DS <key tag> <algorithm> <digest type> <digest>".to_owned());
+    }
+
+    let key_tag = fields[0]
+        .parse()
+        .map_err(|_| format!("invalid key tag {}", fields[0]))?;
+    let algorithm = fields[1]
+        .parse()
+        .map_err(|_| format!("invalid algorithm {}", fields[1]))?;
+    let digest_type = fields[2]
+        .parse()
+        .map_err(|_| format!("invalid digest type {}", fields[2]))?;
+    let digest = hex(&fields[3..].concat()).ok_or("the digest is not hexadecimal")?;
+
+    let expected = match digest_type {
+        DIGEST_SHA1 => 20,
+        DIGEST_SHA256 => 32,
+        DIGEST_SHA384 => 48,
+        _ => return Err(format!("unsupported digest type {}", digest_type)),
+    };
+    if digest.len() != expected {
+        return Err(format!(
+            "the digest is {} bytes long instead of {}",
+            digest.len(),
+            expected
+        ));
+    }
+
+    Ok(Record::DS {
+        name: normalize_name(owner),
+        class: Class::IN,
+        ttl: 0,
+        key_tag,
+        algorithm,
+        digest_type,
+        digest,
+    })
+}
+
+/// Checks that one of `rrsigs` is a valid signature of `rrset` by one of
+/// the `keys` of `zone`
+async fn verify_rrset(
+    rrset: &[&Record],
+    rrsigs: &[&Record],
+    zone: &str,
+    keys: &[Record],
+) -> Result<(), String> {
+    let owner = normalize_name(rrset[0].name());
+    let now = dnssec::now();
+
+    for rrsig in rrsigs {
+        let (algorithm, signed_labels, expiration, inception, tag, signer, signature) = match rrsig
+        {
+            Record::RRSIG {
+                algorithm,
+                labels,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+                ..
+            } => (
+                *algorithm,
+                *labels,
+                *expiration,
+                *inception,
+                *key_tag,
+                signer,
+                signature,
+            ),
+            _ => continue,
+        };
+        if normalize_name(signer) != zone || is_newer(inception, now) || is_newer(now, expiration) {
+            continue;
+        }
+
+        // A wildcard expansion is signed as the wildcard (RFC 4035 5.3.2)
+        let owner_labels = labels(&owner);
+        if signed_labels > owner_labels {
+            continue;
+        }
+        let mut records: Vec<Record> = rrset.iter().map(|record| (*record).clone()).collect();
+        if signed_labels < owner_labels {
+            let closest = owner
+                .splitn(owner_labels as usize - signed_labels as usize + 1, '.')
+                .last();
+            let wildcard = format!("*.{}", closest.unwrap_or_default());
+            for record in &mut records {
+                *record.fields_mut().0 = wildcard.clone();
+            }
+        }
+        let records: Vec<&Record> = records.iter().collect();
+        let data = match signed_data(rrsig, &records).await {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+
+        for key in keys {
+            if let Record::DNSKEY {
+                flags,
+                algorithm: key_algorithm,
+                public_key,
+                ..
+            } = key
+            {
+                let rdata = key.rdata().await.unwrap_or_default();
+                if flags & ZONE_KEY != 0
+                    && *key_algorithm == algorithm
+                    && key_tag(&rdata) == tag
+                    && verify_signature(algorithm, public_key, &data, signature)
+                {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    Err(format!(
+        "no valid signature of {}. {:?}",
+        owner,
+        rrset[0].query_type()
+    ))
+}
+
+/// Validates the SOA, NSEC and NSEC3 records of a denial
+async fn verify_proofs(records: &[Record], zone: &str, keys: &[Record]) -> Result<(), String> {
+    for rrset in rrsets(records) {
+        let qtype = rrset[0].query_type();
+        if matches!(qtype, QueryType::SOA | QueryType::NSEC | QueryType::NSEC3) {
+            let rrsigs = covering(records, &normalize_name(rrset[0].name()), &qtype);
+            verify_rrset(&rrset, &rrsigs, zone, keys).await?;
+        }
+    }
+    Ok(())
+}
+
+fn verify_signature(algorithm: u8, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    match algorithm {
+        RSASHA256 | RSASHA512 => {
+            let (e, n) = match rsa_components(public_key) {
+                Some(components) => components,
+                None => return false,
+            };
+            let parameters = if algorithm == RSASHA256 {
+                &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY
+            } else {
+                &signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY
+            };
+            RsaPublicKeyComponents { n, e }
+                .verify(parameters, data, signature)
+                .is_ok()
+        }
+        // Points are given without their uncompressed prefix (RFC 6605 4)
+        ECDSA_P256_SHA256 => UnparsedPublicKey::new(
+            &signature::ECDSA_P256_SHA256_FIXED,
+            [&[4], public_key].concat(),
+        )
+        .verify(data, signature)
+        .is_ok(),
+        ECDSA_P384_SHA384 => UnparsedPublicKey::new(
+            &signature::ECDSA_P384_SHA384_FIXED,
+            [&[4], public_key].concat(),
+        )
+        .verify(data, signature)
+        .is_ok(),
+        dnssec::ED25519 => UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(data, signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+fn is_supported(algorithm: u8) -> bool {
+    matches!(
+        algorithm,
+        RSASHA256 | RSASHA512 | ECDSA_P256_SHA256 | ECDSA_P384_SHA384 | dnssec::ED25519
+    )
+}
+
+/// The exponent and modulus of an RSA key (RFC 3110 2)
+fn rsa_components(key: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (length, rest) = match key.split_first()? {
+        (0, rest) if rest.len() > 2 => {
+            (u16::from_be_bytes([rest[0], rest[1]]) as usize, &rest[2..])
+        }
+        (length, rest) => (*length as usize, rest),
+    };
+    (rest.len() > length).then(|| rest.split_at(length))
+}
+
+/// The digest of a DNSKEY in the DS records pointing at it (RFC 4034 5.1.4)
+fn ds_digest(digest_type: u8, owner: &str, rdata: &[u8]) -> Option<Vec<u8>> {
+    let algorithm = match digest_type {
+        DIGEST_SHA1 => &SHA1_FOR_LEGACY_USE_ONLY,
+        DIGEST_SHA256 => &SHA256,
+        DIGEST_SHA384 => &SHA384,
+        _ => return None,
+    };
+    Some(
+        digest(algorithm, &[wire(owner), rdata.to_vec()].concat())
+            .as_ref()
+            .to_vec(),
+    )
+}
+
+/// The RRSIGs of `records` covering the `qtype` RRset of `owner`
+fn covering<'a>(records: &'a [Record], owner: &str, qtype: &QueryType) -> Vec<&'a Record> {
+    records
+        .iter()
+        .filter(|record| match record {
+            Record::RRSIG {
+                name, type_covered, ..
+            } => normalize_name(name) == owner && QueryType::from(*type_covered) == *qtype,
+            _ => false,
+        })
+        .collect()
+}
+
+/// What the NSEC, or else NSEC3, records of `zone` in `records` prove about
+/// `qname` and `qtype`
+fn denial(qname: &str, qtype: u16, records: &[Record], zone: &str) -> Proof {
+    let nsecs: Vec<(String, String, &[u16])> = records
+        .iter()
+        .filter_map(|record| match record {
+            Record::NSEC {
+                name, next, types, ..
+            } => Some((normalize_name(name), normalize_name(next), &types[..])),
+            _ => None,
+        })
+        .collect();
+
+    if !nsecs.is_empty() {
+        return nsec_denial(qname, qtype, &nsecs);
+    }
+    match Nsec3Chain::new(records, zone) {
+        Some(Ok(chain)) => chain.denial(qname, qtype),
+        Some(Err(())) => Proof::Insecure,
+        None => Proof::None,
+    }
+}
+
+fn denies_type(types: &[u16], qtype: u16) -> bool {
+    !types.contains(&qtype) && !types.contains(&QueryType::CNAME.to_u16())
+}
+
+/// RFC 4035 5.4
+fn nsec_denial(qname: &str, qtype: u16, nsecs: &[(String, String, &[u16])]) -> Proof {
+    let ns = QueryType::NS.to_u16();
+    let soa = QueryType::SOA.to_u16();
+    let dname = 39;
+    let covering = |name: &str| {
+        nsecs.iter().find(|(owner, next, types)| {
+            // Delegations do not deny what is below them (RFC 6840 4.1)
+            let delegation =
+                (types.contains(&ns) && !types.contains(&soa)) || types.contains(&dname);
+            !(delegation && is_subdomain(name, owner) && name != owner) && covers(owner, next, name)
+        })
+    };
+    let matching = |name: &str| nsecs.iter().find(|(owner, _, _)| owner == name);
+
+    if let Some((_, _, types)) = matching(qname) {
+        return if denies_type(types, qtype) {
+            Proof::NoData(types.to_vec())
+        } else {
+            Proof::None
+        };
+    }
+
+    let (owner, next, _) = match covering(qname) {
+        Some(nsec) => nsec,
+        None => return Proof::None,
+    };
+    // An empty non-terminal, the next name being below it
+    if is_subdomain(next, qname) {
+        return Proof::NoData(Vec::new());
+    }
+
+    let closest = [common_ancestor(qname, owner), common_ancestor(qname, next)]
+        .into_iter()
+        .max_by_key(|name| labels(name))
+        .unwrap_or_default();
+    let wildcard = wildcard(closest);
+    if let Some((_, _, types)) = matching(&wildcard) {
+        return if denies_type(types, qtype) {
+            Proof::NoData(types.to_vec())
+        } else {
+            Proof::None
+        };
+    }
+    if covering(&wildcard).is_some() {
+        Proof::NoName
+    } else {
+        Proof::None
+    }
+}
+
+/// Whether the answer expanded from a wildcard for `owner` comes with the
+/// proof that `owner` does not exist
+fn denies_expansion(owner: &str, rrsigs: &[&Record], authority: &[Record], zone: &str) -> bool {
+    let signed_labels = match rrsigs.first() {
+        Some(Record::RRSIG { labels, .. }) => *labels,
+        _ => return false,
+    };
+
+    let covered = authority.iter().any(|record| match record {
+        Record::NSEC { name, next, .. } => {
+            covers(&normalize_name(name), &normalize_name(next), owner)
+        }
+        _ => false,
+    });
+    if covered {
+        return true;
+    }
+
+    // The name one label below the wildcard's parent
+    let next_closer = ancestors(owner)
+        .into_iter()
+        .find(|name| labels(name) == signed_labels + 1);
+    match (Nsec3Chain::new(authority, zone), next_closer) {
+        (Some(Ok(chain)), Some(next_closer)) => chain.covering(next_closer).is_some(),
+        _ => false,
+    }
+}
+
+/// The NSEC3 records of a zone in an answer (RFC 5155 8)
+struct Nsec3Chain<'a> {
+    salt: &'a [u8],
+    iterations: u16,
+    records: Vec<Nsec3<'a>>,
+}
+
+struct Nsec3<'a> {
+    hash: Vec<u8>,
+    next: &'a [u8],
+    flags: u8,
+    types: &'a [u16],
+}
+
+impl<'a> Nsec3Chain<'a> {
+    /// None when there are no NSEC3 records, an error when they can not be used
+    fn new(records: &'a [Record], zone: &str) -> Option<Result<Nsec3Chain<'a>, ()>> {
+        let (hash_algorithm, iterations, salt) =
+            records.iter().find_map(|record| match record {
+                Record::NSEC3 {
+                    hash_algorithm,
+                    iterations,
+                    salt,
+                    ..
+                } => Some((*hash_algorithm, *iterations, salt)),
+                _ => None,
+            })?;
+        if hash_algorithm != NSEC3_SHA1 || iterations > MAX_ITERATIONS {
+            return Some(Err(()));
+        }
+
+        let records = records
+            .iter()
+            .filter_map(|record| match record {
+                Record::NSEC3 {
+                    name,
+                    flags,
+                    iterations: n,
+                    salt: s,
+                    next,
+                    types,
+                    ..
+                } if *n == iterations && s == salt => {
+                    let name = normalize_name(name);
+                    let (hash, parent) = name.split_once('.').unwrap_or((&name, ""));
+                    if parent != zone {
+                        return None;
+                    }
+                    Some(Nsec3 {
+                        hash: base32hex(hash)?,
+                        next,
+                        flags: *flags,
+                        types,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+
+        Some(Ok(Nsec3Chain {
+            salt,
+            iterations,
+            records,
+        }))
+    }
+
+    fn hash(&self, name: &str) -> Vec<u8> {
+        let mut hash = digest(
+            &SHA1_FOR_LEGACY_USE_ONLY,
+            &[wire(name), self.salt.to_vec()].concat(),
+        );
+        for _ in 0..self.iterations {
+            hash = digest(
+                &SHA1_FOR_LEGACY_USE_ONLY,
+                &[hash.as_ref(), self.salt].concat(),
+            );
+        }
+        hash.as_ref().to_vec()
+    }
+
+    /// The types of `name`, when it exists
+    fn matching(&self, name: &str) -> Option<&'a [u16]> {
+        let hash = self.hash(name);
+        self.records
+            .iter()
+            .find(|nsec3| nsec3.hash == hash)
+            .map(|nsec3| nsec3.types)
+    }
+
+    /// The flags of the NSEC3 proving that `name` does not exist
+    fn covering(&self, name: &str) -> Option<u8> {
+        let hash = self.hash(name);
+        self.records
+            .iter()
+            .find(|nsec3| {
+                let (owner, next, hash) = (&nsec3.hash[..], nsec3.next, &hash[..]);
+                if owner < next {
+                    owner < hash && hash < next
+                } else {
+                    owner < hash || hash < next
+                }
+            })
+            .map(|nsec3| nsec3.flags)
+    }
+
+    /// RFC 5155 8.4 to 8.7, with the closest encloser proof of 8.3
+    fn denial(&self, qname: &str, qtype: u16) -> Proof {
+        if let Some(types) = self.matching(qname) {
+            return if denies_type(types, qtype) {
+                Proof::NoData(types.to_vec())
+            } else {
+                Proof::None
+            };
+        }
+
+        let names = ancestors(qname);
+        for (next_closer, closest) in names.iter().zip(names.iter().skip(1)) {
+            if self.matching(closest).is_none() {
+                continue;
+            }
+            let flags = match self.covering(next_closer) {
+                Some(flags) => flags,
+                None => return Proof::None,
+            };
+            if flags & OPT_OUT != 0 {
+                return Proof::Insecure;
+            }
+
+            let wildcard = wildcard(closest);
+            if let Some(types) = self.matching(&wildcard) {
+                return if denies_type(types, qtype) {
+                    Proof::NoData(types.to_vec())
+                } else {
+                    Proof::None
+                };
+            }
+            return match self.covering(&wildcard) {
+                Some(_) => Proof::NoName,
+                None => Proof::None,
+            };
+        }
+        Proof::None
+    }
+}
+
+/// Whether the NSEC from `owner` to `next` covers `name`, the last NSEC of
+/// a zone pointing back at its apex
+fn covers(owner: &str, next: &str, name: &str) -> bool {
+    let after_owner = canonical_order(owner, name) == Ordering::Less;
+    let before_next = canonical_order(name, next) == Ordering::Less;
+    if canonical_order(owner, next) == Ordering::Less {
+        after_owner && before_next
+    } else {
+        after_owner || before_next
+    }
+}
+
+/// Canonical DNS name order, label by label from the root (RFC 4034 6.1)
+fn canonical_order(a: &str, b: &str) -> Ordering {
+    let labels = |name: &str| -> Vec<Vec<u8>> {
+        name.split('.')
+            .filter(|label| !label.is_empty())
+            .rev()
+            .map(|label| {
+                label
+                    .chars()
+                    .map(|c| c.to_ascii_lowercase() as u8)
+                    .collect()
+            })
+            .collect()
+    };
+    labels(a).cmp(&labels(b))
+}
+
+/// `name` and every name above it up to the root, written ""
+fn ancestors(name: &str) -> Vec<&str> {
+    let mut names = vec![name];
+    let mut rest = name;
+    while let Some((_, parent)) = rest.split_once('.') {
+        names.push(parent);
+        rest = parent;
+    }
+    if !name.is_empty() {
+        names.push("");
+    }
+    names
+}
+
+fn is_subdomain(name: &str, zone: &str) -> bool {
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/// The closest name above both `name` and `other`, or at one of them
+fn common_ancestor<'a>(name: &'a str, other: &str) -> &'a str {
+    ancestors(name)
+        .into_iter()
+        .find(|ancestor| is_subdomain(other, ancestor))
+        .unwrap_or_default()
+}
+
+fn wildcard(closest: &str) -> String {
+    if closest.is_empty() {
+        "*".to_owned()
+    } else {
+        format!("*.{}", closest)
+    }
+}
+
+/// A name in lowercase wire format, as hashed and digested
+fn wire(name: &str) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        wire.push(label.chars().count() as u8);
+        wire.extend(label.chars().map(|c| c.to_ascii_lowercase() as u8));
+    }
+    wire.push(0);
+    wire
+}
+
+/// Decodes the base32 with extended hex alphabet of hashed owner names (RFC 4648 7)
+fn base32hex(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'0'..=b'9' => c - b'0',
+            c @ b'A'..=b'V' => c - b'A' + 10,
+            _ => return None,
+        };
+        bits = (bits << 5 | value as u32) & 0xfff;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+fn hex(text: &str) -> Option<Vec<u8>> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        base32hex, denial, parse_trust_anchor, trust_anchors, Cut, Nsec3Chain, Proof, Trust,
+        Validator, CACHE_SIZE, CACHE_TIME, FAILURE_CACHE_TIME,
+    };
+    use crate::core::config::ValidationConfig;
+    use crate::core::dns::message::{Class, Record};
+    use crate::core::dns::upstream::Upstream;
+    use crate::core::metrics::Metrics;
+
+    #[test]
+    fn hashes_names_as_nsec3_owners() {
+        // RFC 5155 appendix A
+        let chain = Nsec3Chain {
+            salt: &[0xaa, 0xbb, 0xcc, 0xdd],
+            iterations: 12,
+            records: Vec::new(),
+        };
+
+        assert_eq!(
+            chain.hash("example"),
+            base32hex("0p9mhaveqvm6t7vbl5lop2u3t2rp3tom").unwrap()
+        );
+        assert_eq!(
+            chain.hash("A.example"),
+            base32hex("35mthgpgcu1qg68fab165klnsnk3dpvl").unwrap()
+        );
+        assert_eq!(
+            chain.hash("ai.example"),
+            base32hex("gjeqe526plbf1g8mklp59enfd789njgi").unwrap()
+        );
+    }
+
+    #[test]
+    fn proves_denials_with_nsec() {
+        let nsec = |name: &str, next: &str, types: &[u16]| Record::NSEC {
+            name: name.to_owned(),
+            class: Class::IN,
+            ttl: 60,
+            next: next.to_owned(),
+            types: types.to_vec(),
+        };
+        let records = [
+            nsec("example", "a.example", &[2, 6, 46, 47, 48]),
+            nsec("a.example", "c.b.example", &[1, 46, 47]),
+            nsec("c.b.example", "example", &[16, 46, 47]),
+        ];
+
+        assert_eq!(
+            denial("a.example", 28, &records, "example"),
+            Proof::NoData(vec![1, 46, 47])
+        );
+        assert_eq!(denial("a.example", 1, &records, "example"), Proof::None);
+        // The wildcard *.example sorts before a.example
+        assert_eq!(denial("d.example", 1, &records, "example"), Proof::NoName);
+        assert_eq!(
+            denial("b.example", 1, &records, "example"),
+            Proof::NoData(Vec::new())
+        );
+        // Compact denial (RFC 9824)
+        let compact = [nsec("db.example", "\0.db.example", &[128, 46, 47])];
+        assert_eq!(
+            denial("db.example", 1, &compact, "example"),
+            Proof::NoData(vec![128, 46, 47])
+        );
+    }
+
+    #[test]
+    fn parses_trust_anchors() {
+        let root =
+            ". IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D";
+
+        match parse_trust_anchor(root).unwrap() {
+            Record::DS {
+                name,
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => {
+                assert_eq!(name, "");
+                assert_eq!((key_tag, algorithm, digest_type), (20326, 8, 2));
+                assert_eq!(digest[..2], [0xe0, 0x6d]);
+            }
+            other => panic!("expected a DS, got {:?}", other),
+        }
+        assert!(parse_trust_anchor("example. DS 1 13 2 E06D").is_err());
+        assert!(parse_trust_anchor("example. DS 1 13").is_err());
+
+        let (anchors, problems) = trust_anchors(&ValidationConfig {
+            trust_anchors: vec![root.to_owned(), "example. DS 1 13".to_owned()],
+        });
+        assert_eq!(anchors.len(), 1);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("validation.trust_anchors[1]: "));
+    }
+
+    #[tokio::test]
+    async fn asks_again_soon_after_failing_to_get_keys() {
+        let validator = Validator::from_config(
+            &ValidationConfig {
+                trust_anchors: vec![
+                    "example. DS 1 13 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D"
+                        .to_owned(),
+                ],
+            },
+            &Arc::default(),
+        );
+        let upstream = Upstream::new(Vec::new(), true, Arc::new(Metrics::new()));
+
+        let asked = Instant::now();
+        assert!(matches!(
+            validator.cut(&upstream, None, "example").await,
+            Cut::Zone(Trust::Bogus(_))
+        ));
+        let cuts = validator.cache.lock().unwrap();
+        let (expires, _) = &cuts.cuts["example"];
+        assert!(*expires <= Instant::now() + FAILURE_CACHE_TIME);
+        assert!(*expires >= asked + FAILURE_CACHE_TIME);
+    }
+
+    #[test]
+    fn keeps_a_bounded_number_of_cuts() {
+        let validator = Validator::from_config(
+            &ValidationConfig {
+                trust_anchors: Vec::new(),
+            },
+            &Arc::default(),
+        );
+        let now = Instant::now();
+        for i in 0..CACHE_SIZE + 10 {
+            validator.remember(
+                &format!("{}.example", i),
+                &Cut::Inside,
+                now + Duration::from_millis(i as u64),
+                CACHE_TIME,
+            );
+        }
+
+        let cuts = validator.cache.lock().unwrap();
+        let cache = &cuts.cuts;
+        assert_eq!(cache.len(), CACHE_SIZE);
+        assert!(!cache.contains_key("0.example"));
+        assert!(cache.contains_key(&format!("{}.example", CACHE_SIZE + 9)));
+    }
+}
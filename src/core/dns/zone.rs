@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::core::acl::Acls;
+use crate::core::config::{
+    normalize_name, parse_upstream, ConfigError, DefaultsConfig, SoaConfig, ZoneConfig,
+};
+
+use super::journal::set_serial;
+use super::message::{Class, QueryType, Record};
+use super::update::Changes;
+
+/// Maximum number of CNAMEs followed inside a zone before giving up
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Outcome of an authoritative lookup
+#[derive(Debug, PartialEq)]
+pub(crate) enum Lookup {
+    /// The records answering the question, possibly preceded by a CNAME chain
+    Answer(Vec<Record>),
+    /// The name exists but has no records of the requested type, carries the zone SOA
+    NoData(Record),
+    /// The name does not exist, carries the zone SOA
+    NxDomain(Record),
+}
+
+#[derive(Debug)]
+pub(crate) struct Zone {
+    name: String,
+    soa: Record,
+    records: Vec<Record>,
+    pub(crate) acl: Acls,
+    /// Secondaries to notify of changes
+    pub(crate) notify: Vec<SocketAddr>,
+    /// Transferred from a primary, which is the one to update it
+    secondary: bool,
+}
+
+impl Zone {
+    pub(crate) fn from_config(
+        config: &ZoneConfig,
+        defaults: &DefaultsConfig,
+    ) -> Result<Zone, String> {
+        let name = normalize_name(&config.name);
+        let ttl = config.ttl.unwrap_or(defaults.ttl);
+
+        let records = config
+            .records
+            .iter()
+            .map(|record| record.to_record(&name, ttl))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let soa = new_soa(&name, &config.soa, defaults, ttl);
+        let mut zone = Zone::new(name, soa, records);
+        zone.acl = Acls::from_config(&config.acl);
+        zone.notify = config
+            .notify
+            .iter()
+            .filter_map(|secondary| parse_upstream(secondary).ok())
+            .collect();
+        Ok(zone)
+    }
+
+    /// A secondary zone from the records transferred from its primary, the
+    /// SOA first
+    pub(crate) fn from_transfer(config: &ZoneConfig, mut records: Vec<Record>) -> Zone {
+        let name = normalize_name(&config.name);
+        // Lookups compare names exactly, as the primary sent them they may
+        // be in any case
+        for record in records.iter_mut() {
+            let (owner, _, _) = record.fields_mut();
+            *owner = normalize_name(owner);
+            if let Record::CNAME { host, .. } = record {
+                *host = normalize_name(host);
+            }
+        }
+        let soa = records.remove(0);
+        records.retain(|record| is_subdomain(record.name(), &name));
+
+        let mut zone = Zone::new(name, soa, records);
+        zone.acl = Acls::from_config(&config.acl);
+        zone.notify = config
+            .notify
+            .iter()
+            .filter_map(|secondary| parse_upstream(secondary).ok())
+            .collect();
+        zone.secondary = true;
+        zone
+    }
+
+    pub(crate) fn new(name: String, soa: Record, records: Vec<Record>) -> Zone {
+        Zone {
+            name,
+            soa,
+            records,
+            acl: Acls::default(),
+            notify: Vec::new(),
+            secondary: false,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn soa(&self) -> &Record {
+        &self.soa
+    }
+
+    pub(crate) fn set_serial(&mut self, serial: u32) {
+        set_serial(&mut self.soa, serial);
+    }
+
+    pub(crate) fn is_secondary(&self) -> bool {
+        self.secondary
+    }
+
+    /// Applies the changes dynamic updates made to the zone
+    pub(crate) fn apply(&mut self, changes: &Changes) {
+        self.records
+            .retain(|record| !changes.removed.contains(record));
+        for record in &changes.added {
+            if !self.records.contains(record) {
+                self.records.push(record.clone());
+            }
+        }
+    }
+
+    /// Whether `qname` (already normalized) is the apex or below it
+    pub(crate) fn contains(&self, qname: &str) -> bool {
+        is_subdomain(qname, &self.name)
+    }
+
+    /// Every record of the zone as sent in a zone transfer, between two
+    /// copies of the SOA (RFC 5936 2.2)
+    pub(crate) fn transfer(&self) -> Vec<Record> {
+        let mut records = Vec::with_capacity(self.records.len() + 2);
+        records.push(self.soa.clone());
+        records.extend(self.records.iter().cloned());
+        records.push(self.soa.clone());
+        records
+    }
+
+    pub(crate) fn lookup(&self, qname: &str, qtype: &QueryType) -> Lookup {
+        if qname == self.name && *qtype == QueryType::SOA {
+            return Lookup::Answer(vec![self.soa.clone()]);
+        }
+
+        let mut answers = Vec::new();
+        let mut name = qname.to_owned();
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            let owned: Vec<&Record> = self.records.iter().filter(|r| r.name() == name).collect();
+
+            if owned.is_empty() {
+                if !answers.is_empty() {
+                    // Dangling CNAME, let the client deal with it
+                    return Lookup::Answer(answers);
+                }
+                let has_children = self.records.iter().any(|r| is_subdomain(r.name(), &name));
+                return if has_children || name == self.name {
+                    Lookup::NoData(self.negative_soa())
+                } else {
+                    Lookup::NxDomain(self.negative_soa())
+                };
+            }
+
+            let matching: Vec<Record> = owned
+                .iter()
+                .filter(|r| r.query_type() == *qtype)
+                .map(|r| (*r).clone())
+                .collect();
+            if !matching.is_empty() {
+                answers.extend(matching);
+                return Lookup::Answer(answers);
+            }
+
+            match owned
+                .iter()
+                .copied()
+                .find(|r| matches!(r, Record::CNAME { .. }))
+            {
+                Some(cname @ Record::CNAME { host, .. }) => {
+                    answers.push(cname.clone());
+                    if !self.contains(host) {
+                        return Lookup::Answer(answers);
+                    }
+                    name = host.clone();
+                }
+                _ if answers.is_empty() => return Lookup::NoData(self.negative_soa()),
+                _ => return Lookup::Answer(answers),
+            }
+        }
+
+        Lookup::Answer(answers)
+    }
+
+    fn negative_soa(&self) -> Record {
+        negative_soa(&self.soa)
+    }
+}
+
+/// The zones of a view, shared by the stores built on updates and
+/// transfers, which only replace the zones they change
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ZoneStore {
+    zones: Vec<Arc<Zone>>,
+}
+
+impl ZoneStore {
+    /// The zones of `configs`, secondary zones being made of the records
+    /// `transferred` for them and left out until their first transfer.
+    pub(crate) fn from_config(
+        configs: &[ZoneConfig],
+        defaults: &DefaultsConfig,
+        transferred: &HashMap<String, Vec<Record>>,
+    ) -> Result<ZoneStore, ConfigError> {
+        let mut zones = Vec::with_capacity(configs.len());
+        let mut problems = Vec::new();
+
+        for config in configs {
+            if !config.primaries.is_empty() {
+                if let Some(records) = transferred.get(&normalize_name(&config.name)) {
+                    zones.push(Arc::new(Zone::from_transfer(config, records.clone())));
+                }
+                continue;
+            }
+
+            match Zone::from_config(config, defaults) {
+                Ok(zone) => zones.push(Arc::new(zone)),
+                Err(e) => problems.push(format!("zone {}: {}", config.name, e)),
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid(problems));
+        }
+
+        Ok(ZoneStore { zones })
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Zone> {
+        self.zones.iter().map(Arc::as_ref)
+    }
+
+    /// The zone named `name` (already normalized)
+    pub(crate) fn get(&self, name: &str) -> Option<&Zone> {
+        self.iter().find(|zone| zone.name == name)
+    }
+
+    /// The zones of a store being built, before it serves them
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut Zone> {
+        self.zones
+            .iter_mut()
+            .map(|zone| Arc::get_mut(zone).expect("zones are only changed before being served"))
+    }
+
+    /// Serves `zone` instead of the zone named `name`, or stops serving
+    /// that one without a zone
+    pub(crate) fn replace(&mut self, name: &str, zone: Option<Zone>) {
+        self.zones.retain(|served| served.name != name);
+        self.zones.extend(zone.map(Arc::new));
+    }
+
+    /// Finds the most specific zone that `qname` (already normalized) belongs to
+    pub(crate) fn find(&self, qname: &str) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|zone| zone.contains(qname))
+            .max_by_key(|zone| zone.name.len())
+            .map(Arc::as_ref)
+    }
+}
+
+pub(crate) fn new_soa(
+    zone: &str,
+    config: &SoaConfig,
+    defaults: &DefaultsConfig,
+    ttl: u32,
+) -> Record {
+    Record::SOA {
+        name: zone.to_owned(),
+        class: Class::IN,
+        mname: config
+            .mname
+            .as_deref()
+            .map(normalize_name)
+            .unwrap_or_else(|| format!("ns.{}", zone)),
+        rname: config
+            .rname
+            .as_deref()
+            .map(normalize_name)
+            .unwrap_or_else(|| format!("hostmaster.{}", zone)),
+        serial: config.serial,
+        refresh: config.refresh,
+        retry: config.retry,
+        expire: config.expire,
+        minimum: config.minimum.unwrap_or(defaults.negative_ttl),
+        ttl,
+    }
+}
+
+/// SOA to put in the authority section of negative answers (RFC 2308 3)
+pub(crate) fn negative_soa(soa: &Record) -> Record {
+    let mut soa = soa.clone();
+    if let Record::SOA { ttl, minimum, .. } = &mut soa {
+        *ttl = (*ttl).min(*minimum);
+    }
+    soa
+}
+
+pub(crate) fn is_subdomain(name: &str, zone: &str) -> bool {
+    name == zone
+        || (name.len() > zone.len()
+            && name.ends_with(zone)
+            && name.as_bytes()[name.len() - zone.len() - 1] == b'.')
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::{Lookup, Zone};
+    use crate::core::config::{DefaultsConfig, ZoneConfig};
+    use crate::core::dns::message::{QueryType, Record};
+
+    fn zone() -> Zone {
+        let config: ZoneConfig = toml::from_str(
+            r#"
+            name = "example.internal"
+            records = [
+                { name = "www", type = "A", value = "10.0.0.1" },
+                { name = "web", type = "CNAME", value = "www" },
+                { name = "a.b", type = "A", value = "10.0.0.2" },
+            ]
+            "#,
+        )
+        .unwrap();
+        Zone::from_config(&config, &DefaultsConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn answers_and_follows_cnames() {
+        let zone = zone();
+        let www = Record::new_type_a(
+            "www.example.internal".to_owned(),
+            Ipv4Addr::new(10, 0, 0, 1),
+            300,
+        );
+
+        assert_eq!(
+            zone.lookup("www.example.internal", &QueryType::A),
+            Lookup::Answer(vec![www.clone()])
+        );
+        match zone.lookup("web.example.internal", &QueryType::A) {
+            Lookup::Answer(answers) => {
+                assert_eq!(answers.len(), 2);
+                assert_eq!(answers[0].query_type(), QueryType::CNAME);
+                assert_eq!(answers[1], www);
+            }
+            other => panic!("expected an answer, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn negative_answers() {
+        let zone = zone();
+
+        assert!(matches!(
+            zone.lookup("www.example.internal", &QueryType::AAAA),
+            Lookup::NoData(_)
+        ));
+        assert!(matches!(
+            zone.lookup("b.example.internal", &QueryType::A),
+            Lookup::NoData(_)
+        ));
+        assert!(matches!(
+            zone.lookup("nope.example.internal", &QueryType::A),
+            Lookup::NxDomain(_)
+        ));
+    }
+
+    #[test]
+    fn transferred_names_are_normalized() {
+        let mut records = zone().transfer();
+        records.pop();
+        for record in records.iter_mut() {
+            let (owner, _, _) = record.fields_mut();
+            *owner = owner.to_ascii_uppercase();
+            if let Record::CNAME { host, .. } = record {
+                *host = host.to_ascii_uppercase();
+            }
+        }
+        let config: ZoneConfig = toml::from_str(
+            r#"
+            name = "example.internal"
+            primaries = ["192.0.2.1:53"]
+            "#,
+        )
+        .unwrap();
+        let zone = Zone::from_transfer(&config, records);
+
+        assert!(matches!(
+            zone.lookup("www.example.internal", &QueryType::A),
+            Lookup::Answer(_)
+        ));
+        match zone.lookup("web.example.internal", &QueryType::A) {
+            Lookup::Answer(answers) => assert_eq!(answers.len(), 2),
+            other => panic!("expected an answer, got {:?}", other),
+        }
+    }
+}
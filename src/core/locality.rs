@@ -0,0 +1,118 @@
+use std::cmp::Reverse;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use super::config::LocalityConfig;
+
+/// Region and zone of a client or of an instance, either may be unknown
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Locality {
+    pub(crate) region: Option<String>,
+    pub(crate) zone: Option<String>,
+}
+
+impl Locality {
+    /// How close `other` is to this locality, lower is closer: 0 for the
+    /// same zone, 1 for the same region and 2 for anywhere else.
+    pub(crate) fn distance(&self, other: &Locality) -> u8 {
+        let same = |a: &Option<String>, b: &Option<String>| a.is_some() && a == b;
+
+        if same(&self.zone, &other.zone) {
+            0
+        } else if same(&self.region, &other.region) {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+/// Maps client networks to the locality of the clients in them
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Localities {
+    networks: Vec<(IpNet, Locality)>,
+}
+
+impl Localities {
+    pub(crate) fn from_config(config: &[LocalityConfig]) -> Localities {
+        let mut networks: Vec<(IpNet, Locality)> = config
+            .iter()
+            .flat_map(|locality| {
+                locality.networks.iter().map(move |network| {
+                    let locality = Locality {
+                        region: locality.region.clone(),
+                        zone: locality.zone.clone(),
+                    };
+                    (network.trunc(), locality)
+                })
+            })
+            .collect();
+
+        // Most specific networks first, so the first match is the longest
+        networks.sort_by_key(|(network, _)| Reverse(network.prefix_len()));
+
+        Localities { networks }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    pub(crate) fn find(&self, addr: IpAddr) -> Option<&Locality> {
+        self.networks
+            .iter()
+            .find(|(network, _)| network.contains(&addr))
+            .map(|(_, locality)| locality)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Localities, Locality};
+    use crate::core::config::Config;
+
+    fn locality(region: &str, zone: Option<&str>) -> Locality {
+        Locality {
+            region: Some(region.to_owned()),
+            zone: zone.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn longest_network_wins() {
+        let config: Config = toml::from_str(
+            r#"
+            [[localities]]
+            networks = ["10.0.0.0/8"]
+            region = "eu-west-1"
+
+            [[localities]]
+            networks = ["10.1.0.0/16", "fd00:1::/48"]
+            region = "eu-west-1"
+            zone = "eu-west-1a"
+            "#,
+        )
+        .unwrap();
+        let localities = Localities::from_config(&config.localities);
+
+        let zone_a = locality("eu-west-1", Some("eu-west-1a"));
+        assert_eq!(localities.find("10.1.2.3".parse().unwrap()), Some(&zone_a));
+        assert_eq!(localities.find("fd00:1::5".parse().unwrap()), Some(&zone_a));
+        assert_eq!(
+            localities.find("10.2.0.1".parse().unwrap()),
+            Some(&locality("eu-west-1", None))
+        );
+        assert_eq!(localities.find("192.168.0.1".parse().unwrap()), None);
+
+        assert_eq!(
+            zone_a.distance(&locality("eu-west-1", Some("eu-west-1a"))),
+            0
+        );
+        assert_eq!(
+            zone_a.distance(&locality("eu-west-1", Some("eu-west-1b"))),
+            1
+        );
+        assert_eq!(zone_a.distance(&Locality::default()), 2);
+    }
+}
@@ -0,0 +1,45 @@
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Registry};
+
+use super::config::{LogFormat, LogLevel};
+
+/// Handle on the global subscriber, used to change the log level when the
+/// configuration is reloaded. The format is only chosen at startup.
+pub struct Logging {
+    level: reload::Handle<LevelFilter, Registry>,
+}
+
+impl Logging {
+    /// Installs the global subscriber, writing to stdout
+    pub fn init(level: LogLevel, format: LogFormat) -> Logging {
+        let (filter, handle) = reload::Layer::new(level_filter(level));
+        let registry = tracing_subscriber::registry().with(filter);
+
+        match format {
+            LogFormat::Text => registry.with(fmt::layer()).init(),
+            LogFormat::Json => registry
+                .with(fmt::layer().json().flatten_event(true))
+                .init(),
+        }
+
+        Logging { level: handle }
+    }
+
+    pub fn set_level(&self, level: LogLevel) {
+        if let Err(e) = self.level.reload(level_filter(level)) {
+            tracing::warn!("could not change the log level: {}", e);
+        }
+    }
+}
+
+fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Error => LevelFilter::ERROR,
+        LogLevel::Warn => LevelFilter::WARN,
+        LogLevel::Info => LevelFilter::INFO,
+        LogLevel::Debug => LevelFilter::DEBUG,
+        LogLevel::Trace => LevelFilter::TRACE,
+    }
+}
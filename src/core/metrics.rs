@@ -0,0 +1,232 @@
+use std::time::Duration;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use super::dns::message::{Message, QueryType, Question};
+use super::dns::responder::Transport;
+use super::registry;
+
+/// Buckets of the latency histograms, in seconds. Authoritative answers
+/// land in the first ones, forwarded queries further up.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Counters and histograms exposed in the Prometheus text format on the
+/// admin API's `/metrics`.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    queries: IntCounterVec,
+    parse_failures: IntCounterVec,
+    response_latency: HistogramVec,
+    upstream_latency: HistogramVec,
+    registry_instances: IntGaugeVec,
+    rate_limited: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let queries = IntCounterVec::new(
+            Opts::new("dns_queries_total", "Queries answered"),
+            &["qtype", "rcode", "transport"],
+        )
+        .unwrap();
+        let parse_failures = IntCounterVec::new(
+            Opts::new(
+                "dns_parse_failures_total",
+                "Requests dropped because they could not be parsed",
+            ),
+            &["transport"],
+        )
+        .unwrap();
+        let response_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "dns_response_duration_seconds",
+                "Time taken to build a response",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["transport"],
+        )
+        .unwrap();
+        let upstream_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "dns_upstream_duration_seconds",
+                "Time taken by upstream resolvers",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["upstream", "outcome"],
+        )
+        .unwrap();
+        let registry_instances = IntGaugeVec::new(
+            Opts::new("dns_registry_instances", "Instances registered per service"),
+            &["view", "service"],
+        )
+        .unwrap();
+
+        let rate_limited = IntCounterVec::new(
+            Opts::new(
+                "dns_rate_limited_total",
+                "UDP responses dropped or slipped by rate limiting",
+            ),
+            &["action"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(queries.clone())).unwrap();
+        registry.register(Box::new(parse_failures.clone())).unwrap();
+        registry
+            .register(Box::new(response_latency.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upstream_latency.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(registry_instances.clone()))
+            .unwrap();
+        registry.register(Box::new(rate_limited.clone())).unwrap();
+
+        Metrics {
+            registry,
+            queries,
+            parse_failures,
+            response_latency,
+            upstream_latency,
+            registry_instances,
+            rate_limited,
+        }
+    }
+
+    pub(crate) fn record_query(
+        &self,
+        transport: Transport,
+        question: Option<&Question>,
+        response: &Message,
+        latency: Duration,
+    ) {
+        // Clients choose the type, the unknown ones share a label so that
+        // they can not make up a series each
+        let qtype = match question.map(|question| &question.r#type) {
+            Some(QueryType::UNKNOWN(_)) => "OTHER".to_owned(),
+            Some(qtype) => format!("{:?}", qtype),
+            None => String::new(),
+        };
+        let rcode = format!("{:?}", response.header.result_code());
+        let transport = transport.to_string();
+
+        self.queries
+            .with_label_values(&[qtype.as_str(), rcode.as_str(), transport.as_str()])
+            .inc();
+        self.response_latency
+            .with_label_values(&[transport.as_str()])
+            .observe(latency.as_secs_f64());
+    }
+
+    pub(crate) fn record_parse_failure(&self, transport: Transport) {
+        self.parse_failures
+            .with_label_values(&[transport.to_string()])
+            .inc();
+    }
+
+    pub(crate) fn record_upstream(&self, upstream: &str, ok: bool, latency: Duration) {
+        let outcome = if ok { "ok" } else { "error" };
+        self.upstream_latency
+            .with_label_values(&[upstream, outcome])
+            .observe(latency.as_secs_f64());
+    }
+
+    /// Counts a response held back by rate limiting, `action` being "drop"
+    /// or "slip"
+    pub(crate) fn record_rate_limited(&self, action: &str) {
+        self.rate_limited.with_label_values(&[action]).inc();
+    }
+
+    /// Replaces the instance counts with the ones of the registry of every
+    /// view, dropping services that are no longer registered.
+    pub(crate) fn set_registries<'a>(
+        &self,
+        registries: impl Iterator<Item = (&'a str, &'a registry::Registry)>,
+    ) {
+        self.registry_instances.reset();
+        for (view, registry) in registries {
+            for (name, service) in registry.services() {
+                self.registry_instances
+                    .with_label_values(&[view, name])
+                    .set(service.instances.len() as i64);
+            }
+        }
+    }
+
+    /// Every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Metrics;
+    use crate::core::dns::message::{Class, QueryType};
+    use crate::core::dns::message_builder::MessageBuilder;
+    use crate::core::dns::responder::Transport;
+
+    #[test]
+    fn renders_queries_by_label() {
+        let metrics = Metrics::new();
+        let request = MessageBuilder::new_request(1)
+            .add_new_question(
+                "www.example.internal".to_owned(),
+                QueryType::AAAA,
+                Class::IN,
+            )
+            .build();
+
+        metrics.record_query(
+            Transport::Tcp,
+            request.questions.first(),
+            &request,
+            Duration::from_millis(1),
+        );
+        metrics.record_parse_failure(Transport::Udp);
+        for qtype in [999, 65000] {
+            let request = MessageBuilder::new_request(2)
+                .add_new_question(
+                    "www.example.internal".to_owned(),
+                    QueryType::UNKNOWN(qtype),
+                    Class::IN,
+                )
+                .build();
+            metrics.record_query(
+                Transport::Udp,
+                request.questions.first(),
+                &request,
+                Duration::from_millis(1),
+            );
+        }
+
+        let text = metrics.render();
+        assert!(
+            text.contains(r#"dns_queries_total{qtype="AAAA",rcode="NOERROR",transport="tcp"} 1"#)
+        );
+        assert!(
+            text.contains(r#"dns_queries_total{qtype="OTHER",rcode="NOERROR",transport="udp"} 2"#)
+        );
+        assert!(text.contains(r#"dns_parse_failures_total{transport="udp"} 1"#));
+        assert!(text.contains(r#"dns_response_duration_seconds_count{transport="tcp"} 1"#));
+    }
+}
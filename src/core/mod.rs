@@ -1 +1,10 @@
+pub mod acl;
+pub mod admin;
+pub mod config;
 pub mod dns;
+pub mod locality;
+pub mod logging;
+pub mod metrics;
+pub mod registry;
+pub mod reload;
+pub mod shutdown;
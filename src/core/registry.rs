@@ -0,0 +1,719 @@
+use std::collections::{BTreeMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rand::seq::SliceRandom;
+
+use super::acl::Acls;
+use super::config::{
+    normalize_name, parse_upstream, AnswerOrder, DefaultsConfig, RegistryConfig, SoaConfig,
+};
+use super::dns::dnssec::{Signer, SigningKey};
+use super::dns::journal::set_serial;
+use super::dns::message::{Class, QueryType, Record};
+use super::dns::update::Changes;
+use super::dns::zone::{is_subdomain, negative_soa, new_soa, Lookup};
+use super::locality::Locality;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Instance {
+    pub(crate) address: IpAddr,
+    pub(crate) weight: u32,
+    pub(crate) locality: Locality,
+}
+
+/// How a service is browsed with DNS-SD, its type lowercased
+#[derive(Debug, Clone)]
+pub(crate) struct DnsSd {
+    service_type: String,
+    port: u16,
+    txt: Vec<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Service {
+    pub(crate) instances: Vec<Instance>,
+    order: AnswerOrder,
+    max_answers: Option<usize>,
+    /// Queries answered so far, the offset of the `round-robin` rotation
+    rotation: AtomicUsize,
+    /// Browsable with DNS-SD when set
+    dns_sd: Option<DnsSd>,
+}
+
+impl Service {
+    /// The instances to answer with, capped to the service `max_answers`.
+    ///
+    /// When the locality of the client is known, the instances in its zone
+    /// come first, then the ones in its region and then all others. Each
+    /// of these groups is in the order of the service, and with the
+    /// `weighted` order drained instances, of weight 0, still come last.
+    fn arrange<'a>(
+        &self,
+        instances: Vec<&'a Instance>,
+        client: Option<&Locality>,
+    ) -> Vec<&'a Instance> {
+        let rotation = match self.order {
+            AnswerOrder::RoundRobin => self.rotation.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+
+        let mut arranged = Vec::with_capacity(instances.len());
+        match client {
+            Some(client) => {
+                for distance in 0..=2 {
+                    let group = instances
+                        .iter()
+                        .copied()
+                        .filter(|instance| client.distance(&instance.locality) == distance)
+                        .collect();
+                    arranged.extend(self.order(group, rotation));
+                }
+            }
+            None => arranged = self.order(instances, rotation),
+        }
+        if self.order == AnswerOrder::Weighted {
+            let (drained, serving): (Vec<_>, Vec<_>) = arranged
+                .into_iter()
+                .partition(|instance| instance.weight == 0);
+            arranged = serving;
+            arranged.extend(drained);
+        }
+
+        if let Some(max) = self.max_answers {
+            arranged.truncate(max);
+        }
+        arranged
+    }
+
+    fn order<'a>(&self, mut instances: Vec<&'a Instance>, rotation: usize) -> Vec<&'a Instance> {
+        match self.order {
+            AnswerOrder::Fixed => {}
+            AnswerOrder::RoundRobin if !instances.is_empty() => {
+                let offset = rotation % instances.len();
+                instances.rotate_left(offset);
+            }
+            AnswerOrder::RoundRobin => {}
+            AnswerOrder::Random => instances.shuffle(&mut rand::rng()),
+            AnswerOrder::Weighted => {
+                // Weighted shuffle (Efraimidis and Spirakis): sorting on
+                // u^(1/weight) puts an instance first with a probability
+                // proportional to its weight.
+                let mut keyed: Vec<(f64, &Instance)> = instances
+                    .into_iter()
+                    .map(|instance| {
+                        let key = match instance.weight {
+                            0 => -1.0,
+                            weight => rand::random::<f64>().powf(1.0 / weight as f64),
+                        };
+                        (key, instance)
+                    })
+                    .collect();
+                keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+                instances = keyed.into_iter().map(|(_, instance)| instance).collect();
+            }
+        }
+
+        instances
+    }
+}
+
+/// Registered services, published as `<service>.<zone>`.
+#[derive(Debug)]
+pub(crate) struct Registry {
+    zone: String,
+    ttl: u32,
+    soa: Record,
+    services: BTreeMap<String, Service>,
+    /// Order and answer cap of the services added by dynamic updates
+    order: AnswerOrder,
+    max_answers: Option<usize>,
+    pub(crate) acl: Acls,
+    /// Secondaries to notify of changes
+    pub(crate) notify: Vec<SocketAddr>,
+    /// Signs the answers when DNSSEC is configured
+    pub(crate) signer: Option<Signer>,
+}
+
+impl Registry {
+    pub(crate) fn from_config(config: &RegistryConfig, defaults: &DefaultsConfig) -> Registry {
+        let zone = normalize_name(&config.zone);
+        let ttl = config.ttl.unwrap_or(defaults.ttl);
+        let soa = new_soa(&zone, &SoaConfig::default(), defaults, ttl);
+
+        let services = config
+            .services
+            .iter()
+            .map(|service| {
+                let instances = service
+                    .instances
+                    .iter()
+                    .map(|instance| Instance {
+                        address: instance.address,
+                        weight: instance.weight,
+                        locality: Locality {
+                            region: instance.region.clone(),
+                            zone: instance.zone.clone(),
+                        },
+                    })
+                    .collect();
+                let service_entry = Service {
+                    instances,
+                    order: service.order.unwrap_or(config.order),
+                    max_answers: service.max_answers.or(config.max_answers),
+                    rotation: AtomicUsize::new(0),
+                    dns_sd: service.dns_sd.as_ref().map(|dns_sd| DnsSd {
+                        service_type: dns_sd.r#type.to_ascii_lowercase(),
+                        port: dns_sd.port,
+                        txt: dns_sd.txt.clone(),
+                    }),
+                };
+                (service.name.to_ascii_lowercase(), service_entry)
+            })
+            .collect();
+
+        // Keys are checked when the configuration is loaded
+        let signer = config
+            .dnssec
+            .as_ref()
+            .and_then(|dnssec| SigningKey::from_config(dnssec).ok())
+            .map(|key| Signer::new(&zone, key, ttl));
+
+        Registry {
+            zone,
+            ttl,
+            soa,
+            services,
+            order: config.order,
+            max_answers: config.max_answers,
+            acl: Acls::from_config(&config.acl),
+            notify: config
+                .notify
+                .iter()
+                .filter_map(|secondary| parse_upstream(secondary).ok())
+                .collect(),
+            signer,
+        }
+    }
+
+    pub(crate) fn zone(&self) -> &str {
+        &self.zone
+    }
+
+    pub(crate) fn soa(&self) -> &Record {
+        &self.soa
+    }
+
+    pub(crate) fn set_serial(&mut self, serial: u32) {
+        set_serial(&mut self.soa, serial);
+    }
+
+    /// Whether a dynamic update may add `record`: only addresses of
+    /// services, one label below the zone.
+    pub(crate) fn accepts(&self, record: &Record) -> bool {
+        self.instance_of(record).is_some()
+    }
+
+    /// Applies the changes dynamic updates made to the instances. Added
+    /// instances have a weight of 1 and no locality, services left without
+    /// instances are removed.
+    pub(crate) fn apply(&mut self, changes: &Changes) {
+        let removed: Vec<_> = changes
+            .removed
+            .iter()
+            .filter_map(|record| self.instance_of(record))
+            .collect();
+        let added: Vec<_> = changes
+            .added
+            .iter()
+            .filter_map(|record| self.instance_of(record))
+            .collect();
+        let mut emptied = HashSet::new();
+
+        for (service, address) in removed {
+            if let Some(entry) = self.services.get_mut(&service) {
+                entry
+                    .instances
+                    .retain(|instance| instance.address != address);
+                emptied.insert(service);
+            }
+        }
+
+        for (service, address) in added {
+            let entry = self.services.entry(service).or_insert_with(|| Service {
+                instances: Vec::new(),
+                order: self.order,
+                max_answers: self.max_answers,
+                rotation: AtomicUsize::new(0),
+                dns_sd: None,
+            });
+            if !entry
+                .instances
+                .iter()
+                .any(|instance| instance.address == address)
+            {
+                entry.instances.push(Instance {
+                    address,
+                    weight: 1,
+                    locality: Locality::default(),
+                });
+            }
+        }
+
+        self.services
+            .retain(|name, service| !emptied.contains(name) || !service.instances.is_empty());
+    }
+
+    /// The service and address of an A or AAAA record of the registry
+    fn instance_of(&self, record: &Record) -> Option<(String, IpAddr)> {
+        let address = match record {
+            Record::A { addr, .. } => IpAddr::V4(*addr),
+            Record::AAAA { addr, .. } => IpAddr::V6(*addr),
+            _ => return None,
+        };
+        let name = normalize_name(record.name());
+        let service = name.strip_suffix(&self.zone)?.strip_suffix('.')?;
+        if service.is_empty() || service.contains('.') {
+            return None;
+        }
+        Some((service.to_owned(), address))
+    }
+
+    /// Whether `qname` (already normalized) is the registry zone or below it
+    pub(crate) fn contains(&self, qname: &str) -> bool {
+        is_subdomain(qname, &self.zone)
+    }
+
+    pub(crate) fn service(&self, name: &str) -> Option<&Service> {
+        self.services.get(name)
+    }
+
+    pub(crate) fn services(&self) -> impl Iterator<Item = (&str, &Service)> {
+        self.services
+            .iter()
+            .map(|(name, service)| (name.as_str(), service))
+    }
+
+    /// Answers `qname`, preferring the instances closest to `client`
+    pub(crate) fn lookup(
+        &self,
+        qname: &str,
+        qtype: &QueryType,
+        client: Option<&Locality>,
+    ) -> Lookup {
+        if qname == self.zone {
+            return match qtype {
+                QueryType::SOA => Lookup::Answer(vec![self.soa.clone()]),
+                QueryType::NS => Lookup::Answer(vec![self.ns()]),
+                QueryType::DNSKEY if self.signer.is_some() => {
+                    Lookup::Answer(self.signer.iter().map(Signer::dnskey).collect())
+                }
+                _ => Lookup::NoData(negative_soa(&self.soa)),
+            };
+        }
+
+        let label = &qname[..qname.len() - self.zone.len() - 1];
+        let service = match self.service(label) {
+            Some(service) => service,
+            None => return self.browse(qname, qtype),
+        };
+
+        let candidates = service
+            .instances
+            .iter()
+            .filter(|instance| Self::answers(qtype, instance))
+            .collect();
+        let answers: Vec<Record> = service
+            .arrange(candidates, client)
+            .into_iter()
+            .filter_map(|instance| self.to_record(qname, qtype, instance))
+            .collect();
+
+        if answers.is_empty() {
+            Lookup::NoData(negative_soa(&self.soa))
+        } else {
+            Lookup::Answer(answers)
+        }
+    }
+
+    /// Answers the DNS-SD names below the zone. The names between them and
+    /// the zone, as `_tcp.<zone>`, exist without records.
+    fn browse(&self, qname: &str, qtype: &QueryType) -> Lookup {
+        let records = self.browsing_records(&self.zone, self.ttl);
+        let answers: Vec<Record> = records
+            .iter()
+            .filter(|record| record.name() == qname && record.query_type() == *qtype)
+            .cloned()
+            .collect();
+
+        if !answers.is_empty() {
+            Lookup::Answer(answers)
+        } else if records
+            .iter()
+            .any(|record| is_subdomain(record.name(), qname))
+        {
+            Lookup::NoData(negative_soa(&self.soa))
+        } else {
+            Lookup::NxDomain(negative_soa(&self.soa))
+        }
+    }
+
+    /// The types of the records at `qname`, None when it does not exist.
+    /// The names between the DNS-SD ones and the zone exist without any.
+    pub(crate) fn types(&self, qname: &str) -> Option<Vec<QueryType>> {
+        if qname == self.zone {
+            let mut types = vec![QueryType::NS, QueryType::SOA];
+            types.extend(self.signer.iter().map(|_| QueryType::DNSKEY));
+            return Some(types);
+        }
+
+        let service = match qname
+            .strip_suffix(&self.zone)
+            .and_then(|label| label.strip_suffix('.'))
+        {
+            Some(label) => self.service(label),
+            None => None,
+        };
+        let service = match service {
+            Some(service) => service,
+            None => {
+                let records = self.browsing_records(&self.zone, self.ttl);
+                if !records
+                    .iter()
+                    .any(|record| is_subdomain(record.name(), qname))
+                {
+                    return None;
+                }
+                let mut types: Vec<QueryType> = records
+                    .iter()
+                    .filter(|record| record.name() == qname)
+                    .map(Record::query_type)
+                    .collect();
+                types.dedup();
+                return Some(types);
+            }
+        };
+        Some(
+            [QueryType::A, QueryType::AAAA]
+                .into_iter()
+                .filter(|qtype| {
+                    service
+                        .instances
+                        .iter()
+                        .any(|instance| Self::answers(qtype, instance))
+                })
+                .collect(),
+        )
+    }
+
+    /// Every instance of every service as sent in a zone transfer, services
+    /// in alphabetical order and their instances in configuration order,
+    /// between two copies of the SOA (RFC 5936 2.2)
+    pub(crate) fn transfer(&self) -> Vec<Record> {
+        let mut records = vec![self.soa.clone(), self.ns()];
+        for (name, service) in &self.services {
+            let qname = format!("{}.{}", name, self.zone);
+            records.extend(service.instances.iter().filter_map(|instance| {
+                let qtype = match instance.address {
+                    IpAddr::V4(_) => QueryType::A,
+                    IpAddr::V6(_) => QueryType::AAAA,
+                };
+                self.to_record(&qname, &qtype, instance)
+            }));
+        }
+        records.extend(self.browsing_records(&self.zone, self.ttl));
+        records.push(self.soa.clone());
+        records
+    }
+
+    /// The addresses of every service, named `<service>.<domain>` rather
+    /// than under the zone, as published on the local link
+    pub(crate) fn local_records(&self, domain: &str, ttl: u32) -> Vec<Record> {
+        let mut records = Vec::new();
+        for (name, service) in &self.services {
+            let qname = format!("{}.{}", name, domain);
+            records.extend(
+                service
+                    .instances
+                    .iter()
+                    .map(|instance| match instance.address {
+                        IpAddr::V4(addr) => Record::new_type_a(qname.clone(), addr, ttl),
+                        IpAddr::V6(addr) => Record::new_type_aaaa(qname.clone(), addr, ttl),
+                    }),
+            );
+        }
+        records.extend(self.browsing_records(domain, ttl));
+        records
+    }
+
+    /// The DNS-SD records of the browsable services under `domain` (RFC 6763):
+    /// the service types listed at `_services._dns-sd._udp`, the instances
+    /// of every type, and the SRV and TXT records of each instance, which
+    /// runs on the addresses of `<service>.<domain>`. Services without
+    /// instances are left out, there is nothing to connect to.
+    fn browsing_records(&self, domain: &str, ttl: u32) -> Vec<Record> {
+        let mut types: BTreeMap<&str, Vec<(&str, &DnsSd)>> = BTreeMap::new();
+        for (name, service) in &self.services {
+            if let Some(dns_sd) = service
+                .dns_sd
+                .as_ref()
+                .filter(|_| !service.instances.is_empty())
+            {
+                types
+                    .entry(&dns_sd.service_type)
+                    .or_default()
+                    .push((name, dns_sd));
+            }
+        }
+
+        let ptr = |name: String, host: String| Record::PTR {
+            name,
+            class: Class::IN,
+            host,
+            ttl,
+        };
+        let mut records: Vec<Record> = types
+            .keys()
+            .map(|service_type| {
+                ptr(
+                    format!("_services._dns-sd._udp.{}", domain),
+                    format!("{}.{}", service_type, domain),
+                )
+            })
+            .collect();
+        for (service_type, instances) in types {
+            let type_name = format!("{}.{}", service_type, domain);
+            records.extend(
+                instances
+                    .iter()
+                    .map(|(name, _)| ptr(type_name.clone(), format!("{}.{}", name, type_name))),
+            );
+            for (name, dns_sd) in instances {
+                let instance = format!("{}.{}", name, type_name);
+                records.push(Record::SRV {
+                    name: instance.clone(),
+                    class: Class::IN,
+                    ttl,
+                    priority: 0,
+                    weight: 0,
+                    port: dns_sd.port,
+                    target: format!("{}.{}", name, domain),
+                });
+                // Even without pairs there is a TXT record, of one empty
+                // string (RFC 6763 6.1)
+                let data = match dns_sd.txt.is_empty() {
+                    true => vec![String::new()],
+                    false => dns_sd.txt.clone(),
+                };
+                records.push(Record::TXT {
+                    name: instance,
+                    class: Class::IN,
+                    data,
+                    ttl,
+                });
+            }
+        }
+        records
+    }
+
+    /// The zone has no configured name servers, its primary is the SOA one.
+    /// Secondaries refuse zones without an NS record at the apex.
+    fn ns(&self) -> Record {
+        let host = match &self.soa {
+            Record::SOA { mname, .. } => mname.clone(),
+            _ => unreachable!("the registry SOA is always a SOA record"),
+        };
+
+        Record::NS {
+            name: self.zone.clone(),
+            class: Class::IN,
+            host,
+            ttl: self.ttl,
+        }
+    }
+
+    /// Whether `instance` has an address of the family asked for
+    fn answers(qtype: &QueryType, instance: &Instance) -> bool {
+        matches!(
+            (qtype, instance.address),
+            (QueryType::A, IpAddr::V4(_)) | (QueryType::AAAA, IpAddr::V6(_))
+        )
+    }
+
+    fn to_record(&self, qname: &str, qtype: &QueryType, instance: &Instance) -> Option<Record> {
+        match (qtype, instance.address) {
+            (QueryType::A, IpAddr::V4(addr)) => {
+                Some(Record::new_type_a(qname.to_owned(), addr, self.ttl))
+            }
+            (QueryType::AAAA, IpAddr::V6(addr)) => {
+                Some(Record::new_type_aaaa(qname.to_owned(), addr, self.ttl))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use super::Registry;
+    use crate::core::config::{DefaultsConfig, RegistryConfig};
+    use crate::core::dns::message::{Class, QueryType, Record};
+    use crate::core::dns::zone::Lookup;
+    use crate::core::locality::Locality;
+
+    fn registry(service: &str) -> Registry {
+        let config: RegistryConfig = toml::from_str(&format!(
+            r#"
+            zone = "svc.internal"
+            [[services]]
+            name = "api"
+            {}
+            instances = [
+                {{ address = "10.0.0.1", region = "eu", zone = "eu-a" }},
+                {{ address = "10.0.0.2", weight = 0, region = "eu", zone = "eu-b" }},
+                {{ address = "10.0.0.3", region = "us", zone = "us-a" }},
+                {{ address = "fd00::1" }},
+            ]
+            "#,
+            service
+        ))
+        .unwrap();
+        Registry::from_config(&config, &DefaultsConfig::default())
+    }
+
+    fn addresses(registry: &Registry) -> Vec<IpAddr> {
+        addresses_near(registry, None)
+    }
+
+    fn addresses_near(registry: &Registry, client: Option<&Locality>) -> Vec<IpAddr> {
+        match registry.lookup("api.svc.internal", &QueryType::A, client) {
+            Lookup::Answer(answers) => answers
+                .into_iter()
+                .map(|record| match record {
+                    Record::A { addr, .. } => IpAddr::V4(addr),
+                    other => panic!("expected an A record, got {:?}", other),
+                })
+                .collect(),
+            _ => panic!("expected an answer"),
+        }
+    }
+
+    #[test]
+    fn round_robin_rotates_the_addresses() {
+        let registry = registry(r#"order = "round-robin""#);
+
+        let first = addresses(&registry);
+        let second = addresses(&registry);
+
+        assert_eq!(first.len(), 3);
+        assert_eq!(second[..2], first[1..]);
+        assert_eq!(second[2], first[0]);
+        assert_eq!(addresses(&registry)[0], first[2]);
+    }
+
+    #[test]
+    fn weighted_keeps_weight_zero_last_and_caps_answers() {
+        let weighted = registry(r#"order = "weighted""#);
+        for _ in 0..20 {
+            assert_eq!(
+                addresses(&weighted)[2],
+                "10.0.0.2".parse::<IpAddr>().unwrap()
+            );
+        }
+
+        let capped = registry("order = \"weighted\"\nmax_answers = 2");
+        for _ in 0..20 {
+            let answers = addresses(&capped);
+            assert_eq!(answers.len(), 2);
+            assert!(!answers.contains(&"10.0.0.2".parse().unwrap()));
+        }
+    }
+
+    #[test]
+    fn closest_instances_come_first() {
+        let registry = registry(r#"order = "round-robin""#);
+        let client = Locality {
+            region: Some("eu".to_owned()),
+            zone: Some("eu-b".to_owned()),
+        };
+
+        for _ in 0..3 {
+            let answers = addresses_near(&registry, Some(&client));
+            assert_eq!(
+                answers,
+                ["10.0.0.2", "10.0.0.1", "10.0.0.3"].map(|a| a.parse::<IpAddr>().unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn drained_instances_stay_last_near_the_client() {
+        let registry = registry(r#"order = "weighted""#);
+        let client = Locality {
+            region: Some("eu".to_owned()),
+            zone: Some("eu-b".to_owned()),
+        };
+
+        // 10.0.0.2 is the only instance of the zone, but has weight 0
+        for _ in 0..10 {
+            let answers = addresses_near(&registry, Some(&client));
+            assert_eq!(
+                answers,
+                ["10.0.0.1", "10.0.0.3", "10.0.0.2"].map(|a| a.parse::<IpAddr>().unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn browses_services_with_dns_sd() {
+        let registry =
+            registry(r#"dns_sd = { type = "_HTTP._tcp", port = 8080, txt = ["path=/v1"] }"#);
+        let answers = |qname: &str, qtype: QueryType| match registry.lookup(qname, &qtype, None) {
+            Lookup::Answer(answers) => answers,
+            _ => panic!("expected an answer for {}", qname),
+        };
+
+        assert_eq!(
+            answers("_services._dns-sd._udp.svc.internal", QueryType::PTR),
+            vec![Record::PTR {
+                name: "_services._dns-sd._udp.svc.internal".to_owned(),
+                class: Class::IN,
+                host: "_http._tcp.svc.internal".to_owned(),
+                ttl: 300,
+            }]
+        );
+        match &answers("_http._tcp.svc.internal", QueryType::PTR)[..] {
+            [Record::PTR { host, .. }] => assert_eq!(host, "api._http._tcp.svc.internal"),
+            other => panic!("expected the instance, got {:?}", other),
+        }
+        match &answers("api._http._tcp.svc.internal", QueryType::SRV)[..] {
+            [Record::SRV { port, target, .. }] => {
+                assert_eq!((*port, target.as_str()), (8080, "api.svc.internal"))
+            }
+            other => panic!("expected where it runs, got {:?}", other),
+        }
+        match &answers("api._http._tcp.svc.internal", QueryType::TXT)[..] {
+            [Record::TXT { data, .. }] => assert_eq!(data, &["path=/v1"]),
+            other => panic!("expected its pairs, got {:?}", other),
+        }
+        assert_eq!(
+            registry.types("api._http._tcp.svc.internal"),
+            Some(vec![QueryType::SRV, QueryType::TXT])
+        );
+        assert_eq!(registry.types("_tcp.svc.internal"), Some(Vec::new()));
+        assert_eq!(registry.types("_ftp._tcp.svc.internal"), None);
+
+        // The names in between exist, others under the types do not
+        assert!(matches!(
+            registry.lookup("_tcp.svc.internal", &QueryType::PTR, None),
+            Lookup::NoData(_)
+        ));
+        assert!(matches!(
+            registry.lookup("_ftp._tcp.svc.internal", &QueryType::PTR, None),
+            Lookup::NxDomain(_)
+        ));
+        assert_eq!(registry.transfer().len(), 2 + 4 + 4 + 1);
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::{Arc, Mutex};
+
+use tracing::{error, info, warn};
+
+use super::config::{Cli, Config, ConfigError};
+use super::dns::responder::Responder;
+use super::dns::tls::Certificates;
+use super::logging::Logging;
+
+/// Re-reads the configuration and swaps it into the responder, keeping the
+/// running configuration whenever the new one does not validate.
+pub struct Reloader {
+    cli: Cli,
+    current: Mutex<Config>,
+    responder: Arc<Responder>,
+    /// The certificate of the encrypted listeners, when there are some
+    certificates: Option<Arc<Certificates>>,
+    logging: Logging,
+}
+
+impl Reloader {
+    pub fn new(
+        cli: Cli,
+        config: Config,
+        responder: Arc<Responder>,
+        certificates: Option<Arc<Certificates>>,
+        logging: Logging,
+    ) -> Reloader {
+        Reloader {
+            cli,
+            current: Mutex::new(config),
+            responder,
+            certificates,
+            logging,
+        }
+    }
+
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let config = Config::load(&self.cli)?;
+        let certified = match (&self.certificates, &config.tls) {
+            (Some(_), Some(tls)) => Some(
+                Certificates::load(tls)
+                    .map_err(|e| ConfigError::Invalid(vec![format!("tls: {}", e)]))?,
+            ),
+            _ => None,
+        };
+        self.responder.reload(&config)?;
+        if let (Some(certificates), Some(certified)) = (&self.certificates, certified) {
+            certificates.replace(certified);
+        }
+        self.logging.set_level(config.log_level);
+
+        let mut current = self.current.lock().unwrap();
+        // Listener ACLs are part of the responder, only the sockets are fixed
+        let sockets_changed = config.listen.udp != current.listen.udp
+            || config.listen.tcp != current.listen.tcp
+            || config.listen.tls != current.listen.tls
+            || config.listen.https != current.listen.https
+            || config.listen.quic != current.listen.quic
+            || config.listen.reuse_port != current.listen.reuse_port;
+        if sockets_changed || config.admin != current.admin || config.mdns != current.mdns {
+            warn!("listen addresses changed, they are only applied after a restart");
+        }
+        if config.log_format != current.log_format {
+            warn!("log format changed, it is only applied after a restart");
+        }
+        if config.updates != current.updates {
+            warn!("updates file changed, it is only applied after a restart");
+        }
+        *current = config;
+
+        Ok(())
+    }
+
+    /// Reloads on a blocking thread, reading the files and rebuilding the
+    /// zones would otherwise stall the queries sharing the runtime worker
+    pub async fn reload_in_background(self: &Arc<Self>) -> Result<(), ConfigError> {
+        let reloader = self.clone();
+        match tokio::task::spawn_blocking(move || reloader.reload()).await {
+            Ok(reloaded) => reloaded,
+            Err(e) => Err(ConfigError::Invalid(vec![format!("reload: {}", e)])),
+        }
+    }
+
+    /// Reloads every time the process receives SIGHUP
+    #[cfg(unix)]
+    pub async fn watch_sighup(self: Arc<Self>) -> std::io::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        while hangup.recv().await.is_some() {
+            match self.reload_in_background().await {
+                Ok(()) => info!("configuration reloaded"),
+                Err(e) => error!("reload failed, keeping the previous configuration: {}", e),
+            }
+        }
+
+        Ok(())
+    }
+}
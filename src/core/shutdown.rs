@@ -0,0 +1,94 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Handle shared by the listeners to stop accepting work and to track the
+/// tasks that are still answering, so they can be drained before exiting.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    /// Completes once shutdown has been triggered
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Spawns a task that shutdown waits for
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tracker.spawn(task)
+    }
+
+    /// Waits up to `deadline` for the tracked tasks, returning how many
+    /// were still running when it expired.
+    pub async fn drain(&self, deadline: Duration) -> usize {
+        self.tracker.close();
+        match timeout(deadline, self.tracker.wait()).await {
+            Ok(()) => 0,
+            Err(_) => self.tracker.len(),
+        }
+    }
+
+    /// Triggers shutdown on SIGTERM or SIGINT
+    #[cfg(unix)]
+    pub async fn trigger_on_signal(self) -> std::io::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = interrupt.recv() => {},
+        }
+
+        self.trigger();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Shutdown;
+
+    #[tokio::test]
+    async fn drain_waits_for_tasks() {
+        let shutdown = Shutdown::new();
+        let waiting = shutdown.clone();
+        shutdown.spawn(async move {
+            waiting.triggered().await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        });
+
+        shutdown.trigger();
+        assert_eq!(shutdown.drain(Duration::from_secs(1)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_after_deadline() {
+        let shutdown = Shutdown::new();
+        shutdown.spawn(tokio::time::sleep(Duration::from_secs(60)));
+
+        shutdown.trigger();
+        assert_eq!(shutdown.drain(Duration::from_millis(10)).await, 1);
+    }
+}
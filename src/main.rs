@@ -1,10 +1,206 @@
 mod core;
 
-use crate::core::dns::udp_listener::UdpListener;
+use std::io::Error;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
 
+use crate::core::admin::AdminServer;
+use crate::core::config::{Cli, Config};
+use crate::core::dns::https_listener::HttpsListener;
+use crate::core::dns::mdns::MdnsResponder;
+use crate::core::dns::quic_listener::QuicListener;
+use crate::core::dns::responder::Responder;
+use crate::core::dns::socket::Sockets;
+use crate::core::dns::tcp_listener::TcpListener;
+use crate::core::dns::tls::Certificates;
+use crate::core::dns::tls_listener::TlsListener;
+use crate::core::dns::udp_listener::UdpListener;
+use crate::core::dns::update::Updates;
+use crate::core::logging::Logging;
+use crate::core::reload::Reloader;
+use crate::core::shutdown::Shutdown;
 
 #[tokio::main]
-async fn main() {
-    let listener = UdpListener::new();
-    let _ = listener.start("0.0.0.0:1053").await;
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    // Logging is configured by the file, so problems with it can only
+    // go to stderr
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let logging = Logging::init(config.log_level, config.log_format);
+
+    // Changes of dynamic updates saved before the restart
+    let updates = match &config.updates {
+        Some(updates) => match Updates::load(&updates.path).await {
+            Ok(updates) => updates,
+            Err(e) => {
+                error!("could not read the dynamic updates: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Updates::default(),
+    };
+    let shutdown = Shutdown::new();
+    let responder = match Responder::with_updates(&config, updates, shutdown.clone()) {
+        Ok(responder) => Arc::new(responder),
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let sockets = match Sockets::bind(&config.listen) {
+        Ok(sockets) => sockets,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Only loaded for the listeners needing it, the files are checked
+    // with the rest of the configuration
+    let encrypted = !config.listen.tls.is_empty()
+        || !config.listen.https.is_empty()
+        || !config.listen.quic.is_empty();
+    let certificates = match (&config.tls, encrypted) {
+        (Some(tls), true) => match Certificates::from_config(tls) {
+            Ok(certificates) => Some(certificates),
+            Err(e) => {
+                error!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => None,
+    };
+
+    info!(
+        "listening on udp {:?}, tcp {:?}, tls {:?}, https {:?} and quic {:?} with {} socket(s) each",
+        config.listen.udp,
+        config.listen.tcp,
+        config.listen.tls,
+        config.listen.https,
+        config.listen.quic,
+        config.listen.reuse_port
+    );
+
+    let mdns = match &config.mdns {
+        Some(mdns) => match MdnsResponder::bind(mdns, responder.clone(), shutdown.clone()) {
+            Ok(mdns) => Some(Arc::new(mdns)),
+            Err(e) => {
+                error!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    #[cfg(unix)]
+    tokio::spawn(shutdown.clone().trigger_on_signal());
+
+    tokio::spawn(responder.clone().refresh_secondaries(shutdown.clone()));
+    if config.updates.is_some() {
+        // Tracked for the last changes to be saved before exiting
+        shutdown.spawn(responder.clone().save_updates(shutdown.clone()));
+    }
+
+    // Every socket gets its own accept loop, all sharing the same responder
+    let udp = Arc::new(UdpListener::new(responder.clone(), shutdown.clone()));
+    let tcp = Arc::new(TcpListener::new(responder.clone(), shutdown.clone()));
+    let mut listeners = JoinSet::new();
+    for socket in sockets.udp {
+        let udp = udp.clone();
+        listeners.spawn(async move { udp.serve(socket).await });
+    }
+    for listener in sockets.tcp {
+        let tcp = tcp.clone();
+        listeners.spawn(async move { tcp.serve(listener).await });
+    }
+    if let Some(certificates) = &certificates {
+        let tls = Arc::new(TlsListener::new(
+            responder.clone(),
+            certificates,
+            shutdown.clone(),
+        ));
+        for listener in sockets.tls {
+            let tls = tls.clone();
+            listeners.spawn(async move { tls.serve(listener).await });
+        }
+        let https = Arc::new(HttpsListener::new(
+            responder.clone(),
+            certificates,
+            shutdown.clone(),
+        ));
+        for listener in sockets.https {
+            let https = https.clone();
+            listeners.spawn(async move { https.serve(listener).await });
+        }
+        let quic = Arc::new(QuicListener::new(
+            responder.clone(),
+            certificates,
+            shutdown.clone(),
+        ));
+        for socket in sockets.quic {
+            let quic = quic.clone();
+            listeners.spawn(async move { quic.serve(socket).await });
+        }
+    }
+
+    if let Some(mdns) = mdns {
+        listeners.spawn(mdns.serve());
+    }
+
+    let admin_listen = config.admin.as_ref().map(|admin| admin.listen);
+    let drain_timeout = Duration::from_secs(config.shutdown.drain_timeout);
+    let metrics = responder.metrics().clone();
+    let reloader = Arc::new(Reloader::new(cli, config, responder, certificates, logging));
+
+    #[cfg(unix)]
+    tokio::spawn(reloader.clone().watch_sighup());
+    if let Some(addr) = admin_listen {
+        let admin = AdminServer::new(reloader, metrics, shutdown.clone());
+        tokio::spawn(async move {
+            if let Err(e) = admin.start(addr).await {
+                error!("admin API on {} stopped: {}", addr, e);
+            }
+        });
+    }
+
+    // Either a listener fails or we are asked to stop, in both cases
+    // stop the others and let the queries being answered finish.
+    let mut result = Ok(());
+    while let Some(joined) = listeners.join_next().await {
+        let failed = match joined {
+            Ok(Ok(())) => continue,
+            Ok(Err(e)) => e,
+            Err(e) => Error::other(format!("listener panicked: {}", e)),
+        };
+        shutdown.trigger();
+        result = Err(failed);
+    }
+
+    let pending = shutdown.drain(drain_timeout).await;
+    if pending > 0 {
+        warn!(
+            "{} queries or notifications still in flight after {:?}, exiting anyway",
+            pending, drain_timeout
+        );
+    }
+
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
 }